@@ -0,0 +1,604 @@
+use byteorder::{
+    ByteOrder,
+    LittleEndian,
+};
+
+use serde_derive::Serialize;
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    mem,
+};
+
+use crate::resync::Framing;
+
+const BAM_MAGIC: [u8; 4] = [0x42, 0x41, 0x4d, 0x01];
+
+// block_size, then refID, pos, l_read_name, mapq, bin, n_cigar_op, flag,
+// l_seq, next_refID, next_pos and tlen
+const RECORD_HEADER_SIZE: usize = 36;
+
+// Larger records exist, but considering them while resynchronizing would make
+// it both slower and less reliable
+const MAX_RESYNC_RECORD_SIZE: i32 = 1 << 24;
+
+// Reference sequence names are buffered to be checked, and are way shorter in
+// practice
+const MAX_REFERENCE_NAME_SIZE: i32 = 1 << 16;
+
+// Errors found in the payload of bam and bcf files, whose kinds are specific
+// to each format
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FormatErrorKind {
+    BamMagic,
+    HeaderTextLength,
+    BamHeaderText,
+    ReferencesCount,
+    ReferenceNameLength,
+    ReferenceName,
+    ReferenceLength,
+    BlockSize,
+    ReadNameLength,
+    ReadName,
+    SequenceLength,
+    ReferenceId,
+    NextReferenceId,
+    BamTruncation,
+    BcfMagic,
+    BcfHeaderText,
+    SharedLength,
+    IndividualLength,
+    ChromosomeId,
+    SamplesCount,
+    BcfTruncation,
+}
+
+impl fmt::Display for FormatErrorKind {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(match self {
+            FormatErrorKind::BamMagic => "invalid bam magic",
+            FormatErrorKind::HeaderTextLength => "invalid header text length",
+            FormatErrorKind::BamHeaderText => "invalid header text",
+            FormatErrorKind::ReferencesCount => "invalid number of reference sequences",
+            FormatErrorKind::ReferenceNameLength => "invalid reference sequence name length",
+            FormatErrorKind::ReferenceName => "invalid reference sequence name",
+            FormatErrorKind::ReferenceLength => "invalid reference sequence length",
+            FormatErrorKind::BlockSize => "invalid alignment block size",
+            FormatErrorKind::ReadNameLength => "invalid read name length",
+            FormatErrorKind::ReadName => "invalid read name",
+            FormatErrorKind::SequenceLength => "invalid sequence length",
+            FormatErrorKind::ReferenceId => "invalid reference sequence id",
+            FormatErrorKind::NextReferenceId => "invalid next reference sequence id",
+            FormatErrorKind::BamTruncation => "truncated bam payload",
+            FormatErrorKind::BcfMagic => "invalid bcf magic",
+            FormatErrorKind::BcfHeaderText => "invalid bcf header text",
+            FormatErrorKind::SharedLength => "invalid shared data length",
+            FormatErrorKind::IndividualLength => "invalid individual data length",
+            FormatErrorKind::ChromosomeId => "invalid chromosome id",
+            FormatErrorKind::SamplesCount => "invalid number of samples",
+            FormatErrorKind::BcfTruncation => "truncated bcf payload",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FormatError {
+    // bgzf virtual offset of the header field or alignment (or bcf) record
+    pub virtual_offset: u64,
+    pub kind: FormatErrorKind,
+}
+
+enum State {
+    Magic,
+    HeaderTextLength,
+    HeaderText {
+        remaining: u64,
+        line_start: bool,
+        padding: bool,
+    },
+    ReferencesCount,
+    ReferenceNameLength {
+        remaining_references: u32,
+    },
+    ReferenceName {
+        remaining_references: u32,
+        length: usize,
+    },
+    ReferenceLength {
+        remaining_references: u32,
+    },
+    RecordHeader,
+    RecordReadName {
+        length: usize,
+        remaining: u64,
+    },
+    RecordData {
+        remaining: u64,
+    },
+    // after a gap, bytes are held in pending until a record is found
+    Searching,
+    // after an error which makes the rest of the payload impossible to parse
+    Lost,
+}
+
+enum Plausibility {
+    Plausible(usize),
+    Implausible,
+    Incomplete,
+}
+
+struct Block {
+    position: u64,
+    inflated_position: u64,
+}
+
+// Checks the inflated payload of a file against the specification of its
+// format, and follows its records to resynchronize it
+pub trait Validate: Framing {
+    // Each non-empty block, with its position in the bgzf file
+    fn feed(&mut self, block_position: u64, bytes: &[u8]);
+    fn errors(&self) -> &[FormatError];
+    fn into_errors(self: Box<Self>) -> Vec<FormatError>;
+    // Some blocks could not be inflated, so the payload can't be followed anymore
+    fn gap(&mut self);
+    fn finish(&mut self);
+}
+
+// Validates the inflated payload of a bam file against the bam specification,
+// block after block, without ever holding more than a record header in memory,
+// or than a record after a gap, where it goes on from the next record found
+pub struct Validator {
+    state: State,
+    references_count: i32,
+    references_lengths: Vec<i32>,
+    pending: Vec<u8>,
+    blocks: VecDeque<Block>,
+    inflated_position: u64,
+    item_position: u64,
+    errors: Vec<FormatError>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self {
+            state: State::Magic,
+            references_count: 0,
+            references_lengths: vec![],
+            pending: vec![],
+            blocks: VecDeque::new(),
+            inflated_position: 0,
+            item_position: 0,
+            errors: vec![],
+        }
+    }
+
+    fn virtual_offset(&self, inflated_position: u64) -> u64 {
+        match self.blocks.iter().rev().find(|block| block.inflated_position <= inflated_position) {
+            Some(block) => (block.position << 16) | (inflated_position - block.inflated_position),
+            None => 0,
+        }
+    }
+
+    fn error(&mut self, kind: FormatErrorKind) {
+        self.errors.push(FormatError {
+            virtual_offset: self.virtual_offset(self.item_position),
+            kind,
+        });
+    }
+
+    fn lose(&mut self, kind: FormatErrorKind) {
+        self.error(kind);
+        self.state = State::Lost;
+    }
+
+    fn start_item(&mut self, state: State) {
+        self.state = state;
+        self.item_position = self.inflated_position;
+        self.pending.clear();
+        while self.blocks.len() > 1 && self.blocks[1].inflated_position <= self.item_position {
+            self.blocks.pop_front();
+        }
+    }
+
+    // Moves bytes to the pending buffer until it holds size bytes
+    fn fill(&mut self, bytes: &[u8], offset: &mut usize, size: usize) -> bool {
+        let missing = size - self.pending.len();
+        let available = missing.min(bytes.len() - *offset);
+        self.pending.extend_from_slice(&bytes[*offset..*offset + available]);
+        *offset += available;
+        self.inflated_position += available as u64;
+        self.pending.len() == size
+    }
+
+    fn skip(&mut self, bytes: &[u8], offset: &mut usize, remaining: u64) -> u64 {
+        let available = remaining.min((bytes.len() - *offset) as u64);
+        *offset += available as usize;
+        self.inflated_position += available;
+        remaining - available
+    }
+
+    fn check_record_header(&mut self) {
+        let block_size = LittleEndian::read_i32(&self.pending[0..4]);
+        let reference_id = LittleEndian::read_i32(&self.pending[4..8]);
+        let read_name_length = self.pending[12] as usize;
+        let cigar_operations_count = LittleEndian::read_u16(&self.pending[16..18]) as u64;
+        let sequence_length = LittleEndian::read_i32(&self.pending[20..24]);
+        let next_reference_id = LittleEndian::read_i32(&self.pending[24..28]);
+
+        if reference_id < -1 || reference_id >= self.references_count {
+            self.error(FormatErrorKind::ReferenceId);
+        }
+        if next_reference_id < -1 || next_reference_id >= self.references_count {
+            self.error(FormatErrorKind::NextReferenceId);
+        }
+        if read_name_length < 1 {
+            self.lose(FormatErrorKind::ReadNameLength);
+            return;
+        }
+        if sequence_length < 0 {
+            self.lose(FormatErrorKind::SequenceLength);
+            return;
+        }
+        let sequence_length = sequence_length as u64;
+        let minimum_size = (RECORD_HEADER_SIZE - 4 + read_name_length) as u64 + 4 * cigar_operations_count + sequence_length.div_ceil(2) + sequence_length;
+        if block_size < 0 || (block_size as u64) < minimum_size {
+            self.lose(FormatErrorKind::BlockSize);
+            return;
+        }
+        self.state = State::RecordReadName {
+            length: read_name_length,
+            remaining: block_size as u64 - (RECORD_HEADER_SIZE - 4 + read_name_length) as u64,
+        };
+    }
+
+    fn plausible_position(&self, reference_id: i32, position: i32) -> bool {
+        match reference_id {
+            -1 => position == -1,
+            reference_id if reference_id >= 0 && (reference_id as usize) < self.references_lengths.len() =>
+                position >= -1 && position <= self.references_lengths[reference_id as usize],
+            _ => false,
+        }
+    }
+
+    // Tells whether bytes could be the beginning of a record from this bam file
+    fn plausible_record(&self, bytes: &[u8]) -> Plausibility {
+        if bytes.len() < RECORD_HEADER_SIZE {
+            return Plausibility::Incomplete;
+        }
+        let block_size = LittleEndian::read_i32(&bytes[0..4]);
+        let reference_id = LittleEndian::read_i32(&bytes[4..8]);
+        let position = LittleEndian::read_i32(&bytes[8..12]);
+        let read_name_length = bytes[12] as usize;
+        let cigar_operations_count = LittleEndian::read_u16(&bytes[16..18]) as u64;
+        let sequence_length = LittleEndian::read_i32(&bytes[20..24]);
+        let next_reference_id = LittleEndian::read_i32(&bytes[24..28]);
+        let next_position = LittleEndian::read_i32(&bytes[28..32]);
+
+        if !self.plausible_position(reference_id, position) ||
+           !self.plausible_position(next_reference_id, next_position) ||
+           read_name_length < 2 ||
+           sequence_length < 0 {
+            return Plausibility::Implausible;
+        }
+        let sequence_length = sequence_length as u64;
+        let minimum_size = (RECORD_HEADER_SIZE - 4 + read_name_length) as u64 + 4 * cigar_operations_count + sequence_length.div_ceil(2) + sequence_length;
+        if block_size < 0 || (block_size as u64) < minimum_size || block_size > MAX_RESYNC_RECORD_SIZE {
+            return Plausibility::Implausible;
+        }
+
+        if bytes.len() < RECORD_HEADER_SIZE + read_name_length {
+            return Plausibility::Incomplete;
+        }
+        let read_name = &bytes[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + read_name_length];
+        // [!-?A-~]{1,254}, according to the sam specification
+        if read_name[read_name_length - 1] != 0 ||
+           !read_name[..read_name_length - 1].iter().all(|&byte| (b'!'..=b'~').contains(&byte) && byte != b'@') {
+            return Plausibility::Implausible;
+        }
+
+        Plausibility::Plausible(4 + block_size as usize)
+    }
+
+    // Follows the payload from the current state
+    fn process(&mut self, bytes: &[u8]) {
+        let mut offset = 0;
+        while offset < bytes.len() {
+            match self.state {
+                State::Magic => {
+                    if self.fill(bytes, &mut offset, BAM_MAGIC.len()) {
+                        if self.pending[..] != BAM_MAGIC {
+                            self.lose(FormatErrorKind::BamMagic);
+                        } else {
+                            self.start_item(State::HeaderTextLength);
+                        }
+                    }
+                },
+                State::HeaderTextLength => {
+                    if self.fill(bytes, &mut offset, 4) {
+                        let header_text_length = LittleEndian::read_i32(&self.pending);
+                        if header_text_length < 0 {
+                            self.lose(FormatErrorKind::HeaderTextLength);
+                        } else {
+                            self.start_item(State::HeaderText {
+                                remaining: header_text_length as u64,
+                                line_start: true,
+                                padding: false,
+                            });
+                        }
+                    }
+                },
+                State::HeaderText { remaining, mut line_start, mut padding } => {
+                    let available = remaining.min((bytes.len() - offset) as u64) as usize;
+                    let mut valid = true;
+                    // header lines all start with '@', and the text may be
+                    // padded with NUL bytes, but nothing may follow them
+                    for &byte in &bytes[offset..offset + available] {
+                        if byte == 0 {
+                            padding = true;
+                        } else if padding || (line_start && byte != b'@') {
+                            valid = false;
+                            break;
+                        } else {
+                            line_start = byte == b'\n';
+                        }
+                    }
+                    if !valid {
+                        self.lose(FormatErrorKind::BamHeaderText);
+                        continue;
+                    }
+                    let remaining = self.skip(bytes, &mut offset, remaining);
+                    if remaining == 0 {
+                        self.start_item(State::ReferencesCount);
+                    } else {
+                        self.state = State::HeaderText {
+                            remaining,
+                            line_start,
+                            padding,
+                        };
+                    }
+                },
+                State::ReferencesCount => {
+                    if self.fill(bytes, &mut offset, 4) {
+                        self.references_count = LittleEndian::read_i32(&self.pending);
+                        if self.references_count < 0 {
+                            self.lose(FormatErrorKind::ReferencesCount);
+                        } else if self.references_count == 0 {
+                            self.start_item(State::RecordHeader);
+                        } else {
+                            self.start_item(State::ReferenceNameLength {
+                                remaining_references: self.references_count as u32,
+                            });
+                        }
+                    }
+                },
+                State::ReferenceNameLength { remaining_references } => {
+                    if self.fill(bytes, &mut offset, 4) {
+                        let reference_name_length = LittleEndian::read_i32(&self.pending);
+                        if !(1..=MAX_REFERENCE_NAME_SIZE).contains(&reference_name_length) {
+                            self.lose(FormatErrorKind::ReferenceNameLength);
+                        } else {
+                            self.start_item(State::ReferenceName {
+                                remaining_references,
+                                length: reference_name_length as usize,
+                            });
+                        }
+                    }
+                },
+                State::ReferenceName { remaining_references, length } => {
+                    if self.fill(bytes, &mut offset, length) {
+                        if self.pending.iter().position(|&byte| byte == 0) != Some(length - 1) {
+                            self.error(FormatErrorKind::ReferenceName);
+                        }
+                        self.start_item(State::ReferenceLength {
+                            remaining_references,
+                        });
+                    }
+                },
+                State::ReferenceLength { remaining_references } => {
+                    if self.fill(bytes, &mut offset, 4) {
+                        let reference_length = LittleEndian::read_i32(&self.pending);
+                        if reference_length < 0 {
+                            self.error(FormatErrorKind::ReferenceLength);
+                        }
+                        self.references_lengths.push(reference_length);
+                        if remaining_references > 1 {
+                            self.start_item(State::ReferenceNameLength {
+                                remaining_references: remaining_references - 1,
+                            });
+                        } else {
+                            self.start_item(State::RecordHeader);
+                        }
+                    }
+                },
+                State::RecordHeader => {
+                    if self.fill(bytes, &mut offset, RECORD_HEADER_SIZE) {
+                        self.check_record_header();
+                    }
+                },
+                State::RecordReadName { length, remaining } => {
+                    if self.fill(bytes, &mut offset, RECORD_HEADER_SIZE + length) {
+                        if self.pending[RECORD_HEADER_SIZE..].iter().position(|&byte| byte == 0) != Some(length - 1) {
+                            self.error(FormatErrorKind::ReadName);
+                        }
+                        if remaining == 0 {
+                            self.start_item(State::RecordHeader);
+                        } else {
+                            self.state = State::RecordData {
+                                remaining,
+                            };
+                        }
+                    }
+                },
+                State::RecordData { remaining } => {
+                    let remaining = self.skip(bytes, &mut offset, remaining);
+                    if remaining == 0 {
+                        self.start_item(State::RecordHeader);
+                    } else {
+                        self.state = State::RecordData {
+                            remaining,
+                        };
+                    }
+                },
+                State::Searching => {
+                    self.pending.extend_from_slice(&bytes[offset..]);
+                    self.inflated_position += (bytes.len() - offset) as u64;
+                    offset = bytes.len();
+                    self.search(false);
+                },
+                State::Lost => {
+                    self.inflated_position += (bytes.len() - offset) as u64;
+                    offset = bytes.len();
+                },
+            }
+        }
+    }
+
+    // Goes on from the next record found in the bytes held since the gap,
+    // which holds fewer bytes than the largest record it looks for
+    fn search(&mut self, finishing: bool) {
+        match self.next_record(&self.pending, finishing) {
+            Ok(record_offset) => {
+                let bytes = mem::take(&mut self.pending);
+                self.inflated_position -= (bytes.len() - record_offset) as u64;
+                self.start_item(State::RecordHeader);
+                self.process(&bytes[record_offset..]);
+            },
+            Err(record_offset) => {
+                self.pending.drain(..record_offset);
+                let search_position = self.inflated_position - self.pending.len() as u64;
+                while self.blocks.len() > 1 && self.blocks[1].inflated_position <= search_position {
+                    self.blocks.pop_front();
+                }
+            },
+        }
+    }
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Validate for Validator {
+    fn feed(&mut self, block_position: u64, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        // errors can't be found anymore, so no virtual offsets are needed
+        if matches!(self.state, State::Lost) {
+            self.inflated_position += bytes.len() as u64;
+            return;
+        }
+        self.blocks.push_back(Block {
+            position: block_position,
+            inflated_position: self.inflated_position,
+        });
+        self.process(bytes);
+    }
+
+    fn errors(&self) -> &[FormatError] {
+        &self.errors
+    }
+
+    fn into_errors(self: Box<Self>) -> Vec<FormatError> {
+        self.errors
+    }
+
+    // Some blocks could not be inflated: records are looked for from the next
+    // block, but the rest of the header can't be followed anymore
+    fn gap(&mut self) {
+        match self.state {
+            State::Lost => (),
+            State::RecordHeader | State::RecordReadName { .. } | State::RecordData { .. } | State::Searching => {
+                self.state = State::Searching;
+                self.pending.clear();
+            },
+            _ => {
+                self.state = State::Lost;
+                self.pending.clear();
+            },
+        }
+    }
+
+    fn finish(&mut self) {
+        if matches!(self.state, State::Searching) {
+            self.search(true);
+        }
+        match self.state {
+            State::Lost | State::Searching => (),
+            State::RecordHeader if self.pending.is_empty() => (),
+            _ => self.lose(FormatErrorKind::BamTruncation),
+        }
+    }
+}
+
+impl Framing for Validator {
+    fn feed_payload(&mut self, bytes: &[u8]) {
+        self.feed(0, bytes);
+    }
+
+    fn payload_position(&self) -> u64 {
+        self.inflated_position
+    }
+
+    fn is_lost(&self) -> bool {
+        matches!(self.state, State::Lost)
+    }
+
+    fn in_records(&self) -> bool {
+        matches!(self.state, State::RecordHeader | State::RecordReadName { .. } | State::RecordData { .. })
+    }
+
+    // the resynchronizer looks for records by itself
+    fn lose_track(&mut self) {
+        self.state = State::Lost;
+        self.pending.clear();
+    }
+
+    // Either because the bytes belong to a record which is not complete yet,
+    // or because the header itself is not complete yet
+    fn incomplete_since(&self) -> Option<u64> {
+        match self.state {
+            State::Lost | State::Searching => None,
+            State::RecordHeader if self.pending.is_empty() => None,
+            State::RecordHeader | State::RecordReadName { .. } | State::RecordData { .. } => Some(self.item_position),
+            _ => Some(0),
+        }
+    }
+
+    fn resync(&mut self) {
+        self.start_item(State::RecordHeader);
+    }
+
+    // The beginning of a record is confirmed by the beginning of the next one,
+    // unless there are no more bytes to look at
+    fn next_record(&self, bytes: &[u8], finishing: bool) -> Result<usize, usize> {
+        let mut offset = 0;
+        while offset < bytes.len() {
+            match self.plausible_record(&bytes[offset..]) {
+                Plausibility::Plausible(size) => {
+                    let next_record = if offset + size <= bytes.len() {
+                        self.plausible_record(&bytes[offset + size..])
+                    } else {
+                        Plausibility::Incomplete
+                    };
+                    match next_record {
+                        Plausibility::Implausible => (),
+                        Plausibility::Incomplete if !finishing => break,
+                        Plausibility::Plausible(_) |
+                        Plausibility::Incomplete => return Ok(offset),
+                    }
+                },
+                Plausibility::Implausible => (),
+                Plausibility::Incomplete => if !finishing {
+                    break;
+                },
+            }
+            offset += 1;
+        }
+        Err(offset)
+    }
+}
@@ -0,0 +1,477 @@
+use byteorder::{
+    ByteOrder,
+    LittleEndian,
+};
+
+use std::collections::VecDeque;
+
+use crate::{
+    bam::{
+        FormatError,
+        FormatErrorKind,
+        Validate,
+    },
+    resync::Framing,
+};
+
+const BCF_MAGIC: [u8; 5] = [0x42, 0x43, 0x46, 0x02, 0x02];
+
+// l_shared and l_indiv, then CHROM, POS, rlen, QUAL, n_allele_info and
+// n_fmt_sample
+const RECORD_HEADER_SIZE: usize = 32;
+const SHARED_FIXED_SIZE: u64 = 24;
+
+// typed value of the ID, which is a string, even when missing
+const CHARACTER_TYPE: u8 = 7;
+
+// Larger records exist, but considering them while resynchronizing would make
+// it both slower and less reliable
+const MAX_RESYNC_RECORD_SIZE: u32 = 1 << 24;
+
+// Longer header lines are only kept up to there, which is plenty for the
+// attributes of contig lines, while the columns of the #CHROM line are
+// counted as they go
+const MAX_HEADER_LINE_SIZE: usize = 1 << 16;
+
+// Contig indexes given by IDX may leave some unused, but not that many more
+// than there are contig lines, as each of them takes room for its length
+const MAX_CONTIG_INDEX_GAP: usize = 1 << 10;
+
+enum State {
+    Magic,
+    HeaderTextLength,
+    HeaderText {
+        remaining: u64,
+    },
+    RecordHeader,
+    RecordData {
+        remaining: u64,
+    },
+    // after an error which makes the rest of the payload impossible to parse
+    Lost,
+}
+
+enum Plausibility {
+    Plausible(usize),
+    Implausible,
+    Incomplete,
+}
+
+struct Block {
+    position: u64,
+    inflated_position: u64,
+}
+
+// The header is vcf text, NUL-terminated, which defines the contigs the
+// records refer to and the samples they hold; it is read line by line, as
+// its length may well be corrupted
+struct HeaderText {
+    line: Vec<u8>,
+    tabs_count: usize,
+    first_line: bool,
+    // the text may be padded with NUL bytes, but nothing may follow them,
+    // so that an overlong length is caught at the first record
+    ended: bool,
+    contigs_count: usize,
+    contigs_lengths: Vec<Option<i64>>,
+    samples_count: Option<u32>,
+}
+
+impl HeaderText {
+    fn new() -> Self {
+        Self {
+            line: vec![],
+            tabs_count: 0,
+            first_line: true,
+            ended: false,
+            contigs_count: 0,
+            contigs_lengths: vec![],
+            samples_count: None,
+        }
+    }
+
+    // false as soon as the text is known to be invalid
+    fn feed(&mut self, bytes: &[u8]) -> bool {
+        for &byte in bytes {
+            if self.ended {
+                if byte != 0 {
+                    return false;
+                }
+                continue;
+            }
+            match byte {
+                0 | b'\n' => {
+                    self.ended = byte == 0;
+                    if !self.read_line() {
+                        return false;
+                    }
+                },
+                _ => {
+                    if byte == b'\t' {
+                        self.tabs_count += 1;
+                    }
+                    if self.line.len() < MAX_HEADER_LINE_SIZE {
+                        self.line.push(byte);
+                    }
+                },
+            }
+        }
+        true
+    }
+
+    fn read_line(&mut self) -> bool {
+        let line = std::mem::take(&mut self.line);
+        let tabs_count = std::mem::take(&mut self.tabs_count);
+        if std::mem::replace(&mut self.first_line, false) && !line.starts_with(b"##fileformat=VCF") {
+            return false;
+        }
+        if let Some(contig) = line.strip_prefix(b"##contig=<") {
+            let attribute = |name: &[u8]| contig.split(|&byte| byte == b',' || byte == b'>')
+                .find_map(|field| field.strip_prefix(name))
+                .and_then(|value| std::str::from_utf8(value).ok())
+                .and_then(|value| value.parse::<i64>().ok());
+            self.contigs_count += 1;
+            let index = match attribute(b"IDX=") {
+                Some(index) if index >= 0 && index as usize <= self.contigs_count + MAX_CONTIG_INDEX_GAP => index as usize,
+                Some(_) => return false,
+                None => self.contigs_lengths.len(),
+            };
+            if self.contigs_lengths.len() <= index {
+                self.contigs_lengths.resize(index + 1, None);
+            }
+            self.contigs_lengths[index] = attribute(b"length=");
+        } else if line.starts_with(b"#CHROM") {
+            // CHROM to INFO, then FORMAT and the samples
+            self.samples_count = Some(tabs_count.saturating_sub(8) as u32);
+        }
+        true
+    }
+}
+
+// Validates the inflated payload of a bcf 2.2 file: its header, then the
+// lengths of each record against what they hold, block after block
+pub struct Validator {
+    state: State,
+    // from the contig lines of the header, None for those without length
+    contigs_lengths: Vec<Option<i64>>,
+    samples_count: u32,
+    header_text: HeaderText,
+    pending: Vec<u8>,
+    blocks: VecDeque<Block>,
+    inflated_position: u64,
+    item_position: u64,
+    errors: Vec<FormatError>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self {
+            state: State::Magic,
+            contigs_lengths: vec![],
+            samples_count: 0,
+            header_text: HeaderText::new(),
+            pending: vec![],
+            blocks: VecDeque::new(),
+            inflated_position: 0,
+            item_position: 0,
+            errors: vec![],
+        }
+    }
+
+    fn virtual_offset(&self, inflated_position: u64) -> u64 {
+        match self.blocks.iter().rev().find(|block| block.inflated_position <= inflated_position) {
+            Some(block) => (block.position << 16) | (inflated_position - block.inflated_position),
+            None => 0,
+        }
+    }
+
+    fn error(&mut self, kind: FormatErrorKind) {
+        self.errors.push(FormatError {
+            virtual_offset: self.virtual_offset(self.item_position),
+            kind,
+        });
+    }
+
+    fn lose(&mut self, kind: FormatErrorKind) {
+        self.error(kind);
+        self.state = State::Lost;
+    }
+
+    fn start_item(&mut self, state: State) {
+        self.state = state;
+        self.item_position = self.inflated_position;
+        self.pending.clear();
+        while self.blocks.len() > 1 && self.blocks[1].inflated_position <= self.item_position {
+            self.blocks.pop_front();
+        }
+    }
+
+    // Moves bytes to the pending buffer until it holds size bytes
+    fn fill(&mut self, bytes: &[u8], offset: &mut usize, size: usize) -> bool {
+        let missing = size - self.pending.len();
+        let available = missing.min(bytes.len() - *offset);
+        self.pending.extend_from_slice(&bytes[*offset..*offset + available]);
+        *offset += available;
+        self.inflated_position += available as u64;
+        self.pending.len() == size
+    }
+
+    fn skip(&mut self, bytes: &[u8], offset: &mut usize, remaining: u64) -> u64 {
+        let available = remaining.min((bytes.len() - *offset) as u64);
+        *offset += available as usize;
+        self.inflated_position += available;
+        remaining - available
+    }
+
+    fn check_record_header(&mut self) {
+        let shared_length = LittleEndian::read_u32(&self.pending[0..4]) as u64;
+        let individual_length = LittleEndian::read_u32(&self.pending[4..8]) as u64;
+        let chromosome_id = LittleEndian::read_i32(&self.pending[8..12]);
+        let alleles_count = (LittleEndian::read_u32(&self.pending[24..28]) >> 16) as u64;
+        let samples_count = LittleEndian::read_u32(&self.pending[28..32]) & 0xffffff;
+        let formats_count = (LittleEndian::read_u32(&self.pending[28..32]) >> 24) as u64;
+
+        if chromosome_id < 0 || chromosome_id as usize >= self.contigs_lengths.len() {
+            self.error(FormatErrorKind::ChromosomeId);
+        }
+        if samples_count != self.samples_count {
+            self.error(FormatErrorKind::SamplesCount);
+        }
+        // the id, each allele and the filters take at least a byte each
+        if shared_length < SHARED_FIXED_SIZE + 2 + alleles_count {
+            self.lose(FormatErrorKind::SharedLength);
+            return;
+        }
+        // each format takes at least a typed key and a type, without samples
+        if (formats_count == 0) != (individual_length == 0) || individual_length < 3 * formats_count {
+            self.lose(FormatErrorKind::IndividualLength);
+            return;
+        }
+        self.state = State::RecordData {
+            remaining: shared_length - SHARED_FIXED_SIZE + individual_length,
+        };
+    }
+
+    fn plausible_position(&self, chromosome_id: i32, position: i32) -> bool {
+        chromosome_id >= 0 && position >= -1 && match self.contigs_lengths.get(chromosome_id as usize) {
+            Some(Some(length)) => (position as i64) <= *length,
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    // Tells whether bytes could be the beginning of a record from this bcf file
+    fn plausible_record(&self, bytes: &[u8]) -> Plausibility {
+        if bytes.len() < RECORD_HEADER_SIZE + 1 {
+            return Plausibility::Incomplete;
+        }
+        let shared_length = LittleEndian::read_u32(&bytes[0..4]);
+        let individual_length = LittleEndian::read_u32(&bytes[4..8]);
+        let chromosome_id = LittleEndian::read_i32(&bytes[8..12]);
+        let position = LittleEndian::read_i32(&bytes[12..16]);
+        let reference_length = LittleEndian::read_i32(&bytes[16..20]);
+        let alleles_count = LittleEndian::read_u32(&bytes[24..28]) >> 16;
+        let samples_count = LittleEndian::read_u32(&bytes[28..32]) & 0xffffff;
+        let formats_count = LittleEndian::read_u32(&bytes[28..32]) >> 24;
+
+        if !self.plausible_position(chromosome_id, position) ||
+           reference_length < 0 ||
+           alleles_count < 1 ||
+           samples_count != self.samples_count ||
+           (formats_count == 0) != (individual_length == 0) ||
+           bytes[RECORD_HEADER_SIZE] & 0xf != CHARACTER_TYPE {
+            return Plausibility::Implausible;
+        }
+        if (shared_length as u64) < SHARED_FIXED_SIZE + 2 + alleles_count as u64 ||
+           shared_length > MAX_RESYNC_RECORD_SIZE ||
+           individual_length < 3 * formats_count ||
+           individual_length > MAX_RESYNC_RECORD_SIZE {
+            return Plausibility::Implausible;
+        }
+
+        Plausibility::Plausible(8 + shared_length as usize + individual_length as usize)
+    }
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Validate for Validator {
+    fn feed(&mut self, block_position: u64, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        // errors can't be found anymore, so no virtual offsets are needed
+        if matches!(self.state, State::Lost) {
+            self.inflated_position += bytes.len() as u64;
+            return;
+        }
+        self.blocks.push_back(Block {
+            position: block_position,
+            inflated_position: self.inflated_position,
+        });
+
+        let mut offset = 0;
+        while offset < bytes.len() {
+            match self.state {
+                State::Magic => {
+                    if self.fill(bytes, &mut offset, BCF_MAGIC.len()) {
+                        if self.pending[..] != BCF_MAGIC {
+                            self.lose(FormatErrorKind::BcfMagic);
+                        } else {
+                            self.start_item(State::HeaderTextLength);
+                        }
+                    }
+                },
+                State::HeaderTextLength => {
+                    if self.fill(bytes, &mut offset, 4) {
+                        let length = LittleEndian::read_u32(&self.pending) as u64;
+                        if length == 0 {
+                            self.lose(FormatErrorKind::BcfHeaderText);
+                        } else {
+                            self.header_text = HeaderText::new();
+                            self.start_item(State::HeaderText {
+                                remaining: length,
+                            });
+                        }
+                    }
+                },
+                State::HeaderText { remaining } => {
+                    let available = remaining.min((bytes.len() - offset) as u64) as usize;
+                    if !self.header_text.feed(&bytes[offset..offset + available]) {
+                        self.lose(FormatErrorKind::BcfHeaderText);
+                        continue;
+                    }
+                    let remaining = self.skip(bytes, &mut offset, remaining);
+                    if remaining > 0 {
+                        self.state = State::HeaderText {
+                            remaining,
+                        };
+                        continue;
+                    }
+                    let header_text = std::mem::replace(&mut self.header_text, HeaderText::new());
+                    match header_text.samples_count.filter(|_| header_text.ended) {
+                        Some(samples_count) => {
+                            self.contigs_lengths = header_text.contigs_lengths;
+                            self.samples_count = samples_count;
+                            self.start_item(State::RecordHeader);
+                        },
+                        None => self.lose(FormatErrorKind::BcfHeaderText),
+                    }
+                },
+                State::RecordHeader => {
+                    if self.fill(bytes, &mut offset, RECORD_HEADER_SIZE) {
+                        self.check_record_header();
+                    }
+                },
+                State::RecordData { remaining } => {
+                    let remaining = self.skip(bytes, &mut offset, remaining);
+                    if remaining == 0 {
+                        self.start_item(State::RecordHeader);
+                    } else {
+                        self.state = State::RecordData {
+                            remaining,
+                        };
+                    }
+                },
+                State::Lost => {
+                    self.inflated_position += (bytes.len() - offset) as u64;
+                    offset = bytes.len();
+                },
+            }
+        }
+    }
+
+    fn errors(&self) -> &[FormatError] {
+        &self.errors
+    }
+
+    fn into_errors(self: Box<Self>) -> Vec<FormatError> {
+        self.errors
+    }
+
+    // Some blocks could not be inflated, so the payload can't be followed anymore
+    fn gap(&mut self) {
+        if !matches!(self.state, State::Lost) {
+            self.state = State::Lost;
+            self.pending.clear();
+        }
+    }
+
+    fn finish(&mut self) {
+        match self.state {
+            State::Lost => (),
+            State::RecordHeader if self.pending.is_empty() => (),
+            _ => self.lose(FormatErrorKind::BcfTruncation),
+        }
+    }
+}
+
+impl Framing for Validator {
+    fn feed_payload(&mut self, bytes: &[u8]) {
+        self.feed(0, bytes);
+    }
+
+    fn payload_position(&self) -> u64 {
+        self.inflated_position
+    }
+
+    fn is_lost(&self) -> bool {
+        matches!(self.state, State::Lost)
+    }
+
+    fn in_records(&self) -> bool {
+        matches!(self.state, State::RecordHeader | State::RecordData { .. })
+    }
+
+    fn lose_track(&mut self) {
+        self.gap();
+    }
+
+    // Either because the bytes belong to a record which is not complete yet,
+    // or because the header itself is not complete yet
+    fn incomplete_since(&self) -> Option<u64> {
+        match self.state {
+            State::Lost => None,
+            State::RecordHeader if self.pending.is_empty() => None,
+            State::RecordHeader | State::RecordData { .. } => Some(self.item_position),
+            _ => Some(0),
+        }
+    }
+
+    fn resync(&mut self) {
+        self.start_item(State::RecordHeader);
+    }
+
+    // The beginning of a record is confirmed by the beginning of the next one,
+    // unless there are no more bytes to look at
+    fn next_record(&self, bytes: &[u8], finishing: bool) -> Result<usize, usize> {
+        let mut offset = 0;
+        while offset < bytes.len() {
+            match self.plausible_record(&bytes[offset..]) {
+                Plausibility::Plausible(size) => {
+                    let next_record = if offset + size <= bytes.len() {
+                        self.plausible_record(&bytes[offset + size..])
+                    } else {
+                        Plausibility::Incomplete
+                    };
+                    match next_record {
+                        Plausibility::Implausible => (),
+                        Plausibility::Incomplete if !finishing => break,
+                        Plausibility::Plausible(_) |
+                        Plausibility::Incomplete => return Ok(offset),
+                    }
+                },
+                Plausibility::Implausible => (),
+                Plausibility::Incomplete => if !finishing {
+                    break;
+                },
+            }
+            offset += 1;
+        }
+        Err(offset)
+    }
+}
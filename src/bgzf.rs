@@ -0,0 +1,115 @@
+use byteorder::{
+    ByteOrder,
+    LittleEndian,
+    WriteBytesExt,
+};
+
+use std::io::{
+    self,
+    ErrorKind,
+    Read,
+};
+
+use miniz_oxide::inflate::core::{
+    decompress,
+    inflate_flags,
+    DecompressorOxide,
+};
+
+use crate::{
+    BGZF_IDENTIFIER,
+    CRC32,
+};
+
+// Same as htslib, so that even incompressible data fits in a single block
+pub const MAX_INFLATED_BLOCK_SIZE: usize = 0xff00;
+
+// The specification itself doesn't allow more, whatever the ISIZE says
+const MAX_INFLATED_PAYLOAD_SIZE: usize = 1 << 16;
+
+// Largest bgzf block, whose size is stored on 16 bits
+pub const MAX_BLOCK_SIZE: usize = 1 << 16;
+
+const COMPRESSION_LEVEL: u8 = 6;
+
+// Size of a bgzf block from its header, 0 if it has no bgzf subfield, None if
+// the header is not whole
+pub fn block_size(bytes: &[u8]) -> Option<usize> {
+    let extra_field_size = LittleEndian::read_u16(bytes.get(10..12)?) as usize;
+    let extra_field = bytes.get(12..12 + extra_field_size)?;
+    let mut position = 0;
+    while position + 4 <= extra_field.len() {
+        let subfield_size = LittleEndian::read_u16(&extra_field[position + 2..position + 4]) as usize;
+        if extra_field[position..position + 2] == BGZF_IDENTIFIER && subfield_size == 2 {
+            return Some(LittleEndian::read_u16(extra_field.get(position + 4..position + 6)?) as usize + 1);
+        }
+        position += 4 + subfield_size;
+    }
+    Some(0)
+}
+
+// Reads the next block into bytes, as long as it's a whole bgzf block no
+// larger than max_size
+pub fn read_block(reader: &mut dyn Read, bytes: &mut Vec<u8>, max_size: u64) -> io::Result<bool> {
+    let mut read = |bytes: &mut Vec<u8>, size: usize| {
+        let start = bytes.len();
+        bytes.resize(start + size, 0);
+        match reader.read_exact(&mut bytes[start..]) {
+            Ok(()) => Ok(true),
+            Err(ref cause) if cause.kind() == ErrorKind::UnexpectedEof => Ok(false),
+            Err(cause) => Err(cause),
+        }
+    };
+    bytes.clear();
+    if !read(bytes, 12)? || !read(bytes, LittleEndian::read_u16(&bytes[10..12]) as usize)? {
+        return Ok(false);
+    }
+    match block_size(bytes) {
+        Some(size) if size >= bytes.len() && size as u64 <= max_size => read(bytes, size - bytes.len()),
+        _ => Ok(false),
+    }
+}
+
+// Compresses data into as many new bgzf blocks as needed
+pub fn blocks(inflated_payload_bytes: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for chunk in inflated_payload_bytes.chunks(MAX_INFLATED_BLOCK_SIZE) {
+        bytes.extend(block(chunk));
+    }
+    bytes
+}
+
+pub fn block(inflated_payload_bytes: &[u8]) -> Vec<u8> {
+    let mut deflated_payload_bytes = miniz_oxide::deflate::compress_to_vec(inflated_payload_bytes, COMPRESSION_LEVEL);
+    if deflated_payload_bytes.len() > inflated_payload_bytes.len() + 5 {
+        // a single stored block is never larger than the data plus 5 bytes
+        deflated_payload_bytes = miniz_oxide::deflate::compress_to_vec(inflated_payload_bytes, 0);
+    }
+
+    let mut bytes = vec![
+        0x1f, 0x8b,             // gzip identifier
+        0x08,                   // method (deflate)
+        0x04,                   // flags (FEXTRA)
+        0x00, 0x00, 0x00, 0x00, // modification time
+        0x00,                   // extra flags
+        0xff,                   // operating system (unknown)
+        0x06, 0x00,             // extra field size (6 bytes)
+        0x42, 0x43,             // bgzf identifier
+        0x02, 0x00,             // extra subfield length (2 bytes)
+    ];
+    bytes.write_u16::<LittleEndian>((bytes.len() + 2 + deflated_payload_bytes.len() + 8 - 1) as u16).unwrap(); // bgzf block size, minus one
+    bytes.extend(deflated_payload_bytes);
+    bytes.write_u32::<LittleEndian>(CRC32.checksum(inflated_payload_bytes)).unwrap();
+    bytes.write_u32::<LittleEndian>(inflated_payload_bytes.len() as u32).unwrap();
+    bytes
+}
+
+// Inflates a corrupted deflated payload up to the first error, the inflated
+// payload size coming from the corrupted block as well
+pub fn inflate_prefix(deflated_payload_bytes: &[u8], inflated_payload_size: u32) -> Vec<u8> {
+    let mut inflated_payload_bytes = vec![0u8; (inflated_payload_size as usize).min(MAX_INFLATED_PAYLOAD_SIZE)];
+    let (_, _, inflated_prefix_size) = decompress(&mut DecompressorOxide::new(), deflated_payload_bytes, &mut inflated_payload_bytes, 0, inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF);
+    inflated_payload_bytes.truncate(inflated_prefix_size);
+    inflated_payload_bytes.shrink_to_fit();
+    inflated_payload_bytes
+}
@@ -0,0 +1,73 @@
+use std::{
+    ops::Range,
+    sync::atomic::{
+        AtomicUsize,
+        Ordering,
+    },
+};
+
+use crate::CRC32;
+
+// Trying every pair of bits would be way too slow; flipped bits caused by the
+// same event are usually close to each other anyway
+const DOUBLE_BITFLIPS_WINDOW: usize = 16;
+
+pub struct Fix {
+    // offsets in the deflated payload, in bits, starting from the least
+    // significant bit of each byte, like deflate does
+    pub bits: Vec<usize>,
+    pub deflated_payload_bytes: Vec<u8>,
+    pub inflated_payload_bytes: Vec<u8>,
+}
+
+fn flip(bytes: &mut [u8], bit: usize) {
+    bytes[bit / 8] ^= 1 << (bit % 8);
+}
+
+fn inflate(deflated_payload_bytes: &[u8], inflated_payload_crc32: u32, inflated_payload_size: u32) -> Option<Vec<u8>> {
+    // unlike the inflate crate, miniz_oxide never panics and can stop early
+    // when a candidate inflates to more than the expected size
+    match miniz_oxide::inflate::decompress_to_vec_with_limit(deflated_payload_bytes, inflated_payload_size as usize) {
+        Ok(inflated_payload_bytes) if inflated_payload_bytes.len() == inflated_payload_size as usize &&
+                                      CRC32.checksum(&inflated_payload_bytes) == inflated_payload_crc32 => Some(inflated_payload_bytes),
+        _ => None,
+    }
+}
+
+// Looks for one flipped bit among first_bits, or two close flipped bits, the
+// first of which is among first_bits, until any search sets found to a lower
+// first bit: whatever the timing, the fix with the lowest first bit is found
+pub fn find(deflated_payload_bytes: &[u8], inflated_payload_crc32: u32, inflated_payload_size: u32, first_bits: Range<usize>, double_bitflips: bool, found: &AtomicUsize) -> Option<Fix> {
+    let bits_count = deflated_payload_bytes.len() * 8;
+    let mut candidate = deflated_payload_bytes.to_vec();
+    for first_bit in first_bits {
+        if found.load(Ordering::Relaxed) < first_bit {
+            return None;
+        }
+        flip(&mut candidate, first_bit);
+        if let Some(inflated_payload_bytes) = inflate(&candidate, inflated_payload_crc32, inflated_payload_size) {
+            found.fetch_min(first_bit, Ordering::Relaxed);
+            return Some(Fix {
+                bits: vec![first_bit],
+                deflated_payload_bytes: candidate,
+                inflated_payload_bytes,
+            });
+        }
+        if double_bitflips {
+            for second_bit in first_bit + 1..bits_count.min(first_bit + 1 + DOUBLE_BITFLIPS_WINDOW) {
+                flip(&mut candidate, second_bit);
+                if let Some(inflated_payload_bytes) = inflate(&candidate, inflated_payload_crc32, inflated_payload_size) {
+                    found.fetch_min(first_bit, Ordering::Relaxed);
+                    return Some(Fix {
+                        bits: vec![first_bit, second_bit],
+                        deflated_payload_bytes: candidate,
+                        inflated_payload_bytes,
+                    });
+                }
+                flip(&mut candidate, second_bit);
+            }
+        }
+        flip(&mut candidate, first_bit);
+    }
+    None
+}
@@ -0,0 +1,365 @@
+use byteorder::{
+    ByteOrder,
+    LittleEndian,
+};
+
+use std::io::{
+    self,
+    Read,
+    SeekFrom,
+    Write,
+};
+
+use crate::{
+    BadBlock,
+    CorruptionKind,
+    DdrescueMap,
+    InputFormat,
+    ListenProgress,
+    Rescuable,
+    RescueError,
+    Results,
+    BUFFER_SIZE,
+    CRC32,
+};
+
+pub const CRAM_MAGIC: [u8; 4] = [0x43, 0x52, 0x41, 0x4d];
+
+// magic, major and minor versions, and file id
+const FILE_DEFINITION_SIZE: u64 = 26;
+
+// only cram 3 has CRC32s on container headers and blocks
+const MAJOR_VERSION: u8 = 3;
+
+// Empty container which ends every cram 3 file
+const EOF_CONTAINER: [u8; 38] = [
+    0x0f, 0x00, 0x00, 0x00,       // length
+    0xff, 0xff, 0xff, 0xff, 0x0f, // reference sequence id (-1)
+    0xe0, 0x45, 0x4f, 0x46,       // alignment start
+    0x00,                         // alignment span
+    0x00,                         // records count
+    0x00,                         // record counter
+    0x00,                         // bases count
+    0x01,                         // blocks count
+    0x00,                         // landmarks count
+    0x05, 0xbd, 0xd9, 0x4f,       // header CRC32
+    0x00, 0x01, 0x00, 0x06, 0x06, // compression header block
+    0x01, 0x00, 0x01, 0x00, 0x01, 0x00,
+    0xee, 0x63, 0x01, 0x4b,       // block CRC32
+];
+
+// Container headers are read at once, which is enough for several hundreds
+// slices per container
+const MAX_HEADER_SIZE: usize = 4096;
+
+struct ContainerHeader {
+    // of the header itself, CRC32 included
+    size: u64,
+    // of the blocks which follow
+    length: u64,
+    blocks_count: usize,
+}
+
+enum Header {
+    Valid(ContainerHeader),
+    Invalid,
+    // cut by the end of the bytes
+    Incomplete,
+}
+
+// Variable length integers, the leading ones of the first byte giving how many
+// bytes follow; ltf8 are the same, but on up to 9 bytes rather than 5
+fn itf8(bytes: &[u8]) -> Option<(i32, usize)> {
+    let size = (bytes.first()?.leading_ones() as usize).min(4) + 1;
+    let bytes = bytes.get(..size)?;
+    let value = if size == 5 {
+        // only the 4 lowest bits of the last byte are used
+        (bytes[0] as u32 & 0x0f) << 28 | (bytes[1] as u32) << 20 | (bytes[2] as u32) << 12 | (bytes[3] as u32) << 4 | (bytes[4] as u32 & 0x0f)
+    } else {
+        bytes[1..].iter().fold(bytes[0] as u32 & (0xff >> size), |value, &byte| value << 8 | byte as u32)
+    };
+    Some((value as i32, size))
+}
+
+fn ltf8(bytes: &[u8]) -> Option<(i64, usize)> {
+    let size = bytes.first()?.leading_ones() as usize + 1;
+    let bytes = bytes.get(..size)?;
+    let value = bytes[1..].iter().fold(bytes[0] as u64 & (0xff >> size), |value, &byte| value << 8 | byte as u64);
+    Some((value as i64, size))
+}
+
+fn container_header(bytes: &[u8]) -> Header {
+    match parse_container_header(bytes) {
+        Some(Some(header)) => Header::Valid(header),
+        Some(None) => Header::Invalid,
+        None => Header::Incomplete,
+    }
+}
+
+// None if the bytes end before the header does, Some(None) if its CRC32 does
+// not match
+fn parse_container_header(bytes: &[u8]) -> Option<Option<ContainerHeader>> {
+    let length = LittleEndian::read_i32(bytes.get(..4)?);
+    let mut offset = 4;
+    // reference sequence id, alignment start and span, and records count
+    for _ in 0..4 {
+        offset += itf8(&bytes[offset..])?.1;
+    }
+    // record counter and bases count
+    for _ in 0..2 {
+        offset += ltf8(&bytes[offset..])?.1;
+    }
+    let (blocks_count, size) = itf8(&bytes[offset..])?;
+    offset += size;
+    let (landmarks_count, size) = itf8(&bytes[offset..])?;
+    offset += size;
+    for _ in 0..landmarks_count.max(0) {
+        offset += itf8(&bytes[offset..])?.1;
+    }
+    let crc32 = LittleEndian::read_u32(bytes.get(offset..offset + 4)?);
+    if CRC32.checksum(&bytes[..offset]) != crc32 || length < 0 || blocks_count < 0 {
+        return Some(None);
+    }
+    Some(Some(ContainerHeader {
+        size: offset as u64 + 4,
+        length: length as u64,
+        blocks_count: blocks_count as usize,
+    }))
+}
+
+// Positions and sizes of the blocks of a container whose CRC32 does not match;
+// once a block does not fit in the container, the following ones can't be
+// found anymore and the rest of the container is reported at once
+fn corrupted_blocks(bytes: &[u8], blocks_count: usize) -> Vec<(usize, usize)> {
+    let mut corrupted_blocks = vec![];
+    let mut offset = 0;
+    for _ in 0..blocks_count {
+        match block_size(&bytes[offset..]) {
+            Some(size) => {
+                let crc32 = LittleEndian::read_u32(&bytes[offset + size - 4..]);
+                if CRC32.checksum(&bytes[offset..offset + size - 4]) != crc32 {
+                    corrupted_blocks.push((offset, size));
+                }
+                offset += size;
+            },
+            None => {
+                corrupted_blocks.push((offset, bytes.len() - offset));
+                return corrupted_blocks;
+            },
+        }
+    }
+    if offset < bytes.len() {
+        corrupted_blocks.push((offset, bytes.len() - offset));
+    }
+    corrupted_blocks
+}
+
+// Size of the block at the beginning of the bytes, CRC32 included, if it fits
+fn block_size(bytes: &[u8]) -> Option<usize> {
+    // compression method and content type
+    let mut offset = 2;
+    // content id
+    offset += itf8(bytes.get(offset..)?)?.1;
+    let (compressed_size, size) = itf8(&bytes[offset..])?;
+    offset += size;
+    // raw size
+    offset += itf8(&bytes[offset..])?.1;
+    let size = offset + usize::try_from(compressed_size).ok()? + 4;
+    (size <= bytes.len()).then_some(size)
+}
+
+// Looks for the next container header whose CRC32 matches, returning the end
+// of the file if there's none; unreadable regions are jumped over rather than
+// searched, and the search stops where the next one starts
+fn seek_next_container(reader: &mut dyn Rescuable, position: u64, ddrescue_map: Option<&DdrescueMap>) -> Result<u64, RescueError> {
+    let (mut current_position, unreadable_position) = match ddrescue_map {
+        Some(ddrescue_map) => ddrescue_map.search_range(position),
+        None => (position, None),
+    };
+    let unreadable_position = unreadable_position.unwrap_or(u64::MAX);
+    loop {
+        reader.seek(SeekFrom::Start(current_position)).map_err(RescueError::Input)?;
+        let mut bytes = vec![];
+        reader.take(BUFFER_SIZE + MAX_HEADER_SIZE as u64).read_to_end(&mut bytes).map_err(RescueError::Input)?;
+        let last = bytes.len() < BUFFER_SIZE as usize + MAX_HEADER_SIZE;
+        let searched_size = if last { bytes.len() } else { BUFFER_SIZE as usize };
+        if let Some(offset) = (0..searched_size).find(|&offset| matches!(container_header(&bytes[offset..]), Header::Valid(_))) {
+            return Ok(unreadable_position.min(current_position + offset as u64));
+        }
+        if last {
+            return Ok(unreadable_position.min(current_position + bytes.len() as u64));
+        }
+        current_position += BUFFER_SIZE;
+        if current_position >= unreadable_position {
+            return Ok(unreadable_position);
+        }
+    }
+}
+
+fn report_truncated_container(results: &mut Results, progress_listener: &mut Option<&mut dyn ListenProgress>, position: u64, size: u64) {
+    // like truncated bgzf blocks, not counted but still reported
+    results.truncated_in_block = true;
+    let bad_block = BadBlock {
+        position,
+        size,
+        inflated_position: position,
+        kind: CorruptionKind::Truncation,
+    };
+    if let Some(ref mut progress_listener) = progress_listener {
+        progress_listener.on_bad_block(&bad_block);
+    }
+    results.bad_blocks.push(bad_block);
+}
+
+fn report_bad_container(results: &mut Results, progress_listener: &mut Option<&mut dyn ListenProgress>, bad_block: BadBlock) {
+    results.bad_blocks_count += 1;
+    results.bad_blocks_size += bad_block.size;
+    if bad_block.kind == CorruptionKind::Unreadable {
+        results.unreadable_blocks_count += 1;
+    }
+    if let Some(ref mut progress_listener) = progress_listener {
+        progress_listener.on_bad_block(&bad_block);
+    }
+    results.bad_blocks.push(bad_block);
+}
+
+// Checks the CRC32 of each container header and block, containers being the
+// unit of blocks_count and bad_blocks and their size that of blocks_size and
+// bad_blocks_size; only intact containers are rescued, followed by an EOF
+// container if the file doesn't end with one anymore. Containers overlapping
+// an unreadable region are corrupted whatever their CRC32s, and a region
+// where a container header should be is a corrupted container up to the next
+// header after it.
+pub fn process(reader: &mut dyn Rescuable, mut writer: Option<&mut dyn Write>, fail_fast: bool, index: bool, ddrescue_map: Option<&DdrescueMap>, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Result<Results, RescueError> {
+    crate::reader_size(reader, progress_listener)?;
+
+    let mut results = Results::new();
+    results.input_format = Some(InputFormat::Cram);
+
+    let mut file_definition_bytes = vec![];
+    reader.take(FILE_DEFINITION_SIZE).read_to_end(&mut file_definition_bytes).map_err(RescueError::Input)?;
+    if file_definition_bytes.len() < FILE_DEFINITION_SIZE as usize {
+        results.truncated_in_block = true;
+        return Ok(results);
+    }
+    if file_definition_bytes[4] != MAJOR_VERSION {
+        return Err(RescueError::Input(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported cram version {}.{}", file_definition_bytes[4], file_definition_bytes[5]))));
+    }
+    if let Some(ref mut writer) = writer {
+        writer.write_all(&file_definition_bytes).map_err(RescueError::Output)?;
+    }
+
+    let mut position = FILE_DEFINITION_SIZE;
+    let mut ends_with_eof_container = false;
+    let mut rescued_eof_container = false;
+    loop {
+        let mut header_bytes = vec![];
+        reader.take(MAX_HEADER_SIZE as u64).read_to_end(&mut header_bytes).map_err(RescueError::Input)?;
+        if header_bytes.is_empty() {
+            break;
+        }
+        let unreadable_end = ddrescue_map.and_then(|ddrescue_map| ddrescue_map.unreadable_end(position));
+        let header = match container_header(&header_bytes) {
+            Header::Valid(header) if unreadable_end.is_none() => header,
+            Header::Incomplete if unreadable_end.is_none() && header_bytes.len() < MAX_HEADER_SIZE => {
+                report_truncated_container(&mut results, progress_listener, position, header_bytes.len() as u64);
+                break;
+            },
+            _ => {
+                let (kind, next_position) = match unreadable_end {
+                    Some(unreadable_end) => (CorruptionKind::Unreadable, seek_next_container(reader, unreadable_end, ddrescue_map)?),
+                    None => (CorruptionKind::ContainerHeader, seek_next_container(reader, position + 1, ddrescue_map)?),
+                };
+                results.blocks_count += 1;
+                results.blocks_size += next_position - position;
+                report_bad_container(&mut results, progress_listener, BadBlock {
+                    position,
+                    size: next_position - position,
+                    inflated_position: position,
+                    kind,
+                });
+                if fail_fast {
+                    return Ok(results);
+                }
+                ends_with_eof_container = false;
+                position = next_position;
+                reader.seek(SeekFrom::Start(position)).map_err(RescueError::Input)?;
+                if let Some(ref mut progress_listener) = progress_listener {
+                    progress_listener.on_progress(position);
+                }
+                continue;
+            },
+        };
+
+        reader.seek(SeekFrom::Start(position + header.size)).map_err(RescueError::Input)?;
+        let mut container_bytes = header_bytes;
+        container_bytes.truncate(header.size as usize);
+        reader.take(header.length).read_to_end(&mut container_bytes).map_err(RescueError::Input)?;
+        let size = container_bytes.len() as u64;
+        if size < header.size + header.length {
+            report_truncated_container(&mut results, progress_listener, position, size);
+            break;
+        }
+        results.blocks_count += 1;
+        results.blocks_size += size;
+
+        let corrupted_blocks = corrupted_blocks(&container_bytes[header.size as usize..], header.blocks_count);
+        let unreadable = match ddrescue_map {
+            Some(ddrescue_map) => ddrescue_map.overlaps(position, position + size),
+            None => false,
+        };
+        if corrupted_blocks.is_empty() && !unreadable {
+            ends_with_eof_container = container_bytes == EOF_CONTAINER;
+            if let Some(ref mut writer) = writer {
+                writer.write_all(&container_bytes).map_err(RescueError::Output)?;
+                rescued_eof_container = ends_with_eof_container;
+            }
+        } else {
+            for (offset, size) in corrupted_blocks {
+                let block_position = position + header.size + offset as u64;
+                results.bad_cram_blocks.push(BadBlock {
+                    position: block_position,
+                    size: size as u64,
+                    inflated_position: block_position,
+                    kind: CorruptionKind::CramBlock,
+                });
+            }
+            report_bad_container(&mut results, progress_listener, BadBlock {
+                position,
+                size,
+                inflated_position: position,
+                kind: if unreadable { CorruptionKind::Unreadable } else { CorruptionKind::CramBlock },
+            });
+            if fail_fast {
+                return Ok(results);
+            }
+            ends_with_eof_container = false;
+        }
+        position += size;
+        if let Some(ref mut progress_listener) = progress_listener {
+            progress_listener.on_progress(position);
+        }
+    }
+
+    if !ends_with_eof_container && !results.truncated_in_block {
+        results.truncated_between_blocks = true;
+        if fail_fast {
+            return Ok(results);
+        }
+    }
+
+    if let Some(ref mut writer) = writer {
+        if !rescued_eof_container {
+            writer.write_all(&EOF_CONTAINER).map_err(RescueError::Output)?;
+        }
+        if index {
+            return Err(RescueError::Index("not a bam, vcf, bed or gff file"));
+        }
+    }
+
+    if let Some(ref mut progress_listener) = progress_listener {
+        progress_listener.on_finished();
+    }
+
+    Ok(results)
+}
@@ -0,0 +1,129 @@
+use std::io::{
+    self,
+    BufRead,
+    Error,
+    ErrorKind,
+    Write,
+};
+
+use crate::RescueError;
+
+// Regions of the input which GNU ddrescue could not read, from its mapfile;
+// whatever bytes the image has there are not the original ones, even when
+// they happen to pass the checks
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DdrescueMap {
+    // sorted, merged, as start and end positions
+    regions: Vec<(u64, u64)>,
+}
+
+// Positions and sizes are hexadecimal with a 0x prefix, or decimal
+fn parse_number(word: &str) -> Option<u64> {
+    match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        Some(digits) => u64::from_str_radix(digits, 16).ok(),
+        None => word.parse().ok(),
+    }
+}
+
+fn invalid_line(line_number: usize) -> RescueError {
+    RescueError::Input(Error::new(ErrorKind::InvalidData, format!("invalid ddrescue mapfile at line {}", line_number)))
+}
+
+impl DdrescueMap {
+    // Comments start with '#', then come the current position and status of
+    // ddrescue, then the position, size and status of each region; only the
+    // regions whose status is '+' have been read
+    pub fn read(reader: &mut dyn BufRead) -> Result<Self, RescueError> {
+        let mut regions: Vec<(u64, u64)> = vec![];
+        let mut current_status_read = false;
+        for (index, line) in reader.lines().enumerate() {
+            let line = line.map_err(RescueError::Input)?;
+            let line = line.split('#').next().unwrap_or("");
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+            if !current_status_read {
+                if parse_number(words[0]).is_none() || words.len() < 2 {
+                    return Err(invalid_line(index + 1));
+                }
+                current_status_read = true;
+                continue;
+            }
+            let (position, size, status) = match words[..] {
+                [position, size, status] => (parse_number(position), parse_number(size), status),
+                _ => return Err(invalid_line(index + 1)),
+            };
+            let (position, size) = position.zip(size).ok_or_else(|| invalid_line(index + 1))?;
+            if status == "+" || size == 0 {
+                continue;
+            }
+            let end = position.checked_add(size).ok_or_else(|| invalid_line(index + 1))?;
+            regions.push((position, end));
+        }
+        regions.sort_unstable();
+        let mut map = Self::default();
+        for (start, end) in regions {
+            match map.regions.last_mut() {
+                Some(last) if last.1 >= start => last.1 = last.1.max(end),
+                _ => map.regions.push((start, end)),
+            }
+        }
+        Ok(map)
+    }
+
+    // First region which ends after the position
+    fn next_region(&self, position: u64) -> Option<(u64, u64)> {
+        self.regions.get(self.regions.partition_point(|&(_, end)| end <= position)).copied()
+    }
+
+    // End of the region the position is in, if it's unreadable
+    pub fn unreadable_end(&self, position: u64) -> Option<u64> {
+        self.next_region(position).filter(|&(start, _)| start <= position).map(|(_, end)| end)
+    }
+
+    // Where a search for the next block from the position starts, and where
+    // it has to stop: a search from within a region goes on after it, while
+    // one from where a region starts stops right there
+    pub fn search_range(&self, position: u64) -> (u64, Option<u64>) {
+        let position = match self.next_region(position) {
+            Some((start, end)) if start < position => end,
+            _ => position,
+        };
+        (position, self.next_unreadable(position))
+    }
+
+    // Start of the next region, or of the one the position is in
+    pub fn next_unreadable(&self, position: u64) -> Option<u64> {
+        self.next_region(position).map(|(start, _)| start)
+    }
+
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        match self.next_unreadable(start) {
+            Some(unreadable_start) => unreadable_start < end,
+            None => false,
+        }
+    }
+}
+
+// Writes a mapfile whose finished ('+') blocks are the given sorted ranges,
+// and the rest of the file non-tried ('?'), so that ddrescue with it as
+// --domain-mapfile reads only those ranges again
+pub fn write_domain(writer: &mut dyn Write, ranges: &[(u64, u64)], size: Option<u64>) -> io::Result<()> {
+    writeln!(writer, "# Mapfile. Created by bamrescue v{}", crate::version())?;
+    writeln!(writer, "# current_pos  current_status  current_pass")?;
+    writeln!(writer, "0x{:08X}     +               1", ranges.first().map_or(0, |&(start, _)| start))?;
+    writeln!(writer, "#      pos        size  status")?;
+    let mut position = 0u64;
+    for &(start, end) in ranges {
+        if start > position {
+            writeln!(writer, "0x{:08X}  0x{:08X}  ?", position, start - position)?;
+        }
+        writeln!(writer, "0x{:08X}  0x{:08X}  +", start, end - start)?;
+        position = end;
+    }
+    if let Some(size) = size.filter(|&size| size > position) {
+        writeln!(writer, "0x{:08X}  0x{:08X}  ?", position, size - position)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,392 @@
+use std::{
+    collections::{
+        HashMap,
+        VecDeque,
+    },
+    io::{
+        self,
+        BufRead,
+        BufReader,
+        Read,
+        Write,
+    },
+};
+
+use serde_derive::Serialize;
+
+use crate::{
+    bgzf,
+    resync::Framing,
+    RescueError,
+};
+
+// Each record is made of four lines: a header starting with '@', the
+// sequence, a separator starting with '+' and the qualities
+const LINES_PER_RECORD: u8 = 4;
+
+// Records of either file waiting for their mate, beyond which the oldest one
+// is given up on: enough for dozens of lost bgzf blocks, without holding
+// whole files in memory when their names don't match at all
+const MAX_PENDING_RECORDS: usize = 1 << 14;
+
+enum Record {
+    Whole,
+    // not complete yet, but might be once more payload is known
+    Partial,
+    Invalid,
+}
+
+fn strip_cr(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+// Whether a line, or the beginning of it if not whole, can be the given line
+// of a record
+fn is_plausible(index: usize, line: &[u8], sequence: &[u8], whole: bool) -> bool {
+    match index {
+        0 => line.first().map_or(!whole, |&byte| byte == b'@'),
+        1 => line.iter().all(|&byte| byte.is_ascii_alphabetic() || matches!(byte, b'.' | b'-' | b'*')),
+        2 => line.first().map_or(!whole, |&byte| byte == b'+'),
+        _ => line.iter().all(|byte| (b'!'..=b'~').contains(byte)) &&
+            if whole { line.len() == sequence.len() } else { line.len() <= sequence.len() },
+    }
+}
+
+// The last line of the payload doesn't have to end with a newline
+fn record(bytes: &[u8], finishing: bool) -> Record {
+    let mut sequence: &[u8] = &[];
+    let mut position = 0;
+    for index in 0..LINES_PER_RECORD as usize {
+        let (line, whole) = match bytes[position..].iter().position(|&byte| byte == b'\n') {
+            Some(end) => {
+                let line = &bytes[position..position + end];
+                position += end + 1;
+                (strip_cr(line), true)
+            },
+            None => (strip_cr(&bytes[position..]), finishing && index == LINES_PER_RECORD as usize - 1),
+        };
+        if !is_plausible(index, line, sequence, whole) {
+            return Record::Invalid;
+        }
+        if !whole {
+            return Record::Partial;
+        }
+        if index == 1 {
+            sequence = line;
+        }
+    }
+    Record::Whole
+}
+
+// Whether the payload starts with a fastq record, or at least with its header
+// and sequence when cut by the end of the first block
+pub fn is_fastq(payload: &[u8]) -> bool {
+    match record(payload, false) {
+        Record::Whole => true,
+        Record::Partial => payload.iter().filter(|&&byte| byte == b'\n').count() >= 2,
+        Record::Invalid => false,
+    }
+}
+
+// Records of four lines, any of which can be dropped without breaking the others
+pub struct Records {
+    payload_position: u64,
+    record_position: u64,
+    lines_count: u8,
+}
+
+impl Records {
+    pub fn new() -> Self {
+        Self {
+            payload_position: 0,
+            record_position: 0,
+            lines_count: 0,
+        }
+    }
+}
+
+impl Default for Records {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Framing for Records {
+    fn feed_payload(&mut self, bytes: &[u8]) {
+        for (offset, _) in bytes.iter().enumerate().filter(|&(_, &byte)| byte == b'\n') {
+            self.lines_count += 1;
+            if self.lines_count == LINES_PER_RECORD {
+                self.lines_count = 0;
+                self.record_position = self.payload_position + offset as u64 + 1;
+            }
+        }
+        self.payload_position += bytes.len() as u64;
+    }
+
+    fn payload_position(&self) -> u64 {
+        self.payload_position
+    }
+
+    fn is_lost(&self) -> bool {
+        false
+    }
+
+    fn in_records(&self) -> bool {
+        true
+    }
+
+    fn lose_track(&mut self) {
+    }
+
+    fn incomplete_since(&self) -> Option<u64> {
+        if self.record_position < self.payload_position {
+            Some(self.record_position)
+        } else {
+            None
+        }
+    }
+
+    fn resync(&mut self) {
+        self.record_position = self.payload_position;
+        self.lines_count = 0;
+    }
+
+    // The qualities of the last record don't have to end with a newline
+    fn at_end(&mut self) {
+        if self.lines_count == LINES_PER_RECORD - 1 {
+            self.resync();
+        }
+    }
+
+    // What follows a gap is only known to be a whole record after a newline,
+    // and once its four lines have been read; the newline is kept while
+    // waiting for them
+    fn next_record(&self, bytes: &[u8], finishing: bool) -> Result<usize, usize> {
+        for (offset, _) in bytes.iter().enumerate().filter(|&(_, &byte)| byte == b'\n') {
+            match record(&bytes[offset + 1..], finishing) {
+                Record::Whole => return Ok(offset + 1),
+                Record::Partial if !finishing => return Err(offset),
+                Record::Partial |
+                Record::Invalid => (),
+            }
+        }
+        Err(bytes.len())
+    }
+}
+
+// What became of the records of two rescued files of paired reads
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PairResults {
+    pub pairs_count: u64,
+    // records of either file whose mate has been lost, in file order
+    pub unpaired_count: u64,
+    pub mate_unpaired_count: u64,
+    // lines of either file which are not part of a whole record
+    pub skipped_lines_count: u64,
+}
+
+struct PairedRecord {
+    name: Vec<u8>,
+    bytes: Vec<u8>,
+}
+
+// The read name is the first word of the header, without its /1 or /2 suffix
+fn read_name(header: &[u8]) -> Vec<u8> {
+    let name = strip_cr(&header[1..]).split(|byte| byte.is_ascii_whitespace()).next().unwrap_or(&[]);
+    match name.strip_suffix(b"/1").or_else(|| name.strip_suffix(b"/2")) {
+        Some(name) => name.to_vec(),
+        None => name.to_vec(),
+    }
+}
+
+// Reads whole records, skipping the lines which can't be part of one, like
+// those inflated from a corrupted part of a plain gzip file before its
+// corruption could be detected
+struct RecordReader<'a> {
+    reader: Box<dyn BufRead + 'a>,
+    lines: VecDeque<Vec<u8>>,
+    skipped_lines_count: u64,
+}
+
+impl<'a> RecordReader<'a> {
+    fn new(reader: &'a mut dyn Read) -> Self {
+        Self {
+            reader: Box::new(BufReader::new(reader)),
+            lines: VecDeque::new(),
+            skipped_lines_count: 0,
+        }
+    }
+
+    fn is_record(&self) -> bool {
+        let lines: Vec<&[u8]> = self.lines.iter()
+            .map(|line| strip_cr(line.strip_suffix(b"\n").unwrap_or(line)))
+            .collect();
+        lines.iter().enumerate().all(|(index, line)| is_plausible(index, line, lines[1], true))
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<PairedRecord>> {
+        loop {
+            while self.lines.len() < LINES_PER_RECORD as usize {
+                let mut line = vec![];
+                if self.reader.read_until(b'\n', &mut line)? == 0 {
+                    self.skipped_lines_count += self.lines.len() as u64;
+                    self.lines.clear();
+                    return Ok(None);
+                }
+                self.lines.push_back(line);
+            }
+            if self.is_record() {
+                break;
+            }
+            self.lines.pop_front();
+            self.skipped_lines_count += 1;
+        }
+        let mut bytes: Vec<u8> = self.lines.drain(..).flatten().collect();
+        if !bytes.ends_with(b"\n") {
+            bytes.push(b'\n');
+        }
+        let header_size = bytes.iter().position(|&byte| byte == b'\n').unwrap();
+        Ok(Some(PairedRecord {
+            name: read_name(&bytes[..header_size]),
+            bytes,
+        }))
+    }
+}
+
+// Records read from one of the files, not paired yet
+struct Pending {
+    records: VecDeque<PairedRecord>,
+    // index of each pending record by name, counting from the first record
+    // of the file
+    indices: HashMap<Vec<u8>, u64>,
+    first_index: u64,
+    finished: bool,
+}
+
+impl Pending {
+    fn new() -> Self {
+        Self {
+            records: VecDeque::new(),
+            indices: HashMap::new(),
+            first_index: 0,
+            finished: false,
+        }
+    }
+
+    fn push(&mut self, record: PairedRecord, unpaired_count: &mut u64) {
+        let index = self.first_index + self.records.len() as u64;
+        self.indices.entry(record.name.clone()).or_insert(index);
+        self.records.push_back(record);
+        if self.records.len() > MAX_PENDING_RECORDS {
+            self.pop();
+            *unpaired_count += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<PairedRecord> {
+        let record = self.records.pop_front()?;
+        if self.indices.get(&record.name) == Some(&self.first_index) {
+            self.indices.remove(&record.name);
+        }
+        self.first_index += 1;
+        Some(record)
+    }
+
+    // Drops the records before the given one, and returns it
+    fn take(&mut self, index: u64, unpaired_count: &mut u64) -> PairedRecord {
+        while self.first_index < index {
+            self.pop();
+            *unpaired_count += 1;
+        }
+        self.pop().unwrap()
+    }
+}
+
+// Payload compressed again as bgzf blocks of the largest size
+struct BlockWriter<'a> {
+    writer: &'a mut dyn Write,
+    payload: Vec<u8>,
+}
+
+impl<'a> BlockWriter<'a> {
+    fn new(writer: &'a mut dyn Write) -> Self {
+        Self {
+            writer,
+            payload: vec![],
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.payload.extend_from_slice(bytes);
+        if self.payload.len() >= bgzf::MAX_INFLATED_BLOCK_SIZE {
+            let block = bgzf::block(&self.payload[..bgzf::MAX_INFLATED_BLOCK_SIZE]);
+            self.payload.drain(..bgzf::MAX_INFLATED_BLOCK_SIZE);
+            self.writer.write_all(&block)?;
+        }
+        Ok(())
+    }
+
+    // Also writes the bgzf end-of-file marker
+    fn finish(self) -> io::Result<()> {
+        if !self.payload.is_empty() {
+            self.writer.write_all(&bgzf::block(&self.payload))?;
+        }
+        self.writer.write_all(&bgzf::block(&[]))
+    }
+}
+
+// Keeps only the records of two fastq payloads whose mate is in the other
+// one, both being in the same order, and skips the lines which are not part
+// of a whole record. After a record has been lost from either
+// file, records are read from both until a name is found in both, and the
+// records read before it are dropped, as are those which have waited for
+// their mate for too long.
+pub fn pair(reader: &mut dyn Read, mate_reader: &mut dyn Read, writer: &mut dyn Write, mate_writer: &mut dyn Write) -> Result<PairResults, RescueError> {
+    let mut readers = [RecordReader::new(reader), RecordReader::new(mate_reader)];
+    let mut writers = [BlockWriter::new(writer), BlockWriter::new(mate_writer)];
+    let mut pending = [Pending::new(), Pending::new()];
+    let mut unpaired_counts = [0u64; 2];
+    let mut pairs_count = 0u64;
+    while !pending[0].finished || !pending[1].finished {
+        for side in 0..2 {
+            if pending[side].finished {
+                continue;
+            }
+            let record = match readers[side].read_record().map_err(RescueError::Input)? {
+                Some(record) => record,
+                None => {
+                    pending[side].finished = true;
+                    continue;
+                },
+            };
+            let other = 1 - side;
+            let mate_index = match pending[other].indices.get(&record.name) {
+                Some(&mate_index) => mate_index,
+                None => {
+                    pending[side].push(record, &mut unpaired_counts[side]);
+                    continue;
+                },
+            };
+            let mate = pending[other].take(mate_index, &mut unpaired_counts[other]);
+            while pending[side].pop().is_some() {
+                unpaired_counts[side] += 1;
+            }
+            let (first, second) = if side == 0 { (record, mate) } else { (mate, record) };
+            writers[0].write(&first.bytes).map_err(RescueError::Output)?;
+            writers[1].write(&second.bytes).map_err(RescueError::Output)?;
+            pairs_count += 1;
+        }
+    }
+    for side in 0..2 {
+        unpaired_counts[side] += pending[side].records.len() as u64;
+    }
+    let [writer, mate_writer] = writers;
+    writer.finish().map_err(RescueError::Output)?;
+    mate_writer.finish().map_err(RescueError::Output)?;
+    Ok(PairResults {
+        pairs_count,
+        unpaired_count: unpaired_counts[0],
+        mate_unpaired_count: unpaired_counts[1],
+        skipped_lines_count: readers[0].skipped_lines_count + readers[1].skipped_lines_count,
+    })
+}
@@ -0,0 +1,905 @@
+use byteorder::{
+    ByteOrder,
+    LittleEndian,
+};
+
+use crc::Digest;
+
+use miniz_oxide::inflate::{
+    core::{
+        decompress,
+        inflate_flags,
+        DecompressorOxide,
+        TINFL_LZ_DICT_SIZE,
+    },
+    TINFLStatus,
+};
+
+use std::io::{
+    Read,
+    SeekFrom,
+    Write,
+};
+
+use crate::{
+    bgzf,
+    resync::Resynchronizer,
+    text,
+    BadBlock,
+    CorruptionKind,
+    DdrescueMap,
+    InputFormat,
+    ListenProgress,
+    Rescuable,
+    RescueError,
+    Results,
+    BGZF_IDENTIFIER,
+    BUFFER_SIZE,
+    CRC32,
+    DEFLATE,
+    FEXTRA,
+    GZIP_IDENTIFIER,
+};
+
+const FHCRC: u8 = 1 << 1;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+const RESERVED_FLAGS: u8 = 0xe0;
+
+// Extra field of bgzf blocks, right after the fixed part of the gzip header
+const BGZF_EXTRA: [u8; 6] = [6, 0, BGZF_IDENTIFIER[0], BGZF_IDENTIFIER[1], 2, 0];
+
+// CRC32 and size of the payload of the member
+const TRAILER_SIZE: usize = 8;
+
+// Compressed bytes which have to be inflated without error from a possible
+// restart point before it's trusted
+const TRIAL_SIZE: usize = 65536;
+
+// Order in which the lengths of the code length code are stored in the header
+// of deflate blocks with dynamic Huffman codes
+const CODE_LENGTHS_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+enum Header {
+    // size of the member header
+    Valid(usize),
+    Invalid,
+    // cut by the end of the bytes
+    Incomplete,
+}
+
+fn member_header(bytes: &[u8]) -> Header {
+    match parse_member_header(bytes) {
+        Some(Some(size)) => Header::Valid(size),
+        Some(None) => Header::Invalid,
+        None => Header::Incomplete,
+    }
+}
+
+// None if the bytes end before the header does, Some(None) if it's not a
+// gzip header or its CRC16 does not match
+fn parse_member_header(bytes: &[u8]) -> Option<Option<usize>> {
+    if bytes.iter().zip(&[GZIP_IDENTIFIER[0], GZIP_IDENTIFIER[1], DEFLATE]).any(|(byte, expected)| byte != expected) {
+        return Some(None);
+    }
+    let flags = *bytes.get(3)?;
+    if flags & RESERVED_FLAGS != 0 {
+        return Some(None);
+    }
+    // identifier, method, flags, modification time, extra flags and os
+    let mut size = 10;
+    if flags & FEXTRA != 0 {
+        size += 2 + LittleEndian::read_u16(bytes.get(size..size + 2)?) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            size += bytes.get(size..)?.iter().position(|&byte| byte == 0)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        let crc16 = LittleEndian::read_u16(bytes.get(size..size + 2)?);
+        if CRC32.checksum(&bytes[..size]) as u16 != crc16 {
+            return Some(None);
+        }
+        size += 2;
+    }
+    (size <= bytes.len()).then_some(Some(size))
+}
+
+// Plain gzip files start with a member which is not a bgzf block: it has no
+// BC subfield, and doesn't look like a bgzf header with a corrupted byte
+pub fn is_plain_gzip(bytes: &[u8]) -> bool {
+    let size = match member_header(bytes) {
+        Header::Valid(size) => size,
+        _ => return false,
+    };
+    let bgzf_extra_bytes = bytes.iter().skip(10).zip(&BGZF_EXTRA).filter(|(byte, expected)| byte == expected).count();
+    if bgzf_extra_bytes >= BGZF_EXTRA.len() - 1 {
+        return false;
+    }
+    if bytes[3] & FEXTRA != 0 {
+        let mut subfields = &bytes[12..size];
+        while subfields.len() >= 4 {
+            if subfields[..2] == BGZF_IDENTIFIER {
+                return false;
+            }
+            let subfield_size = LittleEndian::read_u16(&subfields[2..]) as usize;
+            subfields = subfields.get(4 + subfield_size..).unwrap_or(&[]);
+        }
+    }
+    true
+}
+
+// Raw deflate decompressor with its own 32 kiB dictionary, so that it can
+// start in the middle of a stream with a dictionary filled with a given byte
+struct Inflater {
+    decompressor: Box<DecompressorOxide>,
+    dictionary: Vec<u8>,
+    position: usize,
+}
+
+impl Inflater {
+    fn new(fill: u8) -> Self {
+        Self {
+            decompressor: Box::default(),
+            dictionary: vec![fill; TINFL_LZ_DICT_SIZE],
+            position: 0,
+        }
+    }
+
+    // Inflates as much of the input as possible, returning the status, the
+    // size of the input consumed and the output
+    fn inflate(&mut self, input: &[u8], more_input: bool) -> (TINFLStatus, usize, Vec<u8>) {
+        let flags = if more_input {
+            inflate_flags::TINFL_FLAG_HAS_MORE_INPUT
+        } else {
+            0
+        };
+        let mut consumed = 0;
+        let mut output = vec![];
+        loop {
+            let (status, read, written) = decompress(&mut self.decompressor, &input[consumed..], &mut self.dictionary, self.position, flags);
+            consumed += read;
+            output.extend_from_slice(&self.dictionary[self.position..self.position + written]);
+            self.position = (self.position + written) % TINFL_LZ_DICT_SIZE;
+            if status != TINFLStatus::HasMoreOutput {
+                return (status, consumed, output);
+            }
+        }
+    }
+}
+
+// Inflates a deflate stream, possibly from a block in its middle, in which
+// case a second inflater starts with a dictionary filled differently: bytes
+// copied from the unknown payload before the block differ between both, until
+// the whole dictionary is made of known bytes
+struct Stream {
+    inflater: Inflater,
+    shadow: Option<Inflater>,
+}
+
+impl Stream {
+    fn new(restarted: bool) -> Self {
+        Self {
+            inflater: Inflater::new(0x00),
+            shadow: restarted.then(|| Inflater::new(0xff)),
+        }
+    }
+
+    // Also returns the output of the shadow inflater, if any
+    fn inflate(&mut self, input: &[u8], more_input: bool) -> (TINFLStatus, usize, Vec<u8>, Option<Vec<u8>>) {
+        let (status, consumed, output) = self.inflater.inflate(input, more_input);
+        let shadow_output = self.shadow.as_mut().map(|shadow| shadow.inflate(input, more_input).2);
+        if self.shadow.as_ref().is_some_and(|shadow| shadow.dictionary == self.inflater.dictionary) {
+            self.shadow = None;
+        }
+        (status, consumed, output, shadow_output)
+    }
+}
+
+// Where inflating starts: the deflate stream of a member, or a deflate block
+// found in its middle after a corruption
+#[derive(Clone, Copy)]
+struct Entry {
+    position: u64,
+    // bits of the byte at position which are not part of the stream
+    shift: u8,
+    // stored blocks are aligned on the bytes of the file rather than on those
+    // of the shifted stream, so their header is given apart, shifted already,
+    // and the stream starts again from their length
+    stored_header: Option<u8>,
+}
+
+impl Entry {
+    fn aligned(position: u64) -> Self {
+        Self {
+            position,
+            shift: 0,
+            stored_header: None,
+        }
+    }
+}
+
+// Drops the lowest bits of the first byte, the last byte being incomplete
+// unless it's the last of the stream
+fn shift_bytes(bytes: &[u8], shift: u8, end: bool) -> Vec<u8> {
+    if shift == 0 {
+        return bytes.to_vec();
+    }
+    let mut shifted_bytes: Vec<u8> = bytes.windows(2).map(|pair| pair[0] >> shift | pair[1] << (8 - shift)).collect();
+    if end {
+        if let Some(&last_byte) = bytes.last() {
+            shifted_bytes.push(last_byte >> shift);
+        }
+    }
+    shifted_bytes
+}
+
+// Compressed bytes of a deflate stream, read from an entry
+struct Input {
+    // of the first byte which is not consumed yet in the file
+    position: u64,
+    // of the next byte to read from the file
+    read_position: u64,
+    shift: u8,
+    bytes: Vec<u8>,
+    // first byte of bytes, which is not read from the file
+    stored_header: bool,
+    // last byte read, which can't be shifted before the next one is read
+    carry: Option<u8>,
+    end: bool,
+    // of the next unreadable region, which is not read
+    unreadable_position: Option<u64>,
+}
+
+impl Input {
+    fn new(entry: Entry, unreadable_position: Option<u64>) -> Self {
+        Self {
+            position: entry.position,
+            read_position: entry.position,
+            shift: entry.shift,
+            bytes: entry.stored_header.into_iter().collect(),
+            stored_header: entry.stored_header.is_some(),
+            carry: None,
+            end: false,
+            unreadable_position,
+        }
+    }
+
+    fn fill(&mut self, reader: &mut dyn Rescuable) -> Result<(), RescueError> {
+        let mut bytes: Vec<u8> = self.carry.take().into_iter().collect();
+        let size = match self.unreadable_position {
+            Some(unreadable_position) => BUFFER_SIZE.min(unreadable_position.saturating_sub(self.read_position)),
+            None => BUFFER_SIZE,
+        };
+        let read_size = reader.take(size).read_to_end(&mut bytes).map_err(RescueError::Input)?;
+        self.read_position += read_size as u64;
+        self.end = read_size == 0 && !self.at_unreadable();
+        if self.shift != 0 && !self.end {
+            self.carry = bytes.last().copied();
+        }
+        self.bytes.extend(shift_bytes(&bytes, self.shift, self.end));
+        Ok(())
+    }
+
+    fn at_unreadable(&self) -> bool {
+        match self.unreadable_position {
+            Some(unreadable_position) => self.read_position >= unreadable_position,
+            None => false,
+        }
+    }
+
+    fn consume(&mut self, size: usize) {
+        self.bytes.drain(..size);
+        if size > 0 && self.stored_header {
+            self.stored_header = false;
+            self.position -= 1;
+        }
+        self.position += size as u64;
+    }
+
+    // Where the member trailer might start once the stream ends: a shifted
+    // stream can end in the first bits of the next byte
+    fn trailer_positions(&self) -> Vec<u64> {
+        if self.shift == 0 {
+            vec![self.position]
+        } else {
+            vec![self.position, self.position + 1]
+        }
+    }
+}
+
+// Bits of a deflate stream, least significant first
+struct Bits<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Bits<'_> {
+    fn read(&mut self, count: usize) -> Option<usize> {
+        let mut value = 0;
+        for bit in 0..count {
+            let byte = *self.bytes.get(self.position / 8)?;
+            value |= ((byte >> (self.position % 8)) as usize & 1) << bit;
+            self.position += 1;
+        }
+        Some(value)
+    }
+}
+
+// Canonical Huffman code, decoded a bit at a time
+struct Huffman {
+    counts: [usize; 16],
+    symbols: Vec<usize>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let symbols = (1..16).flat_map(|length| (0..lengths.len()).filter(move |&symbol| lengths[symbol] == length)).collect();
+        Self {
+            counts,
+            symbols,
+        }
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Option<usize> {
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for &count in &self.counts[1..] {
+            code |= bits.read(1)?;
+            if code < first + count {
+                return self.symbols.get(index + code - first).copied();
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+// Codes left unused by the lengths, in codes of the largest length, or None
+// if they are more than such a prefix code can hold
+fn unused_codes(lengths: &[u8]) -> Option<usize> {
+    let mut unused_codes = 1usize;
+    for length in 1..16 {
+        unused_codes = (unused_codes << 1).checked_sub(lengths.iter().filter(|&&code_length| code_length == length).count())?;
+    }
+    Some(unused_codes)
+}
+
+// Complete prefix codes, or a single code of one bit, which is the only
+// incomplete code deflate streams may use
+fn usable_code(lengths: &[u8]) -> bool {
+    match unused_codes(lengths) {
+        Some(0) => true,
+        Some(_) => lengths.iter().filter(|&&length| length != 0).eq([&1]),
+        None => false,
+    }
+}
+
+// Whether the header of a deflate block with dynamic Huffman codes, once its
+// type has been read, describes usable codes; None if the bytes end before
+fn dynamic_codes(bits: &mut Bits) -> Option<bool> {
+    let literals_count = bits.read(5)? + 257;
+    let distances_count = bits.read(5)? + 1;
+    let code_lengths_count = bits.read(4)? + 4;
+    if literals_count > 286 || distances_count > 30 {
+        return Some(false);
+    }
+    let mut code_lengths = [0; 19];
+    for &symbol in &CODE_LENGTHS_ORDER[..code_lengths_count] {
+        code_lengths[symbol] = bits.read(3)? as u8;
+    }
+    if unused_codes(&code_lengths) != Some(0) {
+        return Some(false);
+    }
+    let code_lengths_code = Huffman::new(&code_lengths);
+    let lengths_count = literals_count + distances_count;
+    let mut lengths = Vec::with_capacity(lengths_count);
+    while lengths.len() < lengths_count {
+        let (length, repeat) = match code_lengths_code.decode(bits)? {
+            16 => match lengths.last() {
+                Some(&length) => (length, bits.read(2)? + 3),
+                None => return Some(false),
+            },
+            17 => (0, bits.read(3)? + 3),
+            18 => (0, bits.read(7)? + 11),
+            length => (length as u8, 1),
+        };
+        if lengths.len() + repeat > lengths_count {
+            return Some(false);
+        }
+        lengths.resize(lengths.len() + repeat, length);
+    }
+    let (literal_lengths, distance_lengths) = lengths.split_at(literals_count);
+    // a block may hold literals only, without any distance code
+    Some(literal_lengths[256] != 0 && usable_code(literal_lengths) && (distance_lengths.iter().all(|&length| length == 0) || usable_code(distance_lengths)))
+}
+
+// Deflate block which might start at the given bit of the bytes; only stored
+// blocks, whose length is checked against its complement, and blocks with
+// dynamic Huffman codes, whose codes must be usable, are looked for, as fixed
+// Huffman codes decode almost anything
+fn block_entry(bytes: &[u8], offset: usize, shift: u8) -> Option<Entry> {
+    let mut bits = Bits {
+        bytes,
+        position: offset * 8 + shift as usize,
+    };
+    let last_block = bits.read(1)? as u8;
+    match bits.read(2)? {
+        0 => {
+            let length_position = bits.position.div_ceil(8);
+            let length_bytes = bytes.get(length_position..length_position + 4)?;
+            (LittleEndian::read_u16(length_bytes) == !LittleEndian::read_u16(&length_bytes[2..])).then_some(Entry {
+                position: length_position as u64,
+                shift: 0,
+                stored_header: Some(last_block),
+            })
+        },
+        2 => dynamic_codes(&mut bits)?.then_some(Entry {
+            position: offset as u64,
+            shift,
+            stored_header: None,
+        }),
+        _ => None,
+    }
+}
+
+// Whether a member trailer at the given offset is followed by another member,
+// or by the end of the file, as far as the bytes tell
+fn ends_member(bytes: &[u8], offset: usize, end: bool) -> bool {
+    match bytes.get(offset + TRAILER_SIZE..) {
+        Some(next_bytes) if next_bytes.len() >= GZIP_IDENTIFIER.len() => next_bytes.starts_with(&GZIP_IDENTIFIER),
+        Some(next_bytes) => !end || next_bytes.is_empty(),
+        None => true,
+    }
+}
+
+// Whether inflating from the entry, relative to the bytes, goes on without
+// error long enough to be trusted, or up to the end of the member
+fn inflates(bytes: &[u8], entry: Entry, end: bool) -> bool {
+    let position = entry.position as usize;
+    // stored blocks are only trusted if what follows them inflates as well
+    let trial_size = TRIAL_SIZE + match entry.stored_header {
+        Some(_) => LittleEndian::read_u16(&bytes[position..]) as usize,
+        None => 0,
+    };
+    let trial_end = bytes.len().min(position + trial_size);
+    let more_input = trial_end < bytes.len() || !end;
+    let mut input: Vec<u8> = entry.stored_header.into_iter().collect();
+    input.extend(shift_bytes(&bytes[position..(trial_end + 1).min(bytes.len())], entry.shift, !more_input));
+    input.truncate(trial_end - position + entry.stored_header.iter().count());
+    let (status, consumed, _) = Inflater::new(0x00).inflate(&input, more_input);
+    match status {
+        TINFLStatus::Done => {
+            let trailer_offset = position + consumed - entry.stored_header.iter().count();
+            ends_member(bytes, trailer_offset, end) || (entry.shift != 0 && ends_member(bytes, trailer_offset + 1, end))
+        },
+        TINFLStatus::NeedsMoreInput => more_input,
+        // cut by the end of the file
+        TINFLStatus::FailedCannotMakeProgress => !more_input,
+        _ => false,
+    }
+}
+
+enum Restart {
+    Member,
+    Block(Entry),
+    // where the next unreadable region starts, for nothing was found before
+    Unreadable,
+}
+
+// Looks for where inflating can go on after a corruption: the next member
+// whose deflate stream inflates, or the next deflate block which does, tried
+// from every bit; returns the end of the file if there's none. Unreadable
+// regions are jumped over rather than searched, and the search stops where
+// the next one starts.
+fn find_restart(reader: &mut dyn Rescuable, position: u64, ddrescue_map: Option<&DdrescueMap>) -> Result<(u64, Option<Restart>), RescueError> {
+    // stored blocks are tried on up to twice the trial size
+    let window_size = BUFFER_SIZE as usize + 2 * TRIAL_SIZE;
+    let (mut current_position, unreadable_position) = match ddrescue_map {
+        Some(ddrescue_map) => ddrescue_map.search_range(position),
+        None => (position, None),
+    };
+    loop {
+        reader.seek(SeekFrom::Start(current_position)).map_err(RescueError::Input)?;
+        let mut bytes = vec![];
+        reader.take(window_size as u64).read_to_end(&mut bytes).map_err(RescueError::Input)?;
+        let unreadable = match unreadable_position {
+            Some(unreadable_position) if unreadable_position <= current_position + bytes.len() as u64 => {
+                bytes.truncate((unreadable_position - current_position) as usize);
+                true
+            },
+            _ => false,
+        };
+        let last = bytes.len() < window_size && !unreadable;
+        let searched_size = if last || unreadable { bytes.len() } else { BUFFER_SIZE as usize };
+        for offset in 0..searched_size {
+            if let Header::Valid(size) = member_header(&bytes[offset..]) {
+                if inflates(&bytes, Entry::aligned((offset + size) as u64), last) {
+                    return Ok((current_position + offset as u64, Some(Restart::Member)));
+                }
+            }
+            for shift in 0..8 {
+                if let Some(entry) = block_entry(&bytes, offset, shift) {
+                    if inflates(&bytes, entry, last) {
+                        return Ok((current_position + offset as u64, Some(Restart::Block(Entry {
+                            position: current_position + entry.position,
+                            ..entry
+                        }))));
+                    }
+                }
+            }
+        }
+        if unreadable {
+            return Ok((current_position + bytes.len() as u64, Some(Restart::Unreadable)));
+        }
+        if last {
+            return Ok((current_position + bytes.len() as u64, None));
+        }
+        current_position += BUFFER_SIZE;
+    }
+}
+
+// What is known of the member being inflated
+struct Member {
+    position: u64,
+    // of the payload inflated so far, unknown after a corrupted member header
+    size: Option<u64>,
+    // only while its deflate stream is inflated from its start
+    digest: Option<Digest<'static, u32>>,
+    // payload held until the trailer is checked, while there's a digest and
+    // a rescued file, and the size of what didn't fit in it
+    held: Option<Vec<u8>>,
+    overflow_size: u64,
+}
+
+enum End {
+    // of the deflate stream, with the possible positions of the trailer
+    Stream(Vec<u64>),
+    // corrupted deflate stream, detected at the given position
+    Corrupted(u64),
+    // of the file, at the given position
+    File(u64),
+    // of the readable bytes, where an unreadable region starts
+    Unreadable(u64),
+}
+
+// Most payload held for a member until its trailer is checked; what's beyond
+// is lost, as there's no knowing whether it's corrupted before it's written
+const MAX_HELD_PAYLOAD_SIZE: usize = 1 << 26;
+
+// Rescues the bytes inflated from known payload only, and drops what follows
+// the bytes copied from unknown payload up to the next record
+fn write_payload(results: &mut Results, writer: &mut Option<&mut dyn Write>, resynchronizer: &mut Option<Resynchronizer>, member: &mut Member, output: &[u8], shadow_output: Option<&[u8]>) -> Result<(), RescueError> {
+    if let Some(ref mut size) = member.size {
+        *size += output.len() as u64;
+    }
+    if let Some(ref mut digest) = member.digest {
+        digest.update(output);
+    }
+    let known = |offset: usize| match shadow_output {
+        Some(shadow_output) => shadow_output[offset] == output[offset],
+        None => true,
+    };
+    let mut start = 0;
+    while start < output.len() {
+        let end = (start..output.len()).find(|&offset| known(offset) != known(start)).unwrap_or(output.len());
+        results.blocks_size += (end - start) as u64;
+        if known(start) {
+            if results.input_format.is_none() {
+                crate::set_input_format(results, &mut None, resynchronizer, text::detect(&output[start..end]));
+            }
+            if let Some(ref mut held) = member.held {
+                let held_size = (end - start).min(MAX_HELD_PAYLOAD_SIZE - held.len());
+                held.extend_from_slice(&output[start..start + held_size]);
+                member.overflow_size += (end - start - held_size) as u64;
+            } else if let (Some(ref mut writer), Some(ref mut resynchronizer)) = (writer.as_mut(), resynchronizer.as_mut()) {
+                resynchronizer.write_inflated(*writer, output[start..end].to_vec()).map_err(RescueError::Output)?;
+            }
+        } else {
+            results.bad_blocks_size += (end - start) as u64;
+            if let (Some(ref mut writer), Some(ref mut resynchronizer)) = (writer.as_mut(), resynchronizer.as_mut()) {
+                resynchronizer.gap(*writer).map_err(RescueError::Output)?;
+            }
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn inflate(reader: &mut dyn Rescuable, entry: Entry, member: &mut Member, results: &mut Results, writer: &mut Option<&mut dyn Write>, resynchronizer: &mut Option<Resynchronizer>, ddrescue_map: Option<&DdrescueMap>, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Result<End, RescueError> {
+    reader.seek(SeekFrom::Start(entry.position)).map_err(RescueError::Input)?;
+    let mut input = Input::new(entry, ddrescue_map.and_then(|ddrescue_map| ddrescue_map.next_unreadable(entry.position)));
+    let mut stream = Stream::new(member.digest.is_none());
+    let mut needs_input = true;
+    loop {
+        if needs_input {
+            input.fill(reader)?;
+            if let Some(ref mut progress_listener) = progress_listener {
+                progress_listener.on_progress(input.read_position);
+            }
+        }
+        let (status, consumed, output, shadow_output) = stream.inflate(&input.bytes, !input.end);
+        input.consume(consumed);
+        write_payload(results, writer, resynchronizer, member, &output, shadow_output.as_deref())?;
+        match status {
+            TINFLStatus::Done => return Ok(End::Stream(input.trailer_positions())),
+            TINFLStatus::NeedsMoreInput if input.at_unreadable() => return Ok(End::Unreadable(input.read_position)),
+            TINFLStatus::NeedsMoreInput if !input.end => needs_input = true,
+            TINFLStatus::NeedsMoreInput |
+            TINFLStatus::FailedCannotMakeProgress if input.end => return Ok(End::File(input.read_position)),
+            _ => return Ok(End::Corrupted(input.position)),
+        }
+    }
+}
+
+// Of the possible positions of the trailer, the one followed by another
+// member or by the end of the file; the trailer is shorter than expected if
+// cut by the end of the file
+fn read_trailer(reader: &mut dyn Rescuable, positions: &[u64]) -> Result<(u64, Vec<u8>), RescueError> {
+    let mut trailers = vec![];
+    for &position in positions {
+        reader.seek(SeekFrom::Start(position)).map_err(RescueError::Input)?;
+        let mut bytes = vec![];
+        reader.take((TRAILER_SIZE + GZIP_IDENTIFIER.len()) as u64).read_to_end(&mut bytes).map_err(RescueError::Input)?;
+        if ends_member(&bytes, 0, true) && bytes.len() >= TRAILER_SIZE {
+            bytes.truncate(TRAILER_SIZE);
+            return Ok((position, bytes));
+        }
+        trailers.push((position, bytes));
+    }
+    let (position, mut bytes) = trailers.swap_remove(0);
+    bytes.truncate(TRAILER_SIZE);
+    Ok((position, bytes))
+}
+
+// Rescues the payload held for a member, whose trailer is intact or can't be
+// checked, and drops what didn't fit in it up to the next record
+fn release_payload(results: &mut Results, writer: &mut Option<&mut dyn Write>, resynchronizer: &mut Option<Resynchronizer>, member: &mut Member) -> Result<(), RescueError> {
+    if let (Some(held), Some(ref mut writer), Some(ref mut resynchronizer)) = (member.held.take(), writer.as_mut(), resynchronizer.as_mut()) {
+        if !held.is_empty() {
+            resynchronizer.write_inflated(*writer, held).map_err(RescueError::Output)?;
+        }
+        if member.overflow_size > 0 {
+            results.bad_blocks_size += member.overflow_size;
+            resynchronizer.gap(*writer).map_err(RescueError::Output)?;
+        }
+    }
+    member.overflow_size = 0;
+    Ok(())
+}
+
+fn report_bad_block(results: &mut Results, progress_listener: &mut Option<&mut dyn ListenProgress>, writer: &mut Option<&mut dyn Write>, resynchronizer: &mut Option<Resynchronizer>, bad_block: BadBlock) -> Result<(), RescueError> {
+    results.bad_blocks_count += 1;
+    if bad_block.kind == CorruptionKind::Unreadable {
+        results.unreadable_blocks_count += 1;
+    }
+    if let Some(ref mut progress_listener) = progress_listener {
+        progress_listener.on_bad_block(&bad_block);
+    }
+    results.bad_blocks.push(bad_block);
+    if let (Some(ref mut writer), Some(ref mut resynchronizer)) = (writer.as_mut(), resynchronizer.as_mut()) {
+        resynchronizer.gap(*writer).map_err(RescueError::Output)?;
+    }
+    Ok(())
+}
+
+fn report_truncated_member(results: &mut Results, progress_listener: &mut Option<&mut dyn ListenProgress>, position: u64, size: u64) {
+    // like truncated bgzf blocks, not counted but still reported
+    results.truncated_in_block = true;
+    let bad_block = BadBlock {
+        position,
+        size,
+        inflated_position: results.blocks_size,
+        kind: CorruptionKind::Truncation,
+    };
+    if let Some(ref mut progress_listener) = progress_listener {
+        progress_listener.on_bad_block(&bad_block);
+    }
+    results.bad_blocks.push(bad_block);
+}
+
+// Checks the CRC32 and size of the payload of each member of a plain gzip
+// file, members being the unit of blocks_count; a corrupted member header or
+// deflate stream is reported as a bad block up to where inflating can go on,
+// and the payload lost is only known when copied from it by later bytes, or
+// when the member trailer is intact, as the size it declares tells. Inflating
+// stops where an unreadable region starts, which is a bad block up to where
+// inflating can go on after it. A member whose CRC32 or size doesn't match is
+// a bad block whose whole payload is lost.
+//
+// The rescued payload is written as bgzf, which is still gzip.
+pub fn process(reader: &mut dyn Rescuable, mut writer: Option<&mut dyn Write>, fail_fast: bool, index: bool, input_format: Option<InputFormat>, ddrescue_map: Option<&DdrescueMap>, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Result<Results, RescueError> {
+    crate::reader_size(reader, progress_listener)?;
+
+    let mut results = Results::new();
+    results.plain_gzip = true;
+
+    let mut resynchronizer = writer.as_ref().map(|_| Resynchronizer::new(index));
+    if let Some(input_format) = input_format {
+        crate::set_input_format(&mut results, &mut None, &mut resynchronizer, input_format);
+    }
+
+    let mut position = 0;
+    // block in the middle of a member, after a corruption
+    let mut restart = None;
+    loop {
+        let (entry, mut member) = match restart.take() {
+            Some(restart) => restart,
+            None => {
+                reader.seek(SeekFrom::Start(position)).map_err(RescueError::Input)?;
+                let mut header_bytes = vec![];
+                reader.take(BUFFER_SIZE).read_to_end(&mut header_bytes).map_err(RescueError::Input)?;
+                if header_bytes.is_empty() {
+                    break;
+                }
+                let unreadable_end = ddrescue_map.and_then(|ddrescue_map| ddrescue_map.unreadable_end(position));
+                match member_header(&header_bytes) {
+                    Header::Valid(size) if unreadable_end.is_none() => {
+                        results.blocks_count += 1;
+                        (Entry::aligned(position + size as u64), Member {
+                            position,
+                            size: Some(0),
+                            digest: Some(CRC32.digest()),
+                            held: writer.as_ref().map(|_| vec![]),
+                            overflow_size: 0,
+                        })
+                    },
+                    Header::Incomplete if unreadable_end.is_none() && header_bytes.len() < BUFFER_SIZE as usize => {
+                        report_truncated_member(&mut results, progress_listener, position, header_bytes.len() as u64);
+                        break;
+                    },
+                    _ => {
+                        let (kind, next_position, next) = match unreadable_end {
+                            Some(unreadable_end) => {
+                                let (next_position, next) = find_restart(reader, unreadable_end, ddrescue_map)?;
+                                (CorruptionKind::Unreadable, next_position, next)
+                            },
+                            None => {
+                                let (next_position, next) = find_restart(reader, position + 1, ddrescue_map)?;
+                                (CorruptionKind::GzipHeader, next_position, next)
+                            },
+                        };
+                        let bad_block = BadBlock {
+                            position,
+                            size: next_position - position,
+                            inflated_position: results.blocks_size,
+                            kind,
+                        };
+                        report_bad_block(&mut results, progress_listener, &mut writer, &mut resynchronizer, bad_block)?;
+                        if fail_fast {
+                            return Ok(results);
+                        }
+                        match next {
+                            None => break,
+                            Some(Restart::Member) |
+                            Some(Restart::Unreadable) => position = next_position,
+                            Some(Restart::Block(entry)) => restart = Some((entry, Member {
+                                position,
+                                size: None,
+                                digest: None,
+                                held: None,
+                                overflow_size: 0,
+                            })),
+                        }
+                        continue;
+                    },
+                }
+            },
+        };
+
+        match inflate(reader, entry, &mut member, &mut results, &mut writer, &mut resynchronizer, ddrescue_map, progress_listener)? {
+            End::Stream(trailer_positions) => {
+                let (trailer_position, trailer) = read_trailer(reader, &trailer_positions)?;
+                // a trailer which can't be read tells nothing
+                if let Some(unreadable_position) = ddrescue_map.and_then(|ddrescue_map| ddrescue_map.next_unreadable(trailer_position)) {
+                    if unreadable_position < trailer_position + TRAILER_SIZE as u64 {
+                        release_payload(&mut results, &mut writer, &mut resynchronizer, &mut member)?;
+                        position = unreadable_position.max(trailer_position);
+                        continue;
+                    }
+                }
+                if trailer.len() < TRAILER_SIZE {
+                    release_payload(&mut results, &mut writer, &mut resynchronizer, &mut member)?;
+                    report_truncated_member(&mut results, progress_listener, member.position, trailer_position + trailer.len() as u64 - member.position);
+                    break;
+                }
+                position = trailer_position + TRAILER_SIZE as u64;
+                let crc32 = LittleEndian::read_u32(&trailer);
+                let inflated_size = LittleEndian::read_u32(&trailer[4..]);
+                match (member.digest.take(), member.size) {
+                    (Some(digest), Some(size)) => {
+                        // the whole payload is lost, as there's no telling
+                        // where it's corrupted
+                        let kind = if digest.finalize() != crc32 {
+                            CorruptionKind::Crc32
+                        } else if size as u32 != inflated_size {
+                            CorruptionKind::Isize
+                        } else {
+                            release_payload(&mut results, &mut writer, &mut resynchronizer, &mut member)?;
+                            continue;
+                        };
+                        member.held = None;
+                        results.bad_blocks_size += size;
+                        let bad_block = BadBlock {
+                            position: member.position,
+                            size: position - member.position,
+                            inflated_position: results.blocks_size - size,
+                            kind,
+                        };
+                        report_bad_block(&mut results, progress_listener, &mut writer, &mut resynchronizer, bad_block)?;
+                        if fail_fast {
+                            return Ok(results);
+                        }
+                    },
+                    (None, Some(size)) => {
+                        // the size declared is modulo 4 GiB, like the payload
+                        // lost hopefully is
+                        let lost_size = inflated_size.wrapping_sub(size as u32) as u64;
+                        results.blocks_size += lost_size;
+                        results.bad_blocks_size += lost_size;
+                    },
+                    _ => (),
+                }
+            },
+            End::Corrupted(corruption_position) => {
+                // what was inflated before the corruption is still known
+                release_payload(&mut results, &mut writer, &mut resynchronizer, &mut member)?;
+                let (next_position, next) = find_restart(reader, corruption_position, ddrescue_map)?;
+                let bad_block = BadBlock {
+                    position: corruption_position,
+                    size: next_position - corruption_position,
+                    inflated_position: results.blocks_size,
+                    kind: CorruptionKind::Inflate,
+                };
+                report_bad_block(&mut results, progress_listener, &mut writer, &mut resynchronizer, bad_block)?;
+                if fail_fast {
+                    return Ok(results);
+                }
+                member.digest = None;
+                match next {
+                    None => break,
+                    Some(Restart::Member) |
+                    Some(Restart::Unreadable) => position = next_position,
+                    Some(Restart::Block(entry)) => restart = Some((entry, member)),
+                }
+            },
+            End::File(end_position) => {
+                release_payload(&mut results, &mut writer, &mut resynchronizer, &mut member)?;
+                report_truncated_member(&mut results, progress_listener, member.position, end_position - member.position);
+                break;
+            },
+            // reported from there on, like a corrupted member header
+            End::Unreadable(unreadable_position) => {
+                release_payload(&mut results, &mut writer, &mut resynchronizer, &mut member)?;
+                position = unreadable_position;
+            },
+        }
+    }
+
+    if let (Some(ref mut writer), Some(ref mut resynchronizer)) = (&mut writer, &mut resynchronizer) {
+        // plain gzip files have no end-of-file marker, but the rescued file
+        // must, as if the input had one unless it's truncated
+        if !results.truncated_in_block {
+            resynchronizer.write(*writer, bgzf::block(&[]), vec![]).map_err(RescueError::Output)?;
+        }
+        resynchronizer.finish(*writer, results.truncated_in_block).map_err(RescueError::Output)?;
+        results.dropped_records_size = resynchronizer.dropped_size();
+        if let Some(indexer) = resynchronizer.take_indexer() {
+            results.index = Some(indexer.finish().map_err(RescueError::Index)?);
+        }
+    }
+
+    if let Some(ref mut progress_listener) = progress_listener {
+        progress_listener.on_finished();
+    }
+
+    Ok(results)
+}
@@ -0,0 +1,921 @@
+use byteorder::{
+    ByteOrder,
+    LittleEndian,
+    WriteBytesExt,
+};
+
+use serde_derive::Serialize;
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+        HashSet,
+        VecDeque,
+    },
+    fmt,
+};
+
+use crate::{
+    bgzf,
+    tabix::TabixColumns,
+    text,
+    BadBlock,
+    InputFormat,
+    ListenProgress,
+    Results,
+};
+
+const BAI_MAGIC: [u8; 4] = [0x42, 0x41, 0x49, 0x01];
+const CSI_MAGIC: [u8; 4] = [0x43, 0x53, 0x49, 0x01];
+const TBI_MAGIC: [u8; 4] = [0x54, 0x42, 0x49, 0x01];
+const BAM_MAGIC: [u8; 4] = [0x42, 0x41, 0x4d, 0x01];
+
+// 16 kiB windows, and as many levels as needed for 512 Mbp with bai
+const MIN_SHIFT: u32 = 14;
+const BAI_DEPTH: u32 = 5;
+const BAI_MAX_REFERENCE_LENGTH: i64 = 1 << 29;
+
+// positions have to fit in 64 bits at the smallest level
+const MAX_CSI_SHIFT: i32 = 63;
+
+// deepest csi whose pseudo-bin, in the level below the last one, still fits
+// in the u32 of its bins
+const MAX_CSI_DEPTH: i32 = 10;
+
+// format, col_seq, col_beg, col_end, meta, skip and l_nm, as with tabix
+const AUXILIARY_DATA_HEADER_SIZE: usize = 28;
+
+const UNMAPPED: u16 = 0x4;
+
+// block_size, then refID, pos, l_read_name, mapq, bin, n_cigar_op, flag,
+// l_seq, next_refID, next_pos and tlen
+const RECORD_HEADER_SIZE: usize = 36;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexErrorKind {
+    Magic,
+    // corrupted bgzf block in a compressed index
+    Compression,
+    // csi min_shift and depth
+    Header,
+    AuxiliaryData,
+    Truncation,
+    ReferencesCount,
+    Bin,
+    // offset not at the beginning of a bgzf block
+    Dangling,
+    // offset past the end of the payload of its bgzf block
+    OutsideBlock,
+    // offset in a bgzf block which is corrupted
+    CorruptedBlock,
+    // chunk ending before it begins, linear index going backwards, or
+    // negative number of entries
+    Inconsistent,
+    // number of unplaced reads not matching the bam file
+    UnplacedCount,
+    // names stored like tabix does not matching the data file
+    ReferencesNames,
+}
+
+impl fmt::Display for IndexErrorKind {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(match self {
+            IndexErrorKind::Magic => "invalid index magic",
+            IndexErrorKind::Compression => "corrupted index compression",
+            IndexErrorKind::Header => "invalid minimum shift or depth",
+            IndexErrorKind::AuxiliaryData => "invalid auxiliary data",
+            IndexErrorKind::Truncation => "truncated index",
+            IndexErrorKind::ReferencesCount => "number of reference sequences not matching the bam file",
+            IndexErrorKind::Bin => "invalid bin number",
+            IndexErrorKind::Dangling => "virtual offset not at a bgzf block boundary",
+            IndexErrorKind::OutsideBlock => "virtual offset past the end of its bgzf block",
+            IndexErrorKind::CorruptedBlock => "virtual offset in a corrupted bgzf block",
+            IndexErrorKind::Inconsistent => "inconsistent virtual offsets",
+            IndexErrorKind::UnplacedCount => "number of unplaced reads not matching the bam file",
+            IndexErrorKind::ReferencesNames => "reference sequence names not matching the data file",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct IndexError {
+    // position of the entry in the index file
+    pub position: u64,
+    // 0 for errors about the index file itself
+    pub virtual_offset: u64,
+    pub kind: IndexErrorKind,
+}
+
+#[derive(Serialize)]
+pub struct IndexResults {
+    // None if not recognized
+    pub format: Option<IndexFormat>,
+    pub virtual_offsets_count: u64,
+    pub index_errors: Vec<IndexError>,
+}
+
+// Keeps where each block starts and how large its payload is, and indexes
+// the records again to count them, while forwarding everything to the actual
+// progress listener
+pub struct BlocksListener<'a, 'b> {
+    progress_listener: &'a mut Option<&'b mut dyn ListenProgress>,
+    inflated_payload_sizes: HashMap<u64, u32>,
+    indexer: Indexer,
+}
+
+impl<'a, 'b> BlocksListener<'a, 'b> {
+    pub fn new(progress_listener: &'a mut Option<&'b mut dyn ListenProgress>, indexer: Indexer) -> Self {
+        Self {
+            progress_listener,
+            inflated_payload_sizes: HashMap::new(),
+            indexer,
+        }
+    }
+
+    // Only meaningful if the blocks were all found and not corrupted
+    pub fn into_index(self) -> Result<Index, &'static str> {
+        self.indexer.finish()
+    }
+}
+
+impl ListenProgress for BlocksListener<'_, '_> {
+    fn on_new_target(&mut self, target: Option<u64>) {
+        if let Some(ref mut progress_listener) = self.progress_listener {
+            progress_listener.on_new_target(target);
+        }
+    }
+    fn on_progress(&mut self, progress: u64) {
+        if let Some(ref mut progress_listener) = self.progress_listener {
+            progress_listener.on_progress(progress);
+        }
+    }
+    fn on_bad_block(&mut self, bad_block: &BadBlock) {
+        if let Some(ref mut progress_listener) = self.progress_listener {
+            progress_listener.on_bad_block(bad_block);
+        }
+    }
+    fn on_finished(&mut self) {
+        if let Some(ref mut progress_listener) = self.progress_listener {
+            progress_listener.on_finished();
+        }
+    }
+    fn on_block(&mut self, position: u64, size: u64, inflated_payload_bytes: &[u8]) {
+        self.inflated_payload_sizes.insert(position, inflated_payload_bytes.len() as u32);
+        self.indexer.block(size as usize, inflated_payload_bytes);
+        if let Some(ref mut progress_listener) = self.progress_listener {
+            progress_listener.on_block(position, size, inflated_payload_bytes);
+        }
+    }
+}
+
+struct Checker<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    inflated_payload_sizes: HashMap<u64, u32>,
+    bad_blocks_positions: HashSet<u64>,
+    // None if the bam file could not be indexed again
+    references_lengths: Option<Vec<i64>>,
+    references_names: Option<Vec<Vec<u8>>>,
+    unplaced_count: Option<u64>,
+    min_shift: u32,
+    depth: u32,
+    results: IndexResults,
+}
+
+impl Checker<'_> {
+    fn error(&mut self, position: usize, virtual_offset: u64, kind: IndexErrorKind) {
+        self.results.index_errors.push(IndexError {
+            position: position as u64,
+            virtual_offset,
+            kind,
+        });
+    }
+
+    // None once the index is truncated, which has been reported already
+    fn take(&mut self, size: usize) -> Option<&[u8]> {
+        if self.bytes.len() - self.position < size {
+            let position = self.bytes.len();
+            self.error(position, 0, IndexErrorKind::Truncation);
+            self.position = self.bytes.len();
+            return None;
+        }
+        self.position += size;
+        Some(&self.bytes[self.position - size..self.position])
+    }
+
+    fn read_i32(&mut self) -> Option<i32> {
+        self.take(4).map(LittleEndian::read_i32)
+    }
+
+    fn read_u32(&mut self) -> Option<u32> {
+        self.take(4).map(LittleEndian::read_u32)
+    }
+
+    fn read_count(&mut self) -> Option<usize> {
+        let position = self.position;
+        let count = self.read_i32()?;
+        // a negative count can't be trusted to find the next entries
+        if count < 0 {
+            self.error(position, 0, IndexErrorKind::Inconsistent);
+            self.position = self.bytes.len();
+            return None;
+        }
+        Some(count as usize)
+    }
+
+    fn read_virtual_offset(&mut self) -> Option<u64> {
+        let position = self.position;
+        let virtual_offset = LittleEndian::read_u64(self.take(8)?);
+        self.results.virtual_offsets_count += 1;
+        let block_position = virtual_offset >> 16;
+        let inflated_position = (virtual_offset & 0xffff) as u32;
+        if self.bad_blocks_positions.contains(&block_position) {
+            self.error(position, virtual_offset, IndexErrorKind::CorruptedBlock);
+        } else {
+            match self.inflated_payload_sizes.get(&block_position) {
+                None => self.error(position, virtual_offset, IndexErrorKind::Dangling),
+                // the end of a block is also where the next block begins
+                Some(&inflated_payload_size) if inflated_position > inflated_payload_size => self.error(position, virtual_offset, IndexErrorKind::OutsideBlock),
+                Some(_) => (),
+            }
+        }
+        Some(virtual_offset)
+    }
+
+    fn check_chunk(&mut self) -> Option<()> {
+        let position = self.position;
+        let begin = self.read_virtual_offset()?;
+        let end = self.read_virtual_offset()?;
+        if begin > end {
+            self.error(position, begin, IndexErrorKind::Inconsistent);
+        }
+        Some(())
+    }
+
+    // Whether the bin begins within the reference sequence, if its length
+    // is known
+    fn bin_in_reference(&self, bin: u32, reference_id: usize) -> bool {
+        let reference_length = match self.references_lengths {
+            Some(ref references_lengths) => references_lengths.get(reference_id).copied().unwrap_or(0),
+            None => return true,
+        };
+        let mut level = self.depth;
+        while first_bin(level) > bin {
+            level -= 1;
+        }
+        let bin_begin = ((bin - first_bin(level)) as i64) << (self.min_shift + 3 * (self.depth - level));
+        bin_begin == 0 || bin_begin < reference_length
+    }
+
+    fn check_reference(&mut self, reference_id: usize) -> Option<()> {
+        // the pseudo-bin holds the offsets of the first and last records of
+        // the reference sequence and its numbers of mapped and unmapped reads,
+        // as two fake chunks
+        let pseudo_bin = first_bin(self.depth + 1) + 1;
+        let max_bin = first_bin(self.depth + 1) - 1;
+        let bins_count = self.read_count()?;
+        for _ in 0..bins_count {
+            let position = self.position;
+            let bin = self.read_u32()?;
+            if self.results.format == Some(IndexFormat::Csi) {
+                // offset of the first record overlapping the bin, unused by
+                // the pseudo-bin
+                if bin == pseudo_bin {
+                    self.take(8)?;
+                } else {
+                    self.read_virtual_offset()?;
+                }
+            }
+            let chunks_count = self.read_count()?;
+            if bin == pseudo_bin && chunks_count == 2 {
+                self.check_chunk()?;
+                self.take(16)?;
+                continue;
+            }
+            if bin > max_bin || !self.bin_in_reference(bin, reference_id) {
+                self.error(position, 0, IndexErrorKind::Bin);
+            }
+            for _ in 0..chunks_count {
+                self.check_chunk()?;
+            }
+        }
+        if self.results.format != Some(IndexFormat::Csi) {
+            let intervals_count = self.read_count()?;
+            let mut previous_virtual_offset = 0;
+            for _ in 0..intervals_count {
+                let position = self.position;
+                let virtual_offset = self.read_virtual_offset()?;
+                if virtual_offset < previous_virtual_offset {
+                    self.error(position, virtual_offset, IndexErrorKind::Inconsistent);
+                }
+                previous_virtual_offset = virtual_offset;
+            }
+        }
+        Some(())
+    }
+
+    // Only checks the auxiliary data stored like tabix does, other tools
+    // are free to store anything there
+    fn check_auxiliary_data(&mut self, position: usize, auxiliary_data: &[u8], references_count: usize) {
+        if auxiliary_data.len() < AUXILIARY_DATA_HEADER_SIZE {
+            return;
+        }
+        let names_size = LittleEndian::read_i32(&auxiliary_data[24..]);
+        if names_size < 0 || AUXILIARY_DATA_HEADER_SIZE + names_size as usize != auxiliary_data.len() {
+            return;
+        }
+        let names = &auxiliary_data[AUXILIARY_DATA_HEADER_SIZE..];
+        if names.last().is_some_and(|&byte| byte != 0) || names.iter().filter(|&&byte| byte == 0).count() != references_count {
+            self.error(position, 0, IndexErrorKind::AuxiliaryData);
+            return;
+        }
+        if let Some(ref references_names) = self.references_names {
+            if names.split_last().map_or(0, |(_, names)| names.split(|&byte| byte == 0).count()) != references_names.len() ||
+               !names.split(|&byte| byte == 0).zip(references_names).all(|(name, reference_name)| name == &reference_name[..]) {
+                self.error(position, 0, IndexErrorKind::ReferencesNames);
+            }
+        }
+    }
+
+    fn check(&mut self) -> Option<()> {
+        let magic = self.take(4)?;
+        if magic == BAI_MAGIC {
+            self.results.format = Some(IndexFormat::Bai);
+        } else if magic == TBI_MAGIC {
+            self.results.format = Some(IndexFormat::Tbi);
+        } else if magic == CSI_MAGIC {
+            self.results.format = Some(IndexFormat::Csi);
+            let min_shift = self.read_i32()?;
+            let depth = self.read_i32()?;
+            if min_shift <= 0 || depth <= 0 || depth > MAX_CSI_DEPTH || min_shift + 3 * depth > MAX_CSI_SHIFT {
+                self.error(4, 0, IndexErrorKind::Header);
+                return None;
+            }
+            self.min_shift = min_shift as u32;
+            self.depth = depth as u32;
+        } else {
+            self.error(0, 0, IndexErrorKind::Magic);
+            return None;
+        }
+        let auxiliary_data = if self.results.format == Some(IndexFormat::Csi) {
+            let position = self.position;
+            let auxiliary_data_size = self.read_count()?;
+            Some((position, self.take(auxiliary_data_size)?.to_vec()))
+        } else {
+            None
+        };
+        let position = self.position;
+        let references_count = self.read_count()?;
+        if self.references_lengths.as_ref().is_some_and(|references_lengths| references_lengths.len() != references_count) {
+            self.error(position, 0, IndexErrorKind::ReferencesCount);
+        }
+        // tbi always stores its columns and names like tabix does
+        let auxiliary_data = if self.results.format == Some(IndexFormat::Tbi) {
+            let position = self.position;
+            let mut auxiliary_data = self.take(AUXILIARY_DATA_HEADER_SIZE)?.to_vec();
+            let names_size = LittleEndian::read_i32(&auxiliary_data[24..]);
+            if names_size < 0 || TabixColumns::read(&auxiliary_data).is_none() {
+                self.error(position, 0, IndexErrorKind::AuxiliaryData);
+                return None;
+            }
+            auxiliary_data.extend(self.take(names_size as usize)?);
+            Some((position, auxiliary_data))
+        } else {
+            auxiliary_data
+        };
+        if let Some((position, auxiliary_data)) = auxiliary_data {
+            self.check_auxiliary_data(position, &auxiliary_data, references_count);
+        }
+        for reference_id in 0..references_count {
+            self.check_reference(reference_id)?;
+        }
+        // the number of unplaced unmapped reads is optional
+        if self.bytes.len() - self.position >= 8 {
+            let position = self.position;
+            let unplaced_count = LittleEndian::read_u64(self.take(8)?);
+            if self.unplaced_count.is_some_and(|count| count != unplaced_count) {
+                self.error(position, 0, IndexErrorKind::UnplacedCount);
+            }
+        }
+        Some(())
+    }
+}
+
+// Columns of the data file as stored in a tbi index, to index it again the
+// same way; csi indexes may store them too, but may as well index bam files
+pub fn tabix_columns(bytes: &[u8]) -> Option<TabixColumns> {
+    if bytes.starts_with(&TBI_MAGIC) {
+        TabixColumns::read(bytes.get(8..)?)
+    } else {
+        None
+    }
+}
+
+// Checks every virtual offset of a bai, csi or tbi index against the blocks found,
+// the index being already inflated if it's compressed
+pub fn check(bytes: &[u8], corrupted_compression: bool, blocks_listener: BlocksListener, results: &Results) -> IndexResults {
+    // without corruption, the blocks are contiguous and indexed again as is
+    let intact = results.bad_blocks.is_empty() && !results.truncated_between_blocks;
+    let mut checker = Checker {
+        bytes,
+        position: 0,
+        inflated_payload_sizes: blocks_listener.inflated_payload_sizes,
+        bad_blocks_positions: results.bad_blocks.iter().map(|bad_block| bad_block.position).collect(),
+        references_lengths: blocks_listener.indexer.references_lengths().filter(|_| intact),
+        references_names: blocks_listener.indexer.references_names().filter(|_| intact),
+        unplaced_count: blocks_listener.indexer.unplaced_count().filter(|_| intact),
+        min_shift: MIN_SHIFT,
+        depth: BAI_DEPTH,
+        results: IndexResults {
+            format: None,
+            virtual_offsets_count: 0,
+            index_errors: vec![],
+        },
+    };
+    if corrupted_compression {
+        checker.error(0, 0, IndexErrorKind::Compression);
+    } else {
+        checker.check();
+    }
+    checker.results
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexFormat {
+    Bai,
+    // for reference sequences too long for bai
+    Csi,
+    // for bgzipped text files
+    Tbi,
+}
+
+impl IndexFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            IndexFormat::Bai => "bai",
+            IndexFormat::Csi => "csi",
+            IndexFormat::Tbi => "tbi",
+        }
+    }
+}
+
+pub struct Index {
+    pub format: IndexFormat,
+    pub bytes: Vec<u8>,
+}
+
+// First bin of the given level, the pseudo-bin being the first one of the
+// level below the last one
+fn first_bin(level: u32) -> u32 {
+    (((1u64 << (3 * level)) - 1) / 7) as u32
+}
+
+// Smallest bin holding the whole [begin, end) interval
+fn region_bin(begin: i64, end: i64, depth: u32) -> u32 {
+    let end = end - 1;
+    let mut shift = MIN_SHIFT;
+    for level in (1..=depth).rev() {
+        if begin >> shift == end >> shift {
+            return first_bin(level) + (begin >> shift) as u32;
+        }
+        shift += 3;
+    }
+    0
+}
+
+#[derive(Default)]
+struct ReferenceIndex {
+    bins: BTreeMap<u32, Vec<(u64, u64)>>,
+    // smallest offset of the records overlapping each window, if any
+    windows: Vec<Option<u64>>,
+    // first and last offsets of the records, and their numbers
+    begin: Option<u64>,
+    end: u64,
+    mapped_count: u64,
+    unmapped_count: u64,
+}
+
+impl ReferenceIndex {
+    // Windows without records point to the next records, which is where
+    // reading them should begin
+    fn linear_index(&self) -> Vec<u64> {
+        let mut linear_index = vec![0; self.windows.len()];
+        let mut next_offset = self.end;
+        for (window, offset) in self.windows.iter().enumerate().rev() {
+            next_offset = offset.unwrap_or(next_offset);
+            linear_index[window] = next_offset;
+        }
+        linear_index
+    }
+}
+
+struct EmittedBlock {
+    position: u64,
+    inflated_position: u64,
+    inflated_size: u64,
+}
+
+// What the indexed payload is made of
+#[derive(Clone, Copy)]
+enum Payload {
+    Bam,
+    Lines(TabixColumns),
+}
+
+// Indexes a bam or bgzipped text file while it's being written, block after
+// block
+pub struct Indexer {
+    // None until detected
+    payload: Option<Payload>,
+    pending: Vec<u8>,
+    // inflated position of the first pending byte
+    inflated_position: u64,
+    blocks: VecDeque<EmittedBlock>,
+    position: u64,
+    depth: Option<u32>,
+    // lengths of the sequences, or how far their lines go for text files
+    references_lengths: Vec<i64>,
+    references_names: Vec<Vec<u8>>,
+    references_ids: HashMap<Vec<u8>, usize>,
+    references: Vec<ReferenceIndex>,
+    previous_record: (i32, i64),
+    unplaced_count: u64,
+    lines_count: usize,
+    error: Option<&'static str>,
+}
+
+impl Indexer {
+    pub fn new() -> Self {
+        Self {
+            payload: None,
+            pending: vec![],
+            inflated_position: 0,
+            blocks: VecDeque::new(),
+            position: 0,
+            depth: None,
+            references_lengths: vec![],
+            references_names: vec![],
+            references_ids: HashMap::new(),
+            references: vec![],
+            previous_record: (0, 0),
+            unplaced_count: 0,
+            lines_count: 0,
+            error: None,
+        }
+    }
+
+    pub fn tabix(columns: TabixColumns) -> Self {
+        let mut indexer = Self::new();
+        indexer.set_payload(Payload::Lines(columns));
+        indexer
+    }
+
+    // Unless already known, as it's otherwise detected from the payload
+    pub fn set_input_format(&mut self, input_format: InputFormat) {
+        if self.payload.is_none() {
+            match (input_format, TabixColumns::preset(input_format)) {
+                (InputFormat::Bam, _) => self.set_payload(Payload::Bam),
+                (_, Some(columns)) => self.set_payload(Payload::Lines(columns)),
+                (_, None) => self.error = Some("not a bam, vcf, bed or gff file"),
+            }
+        }
+    }
+
+    fn set_payload(&mut self, payload: Payload) {
+        self.payload = Some(payload);
+        // tabix has no header to choose the depth from
+        if let Payload::Lines(_) = payload {
+            self.depth = Some(BAI_DEPTH);
+        }
+    }
+
+    fn virtual_offset(&self, inflated_position: u64) -> u64 {
+        match self.blocks.iter().find(|block| inflated_position < block.inflated_position + block.inflated_size) {
+            Some(block) => (block.position << 16) | (inflated_position - block.inflated_position),
+            // like htslib, the end of a block is the beginning of the next one
+            None => self.position << 16,
+        }
+    }
+
+    // Each bgzf block written, with its inflated payload
+    pub fn block(&mut self, size: usize, inflated_bytes: &[u8]) {
+        if !inflated_bytes.is_empty() {
+            self.blocks.push_back(EmittedBlock {
+                position: self.position,
+                inflated_position: self.inflated_position + self.pending.len() as u64,
+                inflated_size: inflated_bytes.len() as u64,
+            });
+        }
+        self.position += size as u64;
+        if self.error.is_none() {
+            self.pending.extend_from_slice(inflated_bytes);
+            if self.payload.is_none() && !self.pending.is_empty() {
+                self.set_input_format(text::detect(&self.pending));
+            }
+            let parsed_size = match (self.payload, self.depth) {
+                (None, _) => 0,
+                (Some(Payload::Bam), None) => self.header(),
+                (Some(Payload::Bam), Some(_)) => self.records(),
+                (Some(Payload::Lines(columns)), _) => self.lines(columns, false),
+            };
+            self.pending.drain(..parsed_size);
+            self.inflated_position += parsed_size as u64;
+            while self.blocks.front().is_some_and(|block| block.inflated_position + block.inflated_size <= self.inflated_position) {
+                self.blocks.pop_front();
+            }
+        }
+    }
+
+    // Returns the size of the header, or 0 until it's complete
+    fn header(&mut self) -> usize {
+        if self.pending.len() < 8 {
+            return 0;
+        }
+        if self.pending[..4] != BAM_MAGIC {
+            self.error = Some("not a bam file");
+            return 0;
+        }
+        let mut offset = 8 + LittleEndian::read_i32(&self.pending[4..]).max(0) as usize;
+        if self.pending.len() < offset + 4 {
+            return 0;
+        }
+        let references_count = LittleEndian::read_i32(&self.pending[offset..]).max(0) as usize;
+        offset += 4;
+        let mut references_lengths = vec![];
+        for _ in 0..references_count {
+            if self.pending.len() < offset + 4 {
+                return 0;
+            }
+            offset += 4 + LittleEndian::read_i32(&self.pending[offset..]).max(0) as usize;
+            if self.pending.len() < offset + 4 {
+                return 0;
+            }
+            references_lengths.push(LittleEndian::read_i32(&self.pending[offset..]) as i64);
+            offset += 4;
+        }
+        let max_reference_length = references_lengths.iter().copied().max().unwrap_or(0);
+        // same depth as htslib, so that the whole sequence fits in bin 0
+        let mut depth = 0;
+        let mut covered_length = 1 << MIN_SHIFT;
+        while max_reference_length + 256 > covered_length {
+            depth += 1;
+            covered_length <<= 3;
+        }
+        self.depth = Some(if max_reference_length <= BAI_MAX_REFERENCE_LENGTH { BAI_DEPTH } else { depth });
+        self.references.resize_with(references_count, ReferenceIndex::default);
+        self.references_lengths = references_lengths;
+        offset + self.records_from(offset)
+    }
+
+    fn records(&mut self) -> usize {
+        self.records_from(0)
+    }
+
+    // Returns the size of the complete records starting at the given offset
+    fn records_from(&mut self, start: usize) -> usize {
+        let mut offset = start;
+        while self.error.is_none() && self.pending.len() >= offset + 4 {
+            let record_size = 4 + LittleEndian::read_i32(&self.pending[offset..]).max(0) as usize;
+            if record_size < RECORD_HEADER_SIZE {
+                self.error = Some("invalid alignment record");
+                break;
+            }
+            if self.pending.len() < offset + record_size {
+                break;
+            }
+            let begin = self.virtual_offset(self.inflated_position + offset as u64);
+            let end = self.virtual_offset(self.inflated_position + (offset + record_size) as u64);
+            let record = &self.pending[offset..offset + record_size];
+            let (reference_id, position, reference_length, flag) = (
+                LittleEndian::read_i32(&record[4..]),
+                LittleEndian::read_i32(&record[8..]) as i64,
+                reference_length(record),
+                LittleEndian::read_u16(&record[18..]),
+            );
+            self.record(reference_id, position, reference_length, flag, begin, end);
+            offset += record_size;
+        }
+        offset - start
+    }
+
+    // Returns the size of the complete lines, and of the last one as well when
+    // finishing, as it doesn't have to end with a newline
+    fn lines(&mut self, columns: TabixColumns, finishing: bool) -> usize {
+        let mut offset = 0;
+        while self.error.is_none() {
+            let line_size = match self.pending[offset..].iter().position(|&byte| byte == b'\n') {
+                Some(end) => end + 1,
+                None if finishing && offset < self.pending.len() => self.pending.len() - offset,
+                None => break,
+            };
+            let line = &self.pending[offset..offset + line_size];
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            self.lines_count += 1;
+            if self.lines_count > columns.skip && !columns.is_header(line) {
+                let (sequence, position, end) = match columns.interval(line) {
+                    Some((sequence, position, end)) => (sequence.to_vec(), position, end),
+                    None => {
+                        self.error = Some("invalid line");
+                        break;
+                    },
+                };
+                if end > BAI_MAX_REFERENCE_LENGTH {
+                    self.error = Some("position too large for tbi");
+                    break;
+                }
+                let reference_id = match self.references_ids.get(&sequence) {
+                    Some(&reference_id) => reference_id,
+                    None => {
+                        self.references.push(ReferenceIndex::default());
+                        self.references_lengths.push(0);
+                        self.references_names.push(sequence.clone());
+                        self.references_ids.insert(sequence, self.references.len() - 1);
+                        self.references.len() - 1
+                    },
+                };
+                self.references_lengths[reference_id] = self.references_lengths[reference_id].max(end);
+                let begin = self.virtual_offset(self.inflated_position + offset as u64);
+                let end_offset = self.virtual_offset(self.inflated_position + (offset + line_size) as u64);
+                self.record(reference_id as i32, position, end - position, 0, begin, end_offset);
+            }
+            offset += line_size;
+        }
+        offset
+    }
+
+    fn record(&mut self, reference_id: i32, position: i64, reference_length: i64, flag: u16, begin: u64, end: u64) {
+        let (previous_reference_id, previous_position) = self.previous_record;
+        let unplaced = reference_id < 0 || reference_id as usize >= self.references.len();
+        // unplaced records come last
+        if !unplaced && (previous_reference_id < 0 ||
+                         reference_id < previous_reference_id ||
+                         (reference_id == previous_reference_id && position < previous_position)) {
+            self.error = Some("not sorted by coordinate");
+            return;
+        }
+        self.previous_record = (if unplaced { -1 } else { reference_id }, position);
+        if unplaced {
+            self.unplaced_count += 1;
+            return;
+        }
+        let depth = self.depth.unwrap_or(BAI_DEPTH);
+        let reference = &mut self.references[reference_id as usize];
+        let record_begin = position.max(0);
+        let record_end = if flag & UNMAPPED == 0 && reference_length > 0 {
+            record_begin + reference_length
+        } else {
+            record_begin + 1
+        };
+        let chunks = reference.bins.entry(region_bin(record_begin, record_end, depth)).or_default();
+        match chunks.last_mut() {
+            Some(chunk) if chunk.1 == begin => chunk.1 = end,
+            _ => chunks.push((begin, end)),
+        }
+        let last_window = ((record_end - 1) >> MIN_SHIFT) as usize;
+        if reference.windows.len() <= last_window {
+            reference.windows.resize(last_window + 1, None);
+        }
+        for window in &mut reference.windows[(record_begin >> MIN_SHIFT) as usize..=last_window] {
+            window.get_or_insert(begin);
+        }
+        reference.begin.get_or_insert(begin);
+        reference.end = end;
+        if flag & UNMAPPED == 0 {
+            reference.mapped_count += 1;
+        } else {
+            reference.unmapped_count += 1;
+        }
+    }
+
+    pub fn references_lengths(&self) -> Option<Vec<i64>> {
+        self.depth.filter(|_| self.error.is_none()).map(|_| self.references_lengths.clone())
+    }
+
+    pub fn unplaced_count(&self) -> Option<u64> {
+        self.depth.filter(|_| self.error.is_none()).map(|_| self.unplaced_count)
+    }
+
+    // Only known for text files
+    pub fn references_names(&self) -> Option<Vec<Vec<u8>>> {
+        match self.payload {
+            Some(Payload::Lines(_)) if self.error.is_none() => Some(self.references_names.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn finish(mut self) -> Result<Index, &'static str> {
+        if let (Some(Payload::Lines(columns)), None) = (self.payload, self.error) {
+            self.lines(columns, true);
+        }
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let depth = self.depth.ok_or("incomplete bam header")?;
+        let format = match self.payload {
+            Some(Payload::Lines(_)) => IndexFormat::Tbi,
+            _ if depth == BAI_DEPTH => IndexFormat::Bai,
+            _ => IndexFormat::Csi,
+        };
+        let pseudo_bin = first_bin(depth + 1) + 1;
+        let mut bytes = vec![];
+        match (format, self.payload) {
+            (IndexFormat::Tbi, Some(Payload::Lines(columns))) => {
+                bytes.extend(TBI_MAGIC);
+                bytes.write_i32::<LittleEndian>(self.references.len() as i32).unwrap();
+                columns.write(&mut bytes);
+                let names_size: usize = self.references_names.iter().map(|name| name.len() + 1).sum();
+                bytes.write_i32::<LittleEndian>(names_size as i32).unwrap();
+                for name in &self.references_names {
+                    bytes.extend(name);
+                    bytes.push(0);
+                }
+            },
+            (IndexFormat::Csi, _) => {
+                bytes.extend(CSI_MAGIC);
+                bytes.write_i32::<LittleEndian>(MIN_SHIFT as i32).unwrap();
+                bytes.write_i32::<LittleEndian>(depth as i32).unwrap();
+                // no auxiliary data
+                bytes.write_i32::<LittleEndian>(0).unwrap();
+                bytes.write_i32::<LittleEndian>(self.references.len() as i32).unwrap();
+            },
+            _ => {
+                bytes.extend(BAI_MAGIC);
+                bytes.write_i32::<LittleEndian>(self.references.len() as i32).unwrap();
+            },
+        }
+        for reference in &self.references {
+            let linear_index = reference.linear_index();
+            bytes.write_i32::<LittleEndian>((reference.bins.len() + reference.begin.map_or(0, |_| 1)) as i32).unwrap();
+            for (bin, chunks) in &reference.bins {
+                bytes.write_u32::<LittleEndian>(*bin).unwrap();
+                if format == IndexFormat::Csi {
+                    // offset of the first window covered by the bin
+                    let mut level = depth;
+                    while first_bin(level) > *bin {
+                        level -= 1;
+                    }
+                    let window = ((*bin - first_bin(level)) << (3 * (depth - level))) as usize;
+                    bytes.write_u64::<LittleEndian>(linear_index.get(window).copied().unwrap_or(reference.end)).unwrap();
+                }
+                bytes.write_i32::<LittleEndian>(chunks.len() as i32).unwrap();
+                for (begin, end) in chunks {
+                    bytes.write_u64::<LittleEndian>(*begin).unwrap();
+                    bytes.write_u64::<LittleEndian>(*end).unwrap();
+                }
+            }
+            if let Some(begin) = reference.begin {
+                bytes.write_u32::<LittleEndian>(pseudo_bin).unwrap();
+                if format == IndexFormat::Csi {
+                    bytes.write_u64::<LittleEndian>(0).unwrap();
+                }
+                bytes.write_i32::<LittleEndian>(2).unwrap();
+                bytes.write_u64::<LittleEndian>(begin).unwrap();
+                bytes.write_u64::<LittleEndian>(reference.end).unwrap();
+                bytes.write_u64::<LittleEndian>(reference.mapped_count).unwrap();
+                bytes.write_u64::<LittleEndian>(reference.unmapped_count).unwrap();
+            }
+            if format != IndexFormat::Csi {
+                bytes.write_i32::<LittleEndian>(linear_index.len() as i32).unwrap();
+                for offset in linear_index {
+                    bytes.write_u64::<LittleEndian>(offset).unwrap();
+                }
+            }
+        }
+        bytes.write_u64::<LittleEndian>(self.unplaced_count).unwrap();
+        if format != IndexFormat::Bai {
+            // unlike bai, csi and tbi are compressed
+            bytes = bgzf::blocks(&bytes);
+            bytes.extend(bgzf::block(&[]));
+        }
+        Ok(Index {
+            format,
+            bytes,
+        })
+    }
+}
+
+impl Default for Indexer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Number of reference bases covered by the cigar of a record
+fn reference_length(record: &[u8]) -> i64 {
+    let read_name_length = record[12] as usize;
+    let cigar_operations_count = LittleEndian::read_u16(&record[16..]) as usize;
+    let cigar_position = RECORD_HEADER_SIZE + read_name_length;
+    if record.len() < cigar_position + 4 * cigar_operations_count {
+        return 0;
+    }
+    record[cigar_position..cigar_position + 4 * cigar_operations_count].chunks(4)
+        .map(LittleEndian::read_u32)
+        // M, D, N, = and X
+        .filter(|operation| matches!(operation & 0xf, 0 | 2 | 3 | 7 | 8))
+        .map(|operation| (operation >> 4) as i64)
+        .sum()
+}
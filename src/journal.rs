@@ -0,0 +1,750 @@
+use byteorder::{
+    ByteOrder,
+    LittleEndian,
+    WriteBytesExt,
+};
+
+use std::{
+    cell::RefCell,
+
+    collections::VecDeque,
+
+    fs::File,
+
+    io::{
+        self,
+        BufReader,
+        Error,
+        ErrorKind,
+        Read,
+        Seek,
+        SeekFrom,
+        Write,
+    },
+
+    rc::Rc,
+};
+
+use crate::{
+    bgzf,
+    BadBlock,
+    Layout,
+    ListenProgress,
+    RescueError,
+    RescueOptions,
+    Results,
+    CRC32,
+};
+
+// A file whose writes can be made durable, which the journal relies on
+pub trait Durable: Read + Write + Seek {
+    fn sync(&mut self) -> io::Result<()>;
+    fn set_len(&mut self, size: u64) -> io::Result<()>;
+}
+
+impl Durable for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
+}
+
+// The journal starts with two header slots, the valid one of the highest
+// generation being current, followed by the bytes which are not copied from
+// the file itself. Each generation then has its table of segments, a stash
+// and a log of the batches started and done, which runs to the end of the
+// journal.
+const MAGIC: &[u8; 8] = b"BAMRJRNL";
+const HEADER_SIZE: u64 = 128;
+const DATA_OFFSET: u64 = 2 * HEADER_SIZE;
+const SEGMENT_SIZE: usize = 25;
+const RECORD_SIZE: usize = 25;
+
+// Bytes written to the file between two records of the log, and largest
+// stash of the bytes they overwrite
+const BATCH_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Source {
+    File(u64),
+    Journal(u64),
+}
+
+// Bytes written at a position of the file, from another position of the file
+// itself or from the journal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Segment {
+    position: u64,
+    size: u64,
+    source: Source,
+}
+
+impl Segment {
+    // Copies towards the end of the file are done from their end
+    fn batch(&self, done: u64) -> (u64, u64) {
+        let size = BATCH_SIZE.min(self.size - done);
+        match self.source {
+            Source::File(source) if source < self.position => (self.size - done - size, size),
+            _ => (done, size),
+        }
+    }
+
+    // Bytes of the file which are both read and written by the batch, and
+    // have to be stashed for the batch to be done again
+    fn overlap(&self, offset: u64, size: u64) -> Option<(u64, u64)> {
+        match self.source {
+            Source::File(source) => {
+                let start = source.max(self.position) + offset;
+                let end = source.min(self.position) + offset + size;
+                Some((start, end)).filter(|&(start, end)| start < end)
+            },
+            Source::Journal(_) => None,
+        }
+    }
+
+    fn is_noop(&self) -> bool {
+        self.source == Source::File(self.position)
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) {
+        let (kind, source) = match self.source {
+            Source::File(source) => (0, source),
+            Source::Journal(offset) => (1, offset),
+        };
+        bytes.push(kind);
+        bytes.write_u64::<LittleEndian>(self.position).unwrap();
+        bytes.write_u64::<LittleEndian>(self.size).unwrap();
+        bytes.write_u64::<LittleEndian>(source).unwrap();
+    }
+
+    fn read(bytes: &[u8]) -> Option<Self> {
+        let source = LittleEndian::read_u64(&bytes[17..25]);
+        Some(Self {
+            position: LittleEndian::read_u64(&bytes[1..9]),
+            size: LittleEndian::read_u64(&bytes[9..17]),
+            source: match bytes[0] {
+                0 => Source::File(source),
+                1 => Source::Journal(source),
+                _ => return None,
+            },
+        })
+    }
+}
+
+struct Header {
+    generation: u32,
+    rollback: bool,
+    original_size: u64,
+    final_size: u64,
+    table_offset: u64,
+    segments_count: u64,
+    // bytes of the original file which are not copied, after the segments
+    // in the table, only needed to roll back
+    saved_count: u64,
+    // the stash, after the table, is as large as the largest batch
+    log_offset: u64,
+    table_crc32: u32,
+}
+
+impl Header {
+    fn stash_offset(&self) -> u64 {
+        self.table_offset + (self.segments_count + self.saved_count) * SEGMENT_SIZE as u64
+    }
+
+    fn write(&self, journal: &mut dyn Durable) -> io::Result<()> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.write_u32::<LittleEndian>(self.generation)?;
+        bytes.write_u32::<LittleEndian>(self.rollback as u32)?;
+        for value in [self.original_size, self.final_size, self.table_offset, self.segments_count, self.saved_count, self.log_offset] {
+            bytes.write_u64::<LittleEndian>(value)?;
+        }
+        bytes.write_u32::<LittleEndian>(self.table_crc32)?;
+        bytes.write_u32::<LittleEndian>(CRC32.checksum(&bytes))?;
+        journal.seek(SeekFrom::Start(self.generation as u64 % 2 * HEADER_SIZE))?;
+        journal.write_all(&bytes)?;
+        journal.sync()
+    }
+
+    fn read(bytes: &[u8]) -> Option<Self> {
+        if &bytes[..8] != MAGIC || LittleEndian::read_u32(&bytes[68..72]) != CRC32.checksum(&bytes[..68]) {
+            return None;
+        }
+        let mut values = [0u64; 6];
+        LittleEndian::read_u64_into(&bytes[16..64], &mut values);
+        Some(Self {
+            generation: LittleEndian::read_u32(&bytes[8..12]),
+            rollback: LittleEndian::read_u32(&bytes[12..16]) != 0,
+            original_size: values[0],
+            final_size: values[1],
+            table_offset: values[2],
+            segments_count: values[3],
+            saved_count: values[4],
+            log_offset: values[5],
+            table_crc32: LittleEndian::read_u32(&bytes[64..68]),
+        })
+    }
+}
+
+// Batches are started once the bytes they overwrite are stashed, and done once
+// written to the file
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Record {
+    Started {
+        segment: u64,
+        done: u64,
+        stash_crc32: u32,
+    },
+    Done {
+        segment: u64,
+        done: u64,
+    },
+}
+
+impl Record {
+    fn write(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(RECORD_SIZE);
+        let (kind, segment, done, stash_crc32) = match *self {
+            Record::Started { segment, done, stash_crc32 } => (1, segment, done, stash_crc32),
+            Record::Done { segment, done } => (2, segment, done, 0),
+        };
+        bytes.push(kind);
+        bytes.write_u64::<LittleEndian>(segment).unwrap();
+        bytes.write_u64::<LittleEndian>(done).unwrap();
+        bytes.write_u32::<LittleEndian>(stash_crc32).unwrap();
+        let crc32 = CRC32.checksum(&bytes);
+        bytes.write_u32::<LittleEndian>(crc32).unwrap();
+        bytes
+    }
+
+    fn read(bytes: &[u8]) -> Option<Self> {
+        if LittleEndian::read_u32(&bytes[21..25]) != CRC32.checksum(&bytes[..21]) {
+            return None;
+        }
+        let (segment, done) = (LittleEndian::read_u64(&bytes[1..9]), LittleEndian::read_u64(&bytes[9..17]));
+        match bytes[0] {
+            1 => Some(Record::Started {
+                segment,
+                done,
+                stash_crc32: LittleEndian::read_u32(&bytes[17..21]),
+            }),
+            2 => Some(Record::Done {
+                segment,
+                done,
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn invalid_journal(cause: &str) -> RescueError {
+    RescueError::Input(Error::new(ErrorKind::InvalidData, format!("invalid journal: {}", cause)))
+}
+
+fn read_at(file: &mut dyn Durable, position: u64, bytes: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(position))?;
+    file.read_exact(bytes)
+}
+
+fn write_at(file: &mut dyn Durable, position: u64, bytes: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(position))?;
+    file.write_all(bytes)
+}
+
+// The journal of an in-place rescue, as found on the disk
+struct Journal<'a> {
+    journal: &'a mut dyn Durable,
+    header: Header,
+    segments: Vec<Segment>,
+    saved: Vec<Segment>,
+    // the segment being done, how much of it, and whether a batch of it was
+    // started but is not known to be done
+    segment: u64,
+    done: u64,
+    started: Option<u32>,
+    log_end: u64,
+}
+
+impl<'a> Journal<'a> {
+    // None if the journal was not complete yet, in which case the file was
+    // not modified
+    fn read(journal: &'a mut dyn Durable) -> Result<Option<Self>, RescueError> {
+        let journal_size = journal.seek(SeekFrom::End(0)).map_err(RescueError::Input)?;
+        let mut headers = vec![0u8; DATA_OFFSET.min(journal_size) as usize];
+        read_at(journal, 0, &mut headers).map_err(RescueError::Input)?;
+        let header = match headers.chunks_exact(HEADER_SIZE as usize).filter_map(Header::read).max_by_key(|header| header.generation) {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let mut table = vec![0u8; (header.segments_count + header.saved_count) as usize * SEGMENT_SIZE];
+        read_at(journal, header.table_offset, &mut table).map_err(|_| invalid_journal("truncated table"))?;
+        if CRC32.checksum(&table) != header.table_crc32 {
+            return Err(invalid_journal("corrupted table"));
+        }
+        let mut segments = table.chunks_exact(SEGMENT_SIZE).map(Segment::read).collect::<Option<Vec<_>>>().ok_or_else(|| invalid_journal("corrupted table"))?;
+        let saved = segments.split_off(header.segments_count as usize);
+        let mut journal = Self {
+            journal,
+            segments,
+            saved,
+            segment: 0,
+            done: 0,
+            started: None,
+            log_end: header.log_offset,
+            header,
+        };
+        journal.next_segment();
+        // a record cut by a crash ends the log
+        let mut log = vec![];
+        if journal_size > journal.log_end {
+            journal.journal.seek(SeekFrom::Start(journal.log_end)).map_err(RescueError::Input)?;
+            journal.journal.read_to_end(&mut log).map_err(RescueError::Input)?;
+        }
+        for record in log.chunks_exact(RECORD_SIZE).map_while(Record::read) {
+            match record {
+                Record::Started { segment, done, stash_crc32 } if segment == journal.segment && done == journal.done => journal.started = Some(stash_crc32),
+                Record::Done { segment, done } if segment == journal.segment => {
+                    journal.done = done;
+                    journal.started = None;
+                },
+                _ => return Err(invalid_journal("inconsistent log")),
+            }
+            journal.log_end += RECORD_SIZE as u64;
+            journal.next_segment();
+        }
+        if journal_size > journal.log_end {
+            journal.journal.set_len(journal.log_end).map_err(RescueError::Output)?;
+        }
+        Ok(Some(journal))
+    }
+
+    // Writes the table and header of a new generation of the journal, after
+    // what the journal holds so far
+    #[allow(clippy::too_many_arguments)]
+    fn write(journal: &'a mut dyn Durable, generation: u32, rollback: bool, original_size: u64, final_size: u64, table_offset: u64, segments: Vec<Segment>, saved: Vec<Segment>) -> Result<Self, RescueError> {
+        let mut table = Vec::with_capacity((segments.len() + saved.len()) * SEGMENT_SIZE);
+        for segment in segments.iter().chain(&saved) {
+            segment.write(&mut table);
+        }
+        write_at(journal, table_offset, &table).map_err(RescueError::Output)?;
+        let stash_size = segments.iter()
+            .filter(|segment| !segment.is_noop())
+            .map(|segment| BATCH_SIZE.min(segment.size))
+            .max()
+            .unwrap_or(0);
+        journal.sync().map_err(RescueError::Output)?;
+        let header = Header {
+            generation,
+            rollback,
+            original_size,
+            final_size,
+            table_offset,
+            segments_count: segments.len() as u64,
+            saved_count: saved.len() as u64,
+            log_offset: table_offset + table.len() as u64 + stash_size,
+            table_crc32: CRC32.checksum(&table),
+        };
+        header.write(journal).map_err(RescueError::Output)?;
+        let mut journal = Self {
+            journal,
+            segments,
+            saved,
+            segment: 0,
+            done: 0,
+            started: None,
+            log_end: header.log_offset,
+            header,
+        };
+        journal.next_segment();
+        Ok(journal)
+    }
+
+    // Copies to the same position are not done at all
+    fn next_segment(&mut self) {
+        while self.segments.get(self.segment as usize).is_some_and(|segment| self.done == segment.size || segment.is_noop()) {
+            self.segment += 1;
+            self.done = 0;
+        }
+    }
+
+    fn append(&mut self, record: Record) -> Result<(), RescueError> {
+        write_at(self.journal, self.log_end, &record.write()).map_err(RescueError::Output)?;
+        self.journal.sync().map_err(RescueError::Output)?;
+        self.log_end += RECORD_SIZE as u64;
+        Ok(())
+    }
+
+    fn read_source(&mut self, file: &mut dyn Durable, source: Source, bytes: &mut [u8]) -> Result<(), RescueError> {
+        match source {
+            Source::File(position) => read_at(file, position, bytes).map_err(RescueError::Input),
+            Source::Journal(offset) => read_at(self.journal, offset, bytes).map_err(|_| invalid_journal("truncated data")),
+        }
+    }
+
+    // Puts back the bytes overwritten by a batch which may have been cut by
+    // a crash, so that it can be done again
+    fn restore_stash(&mut self, file: &mut dyn Durable) -> Result<(), RescueError> {
+        if let Some(stash_crc32) = self.started.take() {
+            let segment = self.segments[self.segment as usize];
+            let (offset, size) = segment.batch(self.done);
+            if let Some((start, end)) = segment.overlap(offset, size) {
+                let mut stash = vec![0u8; (end - start) as usize];
+                read_at(self.journal, self.header.stash_offset(), &mut stash).map_err(|_| invalid_journal("truncated stash"))?;
+                if CRC32.checksum(&stash) != stash_crc32 {
+                    return Err(invalid_journal("corrupted stash"));
+                }
+                write_at(file, start, &stash).map_err(RescueError::Output)?;
+                file.sync().map_err(RescueError::Output)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, file: &mut dyn Durable) -> Result<(), RescueError> {
+        self.restore_stash(file)?;
+        if self.header.rollback {
+            let size = file.seek(SeekFrom::End(0)).map_err(RescueError::Input)?;
+            if size < self.header.original_size {
+                file.set_len(self.header.original_size).map_err(RescueError::Output)?;
+            }
+        }
+        while let Some(&segment) = self.segments.get(self.segment as usize) {
+            let (offset, size) = segment.batch(self.done);
+            let mut stash = vec![];
+            if let Some((start, end)) = segment.overlap(offset, size) {
+                stash.resize((end - start) as usize, 0u8);
+                read_at(file, start, &mut stash).map_err(RescueError::Input)?;
+                write_at(self.journal, self.header.stash_offset(), &stash).map_err(RescueError::Output)?;
+            }
+            self.append(Record::Started {
+                segment: self.segment,
+                done: self.done,
+                stash_crc32: CRC32.checksum(&stash),
+            })?;
+            let mut bytes = vec![0u8; size as usize];
+            let source = match segment.source {
+                Source::File(position) => Source::File(position + offset),
+                Source::Journal(journal_offset) => Source::Journal(journal_offset + offset),
+            };
+            self.read_source(file, source, &mut bytes)?;
+            write_at(file, segment.position + offset, &bytes).map_err(RescueError::Output)?;
+            file.sync().map_err(RescueError::Output)?;
+            self.done += size;
+            self.append(Record::Done {
+                segment: self.segment,
+                done: self.done,
+            })?;
+            self.next_segment();
+        }
+        file.set_len(self.header.final_size).map_err(RescueError::Output)?;
+        file.sync().map_err(RescueError::Output)
+    }
+
+    // Copies back what was moved, in reverse order, then writes the bytes
+    // which were not copied where they were, as a new generation of the
+    // journal
+    fn rollback(mut self, file: &mut dyn Durable) -> Result<Self, RescueError> {
+        self.restore_stash(file)?;
+        let mut segments = vec![];
+        for (index, segment) in self.segments.iter().enumerate().take(self.segment as usize + 1).rev() {
+            let done = if index < self.segment as usize { segment.size } else { self.done };
+            if let Source::File(source) = segment.source {
+                if done > 0 && !segment.is_noop() {
+                    segments.push(Segment {
+                        position: source,
+                        size: done,
+                        source: Source::File(segment.position),
+                    });
+                }
+            }
+        }
+        segments.extend(self.saved.iter().copied());
+        let original_size = self.header.original_size;
+        Self::write(self.journal, self.header.generation + 1, true, original_size, original_size, self.log_end, segments, vec![])
+    }
+}
+
+// Reads the file from its own position, whatever the position of the file it
+// shares with the planner
+struct Positioned<'a, 'b> {
+    file: Rc<RefCell<&'a mut (dyn Durable + 'b)>>,
+    position: u64,
+}
+
+impl Read for Positioned<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(self.position))?;
+        let size = file.read(buf)?;
+        self.position += size as u64;
+        Ok(size)
+    }
+}
+
+impl Seek for Positioned<'_, '_> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => self.file.borrow_mut().seek(SeekFrom::End(0))?.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid seek to a negative position"))?;
+        Ok(self.position)
+    }
+}
+
+// Turns the rescued file into segments, copying from the file every block
+// which is written as it was read, if it's not moved towards the end of the
+// file, nor before another block already copied. Other blocks, such as those
+// compressed again at the edges of dropped payload, are written to the
+// journal.
+struct Planner<'a, 'b> {
+    file: Rc<RefCell<&'a mut (dyn Durable + 'b)>>,
+    journal: &'a mut dyn Durable,
+    data_end: u64,
+    // non-corrupted blocks read but not written yet
+    blocks: VecDeque<(u64, u64)>,
+    // blocks written, not yet known to have been read as they are
+    written: VecDeque<Vec<u8>>,
+    bytes: Vec<u8>,
+    segments: Vec<Segment>,
+    size: u64,
+    copied_end: u64,
+}
+
+impl Planner<'_, '_> {
+    fn push(&mut self, segment: Segment) {
+        if let Some(last) = self.segments.last_mut() {
+            let contiguous = match (last.source, segment.source) {
+                (Source::File(last_source), Source::File(source)) => last_source + last.size == source,
+                (Source::Journal(last_offset), Source::Journal(offset)) => last_offset + last.size == offset,
+                _ => false,
+            };
+            if contiguous && last.position + last.size == segment.position {
+                last.size += segment.size;
+                return;
+            }
+        }
+        self.segments.push(segment);
+    }
+
+    fn read(&mut self, position: u64, bytes: &mut [u8]) -> io::Result<()> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(bytes)
+    }
+
+    // Every block written has been reported as read by the time the next one
+    // is, if it's written as it was read
+    fn place(&mut self) -> io::Result<()> {
+        while let Some(bytes) = self.written.pop_front() {
+            let mut found = None;
+            for index in 0..self.blocks.len() {
+                let (position, size) = self.blocks[index];
+                if size != bytes.len() as u64 || position < self.size || position < self.copied_end {
+                    continue;
+                }
+                let mut read_bytes = vec![0u8; size as usize];
+                self.read(position, &mut read_bytes)?;
+                if read_bytes == bytes {
+                    found = Some((index, position));
+                    break;
+                }
+            }
+            let source = match found {
+                Some((index, position)) => {
+                    self.blocks.drain(..=index);
+                    self.copied_end = position + bytes.len() as u64;
+                    Source::File(position)
+                },
+                None => {
+                    write_at(self.journal, self.data_end, &bytes)?;
+                    self.data_end += bytes.len() as u64;
+                    Source::Journal(self.data_end - bytes.len() as u64)
+                },
+            };
+            self.push(Segment {
+                position: self.size,
+                size: bytes.len() as u64,
+                source,
+            });
+            self.size += bytes.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+struct PlanWriter<'a, 'b> {
+    planner: Rc<RefCell<Planner<'a, 'b>>>,
+}
+
+impl Write for PlanWriter<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut planner = self.planner.borrow_mut();
+        planner.bytes.extend_from_slice(buf);
+        while let Some(size) = bgzf::block_size(&planner.bytes) {
+            if size == 0 {
+                return Err(Error::new(ErrorKind::InvalidData, "rescued block without bgzf subfield"));
+            }
+            if planner.bytes.len() < size {
+                break;
+            }
+            let rest = planner.bytes.split_off(size);
+            let bytes = std::mem::replace(&mut planner.bytes, rest);
+            planner.written.push_back(bytes);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Blocks are reported as read right after being written, unless held by the
+// resynchronizer, in which case they are reported before
+struct PlanListener<'a, 'b, 'c, 'd> {
+    planner: Rc<RefCell<Planner<'a, 'b>>>,
+    progress_listener: &'c mut Option<&'d mut dyn ListenProgress>,
+    error: Option<io::Error>,
+}
+
+impl ListenProgress for PlanListener<'_, '_, '_, '_> {
+    fn on_new_target(&mut self, target: Option<u64>) {
+        if let Some(ref mut progress_listener) = self.progress_listener {
+            progress_listener.on_new_target(target);
+        }
+    }
+    fn on_progress(&mut self, progress: u64) {
+        if let Some(ref mut progress_listener) = self.progress_listener {
+            progress_listener.on_progress(progress);
+        }
+    }
+    fn on_bad_block(&mut self, bad_block: &BadBlock) {
+        if let Some(ref mut progress_listener) = self.progress_listener {
+            progress_listener.on_bad_block(bad_block);
+        }
+    }
+    fn on_finished(&mut self) {
+        if let Some(ref mut progress_listener) = self.progress_listener {
+            progress_listener.on_finished();
+        }
+    }
+    fn on_block(&mut self, position: u64, size: u64, _inflated_payload_bytes: &[u8]) {
+        let mut planner = self.planner.borrow_mut();
+        planner.blocks.push_back((position, size));
+        if let Err(cause) = planner.place() {
+            self.error.get_or_insert(cause);
+        }
+    }
+}
+
+// What is written to the journal before the file is modified at all, and the
+// size of the rescued file
+struct Plan {
+    results: Results,
+    segments: Vec<Segment>,
+    saved: Vec<Segment>,
+    size: u64,
+    data_end: u64,
+}
+
+fn plan(file: &mut dyn Durable, journal: &mut dyn Durable, original_size: u64, options: RescueOptions, threads: usize, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Result<Plan, RescueError> {
+    let file = Rc::new(RefCell::new(file));
+    let planner = Rc::new(RefCell::new(Planner {
+        file: Rc::clone(&file),
+        journal,
+        data_end: DATA_OFFSET,
+        blocks: VecDeque::new(),
+        written: VecDeque::new(),
+        bytes: vec![],
+        segments: vec![],
+        size: 0,
+        copied_end: 0,
+    }));
+    let mut reader = BufReader::new(Positioned {
+        file: Rc::clone(&file),
+        position: 0,
+    });
+    if !matches!(crate::layout(&mut reader, options.input_format)?, Layout::Bgzf) {
+        return Err(RescueError::Input(Error::new(ErrorKind::InvalidData, "only bgzf files can be rescued in place")));
+    }
+    let mut writer = PlanWriter {
+        planner: Rc::clone(&planner),
+    };
+    let mut plan_listener = PlanListener {
+        planner: Rc::clone(&planner),
+        progress_listener,
+        error: None,
+    };
+    let results = crate::process(&mut reader, Some(&mut writer), None, false, false, options, threads, &mut Some(&mut plan_listener))?;
+    if let Some(cause) = plan_listener.error {
+        return Err(RescueError::Output(cause));
+    }
+    drop((reader, writer, plan_listener));
+    let mut planner = Rc::try_unwrap(planner).map_err(|_| RescueError::Internal("planner still in use".to_owned()))?.into_inner();
+    if !planner.bytes.is_empty() {
+        return Err(RescueError::Internal("rescued file not made of whole bgzf blocks".to_owned()));
+    }
+    planner.place().map_err(RescueError::Output)?;
+    // what is not copied can only be put back from the journal
+    let mut saved = vec![];
+    let mut position = 0u64;
+    let copied: Vec<(u64, u64)> = planner.segments.iter()
+        .filter_map(|segment| match segment.source {
+            Source::File(source) => Some((source, source + segment.size)),
+            Source::Journal(_) => None,
+        })
+        .chain(std::iter::once((original_size, original_size)))
+        .collect();
+    for (start, end) in copied {
+        while position < start {
+            let size = BATCH_SIZE.min(start - position);
+            let mut bytes = vec![0u8; size as usize];
+            planner.read(position, &mut bytes).map_err(RescueError::Input)?;
+            write_at(planner.journal, planner.data_end, &bytes).map_err(RescueError::Output)?;
+            saved.push(Segment {
+                position,
+                size,
+                source: Source::Journal(planner.data_end),
+            });
+            planner.data_end += size;
+            position += size;
+        }
+        position = position.max(end);
+    }
+    Ok(Plan {
+        results,
+        segments: planner.segments,
+        saved,
+        size: planner.size,
+        data_end: planner.data_end,
+    })
+}
+
+pub fn rescue(file: &mut dyn Durable, journal: &mut dyn Durable, options: RescueOptions, threads: usize, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Result<Results, RescueError> {
+    journal.set_len(0).map_err(RescueError::Output)?;
+    let original_size = file.seek(SeekFrom::End(0)).map_err(RescueError::Input)?;
+    // the journal is left empty as long as the file was not modified
+    let plan = plan(file, journal, original_size, options, threads, progress_listener).inspect_err(|_| {
+        let _ = journal.set_len(0);
+    })?;
+    Journal::write(journal, 1, false, original_size, plan.size, plan.data_end, plan.segments, plan.saved)?.run(file)?;
+    Ok(plan.results)
+}
+
+// Goes on with an interrupted in-place rescue, or rolls it back, which can
+// itself be interrupted and goes on from there whatever is asked; returns
+// whether the file is back as it was
+pub fn resume(file: &mut dyn Durable, journal: &mut dyn Durable, rollback: bool) -> Result<bool, RescueError> {
+    let mut journal = match Journal::read(journal)? {
+        Some(journal) => journal,
+        None => return Ok(true),
+    };
+    if rollback && !journal.header.rollback {
+        journal = journal.rollback(file)?;
+    }
+    journal.run(file)?;
+    Ok(journal.header.rollback)
+}
@@ -10,18 +10,71 @@ use crc::{
 
 use futures::Future;
 
+use serde_derive::Serialize;
+
 use std::collections::VecDeque;
 
+mod bam;
+mod bcf;
+mod bgzf;
+mod cram;
+mod bitflips;
+mod ddrescue;
+mod fastq;
+mod gzip;
+mod index;
+mod journal;
+mod lookback;
+mod merge;
+mod patch;
+mod reader;
+mod resync;
+mod tabix;
+mod text;
+
+pub use bam::{
+    FormatError,
+    FormatErrorKind,
+};
+
+pub use ddrescue::DdrescueMap;
+pub use fastq::PairResults;
+
+pub use index::{
+    Index,
+    IndexError,
+    IndexErrorKind,
+    IndexFormat,
+    IndexResults,
+};
+pub use journal::Durable;
+pub use lookback::LookbackReader;
+pub use merge::MergeResults;
+pub use patch::PatchResults;
+pub use reader::RescueReader;
+pub use tabix::TabixColumns;
+
+use bam::Validate;
+use index::Indexer;
+
 use std::{
+    fmt,
     io::{
+        self,
         BufRead,
         Error,
+        ErrorKind,
         Read,
         Seek,
         SeekFrom,
         Write,
     },
+    panic,
     str,
+    sync::{
+        atomic::AtomicUsize,
+        Arc,
+    },
 };
 
 // 100 blocks of 64 kiB, even accounting for a huge overhead,
@@ -39,20 +92,149 @@ const DEFLATE: u8 = 8;
 
 const FEXTRA: u8 = 1 << 2;
 
+// Fixed part of the gzip header, and the bgzf extra subfield
+const MIN_HEADER_SIZE: u64 = 18;
+
 const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
 
 pub fn version() -> &'static str {
-    return option_env!("CARGO_PKG_VERSION").unwrap_or("unknown");
+    option_env!("CARGO_PKG_VERSION").unwrap_or("unknown")
+}
+
+#[derive(Debug)]
+pub enum RescueError {
+    Input(Error),
+    Output(Error),
+    Internal(String),
+    // the rescued file has been written, but can't be indexed, or the file
+    // to index is corrupted
+    Index(&'static str),
+}
+
+impl fmt::Display for RescueError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RescueError::Input(cause) => write!(formatter, "can't read input: {}", cause),
+            RescueError::Output(cause) => write!(formatter, "can't write output: {}", cause),
+            RescueError::Internal(cause) => write!(formatter, "internal error: {}", cause),
+            RescueError::Index(cause) => write!(formatter, "can't index file: {}", cause),
+        }
+    }
+}
+
+impl std::error::Error for RescueError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RescueError::Input(cause) |
+            RescueError::Output(cause) => Some(cause),
+            RescueError::Internal(_) |
+            RescueError::Index(_) => None,
+        }
+    }
 }
 
 pub trait Rescuable: BufRead + Seek {}
 impl<T: BufRead + Seek> Rescuable for T {}
 
+// A file patched in place
+pub trait Patchable: Read + Write + Seek {}
+impl<T: Read + Write + Seek> Patchable for T {}
+
 pub trait ListenProgress {
-    fn on_new_target(&mut self, target: u64);
+    // target is None when reading from a stream
+    fn on_new_target(&mut self, target: Option<u64>);
     fn on_progress(&mut self, progress: u64);
-    fn on_bad_block(&mut self);
+    fn on_bad_block(&mut self, bad_block: &BadBlock);
     fn on_finished(&mut self);
+    // each non-corrupted block (or repaired one), in file order
+    fn on_block(&mut self, _position: u64, _size: u64, _inflated_payload_bytes: &[u8]) {
+    }
+}
+
+// Formats made of bgzf blocks, and cram; anything else is just bgzf
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputFormat {
+    Bam,
+    Bcf,
+    Cram,
+    Vcf,
+    Bed,
+    Gff,
+    Fasta,
+    Fastq,
+    Bgzf,
+}
+
+impl InputFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            InputFormat::Bam => "bam",
+            InputFormat::Bcf => "bcf",
+            InputFormat::Cram => "cram",
+            InputFormat::Vcf => "vcf",
+            InputFormat::Bed => "bed",
+            InputFormat::Gff => "gff",
+            InputFormat::Fasta => "fasta",
+            InputFormat::Fastq => "fastq",
+            InputFormat::Bgzf => "bgzf",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CorruptionKind {
+    GzipHeader,
+    BgzfSubfield,
+    Inflate,
+    Crc32,
+    Isize,
+    Truncation,
+    // cram container header whose CRC32 does not match, or cram block
+    // whose CRC32 does not match or which does not fit in its container
+    ContainerHeader,
+    CramBlock,
+    // overlapping a region which ddrescue could not read
+    Unreadable,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct BadBlock {
+    // position and size of the block in the file
+    pub position: u64,
+    pub size: u64,
+    // position of the block payload once inflated, counting previous blocks
+    // which could not be inflated at the size they declare; same as position for
+    // cram containers and blocks, which are not compressed as a whole
+    pub inflated_position: u64,
+    pub kind: CorruptionKind,
+}
+
+// A bit of the input which has been flipped back when rescuing
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Bitflip {
+    pub position: u64,
+    // 0 being the least significant bit
+    pub bit: u8,
+}
+
+// How rescue and rescue_in_place go about corrupted blocks
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RescueOptions<'a> {
+    // flip single bits of corrupted blocks back, and also pairs of close bits
+    // with double_bitflips, which is only looked at with fix_bitflips
+    pub fix_bitflips: bool,
+    pub double_bitflips: bool,
+    // keep the payload of corrupted blocks up to the first error
+    pub salvage: bool,
+    // also index the rescued file as it's written, see Results::index
+    pub index: bool,
+    // detected from the payload if None
+    pub input_format: Option<InputFormat>,
+    // blocks overlapping the regions ddrescue could not read are corrupted,
+    // see CorruptionKind::Unreadable
+    pub ddrescue_map: Option<&'a DdrescueMap>,
 }
 
 struct BGZFBlock {
@@ -60,35 +242,198 @@ struct BGZFBlock {
     deflated_payload_bytes: Vec<u8>,
     inflated_payload_crc32: u32,
     inflated_payload_size: u32,
-    corrupted: bool,
+    corruption: Option<CorruptionKind>,
+    // single corrupted header byte, already fixed in header_bytes, or wrong
+    // inflated payload size, fixed once the payload has been inflated
+    repair: Option<CorruptionKind>,
+    position: u64,
     end_position: u64,
+    inflated_position: u64,
+}
+
+impl BGZFBlock {
+    fn bad_block(&self, kind: CorruptionKind) -> BadBlock {
+        BadBlock {
+            position: self.position,
+            size: self.end_position - self.position,
+            inflated_position: self.inflated_position,
+            kind,
+        }
+    }
 }
 
 struct BGZFBlockStatus {
-    corrupted: bool,
+    bad_block: Option<BadBlock>,
+    repaired_block: Option<BadBlock>,
+    // bad block which might be fixed by flipping bits of its deflated payload
+    corrupted_block: Option<BGZFBlock>,
     inflated_payload_size: u32,
+    // inflated payload size minus the size the block declares
+    inflated_payload_size_error: i64,
+    inflated_payload_bytes: Vec<u8>,
     block: Option<BGZFBlock>,
 }
 
+#[derive(Serialize)]
 pub struct Results {
+    // given or detected, None if there's no payload to detect it from
+    pub input_format: Option<InputFormat>,
     pub blocks_count: u64,
     pub blocks_size: u64,
     pub bad_blocks_count: u64,
     pub bad_blocks_size: u64,
+    // corrupted blocks in file order; unlike bad_blocks_count, this includes
+    // the block which is cut by the end of the file if truncated_in_block
+    pub bad_blocks: Vec<BadBlock>,
     pub truncated_in_block: bool,
     pub truncated_between_blocks: bool,
+    // blocks with a single corrupted header byte or a wrong inflated payload
+    // size, but a valid payload, which are repaired when rescuing and are not
+    // part of bad_blocks
+    pub repaired_blocks_count: u64,
+    pub repaired_blocks: Vec<BadBlock>,
+    // bits flipped back to repair blocks, only with fix_bitflips
+    pub bitflips: Vec<Bitflip>,
+    // payload inflated from corrupted blocks up to the first error, which is
+    // part of bad_blocks_size but still rescued, only with salvage
+    pub salvaged_size: u64,
+    // blocks of the corrupted containers of cram files, in file order
+    pub bad_cram_blocks: Vec<BadBlock>,
+    // members of a plain gzip file rather than bgzf blocks, in which case
+    // bad_blocks are the corrupted parts of members, see gzip::process
+    pub plain_gzip: bool,
+    // errors in the bam or bcf payload, only checked in deep mode
+    pub format_errors: Vec<FormatError>,
+    // payload of non-corrupted blocks dropped when rescuing, so as to keep
+    // only whole bam records
+    pub dropped_records_size: u64,
+    // bad blocks overlapping the unreadable regions of the ddrescue map,
+    // only when given one
+    pub unreadable_blocks_count: u64,
+    // index of the rescued file, only when asked for
+    #[serde(skip)]
+    pub index: Option<Index>,
+}
+
+impl Results {
+    fn new() -> Self {
+        Self {
+            input_format: None,
+            blocks_count: 0u64,
+            blocks_size: 0u64,
+            bad_blocks_count: 0u64,
+            bad_blocks_size: 0u64,
+            bad_blocks: vec![],
+            truncated_in_block: false,
+            truncated_between_blocks: false,
+            repaired_blocks_count: 0u64,
+            repaired_blocks: vec![],
+            bitflips: vec![],
+            salvaged_size: 0u64,
+            bad_cram_blocks: vec![],
+            plain_gzip: false,
+            format_errors: vec![],
+            dropped_records_size: 0u64,
+            unreadable_blocks_count: 0u64,
+            index: None,
+        }
+    }
+}
+
+// Everything known about a check or a rescue, in a stable format meant to be
+// consumed by other programs
+#[derive(Serialize)]
+pub struct Report {
+    pub version: &'static str,
+    pub command: &'static str,
+    // None when reading from a stream
+    pub input_size: Option<u64>,
+    pub elapsed_seconds: f64,
+    pub results: Results,
+    // only when checking an index
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index_results: Option<IndexResults>,
+    // only when rescuing paired fastq files, for the file of the mates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mate_results: Option<Results>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pair_results: Option<PairResults>,
+    // only when merging replicas
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merge_results: Option<MergeResults>,
+    // only when patching a file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub patch_results: Option<PatchResults>,
+}
+
+// Size of the input, unknown for streams, which is also given to the progress
+// listener
+fn reader_size(reader: &mut dyn Rescuable, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Result<Option<u64>, RescueError> {
+    let reader_size = match reader.seek(SeekFrom::End(0)) {
+        Ok(reader_size) => {
+            reader.seek(SeekFrom::Start(0)).map_err(RescueError::Input)?;
+            Some(reader_size)
+        },
+        Err(error) if error.kind() == ErrorKind::Unsupported => None,
+        Err(error) => return Err(RescueError::Input(error)),
+    };
+    if let Some(ref mut progress_listener) = progress_listener {
+        progress_listener.on_new_target(reader_size);
+    }
+    Ok(reader_size)
 }
 
-fn seek_next_block(reader: &mut dyn Rescuable, block_position: u64) {
-    let mut current_position = block_position;
-    reader.seek(SeekFrom::Start(current_position)).unwrap();
+// How the file is split: cram files are made of containers rather than bgzf
+// blocks, and are told apart by their magic unless the input format is given,
+// while plain gzip files are made of members which are not bgzf blocks
+enum Layout {
+    Bgzf,
+    Cram,
+    Gzip,
+}
+
+fn layout(reader: &mut dyn Rescuable, input_format: Option<InputFormat>) -> Result<Layout, RescueError> {
+    let mut bytes = vec![];
+    reader.take(BUFFER_SIZE).read_to_end(&mut bytes).map_err(RescueError::Input)?;
+    reader.seek(SeekFrom::Start(0)).map_err(RescueError::Input)?;
+    Ok(if input_format == Some(InputFormat::Cram) || (input_format.is_none() && bytes.starts_with(&cram::CRAM_MAGIC)) {
+        Layout::Cram
+    } else if gzip::is_plain_gzip(&bytes) {
+        Layout::Gzip
+    } else {
+        Layout::Bgzf
+    })
+}
+
+// A read error is only a truncation if it is caused by the end of the file,
+// anything else is an actual I/O error which can't be dealt with here
+fn eof_or_input_error(error: Error) -> Result<(), RescueError> {
+    if error.kind() == ErrorKind::UnexpectedEof {
+        Ok(())
+    } else {
+        Err(RescueError::Input(error))
+    }
+}
+
+// Unreadable regions are jumped over rather than scanned, and the scan stops
+// where the next one starts
+fn seek_next_block(reader: &mut dyn Rescuable, block_position: u64, ddrescue_map: Option<&DdrescueMap>) -> Result<(), RescueError> {
+    let (mut current_position, unreadable_position) = match ddrescue_map {
+        Some(ddrescue_map) => ddrescue_map.search_range(block_position),
+        None => (block_position, None),
+    };
+    reader.seek(SeekFrom::Start(current_position)).map_err(RescueError::Input)?;
 
     let mut bytes = vec![];
 
     'seek: loop {
         let mut buffer_reader = reader.take(BUFFER_SIZE);
-        let buffer_size = buffer_reader.read_to_end(&mut bytes).unwrap();
+        let buffer_size = buffer_reader.read_to_end(&mut bytes).map_err(RescueError::Input)?;
         for window in bytes.windows(4) {
+            if let Some(unreadable_position) = unreadable_position.filter(|&unreadable_position| current_position >= unreadable_position) {
+                current_position = unreadable_position;
+                break 'seek;
+            }
             let mut correct_bytes = 0;
             if window[0] == GZIP_IDENTIFIER[0] {
                 correct_bytes += 1;
@@ -109,7 +454,7 @@ fn seek_next_block(reader: &mut dyn Rescuable, block_position: u64) {
             current_position += 1;
         }
         if buffer_size < BUFFER_SIZE as usize {
-            return;
+            return Ok(());
         }
         {
             let (beginning, end) = bytes.split_at_mut(4);
@@ -119,23 +464,43 @@ fn seek_next_block(reader: &mut dyn Rescuable, block_position: u64) {
         current_position -= 4;
     }
 
-    reader.seek(SeekFrom::Start(current_position)).unwrap();
+    reader.seek(SeekFrom::Start(current_position)).map_err(RescueError::Input)?;
+
+    Ok(())
 }
 
-fn process_payload(block: Option<BGZFBlock>) -> Result<BGZFBlockStatus, Error> {
+fn process_payload(block: Option<BGZFBlock>) -> Result<BGZFBlockStatus, RescueError> {
     match block {
         None => Ok(BGZFBlockStatus {
-            corrupted: false,
+            bad_block: None,
+            repaired_block: None,
+            corrupted_block: None,
             inflated_payload_size: 0,
+            inflated_payload_size_error: 0,
+            inflated_payload_bytes: vec![],
+            block: None,
+        }),
+        // whatever its payload, it's not the one which was written
+        Some(block) if block.corruption == Some(CorruptionKind::Unreadable) => Ok(BGZFBlockStatus {
+            bad_block: Some(block.bad_block(CorruptionKind::Unreadable)),
+            repaired_block: None,
+            corrupted_block: None,
+            inflated_payload_size: block.inflated_payload_size,
+            inflated_payload_size_error: 0,
+            inflated_payload_bytes: vec![],
             block: None,
         }),
-        Some(block) => {
+        Some(mut block) => {
             let inflated_payload_bytes = match inflate::inflate_bytes(&block.deflated_payload_bytes) {
                 Ok(inflated_payload_bytes) => inflated_payload_bytes,
                 Err(_) => return Ok(BGZFBlockStatus {
-                    corrupted: true,
+                    bad_block: Some(block.bad_block(CorruptionKind::Inflate)),
+                    repaired_block: None,
                     inflated_payload_size: block.inflated_payload_size,
+                    inflated_payload_size_error: 0,
+                    inflated_payload_bytes: vec![],
                     block: None,
+                    corrupted_block: block.corruption.is_none().then_some(block),
                 }),
             };
 
@@ -144,128 +509,395 @@ fn process_payload(block: Option<BGZFBlock>) -> Result<BGZFBlockStatus, Error> {
             let inflated_payload_crc32 = inflated_payload_digest.finalize();
             if inflated_payload_crc32 != block.inflated_payload_crc32 {
                 return Ok(BGZFBlockStatus {
-                    corrupted: true,
+                    bad_block: Some(block.bad_block(CorruptionKind::Crc32)),
+                    repaired_block: None,
                     inflated_payload_size: block.inflated_payload_size,
+                    inflated_payload_size_error: 0,
+                    inflated_payload_bytes: vec![],
                     block: None,
+                    corrupted_block: block.corruption.is_none().then_some(block),
                 });
             }
 
             let inflated_payload_size = inflated_payload_bytes.len() as u32;
+            let inflated_payload_size_error = inflated_payload_size as i64 - block.inflated_payload_size as i64;
             if inflated_payload_size != block.inflated_payload_size {
-                // TODO recoverable (wrong size is not a big issue if the CRC32 is correct)
-                return Ok(BGZFBlockStatus {
-                    corrupted: true,
-                    inflated_payload_size: block.inflated_payload_size,
-                    block: None,
-                });
+                // wrong size is not a big issue as the CRC32 is correct
+                block.inflated_payload_size = inflated_payload_size;
+                block.repair.get_or_insert(CorruptionKind::Isize);
             }
 
-            Ok(BGZFBlockStatus {
-                corrupted: block.corrupted,
-                inflated_payload_size: block.inflated_payload_size,
-                block: if block.corrupted {
-                    None
-                } else {
-                    Some(block)
-                }
+            Ok(match block.corruption {
+                Some(kind) => BGZFBlockStatus {
+                    bad_block: Some(block.bad_block(kind)),
+                    repaired_block: None,
+                    corrupted_block: None,
+                    inflated_payload_size: block.inflated_payload_size,
+                    inflated_payload_size_error,
+                    inflated_payload_bytes: vec![],
+                    block: None,
+                },
+                None => BGZFBlockStatus {
+                    bad_block: None,
+                    // the payload being fine confirms that the header is fixed
+                    repaired_block: block.repair.map(|kind| block.bad_block(kind)),
+                    corrupted_block: None,
+                    inflated_payload_size: block.inflated_payload_size,
+                    inflated_payload_size_error,
+                    inflated_payload_bytes,
+                    block: Some(block),
+                },
             })
         }
     }
 }
 
-fn write_block(writer: &mut Option<&mut dyn Write>, block: &Option<BGZFBlock>)  {
-    if let Some(ref mut writer) = writer {
-        if let Some(block) = block {
-            writer.write_all(&block.header_bytes).unwrap();
-            writer.write_all(&block.deflated_payload_bytes).unwrap();
-            writer.write_u32::<byteorder::LittleEndian>(block.inflated_payload_crc32).unwrap();
-            writer.write_u32::<byteorder::LittleEndian>(block.inflated_payload_size).unwrap();
+// The inflate crate is not guaranteed not to panic on arbitrary input, and a
+// panic in a worker thread would otherwise be propagated when waiting for it
+fn process_payload_guarded(block: Option<BGZFBlock>) -> Result<BGZFBlockStatus, RescueError> {
+    panic::catch_unwind(panic::AssertUnwindSafe(|| process_payload(block)))
+        .unwrap_or_else(|_| Err(RescueError::Internal("panic while processing a bgzf block payload".to_owned())))
+}
+
+fn write_block(writer: &mut Option<&mut dyn Write>, resynchronizer: &mut Option<resync::Resynchronizer>, payload_status: &BGZFBlockStatus) -> Result<(), RescueError> {
+    if let (Some(ref mut writer), Some(ref mut resynchronizer)) = (writer, resynchronizer) {
+        if let Some(ref block) = payload_status.block {
+            let mut bytes = Vec::with_capacity(block.header_bytes.len() + block.deflated_payload_bytes.len() + 8);
+            bytes.extend_from_slice(&block.header_bytes);
+            bytes.extend_from_slice(&block.deflated_payload_bytes);
+            bytes.write_u32::<byteorder::LittleEndian>(block.inflated_payload_crc32).unwrap();
+            bytes.write_u32::<byteorder::LittleEndian>(block.inflated_payload_size).unwrap();
+            resynchronizer.write(*writer, bytes, payload_status.inflated_payload_bytes.clone()).map_err(RescueError::Output)?;
         }
     }
+    Ok(())
 }
 
-fn report_progress(progress_listener: &mut Option<&mut dyn ListenProgress>, block: &Option<BGZFBlock>)  {
+fn write_payload(payload_writer: &mut Option<&mut dyn Write>, payload_status: &BGZFBlockStatus) -> Result<(), RescueError> {
+    if let Some(ref mut payload_writer) = payload_writer {
+        payload_writer.write_all(&payload_status.inflated_payload_bytes).map_err(RescueError::Output)?;
+    }
+    Ok(())
+}
+
+fn skip_block(writer: &mut Option<&mut dyn Write>, resynchronizer: &mut Option<resync::Resynchronizer>, results: &mut Results, payload_status: &BGZFBlockStatus, salvage: bool) -> Result<(), RescueError> {
+    if let (Some(ref mut writer), Some(ref mut resynchronizer)) = (writer, resynchronizer) {
+        // only a payload which can't be inflated up to its end has a prefix
+        // worth keeping, any other corruption can be anywhere in the payload
+        let salvaged_payload_bytes = match (&payload_status.bad_block, &payload_status.corrupted_block) {
+            (Some(bad_block), Some(block)) if salvage && bad_block.kind == CorruptionKind::Inflate =>
+                bgzf::inflate_prefix(&block.deflated_payload_bytes, block.inflated_payload_size),
+            _ => vec![],
+        };
+        results.salvaged_size += salvaged_payload_bytes.len() as u64;
+        resynchronizer.salvage(*writer, salvaged_payload_bytes).map_err(RescueError::Output)?;
+    }
+    Ok(())
+}
+
+fn report_progress(progress_listener: &mut Option<&mut dyn ListenProgress>, payload_status: &BGZFBlockStatus)  {
     if let Some(ref mut progress_listener) = progress_listener {
-        if let Some(block) = block {
+        if let Some(ref block) = payload_status.block {
+            if payload_status.bad_block.is_none() {
+                progress_listener.on_block(block.position, block.end_position - block.position, &payload_status.inflated_payload_bytes);
+            }
             progress_listener.on_progress(block.end_position);
         }
     }
 }
 
-fn report_bad_block(results: &mut Results, progress_listener: &mut Option<&mut dyn ListenProgress>, payload_status: &BGZFBlockStatus)  {
+fn report_bad_block(results: &mut Results, progress_listener: &mut Option<&mut dyn ListenProgress>, bad_block: BadBlock, inflated_payload_size: u32)  {
     results.bad_blocks_count += 1;
-    results.bad_blocks_size += payload_status.inflated_payload_size as u64;
+    results.bad_blocks_size += inflated_payload_size as u64;
+    if bad_block.kind == CorruptionKind::Unreadable {
+        results.unreadable_blocks_count += 1;
+    }
     if let Some(ref mut progress_listener) = progress_listener {
-        progress_listener.on_bad_block();
+        progress_listener.on_bad_block(&bad_block);
     }
+    results.bad_blocks.push(bad_block);
+}
+
+// Each task looks for flipped bits starting in its own range, so that a single
+// corrupted block keeps all threads busy
+const BITFLIPS_TASK_BITS: usize = 4096;
+
+fn fix_payload_bitflips(pool: &futures_cpupool::CpuPool, results: &mut Results, mut payload_status: BGZFBlockStatus, fix_bitflips: bool, double_bitflips: bool) -> Result<BGZFBlockStatus, RescueError> {
+    if !fix_bitflips {
+        return Ok(payload_status);
+    }
+    let mut block = match payload_status.corrupted_block.take() {
+        Some(block) => block,
+        None => return Ok(payload_status),
+    };
+
+    let deflated_payload_bytes = Arc::new(block.deflated_payload_bytes.clone());
+    let found = Arc::new(AtomicUsize::new(usize::MAX));
+    let bits_count = deflated_payload_bytes.len() * 8;
+    let fix_futures: Vec<_> = (0..bits_count).step_by(BITFLIPS_TASK_BITS).map(|first_bit| {
+        let deflated_payload_bytes = Arc::clone(&deflated_payload_bytes);
+        let found = Arc::clone(&found);
+        let (inflated_payload_crc32, inflated_payload_size) = (block.inflated_payload_crc32, block.inflated_payload_size);
+        pool.spawn_fn(move || {
+            Ok::<_, RescueError>(bitflips::find(&deflated_payload_bytes, inflated_payload_crc32, inflated_payload_size, first_bit..bits_count.min(first_bit + BITFLIPS_TASK_BITS), double_bitflips, &found))
+        })
+    }).collect();
+    // any task with a fix holds the lowest one of its range, and tasks are
+    // waited for in the order of their ranges
+    let mut fix = None;
+    for fix_future in fix_futures {
+        let task_fix = fix_future.wait()?;
+        if fix.is_none() {
+            fix = task_fix;
+        }
+    }
+
+    Ok(match fix {
+        None => {
+            payload_status.corrupted_block = Some(block);
+            payload_status
+        },
+        Some(fix) => {
+            let deflated_payload_position = block.position + block.header_bytes.len() as u64;
+            for bit in fix.bits {
+                results.bitflips.push(Bitflip {
+                    position: deflated_payload_position + (bit / 8) as u64,
+                    bit: (bit % 8) as u8,
+                });
+            }
+            block.deflated_payload_bytes = fix.deflated_payload_bytes;
+            BGZFBlockStatus {
+                bad_block: None,
+                repaired_block: payload_status.bad_block,
+                inflated_payload_size: block.inflated_payload_size,
+                inflated_payload_size_error: 0,
+                inflated_payload_bytes: fix.inflated_payload_bytes,
+                block: Some(block),
+                corrupted_block: None,
+            }
+        },
+    })
+}
+
+// Blocks are read ahead of their payload being inflated, assuming the size
+// their previous blocks declare: once a payload turns out to be of another
+// size, the blocks after it are moved by the difference
+fn correct_inflated_size(results: &mut Results, inflated_size_correction: &mut i64, payload_status: &mut BGZFBlockStatus) {
+    for bad_block in payload_status.bad_block.iter_mut().chain(payload_status.repaired_block.iter_mut()) {
+        bad_block.inflated_position = bad_block.inflated_position.wrapping_add_signed(*inflated_size_correction);
+    }
+    *inflated_size_correction += payload_status.inflated_payload_size_error;
+    results.blocks_size = results.blocks_size.wrapping_add_signed(payload_status.inflated_payload_size_error);
+}
+
+fn set_input_format(results: &mut Results, validator: &mut Option<Box<dyn Validate>>, resynchronizer: &mut Option<resync::Resynchronizer>, input_format: InputFormat) {
+    results.input_format = Some(input_format);
+    // only bam and bcf payloads can be checked in deep mode
+    match input_format {
+        InputFormat::Bam => (),
+        InputFormat::Bcf => if let Some(ref mut previous_validator) = validator {
+            let mut bcf_validator = Box::new(bcf::Validator::new());
+            if previous_validator.is_lost() {
+                bcf_validator.gap();
+            }
+            *previous_validator = bcf_validator;
+        },
+        _ => *validator = None,
+    }
+    if let Some(ref mut resynchronizer) = resynchronizer {
+        resynchronizer.set_input_format(input_format);
+    }
+}
+
+// Unless given, the input format is guessed from the first non-empty payload;
+// deep checks still report unrecognized payloads as invalid bam
+fn detect_input_format(results: &mut Results, validator: &mut Option<Box<dyn Validate>>, resynchronizer: &mut Option<resync::Resynchronizer>, payload_status: &BGZFBlockStatus) {
+    if results.input_format.is_none() && payload_status.bad_block.is_none() && !payload_status.inflated_payload_bytes.is_empty() {
+        let input_format = match text::detect(&payload_status.inflated_payload_bytes) {
+            InputFormat::Bgzf if validator.is_some() => InputFormat::Bam,
+            input_format => input_format,
+        };
+        set_input_format(results, validator, resynchronizer, input_format);
+    }
+}
+
+fn report_repaired_block(results: &mut Results, repaired_block: BadBlock)  {
+    results.repaired_blocks_count += 1;
+    results.repaired_blocks.push(repaired_block);
 }
 
 macro_rules! fail {
-    ($fail_fast: expr, $results: expr, $previous_block: expr, $previous_block_corrupted: expr, $current_block_corrupted_ref: expr, $current_block_corrupted: expr, $truncated_in_block: expr) => {
-        match $previous_block {
-            None => {
-                $current_block_corrupted_ref |= $previous_block_corrupted;
-            },
-            Some(ref mut block) => {
-                block.corrupted |= $previous_block_corrupted;
+    ($fail_fast: expr, $results: expr, $previous_block: expr, $previous_block_corruption: expr, $current_block_corruption_ref: expr, $current_block_corruption: expr, $truncated_in_block: expr) => {
+        if let Some(kind) = $previous_block_corruption {
+            match $previous_block {
+                None => {
+                    $current_block_corruption_ref.get_or_insert(kind);
+                },
+                Some(ref mut block) => {
+                    block.corruption.get_or_insert(kind);
+                }
             }
         }
-        $current_block_corrupted_ref |= $current_block_corrupted;
-        assert!($current_block_corrupted_ref || true); // TODO workaround the "unused assignment warning"
+        if let Some(kind) = $current_block_corruption {
+            $current_block_corruption_ref.get_or_insert(kind);
+        }
+        assert!($current_block_corruption_ref.is_some() || true); // TODO workaround the "unused assignment warning"
         if $truncated_in_block {
             $results.truncated_in_block = true;
         }
         if $fail_fast {
             $results.bad_blocks_count += 1;
-            return $results;
+            return Ok($results);
         }
     }
 }
 
-fn process(reader: &mut dyn Rescuable, mut writer: Option<&mut dyn Write>, fail_fast: bool, threads: usize, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Results {
-    let reader_size = reader.seek(SeekFrom::End(0)).unwrap();
-    reader.seek(SeekFrom::Start(0)).unwrap();
-    if let Some(ref mut progress_listener) = progress_listener {
-        progress_listener.on_new_target(reader_size);
+macro_rules! validate {
+    ($fail_fast: expr, $results: expr, $validator: expr, $payload_status: expr) => {
+        if let Some(ref mut validator) = $validator {
+            match $payload_status.block {
+                Some(ref block) => validator.feed(block.position, &$payload_status.inflated_payload_bytes),
+                None => if $payload_status.bad_block.is_some() {
+                    validator.gap();
+                },
+            }
+            if $fail_fast && !validator.errors().is_empty() {
+                $results.format_errors = validator.errors().to_vec();
+                return Ok($results);
+            }
+        }
     }
+}
+
+#[allow(clippy::too_many_arguments)]
+// writer receives the rescued bgzf blocks, payload_writer the payload of
+// non-corrupted blocks
+fn process(reader: &mut dyn Rescuable, mut writer: Option<&mut dyn Write>, mut payload_writer: Option<&mut dyn Write>, fail_fast: bool, deep: bool, options: RescueOptions, threads: usize, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Result<Results, RescueError> {
+    let RescueOptions { fix_bitflips, double_bitflips, salvage, index, input_format, ddrescue_map } = options;
+    let reader_size = reader_size(reader, progress_listener)?;
 
-    let mut results = Results {
-        blocks_count: 0u64,
-        blocks_size: 0u64,
-        bad_blocks_count: 0u64,
-        bad_blocks_size: 0u64,
-        truncated_in_block: false,
-        truncated_between_blocks: false,
+    let mut results = Results::new();
+
+    // bam unless another format is given or detected
+    let mut validator: Option<Box<dyn Validate>> = if deep {
+        Some(Box::new(bam::Validator::new()))
+    } else {
+        None
     };
 
-    let pool;
-    if threads == 0 {
-        pool = futures_cpupool::CpuPool::new_num_cpus();
+    // only rescued files are resynchronized, checking never drops anything
+    let mut resynchronizer = if writer.is_some() {
+        Some(resync::Resynchronizer::new(index))
     } else {
-        pool = futures_cpupool::CpuPool::new(threads);
+        None
+    };
+
+    if let Some(input_format) = input_format {
+        set_input_format(&mut results, &mut validator, &mut resynchronizer, input_format);
     }
 
-    let mut payload_status_futures = VecDeque::<futures_cpupool::CpuFuture<BGZFBlockStatus, Error>>::with_capacity(MAX_FUTURES);
+    let pool = if threads == 0 {
+        futures_cpupool::CpuPool::new_num_cpus()
+    } else {
+        futures_cpupool::CpuPool::new(threads)
+    };
+
+    let mut payload_status_futures = VecDeque::<futures_cpupool::CpuFuture<BGZFBlockStatus, RescueError>>::with_capacity(MAX_FUTURES);
 
     let mut previous_block: Option<BGZFBlock> = None;
     let mut previous_block_position;
     let mut current_block_position = 0u64;
-    let mut current_block_corrupted = false;
+    let mut current_block_corruption: Option<CorruptionKind> = None;
+    let mut current_block_repair: Option<CorruptionKind>;
+    // results.blocks_size is only corrected once payloads are inflated, the
+    // blocks read in the meantime are placed after the declared sizes
+    let mut declared_blocks_size = 0u64;
+    let mut inflated_size_correction = 0i64;
+    // once the next block is found, the previous one is processed, right
+    // away or by the pool
+    macro_rules! dispatch_previous_block {
+        () => {
+            if threads == 1 {
+                let mut payload_status = fix_payload_bitflips(&pool, &mut results, process_payload_guarded(previous_block)?, fix_bitflips, double_bitflips)?;
+                correct_inflated_size(&mut results, &mut inflated_size_correction, &mut payload_status);
+                detect_input_format(&mut results, &mut validator, &mut resynchronizer, &payload_status);
+                validate!(fail_fast, results, validator, payload_status);
+                previous_block = None;
+                if let Some(bad_block) = payload_status.bad_block.clone() {
+                    report_bad_block(&mut results, progress_listener, bad_block, payload_status.inflated_payload_size);
+                    fail!(fail_fast, results, previous_block, None, current_block_corruption, None, false);
+                    skip_block(&mut writer, &mut resynchronizer, &mut results, &payload_status, salvage)?;
+                } else {
+                    if let Some(repaired_block) = payload_status.repaired_block.clone() {
+                        report_repaired_block(&mut results, repaired_block);
+                    }
+                    write_block(&mut writer, &mut resynchronizer, &payload_status)?;
+                    write_payload(&mut payload_writer, &payload_status)?;
+                }
+                report_progress(progress_listener, &payload_status);
+            } else {
+                let payload_status_future = pool.spawn_fn(move || {
+                    process_payload_guarded(previous_block)
+                });
+                payload_status_futures.push_back(payload_status_future);
+                previous_block = None;
+            }
+        }
+    }
+
     'blocks: loop {
         if payload_status_futures.len() == MAX_FUTURES {
-            let payload_status = payload_status_futures.pop_front().unwrap().wait().unwrap();
-            if payload_status.corrupted {
-                report_bad_block(&mut results, progress_listener, &payload_status);
-                fail!(fail_fast, results, previous_block, false, current_block_corrupted, false, false);
+            let mut payload_status = fix_payload_bitflips(&pool, &mut results, payload_status_futures.pop_front().unwrap().wait()?, fix_bitflips, double_bitflips)?;
+            correct_inflated_size(&mut results, &mut inflated_size_correction, &mut payload_status);
+            detect_input_format(&mut results, &mut validator, &mut resynchronizer, &payload_status);
+            validate!(fail_fast, results, validator, payload_status);
+            if let Some(bad_block) = payload_status.bad_block.clone() {
+                report_bad_block(&mut results, progress_listener, bad_block, payload_status.inflated_payload_size);
+                fail!(fail_fast, results, previous_block, None, current_block_corruption, None, false);
+                skip_block(&mut writer, &mut resynchronizer, &mut results, &payload_status, salvage)?;
             } else {
-                write_block(&mut writer, &payload_status.block);
+                if let Some(repaired_block) = payload_status.repaired_block.clone() {
+                    report_repaired_block(&mut results, repaired_block);
+                }
+                write_block(&mut writer, &mut resynchronizer, &payload_status)?;
+                write_payload(&mut payload_writer, &payload_status)?;
             }
-            report_progress(progress_listener, &payload_status.block);
+            report_progress(progress_listener, &payload_status);
         }
 
         previous_block_position = current_block_position;
-        current_block_position = reader.seek(SeekFrom::Current(0i64)).unwrap();
-        current_block_corrupted = false;
+        current_block_position = reader.stream_position().map_err(RescueError::Input)?;
+        current_block_corruption = None;
+        current_block_repair = None;
+
+        // unreadable bytes are not even looked at: from a header they cut,
+        // they make a block of their own up to the next block found after them
+        let unreadable_end = ddrescue_map.and_then(|ddrescue_map| {
+            ddrescue_map.next_unreadable(current_block_position)
+                .filter(|&unreadable_position| unreadable_position < current_block_position + MIN_HEADER_SIZE)
+                .and_then(|unreadable_position| ddrescue_map.unreadable_end(unreadable_position))
+        });
+        if let Some(unreadable_end) = unreadable_end {
+            // previous_block is replaced right away
+            #[allow(unused_assignments)]
+            {
+                dispatch_previous_block!();
+            }
+            seek_next_block(reader, unreadable_end, ddrescue_map)?;
+            previous_block = Some(BGZFBlock {
+                header_bytes: vec![],
+                deflated_payload_bytes: vec![],
+                inflated_payload_crc32: 0,
+                inflated_payload_size: 0,
+                corruption: Some(CorruptionKind::Unreadable),
+                repair: None,
+                position: current_block_position,
+                end_position: reader.stream_position().map_err(RescueError::Input)?,
+                inflated_position: declared_blocks_size,
+            });
+            results.blocks_count += 1;
+            continue 'blocks;
+        }
 
         let mut header_bytes = vec![];
         {
@@ -277,12 +909,13 @@ fn process(reader: &mut dyn Rescuable, mut writer: Option<&mut dyn Write>, fail_
                     }
 
                     if header_size < 12 {
-                        fail!(fail_fast, results, previous_block, true, current_block_corrupted, false, true);
+                        fail!(fail_fast, results, previous_block, Some(CorruptionKind::Truncation), current_block_corruption, None, true);
                         break 'blocks;
                     }
                 },
-                Err(_) => {
-                    fail!(fail_fast, results, previous_block, true, current_block_corrupted, false, true);
+                Err(error) => {
+                    eof_or_input_error(error)?;
+                    fail!(fail_fast, results, previous_block, Some(CorruptionKind::Truncation), current_block_corruption, None, true);
                     break 'blocks;
                 }
             }
@@ -304,13 +937,15 @@ fn process(reader: &mut dyn Rescuable, mut writer: Option<&mut dyn Write>, fail_
 
         if correct_bytes < 4 {
             if correct_bytes == 3 {
-                fail!(fail_fast, results, previous_block, false, current_block_corrupted, true, false);
                 // single corrupted byte, can probably deal with it in place
-                // TODO fix the four bytes for rescue
+                header_bytes[0..2].copy_from_slice(&GZIP_IDENTIFIER);
+                header_bytes[2] = DEFLATE;
+                header_bytes[3] = FEXTRA;
+                current_block_repair = Some(CorruptionKind::GzipHeader);
             } else {
-                fail!(fail_fast, results, previous_block, true, current_block_corrupted, false, false);
+                fail!(fail_fast, results, previous_block, Some(CorruptionKind::GzipHeader), current_block_corruption, None, false);
                 // multiple corrupted bytes, safer to jump to the next block
-                seek_next_block(reader, previous_block_position + 1);
+                seek_next_block(reader, previous_block_position + 1, ddrescue_map)?;
                 continue 'blocks;
             }
         }
@@ -327,12 +962,13 @@ fn process(reader: &mut dyn Rescuable, mut writer: Option<&mut dyn Write>, fail_
                 match extra_field_reader.read_to_end(&mut header_bytes) {
                     Ok(extra_field_actual_size) => {
                         if extra_field_actual_size < extra_field_size as usize {
-                            fail!(fail_fast, results, previous_block, false, current_block_corrupted, true, true);
+                            fail!(fail_fast, results, previous_block, None, current_block_corruption, Some(CorruptionKind::Truncation), true);
                             break 'blocks;
                         }
                     },
-                    Err(_) => {
-                        fail!(fail_fast, results, previous_block, false, current_block_corrupted, true, true);
+                    Err(error) => {
+                        eof_or_input_error(error)?;
+                        fail!(fail_fast, results, previous_block, None, current_block_corruption, Some(CorruptionKind::Truncation), true);
                         break 'blocks;
                     }
                 }
@@ -340,7 +976,7 @@ fn process(reader: &mut dyn Rescuable, mut writer: Option<&mut dyn Write>, fail_
 
             // TODO potential optimization:
             // Read the extra subfields from header_bytes instead of from reader and don't seek back
-            reader.seek(SeekFrom::Current(-(extra_field_size as i64))).unwrap();
+            reader.seek(SeekFrom::Current(-(extra_field_size as i64))).map_err(RescueError::Input)?;
         }
 
         let mut bgzf_block_size = 0u16;
@@ -348,15 +984,17 @@ fn process(reader: &mut dyn Rescuable, mut writer: Option<&mut dyn Write>, fail_
         let mut remaining_extra_field_size = extra_field_size;
         while remaining_extra_field_size > 4 {
             let mut extra_subfield_identifier = [0u8; 2];
-            if let Err(_) = reader.read_exact(&mut extra_subfield_identifier) {
-                fail!(fail_fast, results, previous_block, false, current_block_corrupted, true, true);
+            if let Err(error) = reader.read_exact(&mut extra_subfield_identifier) {
+                eof_or_input_error(error)?;
+                fail!(fail_fast, results, previous_block, None, current_block_corruption, Some(CorruptionKind::Truncation), true);
                 break 'blocks;
             }
 
             let extra_subfield_size = match reader.read_u16::<byteorder::LittleEndian>() {
                 Ok(extra_subfield_size) => extra_subfield_size,
-                Err(_) => {
-                    fail!(fail_fast, results, previous_block, false, current_block_corrupted, true, true);
+                Err(error) => {
+                    eof_or_input_error(error)?;
+                    fail!(fail_fast, results, previous_block, None, current_block_corruption, Some(CorruptionKind::Truncation), true);
                     break 'blocks;
                 }
             };
@@ -375,64 +1013,59 @@ fn process(reader: &mut dyn Rescuable, mut writer: Option<&mut dyn Write>, fail_
                 correct_bytes += 1;
             }
 
+            // a single bgzf subfield has to be the right size, whatever its
+            // corrupted length says
+            let single_corrupted_byte = correct_bytes == 3 && extra_field_size == 6;
+            let extra_subfield_size = if single_corrupted_byte {
+                2
+            } else {
+                extra_subfield_size
+            };
+
             if extra_subfield_size > remaining_extra_field_size - 4 {
-                fail!(fail_fast, results, previous_block, false, current_block_corrupted, true, false);
-                seek_next_block(reader, current_block_position + 1);
+                fail!(fail_fast, results, previous_block, None, current_block_corruption, Some(CorruptionKind::BgzfSubfield), false);
+                seek_next_block(reader, current_block_position + 1, ddrescue_map)?;
                 continue 'blocks;
             }
 
-            if correct_bytes == 4 ||
-               (correct_bytes == 3 &&
-                extra_field_size == 6) {
-                if correct_bytes != 4 {
-                    fail!(fail_fast, results, previous_block, false, current_block_corrupted, true, false);
+            if correct_bytes == 4 || single_corrupted_byte {
+                if single_corrupted_byte {
                     // single corrupted byte, but most likely at the right place anyway
-                    // TODO fix the four bytes for rescue
+                    if writer.is_some() {
+                        let extra_subfield_position = 12 + (extra_field_size - remaining_extra_field_size) as usize;
+                        header_bytes[extra_subfield_position..extra_subfield_position + 2].copy_from_slice(&BGZF_IDENTIFIER);
+                        header_bytes[extra_subfield_position + 2..extra_subfield_position + 4].copy_from_slice(&[2, 0]);
+                    }
+                    current_block_repair.get_or_insert(CorruptionKind::BgzfSubfield);
                 }
                 bgzf_block_size = match reader.read_u16::<byteorder::LittleEndian>() {
                     Ok(bgzf_block_size) => bgzf_block_size + 1,
-                    Err(_) => {
-                        fail!(fail_fast, results, previous_block, false, current_block_corrupted, true, true);
+                    Err(error) => {
+                        eof_or_input_error(error)?;
+                        fail!(fail_fast, results, previous_block, None, current_block_corruption, Some(CorruptionKind::Truncation), true);
                         break 'blocks;
                     }
                 };
-            } else if let Err(_) = reader.seek(SeekFrom::Current(extra_subfield_size as i64)) {
-                fail!(fail_fast, results, previous_block, false, current_block_corrupted, true, true);
-                break 'blocks;
+            } else {
+                reader.seek(SeekFrom::Current(extra_subfield_size as i64)).map_err(RescueError::Input)?;
             }
 
             remaining_extra_field_size -= 4 + extra_subfield_size;
         }
 
         if remaining_extra_field_size != 0u16 {
-            fail!(fail_fast, results, previous_block, false, current_block_corrupted, true, false);
-            seek_next_block(reader, current_block_position + 1);
+            fail!(fail_fast, results, previous_block, None, current_block_corruption, Some(CorruptionKind::BgzfSubfield), false);
+            seek_next_block(reader, current_block_position + 1, ddrescue_map)?;
             continue 'blocks;
         }
 
         if bgzf_block_size == 0u16 {
-            fail!(fail_fast, results, previous_block, false, current_block_corrupted, true, false);
-            seek_next_block(reader, current_block_position + 1);
+            fail!(fail_fast, results, previous_block, None, current_block_corruption, Some(CorruptionKind::BgzfSubfield), false);
+            seek_next_block(reader, current_block_position + 1, ddrescue_map)?;
             continue 'blocks;
         }
 
-        if threads == 1 {
-            let payload_status = process_payload(previous_block).unwrap();
-            previous_block = None;
-            if payload_status.corrupted {
-                report_bad_block(&mut results, progress_listener, &payload_status);
-                fail!(fail_fast, results, previous_block, false, current_block_corrupted, false, false);
-            } else {
-                write_block(&mut writer, &payload_status.block);
-            }
-            report_progress(progress_listener, &payload_status.block);
-        } else {
-            let payload_status_future = pool.spawn_fn(move || {
-                process_payload(previous_block)
-            });
-            payload_status_futures.push_back(payload_status_future);
-            previous_block = None;
-        }
+        dispatch_previous_block!();
 
         let mut deflated_payload_bytes = vec![];
         {
@@ -441,12 +1074,13 @@ fn process(reader: &mut dyn Rescuable, mut writer: Option<&mut dyn Write>, fail_
             match deflated_payload_reader.read_to_end(&mut deflated_payload_bytes) {
                 Ok(deflated_payload_read_size) => {
                     if deflated_payload_read_size < deflated_payload_size as usize {
-                        fail!(fail_fast, results, previous_block, false, current_block_corrupted, true, true);
+                        fail!(fail_fast, results, previous_block, None, current_block_corruption, Some(CorruptionKind::Truncation), true);
                         break 'blocks;
                     }
                 },
-                Err(_) => {
-                    fail!(fail_fast, results, previous_block, false, current_block_corrupted, true, true);
+                Err(error) => {
+                    eof_or_input_error(error)?;
+                    fail!(fail_fast, results, previous_block, None, current_block_corruption, Some(CorruptionKind::Truncation), true);
                     break 'blocks;
                 }
             }
@@ -454,87 +1088,125 @@ fn process(reader: &mut dyn Rescuable, mut writer: Option<&mut dyn Write>, fail_
 
         let inflated_payload_crc32 = match reader.read_u32::<byteorder::LittleEndian>() {
             Ok(inflated_payload_crc32) => inflated_payload_crc32,
-            Err(_) => {
-                fail!(fail_fast, results, previous_block, false, current_block_corrupted, true, true);
+            Err(error) => {
+                eof_or_input_error(error)?;
+                fail!(fail_fast, results, previous_block, None, current_block_corruption, Some(CorruptionKind::Truncation), true);
                 break 'blocks;
             }
         };
         let inflated_payload_size = match reader.read_u32::<byteorder::LittleEndian>() {
             Ok(inflated_payload_size) => inflated_payload_size,
-            Err(_) => {
-                fail!(fail_fast, results, previous_block, false, current_block_corrupted, true, true);
+            Err(error) => {
+                eof_or_input_error(error)?;
+                fail!(fail_fast, results, previous_block, None, current_block_corruption, Some(CorruptionKind::Truncation), true);
                 break 'blocks;
             }
         };
 
+        let end_position = reader.stream_position().map_err(RescueError::Input)?;
         previous_block = Some(BGZFBlock {
-            header_bytes: header_bytes,
-            deflated_payload_bytes: deflated_payload_bytes,
-            inflated_payload_crc32: inflated_payload_crc32,
-            inflated_payload_size: inflated_payload_size,
-            corrupted: current_block_corrupted,
-            end_position: reader.seek(SeekFrom::Current(0i64)).unwrap(),
+            header_bytes,
+            deflated_payload_bytes,
+            inflated_payload_crc32,
+            inflated_payload_size,
+            corruption: match ddrescue_map {
+                Some(ddrescue_map) if ddrescue_map.overlaps(current_block_position, end_position) => Some(CorruptionKind::Unreadable),
+                _ => current_block_corruption,
+            },
+            repair: current_block_repair,
+            position: current_block_position,
+            end_position,
+            inflated_position: declared_blocks_size,
         });
 
         results.blocks_count += 1;
         results.blocks_size += inflated_payload_size as u64;
+        declared_blocks_size += inflated_payload_size as u64;
     }
 
+    // the end-of-file marker may have been lost in unreadable bytes
+    let unreadable_end = matches!(previous_block, Some(ref block) if block.corruption == Some(CorruptionKind::Unreadable));
     let mut last_inflated_payload_size = 0u32;
     if threads == 1 {
-        let payload_status = process_payload(previous_block).unwrap();
+        let mut payload_status = fix_payload_bitflips(&pool, &mut results, process_payload_guarded(previous_block)?, fix_bitflips, double_bitflips)?;
+        correct_inflated_size(&mut results, &mut inflated_size_correction, &mut payload_status);
+        detect_input_format(&mut results, &mut validator, &mut resynchronizer, &payload_status);
+        validate!(fail_fast, results, validator, payload_status);
         previous_block = None;
-        if payload_status.corrupted {
-            report_bad_block(&mut results, progress_listener, &payload_status);
-            fail!(fail_fast, results, previous_block, false, current_block_corrupted, false, false);
+        if let Some(bad_block) = payload_status.bad_block.clone() {
+            report_bad_block(&mut results, progress_listener, bad_block, payload_status.inflated_payload_size);
+            fail!(fail_fast, results, previous_block, None, current_block_corruption, None, false);
+            skip_block(&mut writer, &mut resynchronizer, &mut results, &payload_status, salvage)?;
         } else {
-            write_block(&mut writer, &payload_status.block);
+            if let Some(repaired_block) = payload_status.repaired_block.clone() {
+                report_repaired_block(&mut results, repaired_block);
+            }
+            write_block(&mut writer, &mut resynchronizer, &payload_status)?;
+            write_payload(&mut payload_writer, &payload_status)?;
         }
         last_inflated_payload_size = payload_status.inflated_payload_size;
-        report_progress(progress_listener, &payload_status.block);
+        report_progress(progress_listener, &payload_status);
     } else {
         let payload_status_future = pool.spawn_fn(move || {
-            process_payload(previous_block)
+            process_payload_guarded(previous_block)
         });
         previous_block = None;
         payload_status_futures.push_back(payload_status_future);
         for payload_status_future in payload_status_futures.iter_mut() {
-            let payload_status = payload_status_future.wait().unwrap();
-            if payload_status.corrupted {
-                report_bad_block(&mut results, progress_listener, &payload_status);
-                fail!(fail_fast, results, previous_block, false, current_block_corrupted, false, false);
+            let mut payload_status = fix_payload_bitflips(&pool, &mut results, payload_status_future.wait()?, fix_bitflips, double_bitflips)?;
+            correct_inflated_size(&mut results, &mut inflated_size_correction, &mut payload_status);
+            detect_input_format(&mut results, &mut validator, &mut resynchronizer, &payload_status);
+            validate!(fail_fast, results, validator, payload_status);
+            if let Some(bad_block) = payload_status.bad_block.clone() {
+                report_bad_block(&mut results, progress_listener, bad_block, payload_status.inflated_payload_size);
+                fail!(fail_fast, results, previous_block, None, current_block_corruption, None, false);
+                skip_block(&mut writer, &mut resynchronizer, &mut results, &payload_status, salvage)?;
             } else {
-                write_block(&mut writer, &payload_status.block);
+                if let Some(repaired_block) = payload_status.repaired_block.clone() {
+                    report_repaired_block(&mut results, repaired_block);
+                }
+                write_block(&mut writer, &mut resynchronizer, &payload_status)?;
+                write_payload(&mut payload_writer, &payload_status)?;
             }
             last_inflated_payload_size = payload_status.inflated_payload_size;
-            report_progress(progress_listener, &payload_status.block);
+            report_progress(progress_listener, &payload_status);
+        }
+    }
+    if let Some(mut validator) = validator {
+        validator.finish();
+        results.format_errors = validator.into_errors();
+    }
+
+    if results.truncated_in_block {
+        // the truncated block is not counted, as it is not part of
+        // blocks_count either, but it's still worth reporting where it is
+        let bad_block = BadBlock {
+            position: current_block_position,
+            size: match reader_size {
+                Some(reader_size) => reader_size,
+                // the whole stream has been read
+                None => reader.stream_position().map_err(RescueError::Input)?,
+            } - current_block_position,
+            inflated_position: results.blocks_size,
+            kind: CorruptionKind::Truncation,
+        };
+        if let Some(ref mut progress_listener) = progress_listener {
+            progress_listener.on_bad_block(&bad_block);
+        }
+        results.bad_blocks.push(bad_block);
+    }
+    if let (Some(ref mut writer), Some(ref mut resynchronizer)) = (&mut writer, &mut resynchronizer) {
+        // the end-of-file marker is also missing when the last block is cut
+        resynchronizer.finish(*writer, last_inflated_payload_size != 0u32 || results.truncated_in_block || unreadable_end).map_err(RescueError::Output)?;
+        results.dropped_records_size = resynchronizer.dropped_size();
+        if let Some(indexer) = resynchronizer.take_indexer() {
+            results.index = Some(indexer.finish().map_err(RescueError::Index)?);
         }
     }
     if last_inflated_payload_size != 0u32 {
         results.truncated_between_blocks = true;
-        write_block(&mut writer, &Some(BGZFBlock {
-            header_bytes: vec![
-                0x1f, 0x8b,             // gzip identifier
-                0x08,                   // method (deflate)
-                0x04,                   // flags (FEXTRA)
-                0x00, 0x00, 0x00, 0x00, // modification time
-                0x00,                   // extra flags
-                0xff,                   // operating system (unknown)
-                0x06, 0x00,             // extra field size (6 bytes)
-                0x42, 0x43,             // bgzf identifier
-                0x02, 0x00,             // extra subfield length (2 bytes)
-                0x1b, 0x00,             // bgzf block size, minus one (28 bytes - 1)
-            ],
-            deflated_payload_bytes: vec![
-                0x03, 0x00 // deflated empty string
-            ],
-            inflated_payload_crc32: 0,
-            inflated_payload_size: 0,
-            corrupted: false,
-            end_position: 0
-        }));
         if fail_fast {
-            return results;
+            return Ok(results);
         }
     }
 
@@ -542,13 +1214,133 @@ fn process(reader: &mut dyn Rescuable, mut writer: Option<&mut dyn Write>, fail_
         progress_listener.on_finished();
     }
 
-    results
+    Ok(results)
+}
+
+// Without input_format, it's detected from the payload; with ddrescue_map,
+// blocks overlapping the regions ddrescue could not read are corrupted, see
+// CorruptionKind::Unreadable
+pub fn check(reader: &mut dyn Rescuable, fail_fast: bool, deep: bool, input_format: Option<InputFormat>, ddrescue_map: Option<&DdrescueMap>, threads: usize, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Result<Results, RescueError> {
+    match layout(reader, input_format)? {
+        Layout::Cram => cram::process(reader, None, fail_fast, false, ddrescue_map, progress_listener),
+        Layout::Gzip => gzip::process(reader, None, fail_fast, false, input_format, ddrescue_map, progress_listener),
+        Layout::Bgzf => process(reader, None, None, fail_fast, deep, RescueOptions { input_format, ddrescue_map, ..Default::default() }, threads, progress_listener),
+    }
+}
+
+pub fn rescue(reader: &mut dyn Rescuable, writer: &mut dyn Write, options: RescueOptions, threads: usize, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Result<Results, RescueError> {
+    match layout(reader, options.input_format)? {
+        Layout::Cram => cram::process(reader, Some(writer), false, options.index, options.ddrescue_map, progress_listener),
+        Layout::Gzip => gzip::process(reader, Some(writer), false, options.index, options.input_format, options.ddrescue_map, progress_listener),
+        Layout::Bgzf => process(reader, Some(writer), None, false, false, options, threads, progress_listener),
+    }
+}
+
+// Checks the bam file like check does, then every virtual offset of its bai
+// or csi index against the bgzf blocks actually found, or those of a bgzipped
+// text file and its tbi index
+pub fn check_index(reader: &mut dyn Rescuable, index_reader: &mut dyn Read, threads: usize, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Result<(Results, IndexResults), RescueError> {
+    let mut index_bytes = vec![];
+    index_reader.read_to_end(&mut index_bytes).map_err(RescueError::Input)?;
+    let mut corrupted_compression = false;
+    // csi and tbi are compressed, bai is not
+    if index_bytes.starts_with(&GZIP_IDENTIFIER) {
+        let mut index_payload = vec![];
+        let index_compression_results = process(&mut io::Cursor::new(&index_bytes), None, Some(&mut index_payload), false, false, RescueOptions::default(), threads, &mut None)?;
+        corrupted_compression = index_compression_results.bad_blocks_count > 0 ||
+                                index_compression_results.truncated_in_block ||
+                                index_compression_results.truncated_between_blocks;
+        index_bytes = index_payload;
+    }
+    // text files are indexed again with the columns of their index
+    let indexer = match index::tabix_columns(&index_bytes) {
+        Some(columns) => Indexer::tabix(columns),
+        None => Indexer::new(),
+    };
+    let mut blocks_listener = index::BlocksListener::new(progress_listener, indexer);
+    let results = process(reader, None, None, false, false, RescueOptions::default(), threads, &mut Some(&mut blocks_listener))?;
+    let index_results = index::check(&index_bytes, corrupted_compression, blocks_listener, &results);
+    Ok((results, index_results))
+}
+
+// Indexes a bgzipped text file again as tabix does, with the given columns or
+// the preset of its format, which has to be intact
+pub fn tabix(reader: &mut dyn Rescuable, columns: Option<TabixColumns>, threads: usize, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Result<(Results, Index), RescueError> {
+    let indexer = match columns {
+        Some(columns) => Indexer::tabix(columns),
+        None => Indexer::new(),
+    };
+    let mut blocks_listener = index::BlocksListener::new(progress_listener, indexer);
+    let results = process(reader, None, None, false, false, RescueOptions::default(), threads, &mut Some(&mut blocks_listener))?;
+    if results.bad_blocks_count > 0 || results.truncated_in_block || results.truncated_between_blocks {
+        return Err(RescueError::Index("corrupted file, rescue it first"));
+    }
+    let index = blocks_listener.into_index().map_err(RescueError::Index)?;
+    if index.format != IndexFormat::Tbi {
+        return Err(RescueError::Index("not a bgzipped text file"));
+    }
+    Ok((results, index))
+}
+
+// Rescues a bgzf file from several replicas sharing the same blocks, taking
+// every block from the first replica where it is not corrupted; the bad
+// blocks of the results are those corrupted in all replicas
+pub fn merge_replicas(replicas: &mut [&mut dyn Rescuable], writer: &mut dyn Write, index: bool, input_format: Option<InputFormat>, threads: usize, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Result<(Results, MergeResults), RescueError> {
+    merge::merge(replicas, writer, index, input_format, threads, progress_listener)
+}
+
+// Writes the compressed byte ranges of the corrupted and repaired blocks of the
+// results as a GNU ddrescue mapfile, to be given to ddrescue as
+// --domain-mapfile so that it reads only those again; with size, the rest of
+// the file is part of the mapfile as well
+pub fn emit_map(writer: &mut dyn Write, results: &Results, size: Option<u64>) -> Result<(), RescueError> {
+    ddrescue::write_domain(writer, &corrupted_ranges(results), size).map_err(RescueError::Output)
+}
+
+// Sorted and merged byte ranges of the corrupted and repaired blocks
+fn corrupted_ranges(results: &Results) -> Vec<(u64, u64)> {
+    let mut blocks: Vec<(u64, u64)> = results.bad_blocks.iter()
+        .chain(&results.repaired_blocks)
+        .filter(|block| block.size > 0)
+        .map(|block| (block.position, block.position + block.size))
+        .collect();
+    blocks.sort_unstable();
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    for (start, end) in blocks {
+        match ranges.last_mut() {
+            Some(last) if last.1 >= start => last.1 = last.1.max(end),
+            _ => ranges.push((start, end)),
+        }
+    }
+    ranges
+}
+
+// Copies the byte ranges of the corrupted blocks of a bgzf file from a donor,
+// such as a copy or an image read again, but only where the donor has whole
+// non-corrupted blocks of the same length, so that the file can only get
+// better; the results are those of the file before it was patched
+pub fn patch(file: &mut dyn Patchable, donor: &mut dyn Rescuable, input_format: Option<InputFormat>, threads: usize, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Result<(Results, PatchResults), RescueError> {
+    patch::patch(file, donor, input_format, threads, progress_listener)
+}
+
+// Rescues a bgzf file like rescue does, but in place: blocks written as they
+// were read are moved towards the beginning of the file, which is then
+// truncated. The journal, emptied first, keeps what is needed to
+// resume or roll back the rescue if it is interrupted, and is no longer needed
+// once it's done.
+pub fn rescue_in_place(file: &mut dyn Durable, journal: &mut dyn Durable, options: RescueOptions, threads: usize, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Result<Results, RescueError> {
+    journal::rescue(file, journal, options, threads, progress_listener)
 }
 
-pub fn check(reader: &mut dyn Rescuable, fail_fast: bool, threads: usize, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Results {
-    process(reader, None, fail_fast, threads, progress_listener)
+// Goes on with an interrupted in-place rescue from its journal, or rolls it
+// back, which can be interrupted as well and is then always gone on with;
+// returns whether the file is back as it was
+pub fn resume_in_place(file: &mut dyn Durable, journal: &mut dyn Durable, rollback: bool) -> Result<bool, RescueError> {
+    journal::resume(file, journal, rollback)
 }
 
-pub fn rescue(reader: &mut dyn Rescuable, writer: &mut dyn Write, threads: usize, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Results {
-    process(reader, Some(writer), false, threads, progress_listener)
+// Keeps only the reads of two rescued fastq payloads whose mate is in the
+// other one, writing both again as bgzf
+pub fn pair(reader: &mut dyn Read, mate_reader: &mut dyn Read, writer: &mut dyn Write, mate_writer: &mut dyn Write) -> Result<PairResults, RescueError> {
+    fastq::pair(reader, mate_reader, writer, mate_writer)
 }
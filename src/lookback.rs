@@ -0,0 +1,108 @@
+use std::io::{
+    BufRead,
+    Error,
+    ErrorKind,
+    Read,
+    Seek,
+    SeekFrom,
+};
+
+// Looking for the next block after a corrupted one can take us back to the
+// beginning of the previous block (at most 64 kiB), and from the end of a
+// 64 kiB search buffer back to where the next block was found
+const LOOKBACK_SIZE: usize = 4 * 65536;
+
+const READ_SIZE: usize = 65536;
+
+// Makes a stream which can only be read forward, like stdin, rescuable by
+// keeping the last bytes read so that it's possible to seek back over them.
+// Seeking relatively to the end is not supported, as the size is unknown.
+pub struct LookbackReader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    // stream positions of buffer[0] and of the next byte to be read
+    buffer_position: u64,
+    position: u64,
+}
+
+impl<R: Read> LookbackReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buffer: vec![],
+            buffer_position: 0,
+            position: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn buffer_end_position(&self) -> u64 {
+        self.buffer_position + self.buffer.len() as u64
+    }
+
+    // Reads more bytes from the stream, returning how many
+    fn read_more(&mut self) -> Result<usize, Error> {
+        let lookback_start = self.position.saturating_sub(LOOKBACK_SIZE as u64).clamp(self.buffer_position, self.buffer_end_position());
+        if lookback_start - self.buffer_position >= LOOKBACK_SIZE as u64 {
+            self.buffer.drain(..(lookback_start - self.buffer_position) as usize);
+            self.buffer_position = lookback_start;
+        }
+        let buffer_size = self.buffer.len();
+        self.buffer.resize(buffer_size + READ_SIZE, 0);
+        let result = loop {
+            match self.inner.read(&mut self.buffer[buffer_size..]) {
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                result => break result,
+            }
+        };
+        self.buffer.truncate(buffer_size + *result.as_ref().unwrap_or(&0));
+        result
+    }
+}
+
+impl<R: Read> Read for LookbackReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let available = self.fill_buf()?;
+        let size = buf.len().min(available.len());
+        buf[..size].copy_from_slice(&available[..size]);
+        self.consume(size);
+        Ok(size)
+    }
+}
+
+impl<R: Read> BufRead for LookbackReader<R> {
+    fn fill_buf(&mut self) -> Result<&[u8], Error> {
+        while self.position >= self.buffer_end_position() {
+            if self.read_more()? == 0 {
+                return Ok(&[]);
+            }
+        }
+        Ok(&self.buffer[(self.position - self.buffer_position) as usize..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position += amount as u64;
+    }
+}
+
+impl<R: Read> Seek for LookbackReader<R> {
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, Error> {
+        let position = match position {
+            SeekFrom::Start(position) => position,
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid seek to a negative position"))?,
+            SeekFrom::End(_) => return Err(Error::new(ErrorKind::Unsupported, "can't seek from the end of a stream")),
+        };
+        if position < self.buffer_position {
+            return Err(Error::new(ErrorKind::Unsupported, "can't seek that far back in a stream"));
+        }
+        // seeking forward only moves the position: the next read goes on
+        // reading the stream, throwing the bytes before the position away, up
+        // to it, and reads nothing if the stream ends before
+        self.position = position;
+        Ok(position)
+    }
+}
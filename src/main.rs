@@ -9,45 +9,201 @@ use number_prefix::NumberPrefix;
 use serde_derive::Deserialize;
 
 use std::{
-    fs::File,
+    fs::{
+        File,
+        OpenOptions,
+    },
 
-    io::BufReader,
+    io::{
+        self,
+        BufReader,
+        BufWriter,
+        Write,
+    },
 
     process,
+
+    time::Instant,
 };
 
+const EXIT_CORRUPTED: i32 = 1;
+const EXIT_INPUT_ERROR: i32 = 2;
+const EXIT_OUTPUT_ERROR: i32 = 3;
+const EXIT_INTERNAL_ERROR: i32 = 4;
+
 const USAGE: &str = "
-Usage: bamrescue check [--quiet] [--threads=<threads>] <bamfile>
-       bamrescue rescue [--threads=<threads>] <bamfile> <output>
+Usage: bamrescue check [--quiet] [--deep] [--input-format=<input-format>] [--ddrescue-map=<mapfile>] [--emit-map=<mapfile>] [--format=<format>] [--threads=<threads>] <bamfile>
+       bamrescue rescue [(--fix-bitflips [--double-bitflips])] [--salvage] [--index] [--input-format=<input-format>] [--ddrescue-map=<mapfile>] [(--mate=<matefile> --mate-output=<mateoutput>)] [--format=<format>] [--threads=<threads>] <bamfile> <output>
+       bamrescue rescue --in-place [(--fix-bitflips [--double-bitflips])] [--salvage] [--index] [--input-format=<input-format>] [--ddrescue-map=<mapfile>] [--format=<format>] [--threads=<threads>] <bamfile>
+       bamrescue rescue --in-place (--resume | --rollback) <bamfile>
+       bamrescue merge-replicas [--index] [--input-format=<input-format>] [--format=<format>] [--threads=<threads>] <bamfile> <replica>... <output>
+       bamrescue patch [--input-format=<input-format>] [--format=<format>] [--threads=<threads>] <bamfile> <donor>
+       bamrescue check-index [--quiet] [--format=<format>] [--threads=<threads>] <bamfile> <indexfile>
+       bamrescue tabix [--quiet] [--format=<format>] [--threads=<threads>] <bamfile> <indexfile>
+       bamrescue tabix --regenerate [--preset=<preset>] [--sequence-column=<column>] [--begin-column=<column>] [--end-column=<column>] [--zero-based] [--quiet] [--format=<format>] [--threads=<threads>] <bamfile> <indexfile>
        bamrescue -h | --help
        bamrescue --version
 
 Commands:
-    check                Check BAM file for corruption.
-    rescue               Keep only non-corrupted blocks of BAM file.
+    check                Check BAM (or other bgzf) file for corruption.
+    rescue               Keep only non-corrupted blocks of BAM (or other bgzf) file.
+    merge-replicas       Rescue BAM (or other bgzf) file from several copies,
+                         taking every block from a copy where it's not corrupted.
+    patch                Copy the corrupted blocks of BAM (or other bgzf) file
+                         in place from a copy or an image read again.
+    check-index          Check BAI or CSI index against its BAM file.
+    tabix                Check TBI index against its bgzipped text file, or
+                         regenerate it.
 
 Arguments:
-    bamfile              BAM file to check or rescue.
-    output               Rescued BAM file.
+    bamfile              BAM file to check or rescue, - for stdin.
+    replica              Other copy of the BAM file, with the same blocks.
+    donor                Copy or image of the BAM file read again, whose blocks
+                         are copied only if valid and of the same length.
+    output               Rescued BAM file, - for stdout.
+    indexfile            BAI or CSI index of the BAM file, or TBI index of the
+                         bgzipped text file.
 
 Options:
     -h, --help           Show this screen.
     -q, --quiet          Do not output statistics, stop at first error.
+    --deep               Also check the bam or bcf payload against its specification.
+    --fix-bitflips       Try to repair corrupted blocks by flipping single bits back (slow).
+    --double-bitflips    With --fix-bitflips, also try pairs of close bits (slower).
+    --salvage            Keep the payload of corrupted blocks up to the first error.
+    --index              Also index the rescued BAM file, as <output>.bai, or
+                         <output>.csi for reference sequences longer than 512 Mbp,
+                         or the rescued VCF, BED or GFF file, as <output>.tbi.
+    --input-format=<input-format>
+                         Format of the file, auto, bam, bcf, vcf, bed, gff,
+                         fasta, fastq or bgzf [default: auto].
+    --ddrescue-map=<mapfile>
+                         GNU ddrescue mapfile of the file, whose blocks overlapping
+                         the regions which could not be read are corrupted.
+    --emit-map=<mapfile>
+                         Write the byte ranges of the corrupted blocks as a GNU
+                         ddrescue mapfile, to read them again with --domain-mapfile.
+    --mate=<matefile>    FASTQ file of the mates of the reads, rescued as well
+                         and kept in sync with the rescued file by read name.
+    --mate-output=<mateoutput>
+                         Rescued FASTQ file of the mates.
+    --in-place           Rescue the file into itself, journaled in <bamfile>.journal
+                         so that an interrupted rescue can be resumed or rolled back.
+    --resume             Go on with an interrupted in-place rescue.
+    --rollback           Put the file back as it was before an interrupted in-place
+                         rescue.
+    --regenerate         Index the file again as <indexfile> rather than check it.
+    --preset=<preset>    Columns to index, auto, vcf, bed or gff [default: auto].
+    --sequence-column=<column>
+                         Column of the sequence names, overriding the preset.
+    --begin-column=<column>
+                         Column of the beginning of intervals, overriding the preset.
+    --end-column=<column>
+                         Column of the end of intervals, 0 for none, overriding
+                         the preset.
+    --zero-based         Intervals are 0-based and half-open, like in BED files.
+    --format=<format>    Statistics format, text or json [default: text].
     --threads=<threads>  Number of threads to use, 0 for auto [default: 0].
     --version            Show version.
 ";
 
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum InputFormat {
+    Auto,
+    Bam,
+    Bcf,
+    Vcf,
+    Bed,
+    Gff,
+    Fasta,
+    Fastq,
+    Bgzf,
+}
+
+impl InputFormat {
+    fn to_bamrescue(&self) -> Option<bamrescue::InputFormat> {
+        match self {
+            InputFormat::Auto => None,
+            InputFormat::Bam => Some(bamrescue::InputFormat::Bam),
+            InputFormat::Bcf => Some(bamrescue::InputFormat::Bcf),
+            InputFormat::Vcf => Some(bamrescue::InputFormat::Vcf),
+            InputFormat::Bed => Some(bamrescue::InputFormat::Bed),
+            InputFormat::Gff => Some(bamrescue::InputFormat::Gff),
+            InputFormat::Fasta => Some(bamrescue::InputFormat::Fasta),
+            InputFormat::Fastq => Some(bamrescue::InputFormat::Fastq),
+            InputFormat::Bgzf => Some(bamrescue::InputFormat::Bgzf),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Preset {
+    Auto,
+    Vcf,
+    Bed,
+    Gff,
+}
+
 #[derive(Deserialize)]
 struct Args {
     cmd_check: bool,
     cmd_rescue: bool,
+    cmd_merge_replicas: bool,
+    cmd_patch: bool,
+    cmd_check_index: bool,
+    cmd_tabix: bool,
     arg_bamfile: String,
     arg_output: String,
+    arg_replica: Vec<String>,
+    arg_donor: String,
+    arg_indexfile: String,
     flag_quiet: bool,
+    flag_deep: bool,
+    flag_fix_bitflips: bool,
+    flag_double_bitflips: bool,
+    flag_salvage: bool,
+    flag_index: bool,
+    flag_input_format: InputFormat,
+    flag_ddrescue_map: Option<String>,
+    flag_emit_map: Option<String>,
+    flag_mate: Option<String>,
+    flag_mate_output: Option<String>,
+    flag_in_place: bool,
+    flag_resume: bool,
+    flag_rollback: bool,
+    flag_regenerate: bool,
+    flag_preset: Preset,
+    flag_sequence_column: Option<usize>,
+    flag_begin_column: Option<usize>,
+    flag_end_column: Option<usize>,
+    flag_zero_based: bool,
+    flag_format: Format,
     flag_threads: usize,
     flag_version: bool,
 }
 
+impl Args {
+    fn rescue_options<'a>(&self, ddrescue_map: Option<&'a bamrescue::DdrescueMap>) -> bamrescue::RescueOptions<'a> {
+        bamrescue::RescueOptions {
+            fix_bitflips: self.flag_fix_bitflips,
+            double_bitflips: self.flag_double_bitflips,
+            salvage: self.flag_salvage,
+            index: self.flag_index,
+            input_format: self.flag_input_format.to_bamrescue(),
+            ddrescue_map,
+        }
+    }
+}
+
 struct ProgressListener {
     progress_bar: ProgressBar,
     blocks_count: u64,
@@ -68,10 +224,18 @@ impl ProgressListener {
 }
 
 impl bamrescue::ListenProgress for ProgressListener {
-    fn on_new_target(&mut self, target: u64) {
-        self.progress_bar.set_length(target);
-        self.progress_bar.set_style(ProgressStyle::default_bar()
-            .template("[{wide_bar}] {percent:>3}% ({binary_bytes}/{binary_total_bytes}) [ETA: {eta_precise}]\n{msg}"));
+    fn on_new_target(&mut self, target: Option<u64>) {
+        match target {
+            Some(target) => {
+                self.progress_bar.set_length(target);
+                self.progress_bar.set_style(ProgressStyle::default_bar()
+                    .template("[{wide_bar}] {percent:>3}% ({binary_bytes}/{binary_total_bytes}) [ETA: {eta_precise}]\n{msg}"));
+            },
+            None => {
+                self.progress_bar.set_style(ProgressStyle::default_spinner()
+                    .template("{spinner} {binary_bytes} processed [{elapsed_precise}]\n{msg}"));
+            },
+        }
         self.update_message();
         self.progress_bar.set_draw_target(ProgressDrawTarget::stderr());
     }
@@ -80,7 +244,7 @@ impl bamrescue::ListenProgress for ProgressListener {
         self.blocks_count += 1;
         self.update_message();
     }
-    fn on_bad_block(&mut self) {
+    fn on_bad_block(&mut self, _bad_block: &bamrescue::BadBlock) {
         self.bad_blocks_count += 1;
         self.update_message();
     }
@@ -89,11 +253,342 @@ impl bamrescue::ListenProgress for ProgressListener {
     }
 }
 
+fn percentage(part: u64, total: u64) -> f64 {
+    if total > 0 {
+        (part as f64 * 100.) / total as f64
+    } else {
+        0.
+    }
+}
+
+fn print_statistics(output: &mut dyn Write, results: &bamrescue::Results, deep: bool, rescue: bool) -> io::Result<()> {
+    // files without any payload to detect their format from are reported as bam
+    let input_format = results.input_format.unwrap_or(bamrescue::InputFormat::Bam);
+    let name = if results.plain_gzip && input_format == bamrescue::InputFormat::Bgzf { "gzip" } else { input_format.name() };
+    // cram files are made of containers rather than bgzf blocks, and plain
+    // gzip files of members, several parts of which can be corrupted
+    let (container_format, block, blocks) = if input_format == bamrescue::InputFormat::Cram {
+        ("cram", "container", "containers")
+    } else if results.plain_gzip {
+        ("gzip", "member", "members")
+    } else {
+        ("bgzf", "block", "blocks")
+    };
+    writeln!(output, "{name} file statistics:")?;
+    match NumberPrefix::binary(results.blocks_size as f64) {
+        NumberPrefix::Standalone(_) => writeln!(output, "{: >7} {container_format} {} checked ({} {} of {name} payload)", results.blocks_count, if results.blocks_count > 1 { blocks } else { block }, results.blocks_size, if results.blocks_size > 1 { "bytes" } else { "byte" })?,
+        NumberPrefix::Prefixed(prefix, number) => writeln!(output, "{: >7} {container_format} {} checked ({:.0} {}B of {name} payload)", results.blocks_count, if results.blocks_count > 1 { blocks } else { block }, number, prefix)?,
+    }
+    if results.plain_gzip {
+        writeln!(output, "{: >7} corrupted {} found", results.bad_blocks_count, if results.bad_blocks_count > 1 { "parts" } else { "part" })?;
+    } else {
+        writeln!(output, "{: >7} corrupted {} found ({:.2}% of total)", results.bad_blocks_count, if results.bad_blocks_count > 1 { blocks } else { block }, percentage(results.bad_blocks_count, results.blocks_count))?;
+    }
+    if results.unreadable_blocks_count > 0 {
+        let (block, blocks) = if results.plain_gzip { ("part", "parts") } else { (block, blocks) };
+        writeln!(output, "{: >7} corrupted {} in sectors ddrescue could not read", results.unreadable_blocks_count, if results.unreadable_blocks_count > 1 { blocks } else { block })?;
+    }
+    match NumberPrefix::binary(results.bad_blocks_size as f64) {
+        NumberPrefix::Standalone(_) => writeln!(output, "{: >7} {} of {name} payload lost ({:.2}% of total)", results.bad_blocks_size, if results.bad_blocks_size > 1 { "bytes" } else { "byte" }, percentage(results.bad_blocks_size, results.blocks_size))?,
+        NumberPrefix::Prefixed(prefix, number) => writeln!(output, "{: >7.0} {}B of {name} payload lost ({:.2}% of total)", number, prefix, percentage(results.bad_blocks_size, results.blocks_size))?,
+    }
+    if results.repaired_blocks_count > 0 {
+        writeln!(output, "{: >7} corrupted {} {} ({:.2}% of total)", results.repaired_blocks_count, if results.repaired_blocks_count > 1 { "blocks" } else { "block" }, if rescue { "repaired" } else { "repairable" }, percentage(results.repaired_blocks_count, results.blocks_count))?;
+    }
+    if results.truncated_in_block {
+        writeln!(output, "        file truncated in a {container_format} {block}")?;
+    }
+    if results.truncated_between_blocks {
+        writeln!(output, "        file truncated between two {container_format} {block}")?;
+    }
+    if deep && matches!(input_format, bamrescue::InputFormat::Bam | bamrescue::InputFormat::Bcf) {
+        writeln!(output, "{: >7} {name} format {} found", results.format_errors.len(), if results.format_errors.len() > 1 { "errors" } else { "error" })?;
+        if let Some(format_error) = results.format_errors.first() {
+            writeln!(output, "        first one at virtual offset {}: {}", format_error.virtual_offset, format_error.kind)?;
+        }
+    }
+    if rescue {
+        for bitflip in &results.bitflips {
+            writeln!(output, "        bit {} of byte {} flipped back", bitflip.bit, bitflip.position)?;
+        }
+        let good_blocks_size = results.blocks_size - results.bad_blocks_size + results.salvaged_size - results.dropped_records_size;
+        if results.salvaged_size > 0 {
+            match NumberPrefix::binary(results.salvaged_size as f64) {
+                NumberPrefix::Standalone(_) => writeln!(output, "{: >7} {} of {name} payload salvaged from corrupted blocks", results.salvaged_size, if results.salvaged_size > 1 { "bytes" } else { "byte" })?,
+                NumberPrefix::Prefixed(prefix, number) => writeln!(output, "{: >7.0} {}B of {name} payload salvaged from corrupted blocks", number, prefix)?,
+            }
+        }
+        if results.dropped_records_size > 0 {
+            match NumberPrefix::binary(results.dropped_records_size as f64) {
+                NumberPrefix::Standalone(_) => writeln!(output, "{: >7} {} of {name} payload dropped to keep {name} records whole", results.dropped_records_size, if results.dropped_records_size > 1 { "bytes" } else { "byte" })?,
+                NumberPrefix::Prefixed(prefix, number) => writeln!(output, "{: >7.0} {}B of {name} payload dropped to keep {name} records whole", number, prefix)?,
+            }
+        }
+        if !results.plain_gzip {
+            let good_blocks_count = results.blocks_count - results.bad_blocks_count;
+            writeln!(output, "{: >7} non-corrupted {} rescued ({:.2}% of total)", good_blocks_count, if good_blocks_count > 1 { blocks } else { block }, percentage(good_blocks_count, results.blocks_count))?;
+        }
+        match NumberPrefix::binary(good_blocks_size as f64) {
+            NumberPrefix::Standalone(_) => writeln!(output, "{: >7} {} of {name} payload rescued ({:.2}% of total)", good_blocks_size, if good_blocks_size > 1 { "bytes" } else { "byte" }, percentage(good_blocks_size, results.blocks_size))?,
+            NumberPrefix::Prefixed(prefix, number) => writeln!(output, "{: >7.0} {}B of {name} payload rescued ({:.2}% of total)", number, prefix, percentage(good_blocks_size, results.blocks_size))?,
+        }
+    }
+    Ok(())
+}
+
+fn print_merge_statistics<'a>(output: &mut dyn Write, merge_results: &bamrescue::MergeResults, replica_paths: impl Iterator<Item = &'a String>) -> io::Result<()> {
+    writeln!(output, "replicas statistics:")?;
+    for (blocks_count, replica_path) in merge_results.replica_blocks_counts.iter().zip(replica_paths) {
+        writeln!(output, "{: >7} non-corrupted {} taken from {}", blocks_count, if *blocks_count > 1 { "blocks" } else { "block" }, replica_path)?;
+    }
+    Ok(())
+}
+
+fn print_patch_statistics(output: &mut dyn Write, patch_results: &bamrescue::PatchResults, donor_path: &str) -> io::Result<()> {
+    writeln!(output, "patch statistics:")?;
+    writeln!(output, "{: >7} corrupted {} copied from {}", patch_results.patched_ranges_count, if patch_results.patched_ranges_count > 1 { "ranges" } else { "range" }, donor_path)?;
+    match NumberPrefix::binary(patch_results.patched_size as f64) {
+        NumberPrefix::Standalone(_) => writeln!(output, "{: >7} {} of bgzf blocks copied", patch_results.patched_size, if patch_results.patched_size > 1 { "bytes" } else { "byte" })?,
+        NumberPrefix::Prefixed(prefix, number) => writeln!(output, "{: >7.0} {}B of bgzf blocks copied", number, prefix)?,
+    }
+    writeln!(output, "{: >7} corrupted {} left as is, without valid blocks of the same length in the donor", patch_results.unpatched_ranges_count, if patch_results.unpatched_ranges_count > 1 { "ranges" } else { "range" })?;
+    Ok(())
+}
+
+fn print_pair_statistics(output: &mut dyn Write, pair_results: &bamrescue::PairResults) -> io::Result<()> {
+    writeln!(output, "fastq pairs statistics:")?;
+    writeln!(output, "{: >7} read {} rescued", pair_results.pairs_count, if pair_results.pairs_count > 1 { "pairs" } else { "pair" })?;
+    writeln!(output, "{: >7} unpaired {} dropped from the first file", pair_results.unpaired_count, if pair_results.unpaired_count > 1 { "reads" } else { "read" })?;
+    writeln!(output, "{: >7} unpaired {} dropped from the mate file", pair_results.mate_unpaired_count, if pair_results.mate_unpaired_count > 1 { "reads" } else { "read" })?;
+    if pair_results.skipped_lines_count > 0 {
+        writeln!(output, "{: >7} {} skipped, not part of a whole read", pair_results.skipped_lines_count, if pair_results.skipped_lines_count > 1 { "lines" } else { "line" })?;
+    }
+    Ok(())
+}
+
+fn print_index_statistics(output: &mut dyn Write, index_results: &bamrescue::IndexResults) -> io::Result<()> {
+    match index_results.format {
+        Some(format) => writeln!(output, "{} file statistics:", format.extension())?,
+        None => writeln!(output, "index file statistics:")?,
+    }
+    writeln!(output, "{: >7} virtual {} checked", index_results.virtual_offsets_count, if index_results.virtual_offsets_count > 1 { "offsets" } else { "offset" })?;
+    writeln!(output, "{: >7} index {} found", index_results.index_errors.len(), if index_results.index_errors.len() > 1 { "errors" } else { "error" })?;
+    for index_error in &index_results.index_errors {
+        writeln!(output, "        at byte {} (virtual offset {}): {}", index_error.position, index_error.virtual_offset, index_error.kind)?;
+    }
+    Ok(())
+}
+
+// Explicit columns override those of the preset, or those of gff like tabix
+// does
+fn tabix_columns(args: &Args) -> Option<bamrescue::TabixColumns> {
+    let preset = match args.flag_preset {
+        Preset::Auto => None,
+        Preset::Vcf => Some(bamrescue::TabixColumns::vcf()),
+        Preset::Bed => Some(bamrescue::TabixColumns::bed()),
+        Preset::Gff => Some(bamrescue::TabixColumns::gff()),
+    };
+    if args.flag_sequence_column.is_none() && args.flag_begin_column.is_none() && args.flag_end_column.is_none() && !args.flag_zero_based {
+        return preset;
+    }
+    let mut columns = preset.unwrap_or_else(bamrescue::TabixColumns::gff);
+    if let Some(sequence) = args.flag_sequence_column {
+        columns.sequence = sequence;
+    }
+    if let Some(begin) = args.flag_begin_column {
+        columns.begin = begin;
+    }
+    if let Some(end) = args.flag_end_column {
+        columns.end = end;
+    }
+    if args.flag_zero_based {
+        columns.zero_based = true;
+    }
+    Some(columns)
+}
+
+// The rescued file may be written to stdout, but not indexed then
+fn rescued_output(args: &Args) -> Box<dyn Write> {
+    if args.arg_output == "-" {
+        if args.flag_index {
+            eprintln!("bamrescue: can't index a rescued file written to stdout");
+            process::exit(EXIT_OUTPUT_ERROR);
+        }
+        Box::new(BufWriter::new(io::stdout()))
+    } else {
+        Box::new(File::create(&args.arg_output).unwrap_or_else(|cause| {
+            eprintln!("bamrescue: can't open file: {}: {}", &args.arg_output, &cause);
+            process::exit(EXIT_OUTPUT_ERROR);
+        }))
+    }
+}
+
+// Next to the rescued file
+fn write_index(output_path: &str, results: &bamrescue::Results) {
+    if let Some(ref index) = results.index {
+        let index_path = format!("{}.{}", output_path, index.format.extension());
+        std::fs::write(&index_path, &index.bytes).unwrap_or_else(|cause| {
+            eprintln!("bamrescue: can't write file: {}: {}", &index_path, &cause);
+            process::exit(EXIT_OUTPUT_ERROR);
+        });
+    }
+}
+
+fn read_ddrescue_map(args: &Args) -> Option<bamrescue::DdrescueMap> {
+    let path = args.flag_ddrescue_map.as_ref()?;
+    let mut reader = BufReader::new(File::open(path).unwrap_or_else(|cause| {
+        eprintln!("bamrescue: can't open file: {}: {}", path, &cause);
+        process::exit(EXIT_INPUT_ERROR);
+    }));
+    Some(bamrescue::DdrescueMap::read(&mut reader).unwrap_or_else(|error| {
+        eprintln!("bamrescue: {}: {}", path, &error);
+        process::exit(EXIT_INPUT_ERROR);
+    }))
+}
+
+// Even when the file isn't corrupted, so that the mapfile of a previous check
+// isn't left behind
+fn write_emitted_map(path: &str, results: &bamrescue::Results, input_size: Option<u64>) {
+    let mut output = create_output(path);
+    bamrescue::emit_map(&mut output, results, input_size)
+        .and_then(|_| output.flush().map_err(bamrescue::RescueError::Output))
+        .unwrap_or_else(|error| {
+            eprintln!("bamrescue: {}: {}", path, &error);
+            process::exit(EXIT_OUTPUT_ERROR);
+        });
+}
+
+fn exit_code(error: &bamrescue::RescueError) -> i32 {
+    match error {
+        bamrescue::RescueError::Input(_) => EXIT_INPUT_ERROR,
+        bamrescue::RescueError::Output(_) |
+        bamrescue::RescueError::Index(_) => EXIT_OUTPUT_ERROR,
+        bamrescue::RescueError::Internal(_) => EXIT_INTERNAL_ERROR,
+    }
+}
+
+fn open_for_writing(path: &str) -> File {
+    OpenOptions::new().read(true).write(true).open(path).unwrap_or_else(|cause| {
+        eprintln!("bamrescue: can't open file for writing: {}: {}", path, &cause);
+        process::exit(EXIT_OUTPUT_ERROR);
+    })
+}
+
+fn remove_journal(path: &str) {
+    std::fs::remove_file(path).unwrap_or_else(|cause| {
+        eprintln!("bamrescue: can't remove file: {}: {}", path, &cause);
+        process::exit(EXIT_OUTPUT_ERROR);
+    });
+}
+
+// Whatever was asked, a rollback already under way is finished
+fn resume_in_place(args: &Args) {
+    let journal_path = format!("{}.journal", &args.arg_bamfile);
+    let mut file = open_for_writing(&args.arg_bamfile);
+    let mut journal = OpenOptions::new().read(true).write(true).open(&journal_path).unwrap_or_else(|cause| {
+        if cause.kind() == io::ErrorKind::NotFound {
+            eprintln!("bamrescue: {}: no interrupted in-place rescue", &args.arg_bamfile);
+            process::exit(EXIT_INPUT_ERROR);
+        }
+        eprintln!("bamrescue: can't open file for writing: {}: {}", &journal_path, &cause);
+        process::exit(EXIT_OUTPUT_ERROR);
+    });
+    let rolled_back = bamrescue::resume_in_place(&mut file, &mut journal, args.flag_rollback).unwrap_or_else(|error| {
+        eprintln!("bamrescue: {}", &error);
+        process::exit(exit_code(&error));
+    });
+    remove_journal(&journal_path);
+    if rolled_back && !args.flag_rollback {
+        eprintln!("bamrescue: {}: in-place rescue rolled back, file left as it was", &args.arg_bamfile);
+    }
+}
+
+// The journal is left empty if the rescue failed before modifying the file
+fn rescue_in_place(args: &Args, ddrescue_map: Option<&bamrescue::DdrescueMap>, progress_listener: &mut ProgressListener) -> Result<bamrescue::Results, bamrescue::RescueError> {
+    if args.arg_bamfile == "-" {
+        eprintln!("bamrescue: can't rescue in place a file read from stdin");
+        process::exit(EXIT_INPUT_ERROR);
+    }
+    let journal_path = format!("{}.journal", &args.arg_bamfile);
+    let mut file = open_for_writing(&args.arg_bamfile);
+    let mut journal = OpenOptions::new().read(true).write(true).create_new(true).open(&journal_path).unwrap_or_else(|cause| {
+        if cause.kind() == io::ErrorKind::AlreadyExists {
+            eprintln!("bamrescue: {}: interrupted in-place rescue, run again with --resume or --rollback", &args.arg_bamfile);
+            process::exit(EXIT_INPUT_ERROR);
+        }
+        eprintln!("bamrescue: can't open file for writing: {}: {}", &journal_path, &cause);
+        process::exit(EXIT_OUTPUT_ERROR);
+    });
+    match bamrescue::rescue_in_place(&mut file, &mut journal, args.rescue_options(ddrescue_map), args.flag_threads, &mut Some(progress_listener)) {
+        Ok(results) => {
+            remove_journal(&journal_path);
+            write_index(&args.arg_bamfile, &results);
+            Ok(results)
+        },
+        Err(error) => {
+            if journal.metadata().is_ok_and(|metadata| metadata.len() == 0) {
+                remove_journal(&journal_path);
+            } else {
+                eprintln!("bamrescue: {}: in-place rescue interrupted, run again with --resume or --rollback", &args.arg_bamfile);
+            }
+            Err(error)
+        },
+    }
+}
+
+fn create_output(path: &str) -> BufWriter<File> {
+    BufWriter::new(File::create(path).unwrap_or_else(|cause| {
+        eprintln!("bamrescue: can't open file: {}: {}", path, &cause);
+        process::exit(EXIT_OUTPUT_ERROR);
+    }))
+}
+
+// Rescues both files of paired reads next to their outputs, then writes again
+// only the reads whose mate has been rescued as well
+fn rescue_mates(args: &Args, reader: &mut dyn bamrescue::Rescuable, mate_path: &str, mate_output_path: &str, ddrescue_map: Option<&bamrescue::DdrescueMap>, progress_listener: &mut ProgressListener) -> Result<(bamrescue::Results, bamrescue::Results, bamrescue::PairResults), bamrescue::RescueError> {
+    if args.arg_output == "-" || mate_output_path == "-" || args.flag_index {
+        eprintln!("bamrescue: can't pair rescued files written to stdout or indexed");
+        process::exit(EXIT_OUTPUT_ERROR);
+    }
+    let mut mate_reader = BufReader::new(File::open(mate_path).unwrap_or_else(|cause| {
+        eprintln!("bamrescue: can't open file: {}: {}", mate_path, &cause);
+        process::exit(EXIT_INPUT_ERROR);
+    }));
+    let unpaired_paths = [format!("{}.unpaired", &args.arg_output), format!("{}.unpaired", mate_output_path)];
+    let paired = rescue_and_pair(args, reader, &mut mate_reader, &unpaired_paths, mate_output_path, ddrescue_map, progress_listener);
+    // whether paired or not, the unpaired files are of no use anymore
+    for unpaired_path in &unpaired_paths {
+        match std::fs::remove_file(unpaired_path) {
+            Err(cause) if paired.is_ok() => return Err(bamrescue::RescueError::Output(cause)),
+            _ => (),
+        }
+    }
+    paired
+}
+
+fn rescue_and_pair(args: &Args, reader: &mut dyn bamrescue::Rescuable, mate_reader: &mut dyn bamrescue::Rescuable, unpaired_paths: &[String; 2], mate_output_path: &str, ddrescue_map: Option<&bamrescue::DdrescueMap>, progress_listener: &mut ProgressListener) -> Result<(bamrescue::Results, bamrescue::Results, bamrescue::PairResults), bamrescue::RescueError> {
+    let with_path = |path: &str, cause: io::Error| io::Error::new(cause.kind(), format!("{}: {}", path, &cause));
+    let create = |path: &str| File::create(path).map(BufWriter::new).map_err(|cause| bamrescue::RescueError::Output(with_path(path, cause)));
+    let open_unpaired = |path: &str| File::open(path).map(|file| bamrescue::RescueReader::new(BufReader::new(file), args.flag_threads)).map_err(|cause| bamrescue::RescueError::Input(with_path(path, cause)));
+    let mut unpaired_output = create(&unpaired_paths[0])?;
+    let results = bamrescue::rescue(reader, &mut unpaired_output, args.rescue_options(ddrescue_map), args.flag_threads, &mut Some(progress_listener))?;
+    unpaired_output.flush().map_err(bamrescue::RescueError::Output)?;
+    let mut mate_unpaired_output = create(&unpaired_paths[1])?;
+    let mate_results = bamrescue::rescue(mate_reader, &mut mate_unpaired_output, args.rescue_options(None), args.flag_threads, &mut Some(&mut ProgressListener::new()))?;
+    mate_unpaired_output.flush().map_err(bamrescue::RescueError::Output)?;
+    let mut output = create(&args.arg_output)?;
+    let mut mate_output = create(mate_output_path)?;
+    let pair_results = bamrescue::pair(&mut open_unpaired(&unpaired_paths[0])?, &mut open_unpaired(&unpaired_paths[1])?, &mut output, &mut mate_output)?;
+    output.flush().map_err(bamrescue::RescueError::Output)?;
+    mate_output.flush().map_err(bamrescue::RescueError::Output)?;
+    Ok((results, mate_results, pair_results))
+}
+
 fn main() {
     let args: Args =
         docopt::Docopt::new(USAGE)
             .and_then(|docopts|
-                docopts.argv(std::env::args().into_iter())
+                docopts.argv(std::env::args())
                    .deserialize()
             )
             .unwrap_or_else(|error|
@@ -102,55 +597,175 @@ fn main() {
 
     if args.flag_version {
         println!("bamrescue v{}", bamrescue::version());
-    } else if args.cmd_check || args.cmd_rescue {
-        let bamfile = File::open(&args.arg_bamfile).unwrap_or_else(|cause| {
-            println!("bamrescue: can't open file: {}: {}", &args.arg_bamfile, &cause);
-            process::exit(1);
-        });
+    } else if args.cmd_rescue && (args.flag_resume || args.flag_rollback) {
+        resume_in_place(&args);
+    } else if args.cmd_check || args.cmd_rescue || args.cmd_merge_replicas || args.cmd_patch || args.cmd_check_index || args.cmd_tabix {
+        let (mut reader, input_size): (Box<dyn bamrescue::Rescuable>, Option<u64>) = if args.arg_bamfile == "-" {
+            (Box::new(bamrescue::LookbackReader::new(io::stdin())), None)
+        } else {
+            let bamfile = File::open(&args.arg_bamfile).unwrap_or_else(|cause| {
+                eprintln!("bamrescue: can't open file: {}: {}", &args.arg_bamfile, &cause);
+                process::exit(EXIT_INPUT_ERROR);
+            });
+            let input_size = bamfile.metadata().map(|metadata| metadata.len()).ok();
+            (Box::new(BufReader::new(bamfile)), input_size)
+        };
         let mut progress_listener = ProgressListener::new();
-        let mut reader = BufReader::new(&bamfile);
+        let start_time = Instant::now();
+        let mut index_results = None;
+        let mut mate_results = None;
+        let mut pair_results = None;
+        let mut merge_results = None;
+        let mut patch_results = None;
+        let ddrescue_map = read_ddrescue_map(&args);
         let results = if args.cmd_check {
-            bamrescue::check(&mut reader, args.flag_quiet, args.flag_threads, &mut Some(&mut progress_listener))
-        } else  {
-            let mut output = File::create(&args.arg_output).unwrap_or_else(|cause| {
-                println!("bamrescue: can't open file: {}: {}", &args.arg_output, &cause);
-                process::exit(1);
+            bamrescue::check(&mut reader, args.flag_quiet, args.flag_deep, args.flag_input_format.to_bamrescue(), ddrescue_map.as_ref(), args.flag_threads, &mut Some(&mut progress_listener))
+        } else if args.cmd_tabix && args.flag_regenerate {
+            bamrescue::tabix(&mut reader, tabix_columns(&args), args.flag_threads, &mut Some(&mut progress_listener))
+                .map(|(results, index)| {
+                    std::fs::write(&args.arg_indexfile, &index.bytes).unwrap_or_else(|cause| {
+                        eprintln!("bamrescue: can't write file: {}: {}", &args.arg_indexfile, &cause);
+                        process::exit(EXIT_OUTPUT_ERROR);
+                    });
+                    results
+                })
+        } else if args.cmd_check_index || args.cmd_tabix {
+            let mut indexfile = File::open(&args.arg_indexfile).unwrap_or_else(|cause| {
+                eprintln!("bamrescue: can't open file: {}: {}", &args.arg_indexfile, &cause);
+                process::exit(EXIT_INPUT_ERROR);
             });
-            bamrescue::rescue(&mut reader, &mut output, args.flag_threads, &mut Some(&mut progress_listener))
-        };
-        if !args.flag_quiet {
-            // TODO distinguish between repairable and unrepairable corruptions
-            println!("bam file statistics:");
-            match NumberPrefix::binary(results.blocks_size as f64) {
-                NumberPrefix::Standalone(_) => println!("{: >7} bgzf {} checked ({} {} of bam payload)", results.blocks_count, if results.blocks_count > 1 { "blocks" } else { "block" }, results.blocks_size, if results.blocks_size > 1 { "bytes" } else { "byte" }),
-                NumberPrefix::Prefixed(prefix, number) => println!("{: >7} bgzf {} checked ({:.0} {}B of bam payload)", results.blocks_count, if results.blocks_count > 1 { "blocks" } else { "block" }, number, prefix),
-            }
-            println!("{: >7} corrupted {} found ({:.2}% of total)", results.bad_blocks_count, if results.bad_blocks_count > 1 { "blocks" } else { "block" }, if results.blocks_count > 0 { (results.bad_blocks_count * 100) / results.blocks_count } else { 0 });
-            match NumberPrefix::binary(results.bad_blocks_size as f64) {
-                NumberPrefix::Standalone(_) => println!("{: >7} {} of bam payload lost ({:.2}% of total)", results.bad_blocks_size, if results.bad_blocks_size > 1 { "bytes" } else { "byte" }, if results.blocks_size > 0 { (results.bad_blocks_size * 100) / results.blocks_size } else { 0 }),
-                NumberPrefix::Prefixed(prefix, number) => println!("{: >7.0} {}B of bam payload lost ({:.2}% of total)", number, prefix, if results.blocks_size > 0 { (results.bad_blocks_size * 100) / results.blocks_size } else { 0 }),
-            }
-            if results.truncated_in_block {
-                println!("        file truncated in a bgzf block");
+            bamrescue::check_index(&mut reader, &mut indexfile, args.flag_threads, &mut Some(&mut progress_listener))
+                .map(|(results, checked_index_results)| {
+                    index_results = Some(checked_index_results);
+                    results
+                })
+        } else if args.cmd_merge_replicas {
+            if args.arg_bamfile == "-" {
+                eprintln!("bamrescue: can't merge replicas read from stdin");
+                process::exit(EXIT_INPUT_ERROR);
             }
-            if results.truncated_between_blocks {
-                println!("        file truncated between two bgzf block");
-            }
-            if args.cmd_rescue {
-                let good_blocks_count = results.blocks_count - results.bad_blocks_count;
-                let good_blocks_size = results.blocks_size - results.bad_blocks_size;
-                println!("{: >7} non-corrupted {} rescued ({:.2}% of total)", good_blocks_count, if good_blocks_count > 1 { "blocks" } else { "block" }, if results.blocks_count > 0 { (good_blocks_count * 100) / results.blocks_count } else { 0 });
-                match NumberPrefix::binary(good_blocks_size as f64) {
-                    NumberPrefix::Standalone(_) => println!("{: >7} {} of bam payload rescued ({:.2}% of total)", good_blocks_size, if good_blocks_size > 1 { "bytes" } else { "byte" }, if results.blocks_size > 0 { (good_blocks_size * 100) / results.blocks_size } else { 0 }),
-                    NumberPrefix::Prefixed(prefix, number) => println!("{: >7.0} {}B of bam payload rescued ({:.2}% of total)", number, prefix, if results.blocks_size > 0 { (good_blocks_size * 100) / results.blocks_size } else { 0 }),
-                }
+            let mut replicas: Vec<BufReader<File>> = args.arg_replica.iter().map(|replica_path| {
+                BufReader::new(File::open(replica_path).unwrap_or_else(|cause| {
+                    eprintln!("bamrescue: can't open file: {}: {}", replica_path, &cause);
+                    process::exit(EXIT_INPUT_ERROR);
+                }))
+            }).collect();
+            let mut replicas: Vec<&mut dyn bamrescue::Rescuable> = std::iter::once(&mut reader as &mut dyn bamrescue::Rescuable)
+                .chain(replicas.iter_mut().map(|replica| replica as &mut dyn bamrescue::Rescuable))
+                .collect();
+            let mut output = rescued_output(&args);
+            bamrescue::merge_replicas(&mut replicas, &mut output, args.flag_index, args.flag_input_format.to_bamrescue(), args.flag_threads, &mut Some(&mut progress_listener))
+                .and_then(|(results, merged_results)| {
+                    output.flush().map_err(bamrescue::RescueError::Output)?;
+                    write_index(&args.arg_output, &results);
+                    merge_results = Some(merged_results);
+                    Ok(results)
+                })
+        } else if args.cmd_patch {
+            if args.arg_bamfile == "-" {
+                eprintln!("bamrescue: can't patch a file read from stdin");
+                process::exit(EXIT_INPUT_ERROR);
             }
+            let mut file = open_for_writing(&args.arg_bamfile);
+            let mut donor = BufReader::new(File::open(&args.arg_donor).unwrap_or_else(|cause| {
+                eprintln!("bamrescue: can't open file: {}: {}", &args.arg_donor, &cause);
+                process::exit(EXIT_INPUT_ERROR);
+            }));
+            bamrescue::patch(&mut file, &mut donor, args.flag_input_format.to_bamrescue(), args.flag_threads, &mut Some(&mut progress_listener))
+                .map(|(results, patched_results)| {
+                    patch_results = Some(patched_results);
+                    results
+                })
+        } else if args.flag_in_place {
+            rescue_in_place(&args, ddrescue_map.as_ref(), &mut progress_listener)
+        } else if let (Some(ref mate_path), Some(ref mate_output_path)) = (&args.flag_mate, &args.flag_mate_output) {
+            rescue_mates(&args, &mut reader, mate_path, mate_output_path, ddrescue_map.as_ref(), &mut progress_listener)
+                .map(|(results, rescued_mate_results, paired_results)| {
+                    mate_results = Some(rescued_mate_results);
+                    pair_results = Some(paired_results);
+                    results
+                })
+        } else  {
+            let mut output = rescued_output(&args);
+            bamrescue::rescue(&mut reader, &mut output, args.rescue_options(ddrescue_map.as_ref()), args.flag_threads, &mut Some(&mut progress_listener))
+                .and_then(|results| {
+                    output.flush().map_err(bamrescue::RescueError::Output)?;
+                    write_index(&args.arg_output, &results);
+                    Ok(results)
+                })
+        };
+        let elapsed_time = start_time.elapsed();
+        let results = results.unwrap_or_else(|error| {
+            progress_listener.progress_bar.abandon();
+            eprintln!("bamrescue: {}", &error);
+            process::exit(exit_code(&error));
+        });
+        if let Some(ref emit_map_path) = args.flag_emit_map {
+            write_emitted_map(emit_map_path, &results, input_size);
+        }
+        let corrupted = results.bad_blocks_count > 0 ||
+                        results.repaired_blocks_count > 0 ||
+                        results.truncated_in_block ||
+                        results.truncated_between_blocks ||
+                        !results.format_errors.is_empty() ||
+                        index_results.as_ref().is_some_and(|index_results| !index_results.index_errors.is_empty());
+        // what could not be copied from the donor
+        let still_corrupted = results.truncated_between_blocks ||
+                              patch_results.as_ref().is_some_and(|patch_results| patch_results.unpatched_ranges_count > 0);
+        if !args.flag_quiet {
+            // the rescued file itself may be going to stdout
+            let mut output: Box<dyn Write> = if (args.cmd_rescue || args.cmd_merge_replicas) && !args.flag_in_place && args.arg_output == "-" {
+                Box::new(io::stderr())
+            } else {
+                Box::new(io::stdout())
+            };
+            match args.flag_format {
+                Format::Text => print_statistics(&mut output, &results, args.flag_deep, args.cmd_rescue || args.cmd_merge_replicas)
+                    .and_then(|_| match index_results {
+                        Some(ref index_results) => print_index_statistics(&mut output, index_results),
+                        None => Ok(()),
+                    })
+                    .and_then(|_| match mate_results {
+                        Some(ref mate_results) => print_statistics(&mut output, mate_results, args.flag_deep, args.cmd_rescue),
+                        None => Ok(()),
+                    })
+                    .and_then(|_| match pair_results {
+                        Some(ref pair_results) => print_pair_statistics(&mut output, pair_results),
+                        None => Ok(()),
+                    })
+                    .and_then(|_| match merge_results {
+                        Some(ref merge_results) => print_merge_statistics(&mut output, merge_results, std::iter::once(&args.arg_bamfile).chain(&args.arg_replica)),
+                        None => Ok(()),
+                    })
+                    .and_then(|_| match patch_results {
+                        Some(ref patch_results) => print_patch_statistics(&mut output, patch_results, &args.arg_donor),
+                        None => Ok(()),
+                    }),
+                Format::Json => {
+                    let report = bamrescue::Report {
+                        version: bamrescue::version(),
+                        command: if args.cmd_check { "check" } else if args.cmd_check_index { "check-index" } else if args.cmd_tabix { "tabix" } else if args.cmd_merge_replicas { "merge-replicas" } else if args.cmd_patch { "patch" } else { "rescue" },
+                        input_size,
+                        elapsed_seconds: elapsed_time.as_secs_f64(),
+                        results,
+                        index_results,
+                        mate_results,
+                        pair_results,
+                        merge_results,
+                        patch_results,
+                    };
+                    serde_json::to_writer_pretty(&mut output, &report)
+                        .map_err(io::Error::from)
+                        .and_then(|_| writeln!(output))
+                },
+            }.unwrap_or_else(|cause| {
+                eprintln!("bamrescue: can't write report: {}", &cause);
+                process::exit(EXIT_OUTPUT_ERROR);
+            });
         }
-        if args.cmd_check &&
-           (results.bad_blocks_count > 0 ||
-            results.truncated_in_block ||
-            results.truncated_between_blocks) {
-            process::exit(1);
+        if ((args.cmd_check || args.cmd_check_index || (args.cmd_tabix && !args.flag_regenerate)) && corrupted) ||
+           (args.cmd_patch && still_corrupted) {
+            process::exit(EXIT_CORRUPTED);
         }
     }
 }
@@ -0,0 +1,206 @@
+use serde_derive::Serialize;
+
+use std::io::{
+    self,
+    BufReader,
+    Cursor,
+    Error,
+    ErrorKind,
+    Read,
+    Seek,
+    SeekFrom,
+    Write,
+};
+
+use crate::{
+    bgzf,
+    InputFormat,
+    Layout,
+    ListenProgress,
+    RescueError,
+    RescueOptions,
+    Rescuable,
+    Results,
+};
+
+// What was taken from each replica of a bgzf file
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MergeResults {
+    // non-corrupted blocks taken from each replica, in the order they were
+    // given; blocks corrupted in all replicas are the bad blocks of the
+    // merged file
+    pub replica_blocks_counts: Vec<u64>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Block {
+    size: u64,
+    inflated_size: u64,
+}
+
+// Contiguous bytes of the merged file, taken from a single replica
+struct Segment {
+    end: u64,
+    replica: usize,
+}
+
+// Reads the merged file, picking the replica to read each block from as the
+// blocks are reached: at every block offset, the first replica whose block
+// there is not corrupted. Bytes where no replica has a non-corrupted block are
+// taken from the first replica long enough, for them to be found corrupted
+// again.
+struct MergedReader<'a, 'b> {
+    replicas: &'a mut [&'b mut dyn Rescuable],
+    sizes: Vec<u64>,
+    // where each replica was left, to avoid seeking it again
+    replica_positions: Vec<Option<u64>>,
+    // picked so far, which only grow where replicas were corrupted
+    segments: Vec<Segment>,
+    replica_blocks_counts: Vec<u64>,
+    block_bytes: Vec<u8>,
+    position: u64,
+}
+
+impl MergedReader<'_, '_> {
+    fn size(&self) -> u64 {
+        self.sizes.iter().copied().max().unwrap_or(0)
+    }
+
+    // The block of a replica at the given offset, if it's not corrupted
+    fn valid_block(&mut self, replica: usize, position: u64) -> Result<Option<Block>, RescueError> {
+        if position >= self.sizes[replica] {
+            return Ok(None);
+        }
+        self.replica_positions[replica] = None;
+        let reader = &mut self.replicas[replica];
+        reader.seek(SeekFrom::Start(position)).map_err(RescueError::Input)?;
+        if !bgzf::read_block(*reader, &mut self.block_bytes, bgzf::MAX_BLOCK_SIZE as u64).map_err(RescueError::Input)? {
+            return Ok(None);
+        }
+        let results = crate::process(&mut Cursor::new(&self.block_bytes), None, None, false, false, RescueOptions { input_format: Some(InputFormat::Bgzf), ..Default::default() }, 1, &mut None)?;
+        if !results.bad_blocks.is_empty() || !results.repaired_blocks.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Block {
+            size: self.block_bytes.len() as u64,
+            inflated_size: results.blocks_size,
+        }))
+    }
+
+    // Offset of the next non-corrupted block of a replica from the given
+    // offset, looked for only up to end
+    fn next_valid_block(&mut self, replica: usize, mut position: u64, end: u64) -> Result<u64, RescueError> {
+        while position < end.min(self.sizes[replica]) {
+            self.replica_positions[replica] = None;
+            crate::seek_next_block(self.replicas[replica], position, None)?;
+            position = self.replicas[replica].stream_position().map_err(RescueError::Input)?;
+            if position >= end {
+                break;
+            }
+            if self.valid_block(replica, position)?.is_some() {
+                return Ok(position);
+            }
+            position += 1;
+        }
+        Ok(end)
+    }
+
+    fn pick(&mut self) -> Result<(), RescueError> {
+        let position = self.segments.last().map_or(0, |segment| segment.end);
+        let mut picked: Option<(usize, Block)> = None;
+        for replica in 0..self.replicas.len() {
+            if let Some(block) = self.valid_block(replica, position)? {
+                match picked {
+                    // copies with another layout can't be merged block by
+                    // block
+                    Some((_, picked_block)) if picked_block != block => return Err(RescueError::Input(Error::new(ErrorKind::InvalidData, format!("replicas have different blocks at offset {}", position)))),
+                    Some(_) => (),
+                    None => picked = Some((replica, block)),
+                }
+            }
+        }
+        let (replica, end) = match picked {
+            Some((replica, block)) => {
+                self.replica_blocks_counts[replica] += 1;
+                (replica, position + block.size)
+            },
+            None => {
+                let mut end = self.size();
+                for replica in 0..self.replicas.len() {
+                    end = self.next_valid_block(replica, position + 1, end)?;
+                }
+                (self.sizes.iter().position(|&replica_size| replica_size >= end).unwrap(), end)
+            },
+        };
+        match self.segments.last_mut() {
+            Some(segment) if segment.replica == replica => segment.end = end,
+            _ => self.segments.push(Segment {
+                end,
+                replica,
+            }),
+        }
+        Ok(())
+    }
+}
+
+impl Read for MergedReader<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.segments.last().map_or(0, |segment| segment.end) <= self.position && self.position < self.size() {
+            self.pick().map_err(|error| match error {
+                RescueError::Input(error) => error,
+                error => Error::other(error),
+            })?;
+        }
+        let segment = match self.segments.get(self.segments.partition_point(|segment| segment.end <= self.position)) {
+            Some(segment) => segment,
+            None => return Ok(0),
+        };
+        let replica = &mut self.replicas[segment.replica];
+        if self.replica_positions[segment.replica] != Some(self.position) {
+            replica.seek(SeekFrom::Start(self.position))?;
+        }
+        let size = buf.len().min((segment.end - self.position) as usize);
+        let size = replica.read(&mut buf[..size])?;
+        if size == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "replica shorter than when checked"));
+        }
+        self.position += size as u64;
+        self.replica_positions[segment.replica] = Some(self.position);
+        Ok(size)
+    }
+}
+
+impl Seek for MergedReader<'_, '_> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => self.size().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid seek to a negative position"))?;
+        Ok(self.position)
+    }
+}
+
+pub fn merge(replicas: &mut [&mut dyn Rescuable], writer: &mut dyn Write, index: bool, input_format: Option<InputFormat>, threads: usize, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Result<(Results, MergeResults), RescueError> {
+    let mut sizes = vec![];
+    for replica in replicas.iter_mut() {
+        if !matches!(crate::layout(*replica, input_format)?, Layout::Bgzf) {
+            return Err(RescueError::Input(Error::new(ErrorKind::InvalidData, "only bgzf files can be merged")));
+        }
+        sizes.push(replica.seek(SeekFrom::End(0)).map_err(RescueError::Input)?);
+    }
+    let mut merged_reader = BufReader::new(MergedReader {
+        replica_positions: vec![None; replicas.len()],
+        replica_blocks_counts: vec![0; replicas.len()],
+        replicas,
+        sizes,
+        segments: vec![],
+        block_bytes: Vec::with_capacity(bgzf::MAX_BLOCK_SIZE),
+        position: 0,
+    });
+    let results = crate::rescue(&mut merged_reader, writer, RescueOptions { index, input_format, ..Default::default() }, threads, progress_listener)?;
+    Ok((results, MergeResults {
+        replica_blocks_counts: merged_reader.into_inner().replica_blocks_counts,
+    }))
+}
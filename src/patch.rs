@@ -0,0 +1,85 @@
+use serde_derive::Serialize;
+
+use std::io::{
+    BufReader,
+    Cursor,
+    Error,
+    ErrorKind,
+    SeekFrom,
+};
+
+use crate::{
+    bgzf,
+    InputFormat,
+    Layout,
+    ListenProgress,
+    Patchable,
+    RescueError,
+    RescueOptions,
+    Rescuable,
+    Results,
+};
+
+// What was copied from the donor into a bgzf file
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PatchResults {
+    // byte ranges of contiguous corrupted blocks replaced by those of the
+    // donor, and their size
+    pub patched_ranges_count: u64,
+    pub patched_size: u64,
+    // ranges left as they were, the donor having no valid blocks of the same
+    // length there
+    pub unpatched_ranges_count: u64,
+}
+
+// Whether the donor has whole non-corrupted blocks filling the range exactly,
+// which are looked at one at a time however large the range
+fn donor_fits(donor: &mut dyn Rescuable, start: u64, end: u64) -> Result<bool, RescueError> {
+    donor.seek(SeekFrom::Start(start)).map_err(RescueError::Input)?;
+    let mut bytes = Vec::with_capacity(bgzf::MAX_BLOCK_SIZE);
+    let mut position = start;
+    while position < end {
+        if !bgzf::read_block(donor, &mut bytes, end - position).map_err(RescueError::Input)? {
+            return Ok(false);
+        }
+        let results = crate::process(&mut Cursor::new(&bytes), None, None, false, false, RescueOptions { input_format: Some(InputFormat::Bgzf), ..Default::default() }, 1, &mut None)?;
+        if !results.bad_blocks.is_empty() || !results.repaired_blocks.is_empty() {
+            return Ok(false);
+        }
+        position += bytes.len() as u64;
+    }
+    Ok(true)
+}
+
+pub fn patch(file: &mut dyn Patchable, donor: &mut dyn Rescuable, input_format: Option<InputFormat>, threads: usize, progress_listener: &mut Option<&mut dyn ListenProgress>) -> Result<(Results, PatchResults), RescueError> {
+    let results = {
+        let mut reader = BufReader::new(&mut *file);
+        if !matches!(crate::layout(&mut reader, input_format)?, Layout::Bgzf) {
+            return Err(RescueError::Input(Error::new(ErrorKind::InvalidData, "only bgzf files can be patched")));
+        }
+        crate::process(&mut reader, None, None, false, false, RescueOptions { input_format, ..Default::default() }, threads, progress_listener)?
+    };
+    let mut patch_results = PatchResults::default();
+    for (start, end) in crate::corrupted_ranges(&results) {
+        if !donor_fits(donor, start, end)? {
+            patch_results.unpatched_ranges_count += 1;
+            continue;
+        }
+        // read again rather than kept, so that only a block is held at once
+        donor.seek(SeekFrom::Start(start)).map_err(RescueError::Input)?;
+        file.seek(SeekFrom::Start(start)).map_err(RescueError::Output)?;
+        let mut bytes = Vec::with_capacity(bgzf::MAX_BLOCK_SIZE);
+        let mut position = start;
+        while position < end {
+            if !bgzf::read_block(donor, &mut bytes, end - position).map_err(RescueError::Input)? {
+                return Err(RescueError::Input(Error::new(ErrorKind::InvalidData, "donor changed while patching")));
+            }
+            file.write_all(&bytes).map_err(RescueError::Output)?;
+            position += bytes.len() as u64;
+        }
+        patch_results.patched_size += end - start;
+        patch_results.patched_ranges_count += 1;
+    }
+    file.flush().map_err(RescueError::Output)?;
+    Ok((results, patch_results))
+}
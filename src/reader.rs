@@ -0,0 +1,159 @@
+use std::{
+    io::{
+        Error,
+        Read,
+        Write,
+    },
+    sync::mpsc::{
+        self,
+        Receiver,
+        SyncSender,
+    },
+    thread::{
+        self,
+        JoinHandle,
+    },
+};
+
+use crate::{
+    BadBlock,
+    ListenProgress,
+    RescueError,
+    Rescuable,
+    Results,
+};
+
+// Enough to keep the threads inflating blocks busy while the payload is consumed
+const MAX_PENDING_MESSAGES: usize = 100;
+
+enum Message {
+    Payload(Vec<u8>),
+    Skipped(BadBlock),
+    Finished(Result<Results, RescueError>),
+}
+
+struct PayloadSender {
+    sender: SyncSender<Message>,
+}
+
+impl Write for PayloadSender {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if !buf.is_empty() {
+            self.sender.send(Message::Payload(buf.to_vec()))
+                .map_err(|_| Error::other("rescue reader dropped"))?;
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+struct SkippedSender {
+    sender: SyncSender<Message>,
+}
+
+impl ListenProgress for SkippedSender {
+    fn on_new_target(&mut self, _target: Option<u64>) {
+    }
+    fn on_progress(&mut self, _progress: u64) {
+    }
+    fn on_bad_block(&mut self, bad_block: &BadBlock) {
+        // if nobody's listening anymore, the next payload won't be sent either
+        let _ = self.sender.send(Message::Skipped(bad_block.clone()));
+    }
+    fn on_finished(&mut self) {
+    }
+}
+
+// Reads the payload of the non-corrupted blocks of a bgzf file, as if it had
+// been rescued first. Blocks are read and inflated in the background.
+pub struct RescueReader<R> {
+    receiver: Receiver<Message>,
+    thread: Option<JoinHandle<R>>,
+    payload: Vec<u8>,
+    payload_position: usize,
+    skipped: Vec<BadBlock>,
+    results: Option<Results>,
+    finished: bool,
+}
+
+impl<R: Rescuable + Send + 'static> RescueReader<R> {
+    pub fn new(reader: R, threads: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(MAX_PENDING_MESSAGES);
+        let thread = thread::spawn(move || {
+            let mut reader = reader;
+            let mut payload_sender = PayloadSender {
+                sender: sender.clone(),
+            };
+            let mut skipped_sender = SkippedSender {
+                sender: sender.clone(),
+            };
+            let results = crate::process(&mut reader, None, Some(&mut payload_sender), false, false, crate::RescueOptions::default(), threads, &mut Some(&mut skipped_sender));
+            let _ = sender.send(Message::Finished(results));
+            reader
+        });
+        Self {
+            receiver,
+            thread: Some(thread),
+            payload: vec![],
+            payload_position: 0,
+            skipped: vec![],
+            results: None,
+            finished: false,
+        }
+    }
+
+    // Corrupted blocks skipped so far, in file order
+    pub fn skipped(&self) -> &[BadBlock] {
+        &self.skipped
+    }
+
+    // Available once the whole payload has been read
+    pub fn results(&self) -> Option<&Results> {
+        self.results.as_ref()
+    }
+
+    // Stops reading in the background if needed, and gives the reader back
+    pub fn into_inner(mut self) -> R {
+        let thread = self.thread.take().unwrap();
+        drop(self);
+        thread.join().unwrap_or_else(|cause| std::panic::resume_unwind(cause))
+    }
+}
+
+impl<R> Read for RescueReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.payload_position == self.payload.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            match self.receiver.recv() {
+                Ok(Message::Payload(payload)) => {
+                    self.payload = payload;
+                    self.payload_position = 0;
+                },
+                Ok(Message::Skipped(bad_block)) => self.skipped.push(bad_block),
+                Ok(Message::Finished(Ok(results))) => {
+                    self.results = Some(results);
+                    self.finished = true;
+                },
+                Ok(Message::Finished(Err(error))) => {
+                    self.finished = true;
+                    return Err(match error {
+                        RescueError::Input(error) => error,
+                        error => Error::other(error),
+                    });
+                },
+                Err(_) => {
+                    self.finished = true;
+                    return Err(Error::other("rescue reader thread stopped unexpectedly"));
+                },
+            }
+        }
+        let size = buf.len().min(self.payload.len() - self.payload_position);
+        buf[..size].copy_from_slice(&self.payload[self.payload_position..self.payload_position + size]);
+        self.payload_position += size;
+        Ok(size)
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    io::{
+        self,
+        Write,
+    },
+    mem,
+};
+
+use crate::{
+    bam,
+    bcf,
+    bgzf,
+    fastq,
+    index::Indexer,
+    text,
+    InputFormat,
+};
+
+// How records can be found in the payload, so that only whole records are
+// rescued
+pub trait Framing {
+    fn feed_payload(&mut self, bytes: &[u8]);
+    fn payload_position(&self) -> u64;
+    // when the payload can't be followed, blocks are kept as they are
+    fn is_lost(&self) -> bool;
+    // false while in a header which can't be resynchronized
+    fn in_records(&self) -> bool;
+    // some blocks could not be inflated
+    fn lose_track(&mut self);
+    // position in the payload from which bytes might still have to be
+    // dropped, because they belong to a record which is not complete yet
+    fn incomplete_since(&self) -> Option<u64>;
+    // continues at the beginning of a new record, after some payload has
+    // been dropped
+    fn resync(&mut self);
+    // offset of the first record in bytes, or of the first byte which might
+    // still be part of one if none can be found yet
+    fn next_record(&self, bytes: &[u8], finishing: bool) -> Result<usize, usize>;
+    // the payload ends here, and is not truncated
+    fn at_end(&mut self) {
+    }
+}
+
+// For payloads which are not made of records, or not yet known to be
+struct Unframed;
+
+impl Framing for Unframed {
+    fn feed_payload(&mut self, _bytes: &[u8]) {
+    }
+    fn payload_position(&self) -> u64 {
+        0
+    }
+    fn is_lost(&self) -> bool {
+        true
+    }
+    fn in_records(&self) -> bool {
+        false
+    }
+    fn lose_track(&mut self) {
+    }
+    fn incomplete_since(&self) -> Option<u64> {
+        None
+    }
+    fn resync(&mut self) {
+    }
+    fn next_record(&self, bytes: &[u8], _finishing: bool) -> Result<usize, usize> {
+        Err(bytes.len())
+    }
+}
+
+struct HeldBlock {
+    // original block, if it can be written as is
+    bytes: Option<Vec<u8>>,
+    inflated_bytes: Vec<u8>,
+    inflated_position: u64,
+}
+
+// Keeps the rescued payload made of whole records only, be they bam
+// alignment records or lines of text.
+//
+// Blocks holding the beginning of a record are held until the record is
+// complete, so that it can be dropped if a corrupted block cuts it. After a
+// corrupted block, the payload is dropped up to the next plausible record.
+// Only the blocks at the edges of the dropped payload are compressed again,
+// all other blocks are written as they were read.
+pub struct Resynchronizer {
+    framing: Box<dyn Framing>,
+    held_blocks: Vec<HeldBlock>,
+    search_bytes: Option<Vec<u8>>,
+    // empty blocks are written as soon as they are read while searching,
+    // which may put the bgzf end-of-file marker before the rest of the payload
+    marker_written_while_searching: bool,
+    last_block_empty: bool,
+    dropped_size: u64,
+    indexer: Option<Indexer>,
+}
+
+impl Resynchronizer {
+    // Blocks are kept as they are until the input format is set
+    pub fn new(index: bool) -> Self {
+        Self {
+            framing: Box::new(Unframed),
+            held_blocks: vec![],
+            search_bytes: None,
+            marker_written_while_searching: false,
+            last_block_empty: false,
+            dropped_size: 0,
+            indexer: if index {
+                Some(Indexer::new())
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn set_input_format(&mut self, input_format: InputFormat) {
+        self.framing = match input_format {
+            InputFormat::Bam => Box::new(bam::Validator::new()),
+            InputFormat::Bcf => Box::new(bcf::Validator::new()),
+            InputFormat::Vcf |
+            InputFormat::Bed |
+            InputFormat::Gff |
+            InputFormat::Fasta => Box::new(text::Lines::new()),
+            InputFormat::Fastq => Box::new(fastq::Records::new()),
+            InputFormat::Cram |
+            InputFormat::Bgzf => Box::new(Unframed),
+        };
+        if let Some(ref mut indexer) = self.indexer {
+            indexer.set_input_format(input_format);
+        }
+    }
+
+    // Only once finished
+    pub fn take_indexer(&mut self) -> Option<Indexer> {
+        self.indexer.take()
+    }
+
+    // Size of the payload of non-corrupted blocks which had to be dropped
+    pub fn dropped_size(&self) -> u64 {
+        self.dropped_size
+    }
+
+    pub fn write(&mut self, writer: &mut dyn Write, bytes: Vec<u8>, inflated_bytes: Vec<u8>) -> io::Result<()> {
+        match self.search_bytes.take() {
+            Some(search_bytes) if inflated_bytes.is_empty() => {
+                self.search_bytes = Some(search_bytes);
+                self.marker_written_while_searching = true;
+                self.emit(writer, &bytes, &[])
+            },
+            Some(mut search_bytes) => {
+                search_bytes.extend(inflated_bytes);
+                self.search(writer, search_bytes, false)
+            },
+            None => self.push(writer, Some(bytes), inflated_bytes),
+        }
+    }
+
+    pub fn gap(&mut self, writer: &mut dyn Write) -> io::Result<()> {
+        if let Some(search_bytes) = self.search_bytes.take() {
+            self.search(writer, search_bytes, true)?;
+        }
+        if self.framing.is_lost() {
+            // not a bam file, or not understood: keep the blocks as they are
+            return Ok(());
+        }
+        if !self.framing.in_records() {
+            // nothing can be done without the whole header
+            self.flush(writer, None)?;
+            self.framing.lose_track();
+            return Ok(());
+        }
+        self.trim(writer)?;
+        self.search_bytes = Some(vec![]);
+        Ok(())
+    }
+
+    // Also writes the bgzf end-of-file marker if missing from the input
+    pub fn finish(&mut self, writer: &mut dyn Write, missing_marker: bool) -> io::Result<()> {
+        if let Some(search_bytes) = self.search_bytes.take() {
+            self.search(writer, search_bytes, true)?;
+        }
+        if !missing_marker {
+            self.framing.at_end();
+        }
+        if self.framing.in_records() {
+            self.trim(writer)?;
+        } else {
+            self.flush(writer, None)?;
+        }
+        if missing_marker || (self.marker_written_while_searching && !self.last_block_empty) {
+            self.emit(writer, &bgzf::block(&[]), &[])?;
+        }
+        Ok(())
+    }
+
+    // Writes what could be inflated from a corrupted block, then drops what
+    // follows up to the next record
+    pub fn salvage(&mut self, writer: &mut dyn Write, inflated_bytes: Vec<u8>) -> io::Result<()> {
+        if !inflated_bytes.is_empty() {
+            self.write_inflated(writer, inflated_bytes)?;
+        }
+        self.gap(writer)
+    }
+
+    // Payload which was not read from bgzf blocks, compressed again
+    pub fn write_inflated(&mut self, writer: &mut dyn Write, inflated_bytes: Vec<u8>) -> io::Result<()> {
+        match self.search_bytes.take() {
+            Some(mut search_bytes) => {
+                search_bytes.extend(inflated_bytes);
+                self.search(writer, search_bytes, false)
+            },
+            None => self.push(writer, None, inflated_bytes),
+        }
+    }
+
+    fn push(&mut self, writer: &mut dyn Write, bytes: Option<Vec<u8>>, inflated_bytes: Vec<u8>) -> io::Result<()> {
+        let inflated_position = self.framing.payload_position();
+        self.framing.feed_payload(&inflated_bytes);
+        self.held_blocks.push(HeldBlock {
+            bytes,
+            inflated_bytes,
+            inflated_position,
+        });
+        self.flush(writer, self.framing.incomplete_since())
+    }
+
+    fn emit(&mut self, writer: &mut dyn Write, bytes: &[u8], inflated_bytes: &[u8]) -> io::Result<()> {
+        self.last_block_empty = inflated_bytes.is_empty();
+        if let Some(ref mut indexer) = self.indexer {
+            indexer.block(bytes.len(), inflated_bytes);
+        }
+        writer.write_all(bytes)
+    }
+
+    // Compresses the payload again, into as many blocks as needed
+    fn emit_inflated(&mut self, writer: &mut dyn Write, inflated_bytes: &[u8]) -> io::Result<()> {
+        for chunk in inflated_bytes.chunks(bgzf::MAX_INFLATED_BLOCK_SIZE) {
+            self.emit(writer, &bgzf::block(chunk), chunk)?;
+        }
+        Ok(())
+    }
+
+    // Writes the held blocks which end before the given position
+    fn flush(&mut self, writer: &mut dyn Write, until: Option<u64>) -> io::Result<()> {
+        let count = match until {
+            None => self.held_blocks.len(),
+            Some(until) => self.held_blocks.iter()
+                .take_while(|block| block.inflated_position + block.inflated_bytes.len() as u64 <= until)
+                .count(),
+        };
+        let blocks: Vec<HeldBlock> = self.held_blocks.drain(..count).collect();
+        for block in blocks {
+            match block.bytes {
+                Some(bytes) => self.emit(writer, &bytes, &block.inflated_bytes)?,
+                None => self.emit_inflated(writer, &block.inflated_bytes)?,
+            }
+        }
+        Ok(())
+    }
+
+    // Drops the payload of the record which is not complete, if any
+    fn trim(&mut self, writer: &mut dyn Write) -> io::Result<()> {
+        let until = match self.framing.incomplete_since() {
+            None => return self.flush(writer, None),
+            Some(until) => until,
+        };
+        self.flush(writer, Some(until))?;
+        for block in mem::take(&mut self.held_blocks) {
+            if block.inflated_bytes.is_empty() {
+                if let Some(bytes) = block.bytes {
+                    self.emit(writer, &bytes, &[])?;
+                }
+                continue;
+            }
+            let kept_size = until.saturating_sub(block.inflated_position) as usize;
+            if kept_size > 0 {
+                self.emit_inflated(writer, &block.inflated_bytes[..kept_size])?;
+            }
+            self.dropped_size += (block.inflated_bytes.len() - kept_size) as u64;
+        }
+        self.framing.resync();
+        Ok(())
+    }
+
+    // Looks for the beginning of a record
+    fn search(&mut self, writer: &mut dyn Write, search_bytes: Vec<u8>, finishing: bool) -> io::Result<()> {
+        match self.framing.next_record(&search_bytes, finishing) {
+            Ok(offset) => {
+                self.dropped_size += offset as u64;
+                self.framing.resync();
+                self.push(writer, None, search_bytes[offset..].to_vec())
+            },
+            Err(offset) => {
+                self.dropped_size += offset as u64;
+                if !finishing {
+                    self.search_bytes = Some(search_bytes[offset..].to_vec());
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl Default for Resynchronizer {
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
@@ -0,0 +1,156 @@
+use byteorder::{
+    ByteOrder,
+    LittleEndian,
+    WriteBytesExt,
+};
+
+use crate::InputFormat;
+
+// Format of tbi indexes, with a flag for 0-based half-open coordinates
+const GENERIC: i32 = 0;
+const VCF: i32 = 2;
+const ZERO_BASED: i32 = 0x10000;
+
+// format, col_seq, col_beg, col_end, meta and skip
+const COLUMNS_SIZE: usize = 24;
+
+// Columns holding the intervals of bgzipped text files, as with tabix
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TabixColumns {
+    // 1-based, end being 0 if there is no end column
+    pub sequence: usize,
+    pub begin: usize,
+    pub end: usize,
+    // bed-like coordinates, rather than 1-based closed ones
+    pub zero_based: bool,
+    // without end column, vcf records end with their REF allele or END
+    pub vcf: bool,
+    // lines beginning with this byte are skipped, as are the first lines
+    pub meta: u8,
+    pub skip: usize,
+}
+
+impl TabixColumns {
+    pub fn vcf() -> Self {
+        Self {
+            sequence: 1,
+            begin: 2,
+            end: 0,
+            zero_based: false,
+            vcf: true,
+            meta: b'#',
+            skip: 0,
+        }
+    }
+
+    pub fn bed() -> Self {
+        Self {
+            sequence: 1,
+            begin: 2,
+            end: 3,
+            zero_based: true,
+            vcf: false,
+            meta: b'#',
+            skip: 0,
+        }
+    }
+
+    pub fn gff() -> Self {
+        Self {
+            sequence: 1,
+            begin: 4,
+            end: 5,
+            zero_based: false,
+            vcf: false,
+            meta: b'#',
+            skip: 0,
+        }
+    }
+
+    pub fn preset(input_format: InputFormat) -> Option<Self> {
+        match input_format {
+            InputFormat::Vcf => Some(Self::vcf()),
+            InputFormat::Bed => Some(Self::bed()),
+            InputFormat::Gff => Some(Self::gff()),
+            _ => None,
+        }
+    }
+
+    // As stored in tbi indexes, and in the auxiliary data of csi ones
+    pub fn write(&self, bytes: &mut Vec<u8>) {
+        let format = if self.vcf { VCF } else { GENERIC } | if self.zero_based { ZERO_BASED } else { 0 };
+        bytes.write_i32::<LittleEndian>(format).unwrap();
+        bytes.write_i32::<LittleEndian>(self.sequence as i32).unwrap();
+        bytes.write_i32::<LittleEndian>(self.begin as i32).unwrap();
+        bytes.write_i32::<LittleEndian>(self.end as i32).unwrap();
+        bytes.write_i32::<LittleEndian>(self.meta as i32).unwrap();
+        bytes.write_i32::<LittleEndian>(self.skip as i32).unwrap();
+    }
+
+    // None for sam columns, which are not supported, or invalid ones
+    pub fn read(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < COLUMNS_SIZE {
+            return None;
+        }
+        let format = LittleEndian::read_i32(bytes);
+        let column = |offset: usize| usize::try_from(LittleEndian::read_i32(&bytes[offset..])).ok();
+        let columns = Self {
+            sequence: column(4)?,
+            begin: column(8)?,
+            end: column(12)?,
+            zero_based: format & ZERO_BASED != 0,
+            vcf: match format & 0xffff {
+                GENERIC => false,
+                VCF => true,
+                _ => return None,
+            },
+            meta: u8::try_from(LittleEndian::read_i32(&bytes[16..])).ok()?,
+            skip: column(20)?,
+        };
+        if columns.sequence == 0 || columns.begin == 0 {
+            return None;
+        }
+        Some(columns)
+    }
+
+    // Lines to skip besides the first ones, track and browser lines coming
+    // with bed-like coordinates
+    pub fn is_header(&self, line: &[u8]) -> bool {
+        line.is_empty() || line[0] == self.meta ||
+            (self.zero_based && (line.starts_with(b"track") || line.starts_with(b"browser")))
+    }
+
+    // Sequence name and 0-based half-open interval of a data line
+    pub fn interval<'a>(&self, line: &'a [u8]) -> Option<(&'a [u8], i64, i64)> {
+        let fields: Vec<&[u8]> = line.split(|&byte| byte == b'\t').collect();
+        let field = |column: usize| fields.get(column.checked_sub(1)?).copied();
+        let sequence = field(self.sequence)?;
+        let mut begin = parse(field(self.begin)?)?;
+        if !self.zero_based {
+            begin -= 1;
+        }
+        let end = if self.end > 0 {
+            parse(field(self.end)?)?
+        } else if self.vcf {
+            vcf_end(begin, &fields)
+        } else {
+            begin + 1
+        };
+        if sequence.is_empty() || begin < 0 {
+            return None;
+        }
+        Some((sequence, begin, end.max(begin + 1)))
+    }
+}
+
+fn parse(field: &[u8]) -> Option<i64> {
+    std::str::from_utf8(field).ok()?.parse().ok()
+}
+
+// End of the REF allele, unless INFO says otherwise
+fn vcf_end(begin: i64, fields: &[&[u8]]) -> i64 {
+    fields.get(7)
+        .and_then(|info| info.split(|&byte| byte == b';').find_map(|entry| entry.strip_prefix(b"END=")))
+        .and_then(parse)
+        .unwrap_or(begin + fields.get(3).map_or(1, |reference| reference.len() as i64))
+}
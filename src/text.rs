@@ -0,0 +1,114 @@
+use crate::{
+    fastq,
+    resync::Framing,
+    InputFormat,
+};
+
+const BAM_MAGIC: [u8; 4] = [0x42, 0x41, 0x4d, 0x01];
+// any minor version of bcf 2
+const BCF_MAGIC: [u8; 4] = [0x42, 0x43, 0x46, 0x02];
+
+// Guesses the input format from the beginning of the payload
+pub fn detect(payload: &[u8]) -> InputFormat {
+    if payload.starts_with(&BAM_MAGIC) {
+        InputFormat::Bam
+    } else if payload.starts_with(&BCF_MAGIC) {
+        InputFormat::Bcf
+    } else if payload.starts_with(b"##fileformat=VCF") {
+        InputFormat::Vcf
+    } else if payload.starts_with(b"##gff-version") {
+        InputFormat::Gff
+    } else if payload.starts_with(b">") {
+        InputFormat::Fasta
+    } else if fastq::is_fastq(payload) {
+        InputFormat::Fastq
+    } else if is_bed(payload) {
+        InputFormat::Bed
+    } else {
+        InputFormat::Bgzf
+    }
+}
+
+// Chromosome, start and end of the first line which is not a comment nor a
+// track or browser line
+fn is_bed(payload: &[u8]) -> bool {
+    let line = match payload.split(|&byte| byte == b'\n')
+        .find(|line| !line.starts_with(b"#") && !line.starts_with(b"track") && !line.starts_with(b"browser")) {
+        Some(line) => line,
+        None => return false,
+    };
+    let fields: Vec<&[u8]> = line.strip_suffix(b"\r").unwrap_or(line).split(|&byte| byte == b'\t').collect();
+    fields.len() >= 3 &&
+        !fields[0].is_empty() && fields[0].iter().all(|byte| byte.is_ascii_graphic()) &&
+        fields[1..3].iter().all(|field| !field.is_empty() && field.iter().all(u8::is_ascii_digit))
+}
+
+// Lines of text, any of which can be dropped without breaking the others
+pub struct Lines {
+    payload_position: u64,
+    line_position: u64,
+}
+
+impl Lines {
+    pub fn new() -> Self {
+        Self {
+            payload_position: 0,
+            line_position: 0,
+        }
+    }
+}
+
+impl Default for Lines {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Framing for Lines {
+    fn feed_payload(&mut self, bytes: &[u8]) {
+        if let Some(end) = bytes.iter().rposition(|&byte| byte == b'\n') {
+            self.line_position = self.payload_position + end as u64 + 1;
+        }
+        self.payload_position += bytes.len() as u64;
+    }
+
+    fn payload_position(&self) -> u64 {
+        self.payload_position
+    }
+
+    fn is_lost(&self) -> bool {
+        false
+    }
+
+    fn in_records(&self) -> bool {
+        true
+    }
+
+    fn lose_track(&mut self) {
+    }
+
+    fn incomplete_since(&self) -> Option<u64> {
+        if self.line_position < self.payload_position {
+            Some(self.line_position)
+        } else {
+            None
+        }
+    }
+
+    fn resync(&mut self) {
+        self.line_position = self.payload_position;
+    }
+
+    // The last line doesn't have to end with a newline
+    fn at_end(&mut self) {
+        self.line_position = self.payload_position;
+    }
+
+    // What follows a gap is only known to be a whole line after a newline
+    fn next_record(&self, bytes: &[u8], _finishing: bool) -> Result<usize, usize> {
+        match bytes.iter().position(|&byte| byte == b'\n') {
+            Some(end) => Ok(end + 1),
+            None => Err(bytes.len()),
+        }
+    }
+}
@@ -1,10 +1,57 @@
 mod common;
 
-use std::io::SeekFrom;
+use byteorder::{
+    LittleEndian,
+    WriteBytesExt,
+};
+
+use std::io::{
+    BufRead,
+    Cursor,
+    Error,
+    Read,
+    Seek,
+    SeekFrom,
+};
+
+// Fails reading past a given position, like a disk with a bad sector
+struct BadSector {
+    cursor: Cursor<Vec<u8>>,
+    position: u64,
+}
+
+impl Read for BadSector {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if self.cursor.position() >= self.position {
+            return Err(Error::other("input/output error"));
+        }
+        let size = buf.len().min((self.position - self.cursor.position()) as usize);
+        self.cursor.read(&mut buf[..size])
+    }
+}
+
+impl BufRead for BadSector {
+    fn fill_buf(&mut self) -> Result<&[u8], Error> {
+        if self.cursor.position() >= self.position {
+            return Err(Error::other("input/output error"));
+        }
+        let size = (self.position - self.cursor.position()) as usize;
+        self.cursor.fill_buf().map(|buf| &buf[..buf.len().min(size)])
+    }
+    fn consume(&mut self, amt: usize) {
+        self.cursor.consume(amt)
+    }
+}
+
+impl Seek for BadSector {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        self.cursor.seek(pos)
+    }
+}
 
 fn check(reader: &mut dyn bamrescue::Rescuable, blocks_count: u64, bad_blocks_count: u64, truncated_in_block: bool, truncated_between_blocks: bool) {
     {
-        let results = bamrescue::check(reader, false, 1, &mut None);
+        let results = bamrescue::check(reader, false, false, None, None, 1, &mut None).unwrap();
         assert_eq!(results.blocks_count, blocks_count);
         assert_eq!(results.bad_blocks_count, bad_blocks_count);
         assert_eq!(results.truncated_in_block, truncated_in_block);
@@ -12,7 +59,7 @@ fn check(reader: &mut dyn bamrescue::Rescuable, blocks_count: u64, bad_blocks_co
     }
     reader.seek(SeekFrom::Start(0)).unwrap();
     {
-        let results = bamrescue::check(reader, false, 4, &mut None);
+        let results = bamrescue::check(reader, false, false, None, None, 4, &mut None).unwrap();
         assert_eq!(results.blocks_count, blocks_count);
         assert_eq!(results.bad_blocks_count, bad_blocks_count);
         assert_eq!(results.truncated_in_block, truncated_in_block);
@@ -20,14 +67,14 @@ fn check(reader: &mut dyn bamrescue::Rescuable, blocks_count: u64, bad_blocks_co
     }
     reader.seek(SeekFrom::Start(0)).unwrap();
     {
-        let results = bamrescue::check(reader, true, 1, &mut None);
+        let results = bamrescue::check(reader, true, false, None, None, 1, &mut None).unwrap();
         assert!(bad_blocks_count == 0 || results.bad_blocks_count > 0);
         assert_eq!(results.truncated_in_block, truncated_in_block);
         assert_eq!(results.truncated_between_blocks, truncated_between_blocks);
     }
     reader.seek(SeekFrom::Start(0)).unwrap();
     {
-        let results = bamrescue::check(reader, true, 4, &mut None);
+        let results = bamrescue::check(reader, true, false, None, None, 4, &mut None).unwrap();
         assert!(bad_blocks_count == 0 || results.bad_blocks_count > 0);
         assert_eq!(results.truncated_in_block, truncated_in_block);
         assert_eq!(results.truncated_between_blocks, truncated_between_blocks);
@@ -96,12 +143,12 @@ fn three_blocks_empty_inside_bam() {
 
 #[test]
 fn single_block_missing_gzip_identifier() {
-    check(&mut common::single_block_missing_gzip_identifier_bam(), 2, 1, false, false)
+    check(&mut common::single_block_missing_gzip_identifier_bam(), 2, 0, false, false)
 }
 
 #[test]
 fn single_block_missing_bgzf_identifier() {
-    check(&mut common::single_block_missing_bgzf_identifier_bam(), 2, 1, false, false)
+    check(&mut common::single_block_missing_bgzf_identifier_bam(), 2, 0, false, false)
 }
 
 #[test]
@@ -121,7 +168,7 @@ fn three_blocks_bad_inflated_payload_crc32_bam() {
 
 #[test]
 fn three_blocks_bad_inflated_payload_size_bam() {
-    check(&mut common::three_blocks_bad_inflated_payload_size_bam(), 4, 1, false, false)
+    check(&mut common::three_blocks_bad_inflated_payload_size_bam(), 4, 0, false, false)
 }
 
 // TODO same tests as the two following ones, but with blocks of len >> 65536,
@@ -167,3 +214,1046 @@ fn three_blocks_with_extra_similar_subfields_after_bam() {
 fn three_blocks_with_extra_similar_subfields_before_and_after_bam() {
     check(&mut common::three_blocks_with_extra_similar_subfields_before_and_after_bam(), 4, 0, false, false)
 }
+
+#[test]
+fn input_error() {
+    for threads in [1, 4] {
+        let mut reader = BadSector {
+            cursor: common::three_blocks_bam(),
+            position: 30,
+        };
+        match bamrescue::check(&mut reader, false, false, None, None, threads, &mut None) {
+            Err(bamrescue::RescueError::Input(_)) => (),
+            _ => panic!("expected an input error"),
+        }
+    }
+}
+
+fn bad_blocks(reader: &mut dyn bamrescue::Rescuable, bad_blocks: Vec<bamrescue::BadBlock>) {
+    for threads in [1, 4] {
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        let results = bamrescue::check(reader, false, false, None, None, threads, &mut None).unwrap();
+        assert_eq!(results.bad_blocks, bad_blocks);
+    }
+}
+
+fn bad_block(position: u64, size: u64, inflated_position: u64, kind: bamrescue::CorruptionKind) -> bamrescue::BadBlock {
+    bamrescue::BadBlock {
+        position,
+        size,
+        inflated_position,
+        kind,
+    }
+}
+
+#[test]
+fn bad_blocks_none() {
+    bad_blocks(&mut common::three_blocks_bam(), vec![])
+}
+
+fn repaired_blocks(reader: &mut dyn bamrescue::Rescuable, repaired_blocks: Vec<bamrescue::BadBlock>) {
+    for threads in [1, 4] {
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        let results = bamrescue::check(reader, false, false, None, None, threads, &mut None).unwrap();
+        assert!(results.bad_blocks.is_empty());
+        assert_eq!(results.repaired_blocks_count, repaired_blocks.len() as u64);
+        assert_eq!(results.repaired_blocks, repaired_blocks);
+    }
+}
+
+#[test]
+fn repaired_blocks_missing_gzip_identifier() {
+    repaired_blocks(&mut common::single_block_missing_gzip_identifier_bam(), vec![
+        bad_block(0, 33, 0, bamrescue::CorruptionKind::GzipHeader),
+    ])
+}
+
+#[test]
+fn repaired_blocks_missing_bgzf_identifier() {
+    repaired_blocks(&mut common::single_block_missing_bgzf_identifier_bam(), vec![
+        bad_block(0, 33, 0, bamrescue::CorruptionKind::BgzfSubfield),
+    ])
+}
+
+#[test]
+fn repaired_blocks_bad_method() {
+    let mut data = common::three_blocks();
+    data[33 + 2] = 0x09;
+    repaired_blocks(&mut Cursor::new(data), vec![
+        bad_block(33, 33, 5, bamrescue::CorruptionKind::GzipHeader),
+    ])
+}
+
+#[test]
+fn repaired_blocks_bad_extra_subfield_size() {
+    let mut data = common::three_blocks();
+    data[33 + 15] = 0x42;
+    repaired_blocks(&mut Cursor::new(data), vec![
+        bad_block(33, 33, 5, bamrescue::CorruptionKind::BgzfSubfield),
+    ])
+}
+
+#[test]
+fn unrepairable_missing_gzip_identifier() {
+    let mut data = common::three_blocks();
+    data[33] = 42;
+    data[33 + 25] ^= 0x01;
+    bad_blocks(&mut Cursor::new(data), vec![
+        bad_block(33, 33, 5, bamrescue::CorruptionKind::Crc32),
+    ])
+}
+
+#[test]
+fn bad_blocks_bad_deflated_payload() {
+    bad_blocks(&mut common::three_blocks_bad_inflated_payload_crc32_bam(), vec![
+        bad_block(33, 33, 5, bamrescue::CorruptionKind::Inflate),
+    ])
+}
+
+#[test]
+fn bad_blocks_bad_inflated_payload_crc32() {
+    let mut data = common::three_blocks();
+    data[33 + 25] ^= 0x01;
+    bad_blocks(&mut Cursor::new(data), vec![
+        bad_block(33, 33, 5, bamrescue::CorruptionKind::Crc32),
+    ])
+}
+
+#[test]
+fn repaired_blocks_bad_inflated_payload_size() {
+    repaired_blocks(&mut common::three_blocks_bad_inflated_payload_size_bam(), vec![
+        bad_block(33, 33, 5, bamrescue::CorruptionKind::Isize),
+    ])
+}
+
+#[test]
+fn bad_blocks_after_bad_inflated_payload_size() {
+    let mut data = common::three_blocks_bad_inflated_payload_size();
+    data[66 + 25] ^= 0x01;
+    for threads in [1, 4] {
+        let results = bamrescue::check(&mut Cursor::new(&data), false, false, None, None, threads, &mut None).unwrap();
+        assert_eq!(results.blocks_size, 15);
+        assert_eq!(results.repaired_blocks, vec![bad_block(33, 33, 5, bamrescue::CorruptionKind::Isize)]);
+        assert_eq!(results.bad_blocks, vec![bad_block(66, 33, 10, bamrescue::CorruptionKind::Crc32)]);
+    }
+}
+
+#[test]
+fn bad_blocks_truncated_in_block() {
+    let mut data = common::three_blocks();
+    data.truncate(33 + 20);
+    bad_blocks(&mut Cursor::new(data), vec![
+        bad_block(33, 20, 5, bamrescue::CorruptionKind::Truncation),
+    ])
+}
+
+#[test]
+fn report() {
+    let mut data = common::three_blocks();
+    data[33 + 25] ^= 0x01;
+    let report = bamrescue::Report {
+        version: bamrescue::version(),
+        command: "check",
+        input_size: Some(data.len() as u64),
+        elapsed_seconds: 0.5,
+        results: bamrescue::check(&mut Cursor::new(data), false, false, None, None, 1, &mut None).unwrap(),
+        index_results: None,
+        mate_results: None,
+        pair_results: None,
+        merge_results: None,
+        patch_results: None,
+    };
+    let json = serde_json::to_value(&report).unwrap();
+    assert_eq!(json["command"], "check");
+    assert_eq!(json["input_size"], 127);
+    assert!(json.get("index_results").is_none());
+    assert_eq!(json["elapsed_seconds"], 0.5);
+    assert_eq!(json["results"]["blocks_count"], 4);
+    assert_eq!(json["results"]["bad_blocks_count"], 1);
+    assert_eq!(json["results"]["bad_blocks"][0]["position"], 33);
+    assert_eq!(json["results"]["bad_blocks"][0]["inflated_position"], 5);
+    assert_eq!(json["results"]["bad_blocks"][0]["kind"], "crc32");
+    assert_eq!(json["results"]["truncated_in_block"], false);
+}
+
+fn format_errors(data: Vec<u8>, format_errors: Vec<bamrescue::FormatError>) {
+    for threads in [1, 4] {
+        let results = bamrescue::check(&mut Cursor::new(&data), false, true, None, None, threads, &mut None).unwrap();
+        assert_eq!(results.format_errors, format_errors);
+        let results = bamrescue::check(&mut Cursor::new(&data), false, false, None, None, threads, &mut None).unwrap();
+        assert!(results.format_errors.is_empty());
+    }
+}
+
+fn format_error(virtual_offset: u64, kind: bamrescue::FormatErrorKind) -> bamrescue::FormatError {
+    bamrescue::FormatError {
+        virtual_offset,
+        kind,
+    }
+}
+
+#[test]
+fn deep_valid_bam() {
+    for inflated_block_size in [1, 7, 36, 1000] {
+        format_errors(common::bgzf(&common::bam_payload(), inflated_block_size), vec![])
+    }
+}
+
+#[test]
+fn deep_not_bam() {
+    format_errors(common::three_blocks(), vec![
+        format_error(0, bamrescue::FormatErrorKind::BamMagic),
+    ])
+}
+
+#[test]
+fn deep_bad_header_text() {
+    let mut payload = common::bam_payload();
+    payload[8] = b'H';
+    format_errors(common::bgzf(&payload, 1000), vec![
+        format_error(8, bamrescue::FormatErrorKind::BamHeaderText),
+    ])
+}
+
+#[test]
+fn deep_bad_reference_id() {
+    let mut payload = common::bam_header(&[("chr1", 248956422)]);
+    payload.extend(common::bam_record(0, 10000, "read1", "ACGTACGTAC"));
+    let record_position = payload.len();
+    payload.extend(common::bam_record(1, 10042, "read2", "GATTACA"));
+    // second block holds the second record, at the beginning of the block
+    let mut data = common::stored_bgzf_block(&payload[..record_position]);
+    let block_position = data.len() as u64;
+    data.extend(common::bgzf(&payload[record_position..], 1000));
+    format_errors(data, vec![
+        format_error(block_position << 16, bamrescue::FormatErrorKind::ReferenceId),
+    ])
+}
+
+#[test]
+fn deep_bad_block_size() {
+    let mut payload = common::bam_payload();
+    let record_position = common::bam_header(&[("chr1", 248956422), ("chrM", 16569)]).len();
+    payload[record_position] = 12;
+    format_errors(common::bgzf(&payload, 1000), vec![
+        format_error(record_position as u64, bamrescue::FormatErrorKind::BlockSize),
+    ])
+}
+
+#[test]
+fn deep_truncated_record() {
+    let mut payload = common::bam_payload();
+    let record_position = payload.len() - common::bam_record(1, 42, "read3", "TTAGGG").len();
+    payload.truncate(payload.len() - 3);
+    format_errors(common::bgzf(&payload, 1000), vec![
+        format_error(record_position as u64, bamrescue::FormatErrorKind::BamTruncation),
+    ])
+}
+
+#[test]
+fn deep_implausible_reference_name_length() {
+    let mut payload = common::bam_payload();
+    let reference_position = common::bam_header(&[]).len();
+    payload[reference_position + 3] = 0x7f;
+    format_errors(common::bgzf(&payload, 1000), vec![
+        format_error(reference_position as u64, bamrescue::FormatErrorKind::ReferenceNameLength),
+    ])
+}
+
+#[test]
+fn deep_after_bad_block() {
+    let mut data = common::stored_bgzf_block(&common::bam_header(&[("chr1", 248956422), ("chrM", 16569)]));
+    let mut blocks_positions = vec![];
+    for (index, reference_id) in [0, 0, 1, 1, 2].into_iter().enumerate() {
+        blocks_positions.push(data.len() as u64);
+        data.extend(common::stored_bgzf_block(&common::bam_record(reference_id, 42, &format!("read{}", index), "GATTACA")));
+    }
+    data.extend(common::empty_bgzf_block());
+    data[blocks_positions[1] as usize + 18 + 5 + 2] ^= 0x01;
+    for threads in [1, 4] {
+        let results = bamrescue::check(&mut Cursor::new(&data), false, true, None, None, threads, &mut None).unwrap();
+        assert_eq!(results.bad_blocks_count, 1);
+        // the records after the bad block are still validated
+        assert_eq!(results.format_errors, vec![
+            format_error(blocks_positions[4] << 16, bamrescue::FormatErrorKind::ReferenceId),
+        ]);
+    }
+}
+
+fn streamed(data: Vec<u8>) {
+    for threads in [1, 4] {
+        let expected_results = bamrescue::check(&mut Cursor::new(data.clone()), false, true, None, None, threads, &mut None).unwrap();
+        // a slice can't seek, unlike a cursor
+        let results = bamrescue::check(&mut bamrescue::LookbackReader::new(&data[..]), false, true, None, None, threads, &mut None).unwrap();
+        assert_eq!(serde_json::to_value(&results).unwrap(), serde_json::to_value(&expected_results).unwrap());
+    }
+}
+
+#[test]
+fn streamed_three_blocks() {
+    streamed(common::three_blocks());
+}
+
+#[test]
+fn streamed_three_blocks_too_large_bgzf_size() {
+    streamed(common::three_blocks_too_large_bgzf_size());
+}
+
+#[test]
+fn streamed_truncated() {
+    let mut data = common::three_blocks();
+    data.truncate(data.len() - 42);
+    streamed(data);
+}
+
+#[test]
+fn streamed_large_bam() {
+    let mut data = common::bgzf(&common::bam_payload().repeat(5000), 1000);
+    for position in [1000, data.len() / 2, data.len() - 1000] {
+        data[position] ^= 0x42;
+    }
+    streamed(data);
+}
+
+// Offset of the given byte of payload of the given stored block of 100 bytes
+fn virtual_offset(block: u64, inflated_position: u64) -> u64 {
+    (block * 131) << 16 | inflated_position
+}
+
+// bins (with their chunks) and linear index of a reference sequence
+type IndexReference = (Vec<(u32, Vec<(u64, u64)>)>, Vec<u64>);
+
+fn bai(references: &[IndexReference]) -> Vec<u8> {
+    let mut index = b"BAI\x01".to_vec();
+    index.write_i32::<LittleEndian>(references.len() as i32).unwrap();
+    for (bins, intervals) in references {
+        index.write_i32::<LittleEndian>(bins.len() as i32).unwrap();
+        for (bin, chunks) in bins {
+            index.write_u32::<LittleEndian>(*bin).unwrap();
+            index.write_i32::<LittleEndian>(chunks.len() as i32).unwrap();
+            for (begin, end) in chunks {
+                index.write_u64::<LittleEndian>(*begin).unwrap();
+                index.write_u64::<LittleEndian>(*end).unwrap();
+            }
+        }
+        index.write_i32::<LittleEndian>(intervals.len() as i32).unwrap();
+        for interval in intervals {
+            index.write_u64::<LittleEndian>(*interval).unwrap();
+        }
+    }
+    index
+}
+
+fn index_errors(data: Vec<u8>, index: Vec<u8>, virtual_offsets_count: u64, expected_index_errors: Vec<(u64, u64, bamrescue::IndexErrorKind)>) {
+    for threads in [1, 4] {
+        let (_, index_results) = bamrescue::check_index(&mut Cursor::new(data.clone()), &mut &index[..], threads, &mut None).unwrap();
+        assert_eq!(index_results.virtual_offsets_count, virtual_offsets_count);
+        assert_eq!(index_results.index_errors, expected_index_errors.iter().map(|&(position, virtual_offset, kind)| bamrescue::IndexError {
+            position,
+            virtual_offset,
+            kind,
+        }).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn index_valid() {
+    let data = common::bgzf(&common::bam_payload(), 100);
+    let index = bai(&[
+        (vec![
+            (4681, vec![(virtual_offset(0, 90), virtual_offset(1, 100))]),
+            (37450, vec![(virtual_offset(0, 90), virtual_offset(1, 100)), (2, 0)]),
+        ], vec![virtual_offset(0, 90)]),
+        (vec![], vec![]),
+    ]);
+    index_errors(data, index, 5, vec![]);
+}
+
+#[test]
+fn index_bad_magic() {
+    let mut index = bai(&[]);
+    index[3] = 2;
+    index_errors(common::three_blocks(), index, 0, vec![
+        (0, 0, bamrescue::IndexErrorKind::Magic),
+    ]);
+}
+
+#[test]
+fn index_dangling_and_inconsistent() {
+    let mut data = common::bgzf(&common::bam_payload(), 100);
+    // corrupt the payload of the third block
+    data[2 * 131 + 18 + 5 + 10] ^= 0x42;
+    let mut index = bai(&[
+        (vec![
+            (4681, vec![
+                (virtual_offset(0, 90), 42 << 16),
+                (virtual_offset(1, 101), virtual_offset(2, 0)),
+                (virtual_offset(1, 10), virtual_offset(0, 10)),
+            ]),
+            (40000, vec![]),
+        ], vec![virtual_offset(1, 0), virtual_offset(0, 0), virtual_offset(1, 0)]),
+    ]);
+    index.truncate(index.len() - 4);
+    index_errors(data, index, 8, vec![
+        (28, 42 << 16, bamrescue::IndexErrorKind::Dangling),
+        (36, virtual_offset(1, 101), bamrescue::IndexErrorKind::OutsideBlock),
+        (44, virtual_offset(2, 0), bamrescue::IndexErrorKind::CorruptedBlock),
+        (52, virtual_offset(1, 10), bamrescue::IndexErrorKind::Inconsistent),
+        (68, 0, bamrescue::IndexErrorKind::Bin),
+        (88, virtual_offset(0, 0), bamrescue::IndexErrorKind::Inconsistent),
+        (100, 0, bamrescue::IndexErrorKind::Truncation),
+    ]);
+}
+
+// bins of a reference sequence, with their offset and chunks
+type CsiReference = Vec<(u32, u64, Vec<(u64, u64)>)>;
+
+fn csi(min_shift: i32, depth: i32, auxiliary_data: &[u8], references: &[CsiReference], unplaced_count: u64) -> Vec<u8> {
+    let mut index = b"CSI\x01".to_vec();
+    index.write_i32::<LittleEndian>(min_shift).unwrap();
+    index.write_i32::<LittleEndian>(depth).unwrap();
+    index.write_i32::<LittleEndian>(auxiliary_data.len() as i32).unwrap();
+    index.extend(auxiliary_data);
+    index.write_i32::<LittleEndian>(references.len() as i32).unwrap();
+    for bins in references {
+        index.write_i32::<LittleEndian>(bins.len() as i32).unwrap();
+        for (bin, offset, chunks) in bins {
+            index.write_u32::<LittleEndian>(*bin).unwrap();
+            index.write_u64::<LittleEndian>(*offset).unwrap();
+            index.write_i32::<LittleEndian>(chunks.len() as i32).unwrap();
+            for (begin, end) in chunks {
+                index.write_u64::<LittleEndian>(*begin).unwrap();
+                index.write_u64::<LittleEndian>(*end).unwrap();
+            }
+        }
+    }
+    index.write_u64::<LittleEndian>(unplaced_count).unwrap();
+    index
+}
+
+#[test]
+fn index_csi_valid() {
+    let data = common::bgzf(&common::bam_payload(), 100);
+    // 4 kiB windows, and 3 levels: the first bin of the last level is 73,
+    // and the pseudo-bin is 586
+    let index = csi(12, 3, &[], &[
+        vec![
+            (75, virtual_offset(0, 90), vec![(virtual_offset(0, 90), virtual_offset(1, 100))]),
+            (586, 0, vec![(virtual_offset(0, 90), virtual_offset(1, 100)), (2, 0)]),
+        ],
+        vec![],
+    ], 0);
+    for index in [index.clone(), common::bgzf(&index, 40)] {
+        index_errors(data.clone(), index, 5, vec![]);
+    }
+}
+
+#[test]
+fn index_csi_deepest() {
+    let data = common::bgzf(&common::bam_payload(), 100);
+    // 16 kiB windows, and 10 levels: the first bin of the last level is
+    // 153391689, and the pseudo-bin is 1227133514
+    let index = csi(14, 10, &[], &[
+        vec![
+            (153391689, virtual_offset(0, 90), vec![(virtual_offset(0, 90), virtual_offset(1, 100))]),
+            (1227133514, 0, vec![(virtual_offset(0, 90), virtual_offset(1, 100)), (2, 0)]),
+        ],
+        vec![],
+    ], 0);
+    index_errors(data, index, 5, vec![]);
+    let index = csi(14, 11, &[], &[], 0);
+    index_errors(common::three_blocks(), index, 0, vec![
+        (4, 0, bamrescue::IndexErrorKind::Header),
+    ]);
+}
+
+#[test]
+fn index_csi_errors() {
+    let data = common::bgzf(&common::bam_payload(), 100);
+    let mut auxiliary_data = vec![];
+    for field in [0, 1, 2, 0, b'#' as i32, 0, 5] {
+        auxiliary_data.write_i32::<LittleEndian>(field).unwrap();
+    }
+    auxiliary_data.extend(b"chr1\0");
+    let index = csi(14, 5, &auxiliary_data, &[
+        vec![],
+        // chrM is only 16569 bases long
+        vec![(4681 + 10, virtual_offset(0, 90), vec![])],
+        vec![],
+    ], 5);
+    index_errors(data, index, 1, vec![
+        (49, 0, bamrescue::IndexErrorKind::ReferencesCount),
+        (12, 0, bamrescue::IndexErrorKind::AuxiliaryData),
+        (61, 0, bamrescue::IndexErrorKind::Bin),
+        (81, 0, bamrescue::IndexErrorKind::UnplacedCount),
+    ]);
+}
+
+#[test]
+fn index_csi_bad_header() {
+    let index = csi(14, 20, &[], &[], 0);
+    index_errors(common::three_blocks(), index, 0, vec![
+        (4, 0, bamrescue::IndexErrorKind::Header),
+    ]);
+    let mut index = common::bgzf(&csi(14, 5, &[], &[], 0), 40);
+    index[18 + 5 + 10] ^= 0x01;
+    index_errors(common::three_blocks(), index, 0, vec![
+        (0, 0, bamrescue::IndexErrorKind::Compression),
+    ]);
+}
+
+#[test]
+fn input_format_detected() {
+    for (payload, input_format) in [
+        (&b"##fileformat=VCFv4.2\n"[..], bamrescue::InputFormat::Vcf),
+        (b"##gff-version 3\n", bamrescue::InputFormat::Gff),
+        (b">chr1\nACGT\n", bamrescue::InputFormat::Fasta),
+        (&common::fastq_payload(3, 1), bamrescue::InputFormat::Fastq),
+        (b"@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n", bamrescue::InputFormat::Bgzf),
+        (b"track name=test\nchr1\t100\t200\tname\n", bamrescue::InputFormat::Bed),
+        (b"BAM\x01", bamrescue::InputFormat::Bam),
+        (b"some text\n", bamrescue::InputFormat::Bgzf),
+    ] {
+        let results = bamrescue::check(&mut Cursor::new(common::bgzf(payload, 100)), false, false, None, None, 1, &mut None).unwrap();
+        assert_eq!(results.input_format, Some(input_format));
+    }
+    let results = bamrescue::check(&mut Cursor::new(common::bgzf(b"some text\n", 100)), false, false, Some(bamrescue::InputFormat::Vcf), None, 1, &mut None).unwrap();
+    assert_eq!(results.input_format, Some(bamrescue::InputFormat::Vcf));
+}
+
+#[test]
+fn deep_not_checking_text() {
+    let results = bamrescue::check(&mut Cursor::new(common::bgzf(b"##fileformat=VCFv4.2\n", 100)), false, true, None, None, 1, &mut None).unwrap();
+    assert_eq!(results.input_format, Some(bamrescue::InputFormat::Vcf));
+    assert!(results.format_errors.is_empty());
+}
+
+fn bed_payload(sequences: &[&str]) -> Vec<u8> {
+    let mut data = b"track name=test\n".to_vec();
+    for sequence in sequences {
+        for index in 0..20 {
+            data.extend(format!("{}\t{}\t{}\tfeature{}\n", sequence, 1000 * index, 1000 * index + 500, index).as_bytes());
+        }
+    }
+    data
+}
+
+#[test]
+fn tabix_valid() {
+    let data = common::bgzf(&bed_payload(&["chr1", "chr2"]), 100);
+    let (results, index) = bamrescue::tabix(&mut Cursor::new(data.clone()), None, 1, &mut None).unwrap();
+    assert_eq!(results.input_format, Some(bamrescue::InputFormat::Bed));
+    assert_eq!(index.format, bamrescue::IndexFormat::Tbi);
+    for threads in [1, 4] {
+        let (_, index_results) = bamrescue::check_index(&mut Cursor::new(data.clone()), &mut &index.bytes[..], threads, &mut None).unwrap();
+        assert_eq!(index_results.format, Some(bamrescue::IndexFormat::Tbi));
+        assert!(index_results.virtual_offsets_count > 0);
+        assert_eq!(index_results.index_errors, vec![]);
+    }
+}
+
+#[test]
+fn tabix_names_mismatch() {
+    let data = common::bgzf(&bed_payload(&["chr1", "chr2"]), 100);
+    let (_, index) = bamrescue::tabix(&mut Cursor::new(common::bgzf(&bed_payload(&["chrA", "chrB"]), 100)), None, 1, &mut None).unwrap();
+    // the index of the other file has the same offsets, but not the same names
+    index_errors(data, index.bytes, 20, vec![(8, 0, bamrescue::IndexErrorKind::ReferencesNames)]);
+}
+
+#[test]
+fn tabix_columns() {
+    let data = common::bgzf(b"#comment\nchr1\tfeature\t100\t200\nchr1\tfeature\t150\t300\n", 100);
+    let columns = bamrescue::TabixColumns {
+        sequence: 1,
+        begin: 3,
+        end: 4,
+        ..bamrescue::TabixColumns::bed()
+    };
+    let (_, index) = bamrescue::tabix(&mut Cursor::new(data.clone()), Some(columns), 1, &mut None).unwrap();
+    let (_, index_results) = bamrescue::check_index(&mut Cursor::new(data.clone()), &mut &index.bytes[..], 1, &mut None).unwrap();
+    assert_eq!(index_results.index_errors, vec![]);
+    // the default bed columns don't hold positions
+    match bamrescue::tabix(&mut Cursor::new(data), Some(bamrescue::TabixColumns::bed()), 1, &mut None) {
+        Err(bamrescue::RescueError::Index(cause)) => assert_eq!(cause, "invalid line"),
+        _ => panic!("invalid columns indexed"),
+    }
+}
+
+#[test]
+fn tabix_corrupted() {
+    let mut data = common::bgzf(&bed_payload(&["chr1"]), 100);
+    data[131 * 2 + 33] ^= 0x01;
+    match bamrescue::tabix(&mut Cursor::new(data), None, 1, &mut None) {
+        Err(bamrescue::RescueError::Index(cause)) => assert_eq!(cause, "corrupted file, rescue it first"),
+        _ => panic!("corrupted file indexed"),
+    }
+}
+
+#[test]
+fn deep_valid_bcf() {
+    for inflated_block_size in [7, 36, 1000] {
+        let data = common::bgzf(&common::bcf_payload(), inflated_block_size);
+        format_errors(data.clone(), vec![]);
+        let results = bamrescue::check(&mut Cursor::new(data), false, true, None, None, 1, &mut None).unwrap();
+        assert_eq!(results.input_format, Some(bamrescue::InputFormat::Bcf));
+    }
+}
+
+#[test]
+fn deep_bad_bcf_header_text() {
+    let mut payload = common::bcf_payload();
+    // NUL terminator of the header text
+    let text_end = common::bcf_header(&[("chr1", 248956422), ("chrM", 16569)], &["sample1", "sample2"]).len() - 1;
+    payload[text_end] = b'\n';
+    format_errors(common::bgzf(&payload, 1000), vec![
+        format_error(9, bamrescue::FormatErrorKind::BcfHeaderText),
+    ])
+}
+
+#[test]
+fn deep_overlong_bcf_header_text() {
+    let mut payload = common::bcf_payload();
+    // l_text, way past the end of the file
+    payload[5..9].copy_from_slice(&0xfffffff0u32.to_le_bytes());
+    format_errors(common::bgzf(&payload, 1000), vec![
+        format_error(9, bamrescue::FormatErrorKind::BcfHeaderText),
+    ])
+}
+
+#[test]
+fn deep_implausible_bcf_contig_index() {
+    let mut payload = common::bcf_header(&[("chr1,IDX=2000000000", 248956422)], &["sample1", "sample2"]);
+    payload.extend(common::bcf_record(0, 10000, &["A", "G"], &[2, 4]));
+    format_errors(common::bgzf(&payload, 1000), vec![
+        format_error(9, bamrescue::FormatErrorKind::BcfHeaderText),
+    ])
+}
+
+#[test]
+fn deep_bad_bcf_records() {
+    let mut payload = common::bcf_header(&[("chr1", 248956422)], &["sample1", "sample2"]);
+    let first_record_position = payload.len() as u64;
+    payload.extend(common::bcf_record(1, 10000, &["A", "G"], &[2, 4]));
+    let second_record_position = payload.len() as u64;
+    payload.extend(common::bcf_record(0, 10042, &["AC", "A"], &[4]));
+    let third_record_position = payload.len() as u64;
+    let mut record = common::bcf_record(0, 10100, &["T", "C"], &[2, 2]);
+    // l_indiv too small for its format
+    record[4] = 2;
+    payload.extend(record);
+    format_errors(common::bgzf(&payload, 1000), vec![
+        format_error(first_record_position, bamrescue::FormatErrorKind::ChromosomeId),
+        format_error(second_record_position, bamrescue::FormatErrorKind::SamplesCount),
+        format_error(third_record_position, bamrescue::FormatErrorKind::IndividualLength),
+    ])
+}
+
+#[test]
+fn deep_truncated_bcf() {
+    let payload = common::bcf_payload();
+    let last_record_position = payload.len() - common::bcf_record(1, 42, &["T", "C", "G"], &[2, 6]).len();
+    format_errors(common::bgzf(&payload[..payload.len() - 3], 1000), vec![
+        format_error(last_record_position as u64, bamrescue::FormatErrorKind::BcfTruncation),
+    ])
+}
+
+// Positions of the containers in common::cram(containers)
+fn cram_container_positions(containers: &[Vec<u8>]) -> Vec<u64> {
+    containers.iter().scan(common::cram_file_definition().len() as u64, |position, container| {
+        let container_position = *position;
+        *position += container.len() as u64;
+        Some(container_position)
+    }).collect()
+}
+
+#[test]
+fn cram_valid() {
+    let containers = common::cram_containers();
+    let data = common::cram(&containers);
+    for threads in [1, 4] {
+        let results = bamrescue::check(&mut Cursor::new(data.clone()), false, false, None, None, threads, &mut None).unwrap();
+        assert_eq!(results.input_format, Some(bamrescue::InputFormat::Cram));
+        assert_eq!(results.blocks_count, containers.len() as u64 + 1);
+        assert_eq!(results.blocks_size, (data.len() - common::cram_file_definition().len()) as u64);
+        assert_eq!(results.bad_blocks_count, 0);
+        assert!(!results.truncated_in_block);
+        assert!(!results.truncated_between_blocks);
+    }
+}
+
+#[test]
+fn cram_bad_block_crc32() {
+    let containers = common::cram_containers();
+    let positions = cram_container_positions(&containers);
+    let mut data = common::cram(&containers);
+    // core data block of the third container
+    let blocks = [
+        common::cram_block(1, 0, &[0x00; 12]),
+        common::cram_block(2, 0, &[0x01; 20]),
+        common::cram_block(5, 0, &b"ACGT".repeat(110)),
+        common::cram_block(4, 2, &b"NNNN".repeat(50)),
+    ];
+    let header_size = containers[2].len() - blocks.iter().map(Vec::len).sum::<usize>();
+    let block_position = positions[2] + (header_size + blocks[0].len() + blocks[1].len()) as u64;
+    data[block_position as usize + 100] ^= 0x10;
+    let results = bamrescue::check(&mut Cursor::new(data), false, false, None, None, 1, &mut None).unwrap();
+    assert_eq!(results.blocks_count, containers.len() as u64 + 1);
+    assert_eq!(results.bad_blocks_count, 1);
+    assert_eq!(results.bad_blocks_size, containers[2].len() as u64);
+    assert_eq!(results.bad_blocks, vec![bamrescue::BadBlock {
+        position: positions[2],
+        size: containers[2].len() as u64,
+        inflated_position: positions[2],
+        kind: bamrescue::CorruptionKind::CramBlock,
+    }]);
+    assert_eq!(results.bad_cram_blocks, vec![bamrescue::BadBlock {
+        position: block_position,
+        size: blocks[2].len() as u64,
+        inflated_position: block_position,
+        kind: bamrescue::CorruptionKind::CramBlock,
+    }]);
+    assert!(!results.truncated_between_blocks);
+}
+
+#[test]
+fn cram_bad_container_header() {
+    let containers = common::cram_containers();
+    let positions = cram_container_positions(&containers);
+    for corrupted_byte in [0, 5, 12] {
+        let mut data = common::cram(&containers);
+        data[positions[3] as usize + corrupted_byte] ^= 0x04;
+        // looking for the next container seeks back, even in streams
+        let readers: [Box<dyn bamrescue::Rescuable>; 2] = [Box::new(Cursor::new(data.clone())), Box::new(bamrescue::LookbackReader::new(Cursor::new(data)))];
+        for mut reader in readers {
+            let results = bamrescue::check(&mut reader, false, false, None, None, 1, &mut None).unwrap();
+            assert_eq!(results.blocks_count, containers.len() as u64 + 1);
+            assert_eq!(results.bad_blocks_count, 1);
+            assert_eq!(results.bad_blocks[0].position, positions[3]);
+            assert_eq!(results.bad_blocks[0].size, containers[3].len() as u64);
+            assert_eq!(results.bad_blocks[0].kind, bamrescue::CorruptionKind::ContainerHeader);
+            assert!(results.bad_cram_blocks.is_empty());
+            assert!(!results.truncated_between_blocks);
+        }
+    }
+}
+
+#[test]
+fn cram_truncated() {
+    let containers = common::cram_containers();
+    let positions = cram_container_positions(&containers);
+    let mut data = common::cram(&containers);
+    data.truncate(positions[4] as usize + 100);
+    let results = bamrescue::check(&mut Cursor::new(data), false, false, None, None, 1, &mut None).unwrap();
+    assert_eq!(results.blocks_count, 4);
+    assert_eq!(results.bad_blocks_count, 0);
+    assert!(results.truncated_in_block);
+    assert_eq!(results.bad_blocks[0].kind, bamrescue::CorruptionKind::Truncation);
+    assert_eq!(results.bad_blocks[0].position, positions[4]);
+}
+
+#[test]
+fn cram_missing_eof_container() {
+    let data = common::cram(&common::cram_containers());
+    let results = bamrescue::check(&mut Cursor::new(&data[..data.len() - common::cram_eof_container().len()]), false, false, None, None, 1, &mut None).unwrap();
+    assert_eq!(results.bad_blocks_count, 0);
+    assert!(!results.truncated_in_block);
+    assert!(results.truncated_between_blocks);
+}
+
+#[test]
+fn cram_unsupported_version() {
+    let mut data = common::cram(&common::cram_containers());
+    data[4] = 2;
+    match bamrescue::check(&mut Cursor::new(data), false, false, None, None, 1, &mut None) {
+        Err(bamrescue::RescueError::Input(_)) => (),
+        _ => panic!("expected an input error"),
+    }
+}
+
+fn gzip_members(payload: &[u8], chunk_size: usize) -> (Vec<u8>, Vec<u64>) {
+    let mut data = vec![];
+    let mut positions = vec![];
+    for chunk in payload.chunks(chunk_size) {
+        positions.push(data.len() as u64);
+        data.extend(common::gzip_member(chunk));
+    }
+    (data, positions)
+}
+
+#[test]
+fn gzip_valid() {
+    let payload = common::large_vcf_payload(5000);
+    for (data, members_count) in [(common::gzip_member(&payload), 1), (gzip_members(&payload, 100000).0, 3)] {
+        let results = bamrescue::check(&mut Cursor::new(data), false, false, None, None, 1, &mut None).unwrap();
+        assert!(results.plain_gzip);
+        assert_eq!(results.input_format, Some(bamrescue::InputFormat::Vcf));
+        assert_eq!(results.blocks_count, members_count);
+        assert_eq!(results.blocks_size, payload.len() as u64);
+        assert_eq!(results.bad_blocks_count, 0);
+        assert!(results.bad_blocks.is_empty());
+        assert!(!results.truncated_in_block);
+        assert!(!results.truncated_between_blocks);
+    }
+}
+
+#[test]
+fn gzip_bad_crc32() {
+    let payload = common::large_vcf_payload(5000);
+    let (mut data, positions) = gzip_members(&payload, 100000);
+    data[positions[2] as usize - 8] ^= 0x01;
+    let results = bamrescue::check(&mut Cursor::new(data), false, false, None, None, 1, &mut None).unwrap();
+    assert_eq!(results.blocks_count, 3);
+    assert_eq!(results.bad_blocks_count, 1);
+    assert_eq!(results.bad_blocks_size, 100000);
+    assert_eq!(results.bad_blocks, vec![bamrescue::BadBlock {
+        position: positions[1],
+        size: positions[2] - positions[1],
+        inflated_position: 100000,
+        kind: bamrescue::CorruptionKind::Crc32,
+    }]);
+}
+
+#[test]
+fn gzip_bad_isize() {
+    let payload = common::large_vcf_payload(5000);
+    let (mut data, positions) = gzip_members(&payload, 100000);
+    data[positions[2] as usize - 4] ^= 0x01;
+    let results = bamrescue::check(&mut Cursor::new(data), false, false, None, None, 1, &mut None).unwrap();
+    assert_eq!(results.bad_blocks_count, 1);
+    assert_eq!(results.bad_blocks_size, 100000);
+    assert_eq!(results.repaired_blocks_count, 0);
+    assert_eq!(results.bad_blocks[0].kind, bamrescue::CorruptionKind::Isize);
+    assert_eq!(results.bad_blocks[0].position, positions[1]);
+}
+
+#[test]
+fn gzip_truncated() {
+    let payload = common::large_vcf_payload(5000);
+    let (data, positions) = gzip_members(&payload, 100000);
+    for size in [positions[2] as usize + 5, positions[2] as usize + 1000, data.len() - 3] {
+        let results = bamrescue::check(&mut Cursor::new(&data[..size]), false, false, None, None, 1, &mut None).unwrap();
+        assert_eq!(results.bad_blocks_count, 0);
+        assert!(results.truncated_in_block);
+        assert_eq!(results.bad_blocks, vec![bamrescue::BadBlock {
+            position: positions[2],
+            size: size as u64 - positions[2],
+            inflated_position: results.blocks_size,
+            kind: bamrescue::CorruptionKind::Truncation,
+        }]);
+    }
+}
+
+#[test]
+fn gzip_bad_deflate_stream() {
+    let payload = common::large_vcf_payload(5000);
+    let (mut data, chunk_positions) = common::gzip_member_with_flushes(&payload, 20000, true);
+    // reserved block type
+    data[chunk_positions[4]] |= 0x06;
+    for fail_fast in [false, true] {
+        let results = bamrescue::check(&mut Cursor::new(data.clone()), fail_fast, false, None, None, 1, &mut None).unwrap();
+        assert_eq!(results.blocks_count, 1);
+        assert_eq!(results.bad_blocks_count, 1);
+        assert_eq!(results.bad_blocks[0].kind, bamrescue::CorruptionKind::Inflate);
+        assert!((chunk_positions[4]..chunk_positions[5]).contains(&(results.bad_blocks[0].position as usize)));
+        assert!(results.bad_blocks[0].position + results.bad_blocks[0].size <= chunk_positions[5] as u64);
+        assert_eq!(results.bad_blocks[0].inflated_position, 80000);
+        if !fail_fast {
+            // known from the size in the member trailer
+            assert_eq!(results.bad_blocks_size, 20000);
+            assert_eq!(results.blocks_size, payload.len() as u64);
+        }
+    }
+}
+
+#[test]
+fn gzip_bad_header() {
+    let payload = common::large_vcf_payload(5000);
+    let (mut data, positions) = gzip_members(&payload, 100000);
+    data[positions[1] as usize + 2] = 0x07;
+    let results = bamrescue::check(&mut Cursor::new(data), false, false, None, None, 1, &mut None).unwrap();
+    assert_eq!(results.blocks_count, 2);
+    assert_eq!(results.bad_blocks_count, 1);
+    // the deflate stream of the member starts right after its header
+    assert_eq!(results.bad_blocks, vec![bamrescue::BadBlock {
+        position: positions[1],
+        size: 10,
+        inflated_position: 100000,
+        kind: bamrescue::CorruptionKind::GzipHeader,
+    }]);
+    assert_eq!(results.blocks_size, payload.len() as u64);
+    assert_eq!(results.bad_blocks_size, 0);
+}
+
+#[test]
+fn gzip_not_bgzf() {
+    // a bgzf block with a single corrupted header byte is still bgzf
+    let mut data = common::three_blocks();
+    data[12] = 0x41;
+    let results = bamrescue::check(&mut Cursor::new(data), false, false, None, None, 1, &mut None).unwrap();
+    assert!(!results.plain_gzip);
+}
+
+#[test]
+fn ddrescue_unreadable_block() {
+    let block_size = common::regular_bgzf_block().len();
+    let regions = [(block_size, 2 * block_size)];
+    // whether the bytes left in the image happen to pass the checks or not
+    for zeroed in [false, true] {
+        let mut data = common::three_blocks();
+        if zeroed {
+            common::zero_regions(&mut data, &regions);
+        }
+        let ddrescue_map = common::ddrescue_map(data.len(), &regions);
+        for threads in [1, 4] {
+            let results = bamrescue::check(&mut Cursor::new(data.clone()), false, false, None, Some(&ddrescue_map), threads, &mut None).unwrap();
+            assert_eq!(results.blocks_count, 4);
+            assert_eq!(results.bad_blocks_count, 1);
+            assert_eq!(results.unreadable_blocks_count, 1);
+            assert_eq!(results.bad_blocks, vec![bamrescue::BadBlock {
+                position: block_size as u64,
+                size: block_size as u64,
+                inflated_position: results.bad_blocks[0].inflated_position,
+                kind: bamrescue::CorruptionKind::Unreadable,
+            }]);
+            assert!(!results.truncated_in_block);
+            assert!(!results.truncated_between_blocks);
+        }
+    }
+}
+
+#[test]
+fn ddrescue_unreadable_across_blocks() {
+    let block_size = common::regular_bgzf_block().len();
+    let regions = [(block_size + 20, 2 * block_size + 10)];
+    let mut data = common::three_blocks();
+    common::zero_regions(&mut data, &regions);
+    let ddrescue_map = common::ddrescue_map(data.len(), &regions);
+    for fail_fast in [false, true] {
+        let results = bamrescue::check(&mut Cursor::new(data.clone()), fail_fast, false, None, Some(&ddrescue_map), 1, &mut None).unwrap();
+        assert_eq!(results.bad_blocks[0].position, block_size as u64);
+        assert_eq!(results.bad_blocks[0].kind, bamrescue::CorruptionKind::Unreadable);
+        if !fail_fast {
+            // the next block is only looked for after the unreadable region
+            assert_eq!(results.blocks_count, 4);
+            assert_eq!(results.bad_blocks_count, 2);
+            assert_eq!(results.unreadable_blocks_count, 2);
+            assert_eq!(results.bad_blocks[1].position, 2 * block_size as u64);
+            assert_eq!(results.bad_blocks[1].size, block_size as u64);
+            assert_eq!(results.bad_blocks[1].kind, bamrescue::CorruptionKind::Unreadable);
+            assert!(!results.truncated_between_blocks);
+        }
+    }
+    // without the map, the zeros are just a corrupted header
+    let results = bamrescue::check(&mut Cursor::new(data.clone()), false, false, None, None, 1, &mut None).unwrap();
+    assert_eq!(results.unreadable_blocks_count, 0);
+    assert!(results.bad_blocks.iter().all(|bad_block| bad_block.kind != bamrescue::CorruptionKind::Unreadable));
+    // nor a header cut by unreadable bytes, whose block goes on up to the
+    // next block after them
+    let regions = [(block_size + 10, 2 * block_size + 10)];
+    let mut data = common::three_blocks();
+    common::zero_regions(&mut data, &regions);
+    let results = bamrescue::check(&mut Cursor::new(data.clone()), false, false, None, Some(&common::ddrescue_map(data.len(), &regions)), 1, &mut None).unwrap();
+    assert_eq!(results.blocks_count, 3);
+    assert_eq!(results.bad_blocks_count, 1);
+    assert_eq!(results.bad_blocks[0].position, block_size as u64);
+    assert_eq!(results.bad_blocks[0].size, 2 * block_size as u64);
+    assert_eq!(results.bad_blocks[0].kind, bamrescue::CorruptionKind::Unreadable);
+}
+
+#[test]
+fn ddrescue_readable() {
+    let data = common::three_blocks();
+    let ddrescue_map = common::ddrescue_map(data.len(), &[]);
+    let results = bamrescue::check(&mut Cursor::new(data), false, false, None, Some(&ddrescue_map), 1, &mut None).unwrap();
+    assert_eq!(results.blocks_count, 4);
+    assert_eq!(results.bad_blocks_count, 0);
+    assert_eq!(results.unreadable_blocks_count, 0);
+}
+
+#[test]
+fn ddrescue_invalid_map() {
+    for mapfile in ["0x00000000\n", "0x00000000 +\n0x00000000 0x00000100\n", "0x00000000 +\n0x00000000 size -\n"] {
+        match bamrescue::DdrescueMap::read(&mut mapfile.as_bytes()) {
+            Err(bamrescue::RescueError::Input(_)) => (),
+            _ => panic!("expected an input error"),
+        }
+    }
+}
+
+#[test]
+fn ddrescue_cram() {
+    let containers = common::cram_containers();
+    let positions = cram_container_positions(&containers);
+    let data = common::cram(&containers);
+    // within the third container, whose CRC32s still match
+    let regions = [(positions[2] as usize + 50, positions[2] as usize + 60)];
+    let results = bamrescue::check(&mut Cursor::new(data.clone()), false, false, None, Some(&common::ddrescue_map(data.len(), &regions)), 1, &mut None).unwrap();
+    assert_eq!(results.blocks_count, containers.len() as u64 + 1);
+    assert_eq!(results.unreadable_blocks_count, 1);
+    assert_eq!(results.bad_blocks, vec![bamrescue::BadBlock {
+        position: positions[2],
+        size: containers[2].len() as u64,
+        inflated_position: positions[2],
+        kind: bamrescue::CorruptionKind::Unreadable,
+    }]);
+    // over the header of the fourth container and part of the fifth
+    let regions = [(positions[3] as usize, positions[4] as usize + 10)];
+    let mut data = data;
+    common::zero_regions(&mut data, &regions);
+    let results = bamrescue::check(&mut Cursor::new(data.clone()), false, false, None, Some(&common::ddrescue_map(data.len(), &regions)), 1, &mut None).unwrap();
+    assert_eq!(results.unreadable_blocks_count, 1);
+    assert_eq!(results.bad_blocks, vec![bamrescue::BadBlock {
+        position: positions[3],
+        size: positions[5] - positions[3],
+        inflated_position: positions[3],
+        kind: bamrescue::CorruptionKind::Unreadable,
+    }]);
+    assert!(!results.truncated_between_blocks);
+}
+
+#[test]
+fn ddrescue_gzip() {
+    let payload = common::large_vcf_payload(5000);
+    let (mut data, positions) = gzip_members(&payload, 100000);
+    let regions = [(positions[1] as usize + 1000, positions[1] as usize + 5000)];
+    common::zero_regions(&mut data, &regions);
+    let results = bamrescue::check(&mut Cursor::new(data.clone()), false, false, None, Some(&common::ddrescue_map(data.len(), &regions)), 1, &mut None).unwrap();
+    assert_eq!(results.bad_blocks_count, 1);
+    assert_eq!(results.unreadable_blocks_count, 1);
+    assert_eq!(results.bad_blocks[0].kind, bamrescue::CorruptionKind::Unreadable);
+    assert_eq!(results.bad_blocks[0].position, regions[0].0 as u64);
+    // inflating goes on after the unreadable region, within the member or from the next one
+    assert!(results.bad_blocks[0].position + results.bad_blocks[0].size <= positions[2]);
+    assert!(!results.truncated_in_block);
+}
+
+#[test]
+fn emit_map() {
+    let mut data = common::three_blocks();
+    data[33 + 25] ^= 0x01;
+    data[66 + 25] ^= 0x01;
+    let results = bamrescue::check(&mut Cursor::new(data.clone()), false, false, None, None, 1, &mut None).unwrap();
+    assert_eq!(results.bad_blocks_count, 2);
+    let mut mapfile = vec![];
+    bamrescue::emit_map(&mut mapfile, &results, Some(data.len() as u64)).unwrap();
+    let mapfile = String::from_utf8(mapfile).unwrap();
+    // adjacent corrupted blocks make a single range
+    assert_eq!(mapfile.lines().filter(|line| !line.starts_with('#')).collect::<Vec<_>>(), vec![
+        "0x00000021     +               1",
+        "0x00000000  0x00000021  ?",
+        "0x00000021  0x00000042  +",
+        "0x00000063  0x0000001C  ?",
+    ]);
+    // which is the only region ddrescue has read, as far as the mapfile goes
+    let ddrescue_map = bamrescue::DdrescueMap::read(&mut mapfile.as_bytes()).unwrap();
+    assert!(!ddrescue_map.overlaps(33, 99));
+    assert_eq!(ddrescue_map.unreadable_end(0), Some(33));
+    assert_eq!(ddrescue_map.unreadable_end(99), Some(data.len() as u64));
+
+    let results = bamrescue::check(&mut Cursor::new(common::three_blocks()), false, false, None, None, 1, &mut None).unwrap();
+    let mut mapfile = vec![];
+    bamrescue::emit_map(&mut mapfile, &results, None).unwrap();
+    assert_eq!(String::from_utf8(mapfile).unwrap().lines().filter(|line| !line.starts_with('#')).collect::<Vec<_>>(), vec![
+        "0x00000000     +               1",
+    ]);
+}
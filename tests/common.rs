@@ -425,3 +425,309 @@ pub fn three_blocks_with_extra_similar_subfields_after_bam() -> Cursor<Vec<u8>>
 pub fn three_blocks_with_extra_similar_subfields_before_and_after_bam() -> Cursor<Vec<u8>> {
     Cursor::new(three_blocks_with_extra_similar_subfields_before_and_after())
 }
+
+// Only uses stored deflate blocks, which are enough for bgzf tests
+pub fn stored_bgzf_block(inflated_payload: &[u8]) -> Vec<u8> {
+    let mut deflated_payload = vec![0x01]; // final stored block
+    deflated_payload.write_u16::<LittleEndian>(inflated_payload.len() as u16).unwrap();
+    deflated_payload.write_u16::<LittleEndian>(!(inflated_payload.len() as u16)).unwrap();
+    deflated_payload.extend(inflated_payload);
+    let crc32 = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(inflated_payload);
+    bgzf_block(deflated_payload, inflated_payload.len() as u32, crc32, 0, None, None)
+}
+
+pub fn bgzf(inflated_payload: &[u8], inflated_block_size: usize) -> Vec<u8> {
+    let mut data = vec![];
+    for chunk in inflated_payload.chunks(inflated_block_size) {
+        data.extend(&stored_bgzf_block(chunk));
+    }
+    data.extend(&empty_bgzf_block());
+    data
+}
+
+pub fn bam_header(references: &[(&str, i32)]) -> Vec<u8> {
+    let text = b"@HD\tVN:1.6\tSO:unsorted\n@PG\tID:bamrescue\n";
+    let mut data = b"BAM\x01".to_vec();
+    data.write_i32::<LittleEndian>(text.len() as i32).unwrap();
+    data.extend(text);
+    data.write_i32::<LittleEndian>(references.len() as i32).unwrap();
+    for (name, length) in references {
+        data.write_i32::<LittleEndian>(name.len() as i32 + 1).unwrap();
+        data.extend(name.as_bytes());
+        data.push(0);
+        data.write_i32::<LittleEndian>(*length).unwrap();
+    }
+    data
+}
+
+pub fn bam_record(reference_id: i32, position: i32, read_name: &str, sequence: &str) -> Vec<u8> {
+    let mut record = vec![];
+    record.write_i32::<LittleEndian>(reference_id).unwrap();
+    record.write_i32::<LittleEndian>(position).unwrap();
+    record.push(read_name.len() as u8 + 1);
+    record.push(60); // mapping quality
+    record.write_u16::<LittleEndian>(4680).unwrap(); // bin
+    record.write_u16::<LittleEndian>(1).unwrap(); // number of cigar operations
+    record.write_u16::<LittleEndian>(0).unwrap(); // flags
+    record.write_i32::<LittleEndian>(sequence.len() as i32).unwrap();
+    record.write_i32::<LittleEndian>(-1).unwrap(); // next reference id
+    record.write_i32::<LittleEndian>(-1).unwrap(); // next position
+    record.write_i32::<LittleEndian>(0).unwrap(); // template length
+    record.extend(read_name.as_bytes());
+    record.push(0);
+    record.write_u32::<LittleEndian>((sequence.len() as u32) << 4).unwrap(); // cigar: all matches
+    for bases in sequence.as_bytes().chunks(2) {
+        let code = |base: u8| "=ACMGRSVTWYHKDBN".bytes().position(|code| code == base).unwrap() as u8;
+        record.push(code(bases[0]) << 4 | bases.get(1).map(|&base| code(base)).unwrap_or(0));
+    }
+    record.extend(vec![30; sequence.len()]); // qualities
+
+    let mut data = vec![];
+    data.write_i32::<LittleEndian>(record.len() as i32).unwrap();
+    data.extend(record);
+    data
+}
+
+pub fn bcf_header(contigs: &[(&str, i32)], samples: &[&str]) -> Vec<u8> {
+    let mut text = "##fileformat=VCFv4.2\n##FILTER=<ID=PASS,Description=\"All filters passed\">\n".to_string();
+    for (name, length) in contigs {
+        text.push_str(&format!("##contig=<ID={},length={}>\n", name, length));
+    }
+    text.push_str("##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">\n");
+    text.push_str("#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO");
+    if !samples.is_empty() {
+        text.push_str("\tFORMAT");
+        for sample in samples {
+            text.push('\t');
+            text.push_str(sample);
+        }
+    }
+    text.push_str("\n\0");
+    let mut data = b"BCF\x02\x02".to_vec();
+    data.write_u32::<LittleEndian>(text.len() as u32).unwrap();
+    data.extend(text.as_bytes());
+    data
+}
+
+// Typed string, as bcf stores ids and alleles
+fn bcf_string(value: &str) -> Vec<u8> {
+    let mut data = vec![(value.len() as u8) << 4 | 7];
+    data.extend(value.as_bytes());
+    data
+}
+
+pub fn bcf_record(chromosome_id: i32, position: i32, alleles: &[&str], genotypes: &[u8]) -> Vec<u8> {
+    let mut shared = vec![];
+    shared.write_i32::<LittleEndian>(chromosome_id).unwrap();
+    shared.write_i32::<LittleEndian>(position).unwrap();
+    shared.write_i32::<LittleEndian>(alleles[0].len() as i32).unwrap(); // rlen
+    shared.write_f32::<LittleEndian>(50.).unwrap(); // quality
+    shared.write_u32::<LittleEndian>((alleles.len() as u32) << 16).unwrap(); // no info
+    shared.write_u32::<LittleEndian>(if genotypes.is_empty() { 0 } else { 1 << 24 | genotypes.len() as u32 }).unwrap();
+    shared.extend(bcf_string("."));
+    for allele in alleles {
+        shared.extend(bcf_string(allele));
+    }
+    shared.extend([0x11, 0]); // PASS
+    let mut individual = vec![];
+    if !genotypes.is_empty() {
+        individual.extend([0x11, 1]); // GT
+        individual.push(0x11); // one int8 per sample
+        individual.extend(genotypes);
+    }
+    let mut data = vec![];
+    data.write_u32::<LittleEndian>(shared.len() as u32).unwrap();
+    data.write_u32::<LittleEndian>(individual.len() as u32).unwrap();
+    data.extend(shared);
+    data.extend(individual);
+    data
+}
+
+pub fn bcf_payload() -> Vec<u8> {
+    let mut data = bcf_header(&[("chr1", 248956422), ("chrM", 16569)], &["sample1", "sample2"]);
+    data.extend(bcf_record(0, 10000, &["A", "G"], &[2, 4]));
+    data.extend(bcf_record(0, 10042, &["AC", "A"], &[4, 4]));
+    data.extend(bcf_record(1, 42, &["T", "C", "G"], &[2, 6]));
+    data
+}
+
+pub fn bam_payload() -> Vec<u8> {
+    let mut data = bam_header(&[("chr1", 248956422), ("chrM", 16569)]);
+    data.extend(bam_record(0, 10000, "read1", "ACGTACGTAC"));
+    data.extend(bam_record(0, 10042, "read2", "GATTACA"));
+    data.extend(bam_record(1, 42, "read3", "TTAGGG"));
+    data
+}
+
+fn itf8(value: u32) -> Vec<u8> {
+    match value {
+        0..=0x7f => vec![value as u8],
+        0x80..=0x3fff => vec![0x80 | (value >> 8) as u8, value as u8],
+        _ => vec![0xc0 | (value >> 16) as u8, (value >> 8) as u8, value as u8],
+    }
+}
+
+// Raw (uncompressed) block of a cram 3 container
+pub fn cram_block(content_type: u8, content_id: u32, data: &[u8]) -> Vec<u8> {
+    let mut block = vec![0x00, content_type];
+    block.extend(itf8(content_id));
+    block.extend(itf8(data.len() as u32)); // compressed size
+    block.extend(itf8(data.len() as u32)); // raw size
+    block.extend(data);
+    let crc32 = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(&block);
+    block.write_u32::<LittleEndian>(crc32).unwrap();
+    block
+}
+
+pub fn cram_container(blocks: &[Vec<u8>]) -> Vec<u8> {
+    let length: usize = blocks.iter().map(Vec::len).sum();
+    let mut container = vec![];
+    container.write_i32::<LittleEndian>(length as i32).unwrap();
+    container.extend(itf8(0)); // reference sequence id
+    container.extend(itf8(1)); // alignment start
+    container.extend(itf8(100)); // alignment span
+    container.extend(itf8(10)); // records count
+    container.extend([0x00, 0x00]); // record counter and bases count
+    container.extend(itf8(blocks.len() as u32));
+    container.extend([0x01, 0x00]); // single landmark
+    let crc32 = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(&container);
+    container.write_u32::<LittleEndian>(crc32).unwrap();
+    for block in blocks {
+        container.extend(block);
+    }
+    container
+}
+
+pub fn cram_file_definition() -> Vec<u8> {
+    let mut data = b"CRAM\x03\x00".to_vec();
+    data.extend(b"bamrescue.cram\0\0\0\0\0\0");
+    data
+}
+
+pub fn cram_eof_container() -> Vec<u8> {
+    vec![
+        0x0f, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0x0f, 0xe0, 0x45, 0x4f, 0x46, 0x00, 0x00, 0x00,
+        0x00, 0x01, 0x00, 0x05, 0xbd, 0xd9, 0x4f, 0x00, 0x01, 0x00, 0x06, 0x06, 0x01, 0x00, 0x01, 0x00,
+        0x01, 0x00, 0xee, 0x63, 0x01, 0x4b,
+    ]
+}
+
+// Header container, then containers of a compression header and slices
+pub fn cram_containers() -> Vec<Vec<u8>> {
+    let mut containers = vec![cram_container(&[cram_block(0, 0, b"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:248956422\n")])];
+    for index in 0..5u32 {
+        containers.push(cram_container(&[
+            cram_block(1, 0, &[0x00; 12]), // compression header
+            cram_block(2, 0, &[0x01; 20]), // slice header
+            cram_block(5, 0, &b"ACGT".repeat(index as usize * 100 + 10)), // core data
+            cram_block(4, index + 1, &b"NNNN".repeat(50)), // external data
+        ]));
+    }
+    containers
+}
+
+pub fn cram(containers: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = cram_file_definition();
+    for container in containers {
+        data.extend(container);
+    }
+    data.extend(cram_eof_container());
+    data
+}
+
+// Plain gzip member, its deflate stream split in blocks with dynamic Huffman
+// codes if the payload is large enough
+pub fn gzip_member(payload: &[u8]) -> Vec<u8> {
+    gzip_member_with_flushes(payload, payload.len().max(1), true).0
+}
+
+// The deflate stream is flushed every chunk, whose first block is at the given
+// positions of the member; like pigz --independent, full flushes also make the
+// compressor forget the payload so far
+pub fn gzip_member_with_flushes(payload: &[u8], chunk_size: usize, full: bool) -> (Vec<u8>, Vec<usize>) {
+    use miniz_oxide::deflate::core::{
+        compress,
+        create_comp_flags_from_zip_params,
+        CompressorOxide,
+        TDEFLFlush,
+    };
+    let mut data = vec![0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff];
+    let mut chunk_positions = vec![];
+    let mut compressor = CompressorOxide::new(create_comp_flags_from_zip_params(6, -15, 0));
+    let chunks: Vec<&[u8]> = payload.chunks(chunk_size).collect();
+    for (index, chunk) in chunks.iter().enumerate() {
+        chunk_positions.push(data.len());
+        let flush = if index + 1 == chunks.len() {
+            TDEFLFlush::Finish
+        } else if full {
+            TDEFLFlush::Full
+        } else {
+            TDEFLFlush::Sync
+        };
+        let mut deflated = vec![0; chunk.len() * 2 + 1024];
+        let (_, read, written) = compress(&mut compressor, chunk, &mut deflated, flush);
+        assert_eq!(read, chunk.len());
+        data.extend(&deflated[..written]);
+    }
+    if chunks.is_empty() {
+        data.extend(miniz_oxide::deflate::compress_to_vec(&[], 6));
+    }
+    data.write_u32::<LittleEndian>(crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(payload)).unwrap();
+    data.write_u32::<LittleEndian>(payload.len() as u32).unwrap();
+    (data, chunk_positions)
+}
+
+// Sorted lines which don't compress too well, so that a few MiB make many
+// deflate blocks
+pub fn large_vcf_payload(lines_count: u32) -> Vec<u8> {
+    let mut data = b"##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n".to_vec();
+    let mut random = 42u32;
+    for index in 0..lines_count {
+        random = random.wrapping_mul(1664525).wrapping_add(1013904223);
+        data.extend(format!("chr1\t{}\trs{}\t{}\t{}\t{}\tPASS\tDP={};AF={}\n", index * 100 + random % 97, random >> 8, ["A", "C", "G", "T"][(random >> 4) as usize % 4], ["A", "C", "G", "T"][(random >> 6) as usize % 4], random % 1000, random % 89, random % 997).as_bytes());
+    }
+    data
+}
+
+// Reads of 50 bases, whose qualities sometimes start with '@' like a header
+pub fn fastq_payload(reads_count: u32, mate: u8) -> Vec<u8> {
+    let mut data = vec![];
+    let mut random = 7u32;
+    for index in 0..reads_count {
+        let mut sequence = String::new();
+        let mut qualities = String::new();
+        for _ in 0..50 {
+            random = random.wrapping_mul(1664525).wrapping_add(1013904223);
+            sequence.push(['A', 'C', 'G', 'T'][(random >> 8) as usize % 4]);
+            qualities.push((b'5' + (random >> 16) as u8 % 12) as char);
+        }
+        data.extend(format!("@read{index}/{mate} sample=1\n{sequence}\n+\n{qualities}\n").as_bytes());
+    }
+    data
+}
+
+// Mapfile of a GNU ddrescue image of the given size, whose given regions
+// could not be read
+pub fn ddrescue_map(size: usize, unreadable_regions: &[(usize, usize)]) -> bamrescue::DdrescueMap {
+    let mut mapfile = "# Mapfile. Created by GNU ddrescue version 1.27\n# current_pos  current_status  current_pass\n0x00000000     +               1\n#      pos        size  status\n".to_owned();
+    let mut position = 0;
+    for &(start, end) in unreadable_regions {
+        if start > position {
+            mapfile += &format!("0x{:08X}  0x{:08X}  +\n", position, start - position);
+        }
+        mapfile += &format!("0x{:08X}  0x{:08X}  -\n", start, end - start);
+        position = end;
+    }
+    if size > position {
+        mapfile += &format!("0x{:08X}  0x{:08X}  +\n", position, size - position);
+    }
+    bamrescue::DdrescueMap::read(&mut mapfile.as_bytes()).unwrap()
+}
+
+// Fills the given regions with zeros, like ddrescue leaves the sectors it
+// could not read
+pub fn zero_regions(data: &mut [u8], regions: &[(usize, usize)]) {
+    for &(start, end) in regions {
+        data[start..end].fill(0);
+    }
+}
@@ -1,37 +1,118 @@
 mod common;
 
-use std::io::{
-    Cursor,
-    SeekFrom
+use std::{
+    cell::Cell,
+
+    io::{
+        Cursor,
+        Error,
+        Read,
+        Seek,
+        SeekFrom,
+        Write,
+    },
+
+    rc::Rc,
 };
 
+struct FullDisk;
+
+impl Write for FullDisk {
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::other("no space left on device"))
+    }
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// File in memory which stops being written once the bytes written to it and
+// to the other files sharing its budget reach that budget, like files being
+// written when the machine crashes
+#[derive(Clone)]
+struct CrashingFile {
+    cursor: Cursor<Vec<u8>>,
+    budget: Rc<Cell<Option<usize>>>,
+}
+
+impl CrashingFile {
+    fn new(bytes: Vec<u8>, budget: &Rc<Cell<Option<usize>>>) -> Self {
+        Self {
+            cursor: Cursor::new(bytes),
+            budget: Rc::clone(budget),
+        }
+    }
+}
+
+impl Read for CrashingFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.cursor.read(buf)
+    }
+}
+
+impl Seek for CrashingFile {
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, Error> {
+        self.cursor.seek(position)
+    }
+}
+
+impl Write for CrashingFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let size = match self.budget.get() {
+            Some(0) => return Err(Error::other("crashed")),
+            Some(budget) => {
+                self.budget.set(Some(budget.saturating_sub(buf.len())));
+                buf.len().min(budget)
+            },
+            None => buf.len(),
+        };
+        self.cursor.write(&buf[..size])
+    }
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl bamrescue::Durable for CrashingFile {
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+    fn set_len(&mut self, size: u64) -> Result<(), Error> {
+        if self.budget.get() == Some(0) {
+            return Err(Error::other("crashed"));
+        }
+        self.cursor.get_mut().resize(size as usize, 0u8);
+        Ok(())
+    }
+}
+
 fn rescue(reader: &mut dyn bamrescue::Rescuable, blocks_count: u64, bad_blocks_count: u64, truncated_in_block: bool, truncated_between_blocks: bool, rescued_bytes: Vec<u8>) {
     let mut writer = vec![];
     {
-        let results = bamrescue::rescue(reader, &mut writer, 1, &mut None);
+        let results = bamrescue::rescue(reader, &mut writer, bamrescue::RescueOptions::default(), 1, &mut None).unwrap();
         assert_eq!(results.blocks_count, blocks_count);
         assert_eq!(results.bad_blocks_count, bad_blocks_count);
         assert_eq!(results.truncated_in_block, truncated_in_block);
         assert_eq!(results.truncated_between_blocks, truncated_between_blocks);
         assert_eq!(writer, rescued_bytes);
-        let results = bamrescue::check(&mut Cursor::new(writer), true, 4, &mut None);
+        let results = bamrescue::check(&mut Cursor::new(writer), true, false, None, None, 4, &mut None).unwrap();
         assert_eq!(results.bad_blocks_count, 0);
-        assert_eq!(results.truncated_in_block, false);
-        assert_eq!(results.truncated_between_blocks, false);
+        assert!(!results.truncated_in_block);
+        assert!(!results.truncated_between_blocks);
     }
     reader.seek(SeekFrom::Start(0)).unwrap();
     writer = vec![];
     {
-        let results = bamrescue::rescue(reader, &mut writer, 4, &mut None);
+        let results = bamrescue::rescue(reader, &mut writer, bamrescue::RescueOptions::default(), 4, &mut None).unwrap();
         assert_eq!(results.blocks_count, blocks_count);
         assert_eq!(results.bad_blocks_count, bad_blocks_count);
         assert_eq!(results.truncated_in_block, truncated_in_block);
         assert_eq!(results.truncated_between_blocks, truncated_between_blocks);
         assert_eq!(writer, rescued_bytes);
-        let results = bamrescue::check(&mut Cursor::new(writer), true, 4, &mut None);
+        let results = bamrescue::check(&mut Cursor::new(writer), true, false, None, None, 4, &mut None).unwrap();
         assert_eq!(results.bad_blocks_count, 0);
-        assert_eq!(results.truncated_in_block, false);
-        assert_eq!(results.truncated_between_blocks, false);
+        assert!(!results.truncated_in_block);
+        assert!(!results.truncated_between_blocks);
     }
 }
 
@@ -97,12 +178,12 @@ fn three_blocks_empty_inside_bam() {
 
 #[test]
 fn single_block_missing_gzip_identifier() {
-    rescue(&mut common::single_block_missing_gzip_identifier_bam(), 2, 1, false, false, common::empty_bgzf_block())
+    rescue(&mut common::single_block_missing_gzip_identifier_bam(), 2, 0, false, false, common::single_block())
 }
 
 #[test]
 fn single_block_missing_bgzf_identifier() {
-    rescue(&mut common::single_block_missing_bgzf_identifier_bam(), 2, 1, false, false, common::empty_bgzf_block())
+    rescue(&mut common::single_block_missing_bgzf_identifier_bam(), 2, 0, false, false, common::single_block())
 }
 
 #[test]
@@ -122,7 +203,7 @@ fn three_blocks_bad_inflated_payload_crc32_bam() {
 
 #[test]
 fn three_blocks_bad_inflated_payload_size_bam() {
-    rescue(&mut common::three_blocks_bad_inflated_payload_size_bam(), 4, 1, false, false, common::two_blocks())
+    rescue(&mut common::three_blocks_bad_inflated_payload_size_bam(), 4, 0, false, false, common::three_blocks())
 }
 
 // TODO same tests as the two following ones, but with blocks of len >> 65536,
@@ -168,3 +249,932 @@ fn three_blocks_with_extra_similar_subfields_after_bam() {
 fn three_blocks_with_extra_similar_subfields_before_and_after_bam() {
     rescue(&mut common::three_blocks_with_extra_similar_subfields_before_and_after_bam(), 4, 0, false, false, common::three_blocks_with_extra_similar_subfields_before_and_after())
 }
+
+#[test]
+fn output_error() {
+    for threads in [1, 4] {
+        match bamrescue::rescue(&mut common::three_blocks_bam(), &mut FullDisk, bamrescue::RescueOptions::default(), threads, &mut None) {
+            Err(bamrescue::RescueError::Output(_)) => (),
+            _ => panic!("expected an output error"),
+        }
+    }
+}
+
+#[test]
+fn valid_bam() {
+    let data = common::bgzf(&common::bam_payload(), 100);
+    let blocks_count = common::bam_payload().len().div_ceil(100) as u64 + 1;
+    rescue(&mut Cursor::new(data.clone()), blocks_count, 0, false, false, data)
+}
+
+#[test]
+fn valid_bcf() {
+    let data = common::bgzf(&common::bcf_payload(), 100);
+    let blocks_count = common::bcf_payload().len().div_ceil(100) as u64 + 1;
+    rescue(&mut Cursor::new(data.clone()), blocks_count, 0, false, false, data)
+}
+
+fn records_bam_payload() -> Vec<u8> {
+    let mut data = common::bam_header(&[("chr1", 248956422), ("chrM", 16569)]);
+    for index in 0..40 {
+        data.extend(common::bam_record(index % 2, 1000 * index, &format!("read{}", index), &"ACGTTGCA".repeat(index as usize % 5 + 1)));
+    }
+    data
+}
+
+// Corrupts the payload of a block from common::bgzf(_, 100), so that its CRC32 does not match
+fn corrupt_block(data: &mut [u8], block_index: usize) {
+    data[block_index * 131 + 18 + 5 + 10] ^= 0x01;
+}
+
+fn resynchronized(data: Vec<u8>, salvage: bool, bad_blocks_count: u64) -> u64 {
+    let mut dropped_records_size = None;
+    for threads in [1, 4] {
+        let mut writer = vec![];
+        let results = bamrescue::rescue(&mut Cursor::new(data.clone()), &mut writer, bamrescue::RescueOptions { salvage, ..Default::default() }, threads, &mut None).unwrap();
+        assert_eq!(results.bad_blocks_count, bad_blocks_count);
+        let rescued_results = bamrescue::check(&mut Cursor::new(writer), false, true, None, None, threads, &mut None).unwrap();
+        assert_eq!(rescued_results.bad_blocks_count, 0);
+        assert!(!rescued_results.truncated_in_block);
+        assert!(!rescued_results.truncated_between_blocks);
+        assert!(rescued_results.format_errors.is_empty());
+        assert_eq!(rescued_results.blocks_size, results.blocks_size - results.bad_blocks_size + results.salvaged_size - results.dropped_records_size);
+        assert_eq!(*dropped_records_size.get_or_insert(results.dropped_records_size), results.dropped_records_size);
+    }
+    dropped_records_size.unwrap()
+}
+
+#[test]
+fn resync_after_bad_block() {
+    let mut data = common::bgzf(&records_bam_payload(), 100);
+    corrupt_block(&mut data, 5);
+    assert!(resynchronized(data, false, 1) > 0);
+}
+
+#[test]
+fn resync_after_consecutive_bad_blocks() {
+    let mut data = common::bgzf(&records_bam_payload(), 100);
+    corrupt_block(&mut data, 4);
+    corrupt_block(&mut data, 5);
+    corrupt_block(&mut data, 6);
+    assert!(resynchronized(data, false, 3) > 0);
+}
+
+#[test]
+fn resync_after_last_bad_block() {
+    let payload = records_bam_payload();
+    let mut data = common::bgzf(&payload, 100);
+    corrupt_block(&mut data, payload.len().div_ceil(100) - 1);
+    resynchronized(data, false, 1);
+}
+
+#[test]
+fn resync_after_truncation() {
+    let mut data = common::bgzf(&records_bam_payload(), 100);
+    data.truncate(7 * 131 + 50);
+    resynchronized(data, false, 0);
+}
+
+#[test]
+fn resync_not_in_records() {
+    let payload = records_bam_payload();
+    let mut data = common::bgzf(&payload, 100);
+    corrupt_block(&mut data, 0);
+    let mut writer = vec![];
+    let results = bamrescue::rescue(&mut Cursor::new(data), &mut writer, bamrescue::RescueOptions::default(), 1, &mut None).unwrap();
+    assert_eq!(results.bad_blocks_count, 1);
+    assert_eq!(results.dropped_records_size, 0);
+    assert_eq!(writer.len(), common::bgzf(&payload[100..], 100).len());
+}
+
+#[test]
+fn repaired_bad_extra_subfield_size() {
+    let mut data = common::three_blocks();
+    data[33 + 15] = 0x42;
+    rescue(&mut Cursor::new(data), 4, 0, false, false, common::three_blocks())
+}
+
+fn bitflips(data: Vec<u8>, double_bitflips: bool, bitflips: Vec<bamrescue::Bitflip>) {
+    for threads in [1, 4] {
+        let mut writer = vec![];
+        let results = bamrescue::rescue(&mut Cursor::new(data.clone()), &mut writer, bamrescue::RescueOptions { fix_bitflips: true, double_bitflips, ..Default::default() }, threads, &mut None).unwrap();
+        assert_eq!(results.bitflips, bitflips);
+        if bitflips.is_empty() {
+            assert_eq!(results.bad_blocks_count, 1);
+        } else {
+            assert_eq!(results.bad_blocks_count, 0);
+            assert_eq!(results.repaired_blocks_count, 1);
+            assert_eq!(writer, common::three_blocks());
+        }
+    }
+}
+
+#[test]
+fn bitflips_single() {
+    let mut data = common::three_blocks();
+    data[33 + 20] ^= 0x08;
+    bitflips(data, false, vec![
+        bamrescue::Bitflip { position: 33 + 20, bit: 3 },
+    ])
+}
+
+#[test]
+fn bitflips_double() {
+    let mut data = common::three_blocks();
+    data[33 + 20] ^= 0x40;
+    data[33 + 21] ^= 0x02;
+    bitflips(data.clone(), false, vec![]);
+    bitflips(data, true, vec![
+        bamrescue::Bitflip { position: 33 + 20, bit: 6 },
+        bamrescue::Bitflip { position: 33 + 21, bit: 1 },
+    ])
+}
+
+#[test]
+fn bitflips_disabled() {
+    let mut data = common::three_blocks();
+    data[33 + 20] ^= 0x08;
+    let mut writer = vec![];
+    let results = bamrescue::rescue(&mut Cursor::new(data), &mut writer, bamrescue::RescueOptions::default(), 1, &mut None).unwrap();
+    assert_eq!(results.bad_blocks_count, 1);
+    assert!(results.bitflips.is_empty());
+}
+
+// Makes a block from common::bgzf(_, 100) inflate only up to its 60th byte
+fn corrupt_block_after_prefix(data: &mut [u8], block_index: usize) {
+    let deflated_payload_position = block_index * 131 + 18;
+    // non-final stored deflate block of 60 bytes, followed by a deflate block of reserved type
+    data[deflated_payload_position..deflated_payload_position + 5].copy_from_slice(&[0x00, 60, 0x00, !60, 0xff]);
+    data[deflated_payload_position + 5 + 60] = 0x06;
+}
+
+#[test]
+fn salvage_prefix() {
+    let mut data = common::bgzf(&records_bam_payload(), 100);
+    corrupt_block_after_prefix(&mut data, 5);
+    let rescued_size = |salvage| {
+        let mut writer = vec![];
+        let results = bamrescue::rescue(&mut Cursor::new(data.clone()), &mut writer, bamrescue::RescueOptions { salvage, ..Default::default() }, 1, &mut None).unwrap();
+        assert_eq!(results.salvaged_size, if salvage { 60 } else { 0 });
+        bamrescue::check(&mut Cursor::new(writer), false, false, None, None, 1, &mut None).unwrap().blocks_size
+    };
+    assert!(rescued_size(true) >= rescued_size(false));
+    resynchronized(data, true, 1);
+}
+
+#[test]
+fn salvage_prefix_implausible_size() {
+    let mut data = common::bgzf(&records_bam_payload(), 100);
+    corrupt_block_after_prefix(&mut data, 5);
+    // ISIZE of the corrupted block
+    data[5 * 131 + 127..6 * 131].copy_from_slice(&[0xff; 4]);
+    let mut writer = vec![];
+    let results = bamrescue::rescue(&mut Cursor::new(data), &mut writer, bamrescue::RescueOptions { salvage: true, ..Default::default() }, 1, &mut None).unwrap();
+    assert_eq!(results.bad_blocks_count, 1);
+    assert_eq!(results.salvaged_size, 60);
+}
+
+#[test]
+fn salvage_disabled() {
+    let mut data = common::bgzf(&records_bam_payload(), 100);
+    corrupt_block_after_prefix(&mut data, 5);
+    let mut writer = vec![];
+    let results = bamrescue::rescue(&mut Cursor::new(data), &mut writer, bamrescue::RescueOptions::default(), 1, &mut None).unwrap();
+    assert_eq!(results.bad_blocks_count, 1);
+    assert_eq!(results.salvaged_size, 0);
+}
+
+#[test]
+fn salvage_not_bam() {
+    let mut data = common::bgzf(&[b'x'; 300], 100);
+    corrupt_block_after_prefix(&mut data, 1);
+    let mut writer = vec![];
+    let results = bamrescue::rescue(&mut Cursor::new(data), &mut writer, bamrescue::RescueOptions { salvage: true, ..Default::default() }, 1, &mut None).unwrap();
+    assert_eq!(results.salvaged_size, 60);
+    let rescued_results = bamrescue::check(&mut Cursor::new(writer), false, false, None, None, 1, &mut None).unwrap();
+    assert_eq!(rescued_results.bad_blocks_count, 0);
+    assert_eq!(rescued_results.blocks_size, 260);
+}
+
+#[test]
+fn rescue_reader() {
+    let payload = records_bam_payload();
+    let mut data = common::bgzf(&payload, 100);
+    corrupt_block(&mut data, 5);
+    corrupt_block(&mut data, 7);
+    for threads in [1, 4] {
+        let mut reader = bamrescue::RescueReader::new(Cursor::new(data.clone()), threads);
+        let mut rescued_payload = vec![];
+        reader.read_to_end(&mut rescued_payload).unwrap();
+        let mut expected_payload = payload[..500].to_vec();
+        expected_payload.extend(&payload[600..700]);
+        expected_payload.extend(&payload[800..]);
+        assert_eq!(rescued_payload, expected_payload);
+        assert_eq!(reader.skipped().iter().map(|bad_block| bad_block.inflated_position).collect::<Vec<_>>(), vec![500, 700]);
+        assert_eq!(reader.results().unwrap().bad_blocks_count, 2);
+        assert_eq!(reader.into_inner().into_inner(), data);
+    }
+}
+
+#[test]
+fn rescue_reader_dropped() {
+    let data = common::bgzf(&records_bam_payload(), 100);
+    let mut reader = bamrescue::RescueReader::new(Cursor::new(data.clone()), 1);
+    let mut buffer = [0u8; 10];
+    reader.read_exact(&mut buffer).unwrap();
+    assert!(reader.results().is_none());
+    assert_eq!(reader.into_inner().into_inner(), data);
+}
+
+#[test]
+fn rescue_streamed() {
+    let mut data = common::bgzf(&common::bam_payload().repeat(5000), 1000);
+    for position in [1000, data.len() / 2, data.len() - 1000] {
+        data[position] ^= 0x42;
+    }
+    data.truncate(data.len() - 42);
+    for threads in [1, 4] {
+        let mut expected_writer = vec![];
+        let expected_results = bamrescue::rescue(&mut Cursor::new(data.clone()), &mut expected_writer, bamrescue::RescueOptions { salvage: true, ..Default::default() }, threads, &mut None).unwrap();
+        let mut writer = vec![];
+        let results = bamrescue::rescue(&mut bamrescue::LookbackReader::new(&data[..]), &mut writer, bamrescue::RescueOptions { salvage: true, ..Default::default() }, threads, &mut None).unwrap();
+        assert_eq!(results.bad_blocks_count, expected_results.bad_blocks_count);
+        assert!(results.bad_blocks_count > 0);
+        assert!(results.truncated_in_block);
+        assert_eq!(writer, expected_writer);
+    }
+}
+
+fn sorted_bam_payload(references: &[(&str, i32)]) -> Vec<u8> {
+    let mut data = common::bam_header(references);
+    for index in 0..40 {
+        let (reference_id, position) = if index < 30 { (0, 20000 * index) } else { (1, 100 * index) };
+        data.extend(common::bam_record(reference_id, position, &format!("read{}", index), &"ACGTTGCA".repeat(index as usize % 5 + 1)));
+    }
+    data.extend(common::bam_record(-1, -1, "unplaced", "ACGT"));
+    data
+}
+
+#[test]
+fn rescue_index() {
+    let mut data = common::bgzf(&sorted_bam_payload(&[("chr1", 248956422), ("chrM", 16569)]), 100);
+    corrupt_block(&mut data, 5);
+    corrupt_block(&mut data, 7);
+    for threads in [1, 4] {
+        let mut writer = vec![];
+        let results = bamrescue::rescue(&mut Cursor::new(data.clone()), &mut writer, bamrescue::RescueOptions { index: true, ..Default::default() }, threads, &mut None).unwrap();
+        assert_eq!(results.bad_blocks_count, 2);
+        let index = results.index.unwrap();
+        assert_eq!(index.format, bamrescue::IndexFormat::Bai);
+        assert_eq!(&index.bytes[..8], b"BAI\x01\x02\x00\x00\x00");
+        // number of unplaced reads
+        assert_eq!(&index.bytes[index.bytes.len() - 8..], &[1, 0, 0, 0, 0, 0, 0, 0]);
+        let (_, index_results) = bamrescue::check_index(&mut Cursor::new(writer), &mut &index.bytes[..], threads, &mut None).unwrap();
+        assert_eq!(index_results.format, Some(bamrescue::IndexFormat::Bai));
+        assert!(index_results.virtual_offsets_count > 0);
+        assert_eq!(index_results.index_errors, vec![]);
+    }
+}
+
+#[test]
+fn rescue_index_csi() {
+    let data = common::bgzf(&sorted_bam_payload(&[("chr1", 1 << 30), ("chrM", 16569)]), 100);
+    let mut writer = vec![];
+    let results = bamrescue::rescue(&mut Cursor::new(data.clone()), &mut writer, bamrescue::RescueOptions { index: true, ..Default::default() }, 1, &mut None).unwrap();
+    assert_eq!(writer, data);
+    let index = results.index.unwrap();
+    assert_eq!(index.format, bamrescue::IndexFormat::Csi);
+    // csi is compressed
+    let mut reader = bamrescue::RescueReader::new(Cursor::new(index.bytes), 1);
+    let mut index_payload = vec![];
+    reader.read_to_end(&mut index_payload).unwrap();
+    assert_eq!(reader.results().unwrap().bad_blocks_count, 0);
+    // magic, minimum shift, depth, auxiliary data size and number of references
+    assert_eq!(&index_payload[..20], b"CSI\x01\x0e\x00\x00\x00\x06\x00\x00\x00\x00\x00\x00\x00\x02\x00\x00\x00");
+    let (_, index_results) = bamrescue::check_index(&mut Cursor::new(writer), &mut &reader.into_inner().into_inner()[..], 1, &mut None).unwrap();
+    assert_eq!(index_results.format, Some(bamrescue::IndexFormat::Csi));
+    assert!(index_results.virtual_offsets_count > 0);
+    assert_eq!(index_results.index_errors, vec![]);
+}
+
+#[test]
+fn rescue_index_unsorted() {
+    let data = common::bgzf(&records_bam_payload(), 100);
+    let mut writer = vec![];
+    match bamrescue::rescue(&mut Cursor::new(data.clone()), &mut writer, bamrescue::RescueOptions { index: true, ..Default::default() }, 1, &mut None) {
+        Err(bamrescue::RescueError::Index(cause)) => assert_eq!(cause, "not sorted by coordinate"),
+        _ => panic!("unsorted file indexed"),
+    }
+    assert_eq!(writer, data);
+}
+
+fn vcf_payload() -> Vec<u8> {
+    let mut data = b"##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n".to_vec();
+    for index in 0..40 {
+        data.extend(format!("chr1\t{}\trs{}\tA\tG\t50\tPASS\tDP={}\n", 1000 * index + 1, index, index % 7).as_bytes());
+    }
+    data
+}
+
+fn records_bcf_payload() -> Vec<u8> {
+    let mut data = common::bcf_header(&[("chr1", 248956422), ("chrM", 16569)], &["sample1", "sample2"]);
+    for index in 0..40 {
+        data.extend(common::bcf_record(index % 2, 1000 * index, &["A", &"C".repeat(index as usize % 5 + 1)], &[2, 4]));
+    }
+    data
+}
+
+#[test]
+fn resync_bcf_after_bad_block() {
+    let mut data = common::bgzf(&records_bcf_payload(), 100);
+    corrupt_block(&mut data, 5);
+    assert!(resynchronized(data.clone(), false, 1) > 0);
+    let (results, _) = rescued_payload(data, None);
+    assert_eq!(results.input_format, Some(bamrescue::InputFormat::Bcf));
+}
+
+#[test]
+fn resync_bcf_after_truncation() {
+    let mut data = common::bgzf(&records_bcf_payload(), 100);
+    data.truncate(7 * 131 + 50);
+    assert!(resynchronized(data, false, 0) > 0);
+}
+
+fn rescued_payload(data: Vec<u8>, input_format: Option<bamrescue::InputFormat>) -> (bamrescue::Results, Vec<u8>) {
+    let mut writer = vec![];
+    let results = bamrescue::rescue(&mut Cursor::new(data), &mut writer, bamrescue::RescueOptions { input_format, ..Default::default() }, 1, &mut None).unwrap();
+    let mut reader = bamrescue::RescueReader::new(Cursor::new(writer), 1);
+    let mut payload = vec![];
+    reader.read_to_end(&mut payload).unwrap();
+    assert_eq!(reader.results().unwrap().bad_blocks_count, 0);
+    (results, payload)
+}
+
+#[test]
+fn rescue_vcf_whole_lines() {
+    let payload = vcf_payload();
+    let mut data = common::bgzf(&payload, 100);
+    corrupt_block(&mut data, 5);
+    for input_format in [None, Some(bamrescue::InputFormat::Vcf)] {
+        let (results, rescued) = rescued_payload(data.clone(), input_format);
+        assert_eq!(results.input_format, Some(bamrescue::InputFormat::Vcf));
+        assert!(results.dropped_records_size > 0);
+        assert_eq!(rescued.len() as u64, results.blocks_size - results.bad_blocks_size - results.dropped_records_size);
+        let rescued_lines: Vec<&[u8]> = rescued.split_inclusive(|&byte| byte == b'\n').collect();
+        let lines: Vec<&[u8]> = payload.split_inclusive(|&byte| byte == b'\n').collect();
+        assert!(rescued_lines.len() < lines.len());
+        assert!(rescued_lines.iter().all(|line| lines.contains(line)));
+    }
+}
+
+#[test]
+fn rescue_vcf_after_truncation() {
+    let mut data = common::bgzf(&vcf_payload(), 100);
+    data.truncate(7 * 131 + 50);
+    let (_, rescued) = rescued_payload(data, None);
+    assert_eq!(rescued.last(), Some(&b'\n'));
+}
+
+#[test]
+fn rescue_text_without_final_newline() {
+    for payload in [&b">chr1\nACGT\nACGT"[..], b"chr1\t100\t200\nchr1\t300\t400"] {
+        let data = common::bgzf(payload, 4);
+        let mut writer = vec![];
+        let results = bamrescue::rescue(&mut Cursor::new(data.clone()), &mut writer, bamrescue::RescueOptions::default(), 1, &mut None).unwrap();
+        assert_eq!(results.dropped_records_size, 0);
+        assert_eq!(writer, data);
+    }
+}
+
+#[test]
+fn rescue_index_vcf() {
+    let mut data = common::bgzf(&vcf_payload(), 100);
+    corrupt_block(&mut data, 5);
+    let mut writer = vec![];
+    let results = bamrescue::rescue(&mut Cursor::new(data), &mut writer, bamrescue::RescueOptions { index: true, ..Default::default() }, 1, &mut None).unwrap();
+    let index = results.index.unwrap();
+    assert_eq!(index.format, bamrescue::IndexFormat::Tbi);
+    let (_, index_results) = bamrescue::check_index(&mut Cursor::new(writer.clone()), &mut &index.bytes[..], 1, &mut None).unwrap();
+    assert_eq!(index_results.format, Some(bamrescue::IndexFormat::Tbi));
+    assert_eq!(index_results.index_errors, vec![]);
+    // same as indexing the rescued file again
+    let (_, regenerated_index) = bamrescue::tabix(&mut Cursor::new(writer), None, 1, &mut None).unwrap();
+    assert_eq!(regenerated_index.bytes, index.bytes);
+}
+
+fn rescued_cram(data: Vec<u8>) -> (bamrescue::Results, Vec<u8>) {
+    let mut writer = vec![];
+    let results = bamrescue::rescue(&mut Cursor::new(data), &mut writer, bamrescue::RescueOptions::default(), 1, &mut None).unwrap();
+    let rescued_results = bamrescue::check(&mut Cursor::new(writer.clone()), false, false, None, None, 1, &mut None).unwrap();
+    assert_eq!(rescued_results.input_format, Some(bamrescue::InputFormat::Cram));
+    assert_eq!(rescued_results.bad_blocks_count, 0);
+    assert!(!rescued_results.truncated_in_block);
+    assert!(!rescued_results.truncated_between_blocks);
+    (results, writer)
+}
+
+#[test]
+fn rescue_valid_cram() {
+    let data = common::cram(&common::cram_containers());
+    let (_, rescued) = rescued_cram(data.clone());
+    assert_eq!(rescued, data);
+}
+
+#[test]
+fn rescue_cram_drops_bad_containers() {
+    let mut containers = common::cram_containers();
+    let mut data = common::cram(&containers);
+    let position = data.len() - common::cram_eof_container().len() - containers[5].len() - containers[4].len();
+    // header of the fifth container, and data of the sixth one
+    data[position + 2] ^= 0x01;
+    data[position + containers[4].len() + 100] ^= 0x01;
+    let (results, rescued) = rescued_cram(data);
+    assert_eq!(results.bad_blocks_count, 2);
+    containers.truncate(4);
+    assert_eq!(rescued, common::cram(&containers));
+}
+
+#[test]
+fn rescue_cram_adds_eof_container() {
+    let containers = common::cram_containers();
+    let data = common::cram(&containers);
+    let (results, rescued) = rescued_cram(data[..data.len() - common::cram_eof_container().len() - 10].to_vec());
+    assert!(results.truncated_in_block);
+    assert_eq!(rescued, common::cram(&containers[..5]));
+}
+
+#[test]
+fn rescue_cram_index() {
+    let data = common::cram(&common::cram_containers());
+    let mut writer = vec![];
+    match bamrescue::rescue(&mut Cursor::new(data.clone()), &mut writer, bamrescue::RescueOptions { index: true, ..Default::default() }, 1, &mut None) {
+        Err(bamrescue::RescueError::Index(_)) => (),
+        _ => panic!("expected an index error"),
+    }
+    assert_eq!(writer, data);
+}
+
+fn rescued_lines(payload: &[u8], rescued: &[u8]) -> usize {
+    let lines: Vec<&[u8]> = payload.split_inclusive(|&byte| byte == b'\n').collect();
+    let rescued_lines: Vec<&[u8]> = rescued.split_inclusive(|&byte| byte == b'\n').collect();
+    assert!(rescued_lines.iter().all(|line| lines.contains(line)));
+    rescued_lines.len()
+}
+
+#[test]
+fn rescue_plain_gzip() {
+    let payload = common::large_vcf_payload(5000);
+    let mut data = common::gzip_member(&payload[..100000]);
+    data.extend(common::gzip_member(&payload[100000..]));
+    let (results, rescued) = rescued_payload(data, None);
+    assert!(results.plain_gzip);
+    assert_eq!(rescued, payload);
+}
+
+#[test]
+fn rescue_plain_gzip_resync_in_member() {
+    let payload = common::large_vcf_payload(5000);
+    let (mut data, chunk_positions) = common::gzip_member_with_flushes(&payload, 20000, true);
+    // reserved block type
+    data[chunk_positions[4]] |= 0x06;
+    let (results, rescued) = rescued_payload(data, None);
+    assert_eq!(results.bad_blocks_count, 1);
+    assert_eq!(results.bad_blocks_size, 20000);
+    assert_eq!(rescued.len() as u64, results.blocks_size - results.bad_blocks_size - results.dropped_records_size);
+    assert_eq!(&rescued[..80000 - results.dropped_records_size as usize], &payload[..80000 - results.dropped_records_size as usize]);
+    // only the lines cut by the corrupted chunk are lost
+    let chunk_lines = payload[80000..100000].iter().filter(|&&byte| byte == b'\n').count();
+    assert!(rescued_lines(&payload, &rescued) + chunk_lines + 2 >= rescued_lines(&payload, &payload));
+}
+
+#[test]
+fn rescue_plain_gzip_bad_header() {
+    let payload = common::large_vcf_payload(5000);
+    let mut data = common::gzip_member(&payload[..100000]);
+    let position = data.len();
+    data.extend(common::gzip_member(&payload[100000..]));
+    data[position] = 0x00;
+    let (results, rescued) = rescued_payload(data, None);
+    assert_eq!(results.bad_blocks_count, 1);
+    assert_eq!(rescued.len() as u64, payload.len() as u64 - results.dropped_records_size);
+    rescued_lines(&payload, &rescued);
+}
+
+#[test]
+fn rescue_plain_gzip_bad_crc32() {
+    let payload = common::large_vcf_payload(5000);
+    let mut data = common::gzip_member(&payload[..100000]);
+    data.extend(common::gzip_member(&payload[100000..200000]));
+    let position = data.len();
+    data.extend(common::gzip_member(&payload[200000..]));
+    data[position - 8] ^= 0x01;
+    let (results, rescued) = rescued_payload(data, None);
+    assert_eq!(results.bad_blocks_count, 1);
+    assert_eq!(results.bad_blocks_size, 100000);
+    assert_eq!(rescued.len() as u64, results.blocks_size - results.bad_blocks_size - results.dropped_records_size);
+    assert_eq!(&rescued[..100000 - results.dropped_records_size as usize], &payload[..100000 - results.dropped_records_size as usize]);
+    assert!(rescued.ends_with(&payload[payload.len() - 1000..]));
+    rescued_lines(&payload, &rescued);
+}
+
+#[test]
+fn rescue_plain_gzip_lost_references() {
+    let payload = common::large_vcf_payload(5000);
+    let (mut data, chunk_positions) = common::gzip_member_with_flushes(&payload, 20000, false);
+    data[chunk_positions[4]] |= 0x06;
+    let (results, rescued) = rescued_payload(data, None);
+    assert_eq!(results.bad_blocks_count, 1);
+    // what follows also copies from the payload lost
+    assert!(results.bad_blocks_size > 20000);
+    assert_eq!(results.blocks_size, payload.len() as u64);
+    assert_eq!(rescued.len() as u64, results.blocks_size - results.bad_blocks_size - results.dropped_records_size);
+    rescued_lines(&payload, &rescued);
+}
+
+#[test]
+fn rescue_plain_gzip_index() {
+    let payload = common::large_vcf_payload(5000);
+    let mut writer = vec![];
+    let results = bamrescue::rescue(&mut Cursor::new(common::gzip_member(&payload)), &mut writer, bamrescue::RescueOptions { index: true, ..Default::default() }, 1, &mut None).unwrap();
+    assert!(results.index.is_some());
+}
+
+fn rescued_reads(payload: &[u8], rescued: &[u8]) -> Vec<Vec<u8>> {
+    let lines: Vec<&[u8]> = payload.split_inclusive(|&byte| byte == b'\n').collect();
+    let reads: Vec<Vec<u8>> = lines.chunks(4).map(|read| read.concat()).collect();
+    let rescued_lines: Vec<&[u8]> = rescued.split_inclusive(|&byte| byte == b'\n').collect();
+    assert_eq!(rescued_lines.len() % 4, 0);
+    let rescued_reads: Vec<Vec<u8>> = rescued_lines.chunks(4).map(|read| read.concat()).collect();
+    assert!(rescued_reads.iter().all(|read| reads.contains(read)));
+    rescued_reads
+}
+
+#[test]
+fn rescue_fastq_whole_records() {
+    let payload = common::fastq_payload(40, 1);
+    let mut data = common::bgzf(&payload, 100);
+    corrupt_block(&mut data, 5);
+    for input_format in [None, Some(bamrescue::InputFormat::Fastq)] {
+        let (results, rescued) = rescued_payload(data.clone(), input_format);
+        assert_eq!(results.input_format, Some(bamrescue::InputFormat::Fastq));
+        assert!(results.dropped_records_size > 0);
+        assert_eq!(rescued.len() as u64, results.blocks_size - results.bad_blocks_size - results.dropped_records_size);
+        assert_eq!(rescued_reads(&payload, &rescued).len(), 39);
+    }
+}
+
+#[test]
+fn rescue_fastq_without_final_newline() {
+    let payload = &common::fastq_payload(3, 1)[..];
+    let payload = &payload[..payload.len() - 1];
+    let data = common::bgzf(payload, 40);
+    let mut writer = vec![];
+    let results = bamrescue::rescue(&mut Cursor::new(data.clone()), &mut writer, bamrescue::RescueOptions::default(), 1, &mut None).unwrap();
+    assert_eq!(results.dropped_records_size, 0);
+    assert_eq!(writer, data);
+}
+
+#[test]
+fn rescue_plain_gzip_fastq() {
+    let payload = common::fastq_payload(2000, 1);
+    let (mut data, chunk_positions) = common::gzip_member_with_flushes(&payload, 20000, true);
+    // reserved block type
+    data[chunk_positions[4]] |= 0x06;
+    let (results, rescued) = rescued_payload(data, None);
+    assert_eq!(results.input_format, Some(bamrescue::InputFormat::Fastq));
+    assert_eq!(results.bad_blocks_size, 20000);
+    let rescued_reads = rescued_reads(&payload, &rescued);
+    assert!(rescued_reads.len() < 2000);
+    // the reads cut by the lost deflate block, and those at its edges
+    assert!(rescued_reads.len() >= 2000 - 20000 / (payload.len() / 2000) - 2);
+}
+
+#[test]
+fn pair_fastq() {
+    let payload = common::fastq_payload(40, 1);
+    let mate_payload = common::fastq_payload(40, 2);
+    let mut data = common::bgzf(&payload, 100);
+    corrupt_block(&mut data, 5);
+    let mut mate_data = common::bgzf(&mate_payload, 100);
+    corrupt_block(&mut mate_data, 20);
+    let (_, rescued) = rescued_payload(data, None);
+    let (_, mate_rescued) = rescued_payload(mate_data, None);
+    let mut writer = vec![];
+    let mut mate_writer = vec![];
+    let pair_results = bamrescue::pair(&mut Cursor::new(&rescued), &mut Cursor::new(&mate_rescued), &mut writer, &mut mate_writer).unwrap();
+    assert_eq!(pair_results.pairs_count, 37);
+    assert_eq!(pair_results.unpaired_count, 2);
+    assert_eq!(pair_results.mate_unpaired_count, 1);
+    let (_, paired) = rescued_payload(writer, None);
+    let (_, mate_paired) = rescued_payload(mate_writer, None);
+    let names = |reads: Vec<Vec<u8>>| -> Vec<Vec<u8>> {
+        reads.iter().map(|read| read.split(|&byte| byte == b'/').next().unwrap().to_vec()).collect()
+    };
+    let paired_names = names(rescued_reads(&payload, &paired));
+    assert_eq!(paired_names.len(), 37);
+    assert_eq!(paired_names, names(rescued_reads(&mate_payload, &mate_paired)));
+}
+
+#[test]
+fn pair_fastq_skips_malformed_lines() {
+    let payload = common::fastq_payload(10, 1);
+    let mate_payload = common::fastq_payload(10, 2);
+    let mut garbled = payload[..3 * 122].to_vec();
+    garbled.extend(b"@read3/1\nAC#GT\n@read4\n");
+    garbled.extend(&payload[4 * 122..]);
+    let mut writer = vec![];
+    let mut mate_writer = vec![];
+    let pair_results = bamrescue::pair(&mut Cursor::new(&garbled), &mut Cursor::new(&mate_payload), &mut writer, &mut mate_writer).unwrap();
+    assert_eq!(pair_results, bamrescue::PairResults {
+        pairs_count: 9,
+        unpaired_count: 0,
+        mate_unpaired_count: 1,
+        skipped_lines_count: 3,
+    });
+}
+
+#[test]
+fn pair_fastq_unmatched_names() {
+    let payload = common::fastq_payload(20000, 1);
+    let mate_payload = String::from_utf8(common::fastq_payload(20000, 2)).unwrap().replace("@read", "@other");
+    let mut writer = vec![];
+    let mut mate_writer = vec![];
+    let pair_results = bamrescue::pair(&mut Cursor::new(&payload), &mut Cursor::new(&mate_payload), &mut writer, &mut mate_writer).unwrap();
+    assert_eq!(pair_results, bamrescue::PairResults {
+        pairs_count: 0,
+        unpaired_count: 20000,
+        mate_unpaired_count: 20000,
+        skipped_lines_count: 0,
+    });
+}
+
+fn merged(replicas: &[Vec<u8>]) -> Result<(bamrescue::Results, bamrescue::MergeResults, Vec<u8>), bamrescue::RescueError> {
+    let mut cursors: Vec<Cursor<Vec<u8>>> = replicas.iter().cloned().map(Cursor::new).collect();
+    let mut replicas: Vec<&mut dyn bamrescue::Rescuable> = cursors.iter_mut().map(|cursor| cursor as &mut dyn bamrescue::Rescuable).collect();
+    let mut writer = vec![];
+    let (results, merge_results) = bamrescue::merge_replicas(&mut replicas, &mut writer, false, None, 1, &mut None)?;
+    Ok((results, merge_results, writer))
+}
+
+#[test]
+fn merge_replicas_corrupted_in_different_blocks() {
+    let data = common::bgzf(&vcf_payload(), 100);
+    let mut first = data.clone();
+    corrupt_block(&mut first, 5);
+    corrupt_block(&mut first, 10);
+    let mut second = data.clone();
+    corrupt_block(&mut second, 5);
+    let mut third = data.clone();
+    corrupt_block(&mut third, 12);
+    let (results, merge_results, writer) = merged(&[first, second, third]).unwrap();
+    assert_eq!(results.bad_blocks_count, 0);
+    assert_eq!(merge_results.replica_blocks_counts, vec![results.blocks_count - 2, 1, 1]);
+    assert_eq!(writer, data);
+}
+
+#[test]
+fn merge_replicas_corrupted_in_all() {
+    let payload = vcf_payload();
+    let data = common::bgzf(&payload, 100);
+    let mut first = data.clone();
+    corrupt_block(&mut first, 5);
+    corrupt_block(&mut first, 8);
+    let mut second = data.clone();
+    corrupt_block(&mut second, 5);
+    let (results, merge_results, writer) = merged(&[first, second]).unwrap();
+    assert_eq!(results.bad_blocks_count, 1);
+    assert_eq!(results.bad_blocks[0].position, 5 * 131);
+    assert_eq!(merge_results.replica_blocks_counts[1], 1);
+    let mut reader = bamrescue::RescueReader::new(Cursor::new(writer), 1);
+    let mut rescued = vec![];
+    reader.read_to_end(&mut rescued).unwrap();
+    assert_eq!(rescued.len() as u64, results.blocks_size - results.bad_blocks_size - results.dropped_records_size);
+    assert!(rescued_lines(&payload, &rescued) < payload.split_inclusive(|&byte| byte == b'\n').count());
+}
+
+#[test]
+fn merge_replicas_truncated() {
+    let data = common::bgzf(&vcf_payload(), 100);
+    let mut truncated = data.clone();
+    truncated.truncate(10 * 131 + 50);
+    let (results, merge_results, writer) = merged(&[truncated, data.clone()]).unwrap();
+    assert!(!results.truncated_in_block);
+    assert_eq!(merge_results.replica_blocks_counts[0], 10);
+    assert_eq!(writer, data);
+}
+
+#[test]
+fn merge_replicas_different_layouts() {
+    let payload = vcf_payload();
+    assert!(matches!(merged(&[common::bgzf(&payload, 100), common::bgzf(&payload, 200)]), Err(bamrescue::RescueError::Input(_))));
+}
+
+fn rescued_with_ddrescue_map(data: &[u8], regions: &[(usize, usize)], bad_blocks_count: u64) -> bamrescue::Results {
+    let mut data = data.to_vec();
+    common::zero_regions(&mut data, regions);
+    let ddrescue_map = common::ddrescue_map(data.len(), regions);
+    let mut rescued_results = vec![];
+    for threads in [1, 4] {
+        let mut writer = vec![];
+        let results = bamrescue::rescue(&mut Cursor::new(data.clone()), &mut writer, bamrescue::RescueOptions { ddrescue_map: Some(&ddrescue_map), ..Default::default() }, threads, &mut None).unwrap();
+        assert_eq!(results.bad_blocks_count, bad_blocks_count);
+        assert_eq!(results.unreadable_blocks_count, bad_blocks_count);
+        let checked_results = bamrescue::check(&mut Cursor::new(writer), false, true, None, None, threads, &mut None).unwrap();
+        assert_eq!(checked_results.bad_blocks_count, 0);
+        assert!(!checked_results.truncated_in_block);
+        assert!(!checked_results.truncated_between_blocks);
+        assert!(checked_results.format_errors.is_empty());
+        assert_eq!(checked_results.blocks_size, results.blocks_size - results.bad_blocks_size - results.dropped_records_size);
+        rescued_results.push(results);
+    }
+    rescued_results.pop().unwrap()
+}
+
+#[test]
+fn rescue_unreadable_blocks() {
+    let data = common::bgzf(&records_bam_payload(), 100);
+    // from the payload of a block to the payload of the second next one
+    let results = rescued_with_ddrescue_map(&data, &[(4 * 131 + 20, 6 * 131 + 40)], 2);
+    assert_eq!(results.bad_blocks[0].position, 4 * 131);
+    assert_eq!(results.bad_blocks[1].position, 5 * 131);
+    assert_eq!(results.bad_blocks[1].size, 2 * 131);
+    assert!(results.dropped_records_size > 0);
+}
+
+#[test]
+fn rescue_unreadable_end() {
+    let payload = records_bam_payload();
+    let data = common::bgzf(&payload, 100);
+    // the end-of-file marker is lost as well, and written again
+    let last_block_position = (payload.len().div_ceil(100) - 1) * 131;
+    let results = rescued_with_ddrescue_map(&data, &[(last_block_position + 30, data.len())], 2);
+    assert_eq!(results.bad_blocks[1].position + results.bad_blocks[1].size, data.len() as u64);
+}
+
+fn patched(data: &[u8], donor: &[u8]) -> (bamrescue::Results, bamrescue::PatchResults, Vec<u8>) {
+    let mut file = Cursor::new(data.to_vec());
+    let (results, patch_results) = bamrescue::patch(&mut file, &mut Cursor::new(donor.to_vec()), None, 1, &mut None).unwrap();
+    (results, patch_results, file.into_inner())
+}
+
+#[test]
+fn patch_from_donor() {
+    let data = common::bgzf(&vcf_payload(), 100);
+    let mut file = data.clone();
+    corrupt_block(&mut file, 5);
+    corrupt_block(&mut file, 6);
+    // the block is longer than that of the donor
+    file[8 * 131 + 16] ^= 0x40;
+    corrupt_block(&mut file, 10);
+    let mut donor = data.clone();
+    corrupt_block(&mut donor, 10);
+    let (results, patch_results, patched_file) = patched(&file, &donor);
+    assert_eq!(results.bad_blocks_count, 4);
+    assert_eq!(patch_results, bamrescue::PatchResults {
+        patched_ranges_count: 1,
+        patched_size: 2 * 131,
+        unpatched_ranges_count: 2,
+    });
+    assert_eq!(patched_file[..8 * 131], data[..8 * 131]);
+    assert_eq!(patched_file[8 * 131..], file[8 * 131..]);
+}
+
+#[test]
+fn patch_many_blocks() {
+    let data = common::bgzf(&vcf_payload(), 100);
+    let mut file = data.clone();
+    for block_index in 2..12 {
+        corrupt_block(&mut file, block_index);
+    }
+    let (_, patch_results, patched_file) = patched(&file, &data);
+    assert_eq!(patch_results.patched_ranges_count, 1);
+    assert_eq!(patch_results.patched_size, 10 * 131);
+    assert_eq!(patched_file, data);
+    // a single corrupted block of the donor is enough to leave it all
+    let mut donor = data.clone();
+    corrupt_block(&mut donor, 11);
+    let (_, patch_results, patched_file) = patched(&file, &donor);
+    assert_eq!(patch_results.unpatched_ranges_count, 1);
+    assert_eq!(patched_file, file);
+}
+
+#[test]
+fn patch_from_different_layout() {
+    let payload = vcf_payload();
+    let mut file = common::bgzf(&payload, 100);
+    corrupt_block(&mut file, 5);
+    let (_, patch_results, patched_file) = patched(&file, &common::bgzf(&payload, 200));
+    assert_eq!(patch_results.patched_ranges_count, 0);
+    assert_eq!(patch_results.unpatched_ranges_count, 1);
+    assert_eq!(patched_file, file);
+}
+
+#[test]
+fn patch_from_truncated_donor() {
+    let data = common::bgzf(&vcf_payload(), 100);
+    let mut file = data.clone();
+    corrupt_block(&mut file, 10);
+    let (_, patch_results, patched_file) = patched(&file, &data[..10 * 131 + 50]);
+    assert_eq!(patch_results.unpatched_ranges_count, 1);
+    assert_eq!(patched_file, file);
+}
+
+#[test]
+fn patch_truncated_file() {
+    let data = common::bgzf(&vcf_payload(), 100);
+    // the file can't grow to the size of the donor
+    let (results, patch_results, patched_file) = patched(&data[..10 * 131 + 50], &data);
+    assert!(results.truncated_in_block);
+    assert_eq!(patch_results.unpatched_ranges_count, 1);
+    assert_eq!(patched_file, data[..10 * 131 + 50]);
+}
+
+fn rescued_in_place(data: &[u8], budget: Option<usize>) -> (Result<bamrescue::Results, bamrescue::RescueError>, CrashingFile, CrashingFile) {
+    let budget = Rc::new(Cell::new(budget));
+    let mut file = CrashingFile::new(data.to_vec(), &budget);
+    let mut journal = CrashingFile::new(vec![], &budget);
+    let results = bamrescue::rescue_in_place(&mut file, &mut journal, bamrescue::RescueOptions::default(), 1, &mut None);
+    budget.set(None);
+    (results, file, journal)
+}
+
+fn corrupted_bam() -> Vec<u8> {
+    let mut data = common::bgzf(&records_bam_payload(), 100);
+    corrupt_block(&mut data, 4);
+    corrupt_block(&mut data, 9);
+    data
+}
+
+#[test]
+fn rescue_in_place() {
+    for data in [corrupted_bam(), common::bgzf(&records_bam_payload(), 100)] {
+        let mut rescued = vec![];
+        let expected_results = bamrescue::rescue(&mut Cursor::new(data.clone()), &mut rescued, bamrescue::RescueOptions::default(), 1, &mut None).unwrap();
+        let (results, file, _) = rescued_in_place(&data, None);
+        let results = results.unwrap();
+        assert_eq!(results.bad_blocks, expected_results.bad_blocks);
+        assert_eq!(results.dropped_records_size, expected_results.dropped_records_size);
+        assert_eq!(file.cursor.into_inner(), rescued);
+    }
+}
+
+#[test]
+fn rescue_in_place_missing_marker() {
+    let data = common::bgzf(&records_bam_payload(), 100);
+    let (results, file, _) = rescued_in_place(&data[..data.len() - 28], None);
+    assert!(results.unwrap().truncated_between_blocks);
+    assert_eq!(file.cursor.into_inner(), data);
+}
+
+#[test]
+fn rescue_in_place_not_bgzf() {
+    let (results, file, journal) = rescued_in_place(&common::cram(&common::cram_containers()), None);
+    assert!(matches!(results, Err(bamrescue::RescueError::Input(_))));
+    assert!(journal.cursor.into_inner().is_empty());
+    assert_eq!(file.cursor.into_inner(), common::cram(&common::cram_containers()));
+}
+
+#[test]
+fn rescue_in_place_interrupted() {
+    let data = corrupted_bam();
+    let mut rescued = vec![];
+    bamrescue::rescue(&mut Cursor::new(data.clone()), &mut rescued, bamrescue::RescueOptions::default(), 1, &mut None).unwrap();
+    let mut crashes_count = 0;
+    for budget in (0..).step_by(53) {
+        let (results, file, journal) = rescued_in_place(&data, Some(budget));
+        if results.is_ok() {
+            break;
+        }
+        crashes_count += 1;
+        let (mut resumed_file, mut resumed_journal) = (file.clone(), journal.clone());
+        // unless it crashed before the journal was complete, in which case
+        // the file was not modified
+        let rolled_back = bamrescue::resume_in_place(&mut resumed_file, &mut resumed_journal, false).unwrap();
+        assert_eq!(&resumed_file.cursor.into_inner(), if rolled_back { &data } else { &rescued });
+        let (mut rolled_back_file, mut rolled_back_journal) = (file, journal);
+        assert!(bamrescue::resume_in_place(&mut rolled_back_file, &mut rolled_back_journal, true).unwrap());
+        assert_eq!(rolled_back_file.cursor.into_inner(), data);
+    }
+    assert!(crashes_count > 10);
+}
+
+#[test]
+fn rescue_in_place_rollback_interrupted() {
+    let data = corrupted_bam();
+    let mut rescued = vec![];
+    bamrescue::rescue(&mut Cursor::new(data.clone()), &mut rescued, bamrescue::RescueOptions::default(), 1, &mut None).unwrap();
+    let (results, file, journal) = rescued_in_place(&data, Some(2000));
+    assert!(results.is_err());
+    for budget in (0..).step_by(29) {
+        let budget = Rc::new(Cell::new(Some(budget)));
+        let mut rolled_back_file = CrashingFile::new(file.cursor.get_ref().clone(), &budget);
+        let mut rolled_back_journal = CrashingFile::new(journal.cursor.get_ref().clone(), &budget);
+        if bamrescue::resume_in_place(&mut rolled_back_file, &mut rolled_back_journal, true).is_ok() {
+            break;
+        }
+        budget.set(None);
+        // going on with the rollback once started, even if not asked to
+        let rolled_back = bamrescue::resume_in_place(&mut rolled_back_file, &mut rolled_back_journal, false).unwrap();
+        assert_eq!(&rolled_back_file.cursor.into_inner(), if rolled_back { &data } else { &rescued });
+    }
+}